@@ -1,6 +1,9 @@
 local utils = require("mp.utils")
 local options = require("mp.options")
 
+-- Kept in step with the version in Cargo.toml; the server compares the two
+local SCRIPT_VERSION = "0.1.2"
+
 local opts = {
   -- Either adjust settings here OR in script-opts/mpv-subtitleminer.conf
   -- ========== SETTINGS ==========
@@ -8,6 +11,9 @@ local opts = {
   ports = { 61777, 61778, 61779, 61780, 61781 },
   -- When true (default), starts the mpv-subtitleminer server automatically on mpv startup.
   auto_start = true,
+  -- Extra arguments for the server, e.g. "--anki --deck Mining --audio-field Audio"
+  -- to let Ctrl+e mine the current line without the browser.
+  server_args = "",
   -- ==============================
 }
 
@@ -166,6 +172,27 @@ local function get_mpv_pid()
   return nil
 end
 
+-- Where the server writes the port it actually listens on, which is the
+-- next free one when the configured port is taken
+local function discovery_file_path()
+  local dir = os.getenv("XDG_RUNTIME_DIR") or os.getenv("TEMP") or os.getenv("TMPDIR") or "/tmp"
+  return utils.join_path(dir, "mpv-subtitleminer-" .. tostring(get_mpv_pid() or "mpv") .. ".json")
+end
+
+local function read_discovered_port(path)
+  local file = io.open(path, "r")
+  if not file then
+    return nil
+  end
+  local content = file:read("*a")
+  file:close()
+  local info = utils.parse_json(content)
+  if type(info) == "table" and type(info.port) == "number" then
+    return info.port
+  end
+  return nil
+end
+
 local function try_start_on_port(port_index)
   if port_index > #opts.ports then
     mp.msg.error("Failed to start server on any port")
@@ -177,7 +204,7 @@ local function try_start_on_port(port_index)
   mp.msg.info("Trying to start server on port " .. port .. "...")
   current_port = port
 
-  local args = { binary_path, mpv_socket, tostring(port), ffmpeg_path }
+  local args = { binary_path, "serve", mpv_socket, tostring(port), ffmpeg_path }
   local mpv_pid = get_mpv_pid()
   if mpv_pid then
     table.insert(args, "--expected-mpv-pid")
@@ -185,6 +212,13 @@ local function try_start_on_port(port_index)
   else
     mp.msg.warn("Could not determine mpv PID; instance validation disabled")
   end
+  local discovery_file = discovery_file_path()
+  os.remove(discovery_file)
+  table.insert(args, "--discovery-file")
+  table.insert(args, discovery_file)
+  for arg in opts.server_args:gmatch("%S+") do
+    table.insert(args, arg)
+  end
 
   server_process = mp.command_native_async({
     name = "subprocess",
@@ -229,6 +263,13 @@ local function try_start_on_port(port_index)
       return
     end
 
+    if stderr_text:find("is already serving") then
+      mp.msg.error("Another mpv-subtitleminer is already serving " .. mpv_socket)
+      mp.osd_message("[mpv-subtitleminer]: another miner is already serving this mpv", 6)
+      current_port = nil
+      return
+    end
+
     if stderr_text:find("Failed to connect to mpv socket") or stderr_text:find("Failed to connect to mpv pipe") then
       mp.msg.error("Could not connect to mpv IPC socket at: " .. mpv_socket)
       mp.osd_message("[mpv-subtitleminer]: can't connect to mpv IPC socket\n" .. mpv_socket, 6)
@@ -264,6 +305,8 @@ local function try_start_on_port(port_index)
       startup_timer = nil
       if server_process then
         server_running = true
+        port = read_discovered_port(discovery_file) or port
+        current_port = port
         mp.msg.info("Server confirmed running on port " .. port)
         mp.osd_message("[mpv-subtitleminer] server started on port " .. port, 3)
       end
@@ -318,6 +361,25 @@ end
 mp.register_script_message("toggle-subtitleminer", toggle_server)
 mp.add_key_binding("Ctrl+a", "toggle-subtitleminer", toggle_server)
 
+-- The server listens for this on the IPC socket and mines the current line
+mp.add_key_binding("Ctrl+e", "mine-current-line", function()
+  if not server_running then
+    mp.osd_message("[mpv-subtitleminer] Server not running", 2)
+    return
+  end
+  mp.commandv("script-message", "subtitleminer-mine")
+end)
+
+-- The server sends its version once connected; answer with ours so a
+-- script left over from an older release is reported
+mp.register_script_message("subtitleminer-hello", function(server_version)
+  mp.commandv("script-message", "subtitleminer-script-version", SCRIPT_VERSION)
+  if server_version ~= SCRIPT_VERSION then
+    mp.msg.warn("Script version " .. SCRIPT_VERSION .. " does not match server version " .. tostring(server_version))
+    mp.msg.warn("Run `mpv-subtitleminer install-script` to update the script")
+  end
+end)
+
 mp.register_event("shutdown", function()
   if startup_timer then
     startup_timer:kill()
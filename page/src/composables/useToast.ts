@@ -11,8 +11,8 @@ const TOAST_CONFIG = {
 
 export type ToastType = keyof typeof TOAST_CONFIG
 export type ToastAction = { label: string; onClick: () => void }
-export type Toast = { id: number; message: string; type: ToastType; action?: ToastAction }
-export type ToastOptions = { duration?: number; action?: ToastAction }
+export type Toast = { id: number; message: string; type: ToastType; actions: ToastAction[] }
+export type ToastOptions = { duration?: number; action?: ToastAction; actions?: ToastAction[] }
 
 export const toastIcons: Record<ToastType, string> = Object.fromEntries(
   (Object.entries(TOAST_CONFIG) as [ToastType, (typeof TOAST_CONFIG)[ToastType]][]).map(
@@ -34,10 +34,10 @@ function pushToast(
   message: string,
   type: ToastType = 'info',
   duration = TOAST_DURATION,
-  action?: ToastAction,
+  actions: ToastAction[] = [],
 ) {
   const id = nextToastId++
-  toasts.value.push({ id, message, type, action })
+  toasts.value.push({ id, message, type, actions })
   window.setTimeout(() => dismissToast(id), duration)
   return id
 }
@@ -47,7 +47,12 @@ export type ToastApi = Record<ToastType, ToastFn>
 
 function createToast(type: ToastType): ToastFn {
   return (message: string, options?: ToastOptions) =>
-    pushToast(message, type, options?.duration ?? TOAST_DURATION, options?.action)
+    pushToast(
+      message,
+      type,
+      options?.duration ?? TOAST_DURATION,
+      options?.actions ?? (options?.action ? [options.action] : []),
+    )
 }
 
 const toastTypes = Object.keys(TOAST_CONFIG) as ToastType[]
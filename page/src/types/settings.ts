@@ -1,12 +1,16 @@
 export interface MediaSettings {
   audioOffsetStart: number
   audioOffsetEnd: number
-  imageFormat: 'webp' | 'jpeg' | 'avif'
+  imageFormat: 'webp' | 'jpeg' | 'avif' | 'png'
   imageQuality: number
   imageAnimated: boolean
-  audioFormat: 'opus' | 'mp3'
+  imageSmartFrame: boolean
+  audioFormat: 'opus' | 'mp3' | 'flac' | 'aac' | 'vorbis'
   audioQuality: number
   audioFilters: string
+  audioChannels: '' | 'stereo' | 'mono' | 'center'
+  audioGainDb: number
+  audioTempo: number
   imageSize: string
   imageAdvanced: boolean
   imageAdvancedArgs: string
@@ -22,6 +26,8 @@ export interface AnkiSettings {
   sentenceField: string
   audioField: string
   imageField: string
+  translationField: string
+  readingField: string
   maxCardAgeMinutes: number
 }
 
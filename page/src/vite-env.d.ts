@@ -1,5 +1,8 @@
 /// <reference types="vite/client" />
 
+/** The crate version from Cargo.toml, set by vite.config.ts */
+declare const __APP_VERSION__: string
+
 declare module '*.vue' {
   import type { DefineComponent } from 'vue'
   const component: DefineComponent<{}, {}, any>
@@ -1,9 +1,17 @@
 import { defineConfig } from 'vite'
 import vue from '@vitejs/plugin-vue'
 import { viteSingleFile } from 'vite-plugin-singlefile'
+import { readFileSync } from 'node:fs'
+
+// The page reports this to the server, which warns when the two differ
+const cargoToml = readFileSync(new URL('../Cargo.toml', import.meta.url), 'utf8')
+const version = cargoToml.match(/^version\s*=\s*"([^"]+)"/m)?.[1] ?? 'unknown'
 
 export default defineConfig({
   plugins: [vue(), viteSingleFile()],
+  define: {
+    __APP_VERSION__: JSON.stringify(version),
+  },
   server: {
     host: '0.0.0.0',
     port: 5173,
@@ -0,0 +1,195 @@
+//! Forced alignment: timing a plain-text script by matching it against
+//! what speech recognition hears, so the script's exact wording gets
+//! whisper's timestamps.
+
+use crate::asr::{self, AsrOptions, Segment};
+use crate::event_loop::Subtitle;
+use crate::media::FfmpegRequest;
+
+/// Least distance either side of the diagonal the alignment searches, in
+/// characters; the script and the transcript drift apart by more than their
+/// length ratio suggests wherever either skips a passage.
+const MIN_BAND: usize = 200;
+/// Most distance the band reaches. The alignment keeps a move per character
+/// of the script and of its band, so this and [`MAX_SCRIPT_CHARS`] bound its
+/// memory to a few hundred MB.
+const MAX_BAND: usize = 2_000;
+/// Longest script aligned, in letters and digits; a feature film's worth.
+pub const MAX_SCRIPT_CHARS: usize = 60_000;
+
+/// Times every non-empty line of `script` against the speech in `range`'s
+/// part of its media. The lines keep `range`'s file and track; ids are left
+/// at 0. Blocks while ffmpeg and whisper run.
+pub fn align_media(
+    range: &Subtitle,
+    script: &str,
+    options: &AsrOptions,
+) -> Result<Vec<Subtitle>, String> {
+    let chars = normalized(script).count();
+    if chars > MAX_SCRIPT_CHARS {
+        return Err(format!(
+            "the script is too long to align ({} characters, at most {}); split it up",
+            chars, MAX_SCRIPT_CHARS
+        ));
+    }
+    let wav = FfmpegRequest::speech(range)
+        .run()
+        .map_err(|e| e.to_string())?;
+    let segments = asr::transcribe(&wav, options).map_err(|e| e.to_string())?;
+    if segments.is_empty() {
+        return Err("no speech recognized".into());
+    }
+    Ok(align(script, &segments)
+        .into_iter()
+        .map(|(start, end, text)| Subtitle {
+            text,
+            sub_start: range.sub_start + start,
+            sub_end: range.sub_start + end,
+            ..range.clone()
+        })
+        .collect())
+}
+
+/// `(start, end, text)` for every non-empty line of `script`, timed by the
+/// transcript `segments`. Lines that match nothing are placed between their
+/// neighbours.
+pub fn align(script: &str, segments: &[Segment]) -> Vec<(f64, f64, String)> {
+    let lines: Vec<&str> = script
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+
+    // Only letters and digits count, so punctuation and spacing that
+    // whisper guesses differently don't get in the way
+    let mut script_chars = Vec::new();
+    let mut line_of = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        for c in normalized(line) {
+            script_chars.push(c);
+            line_of.push(i);
+        }
+    }
+    let mut heard = Vec::new();
+    let mut spans = Vec::new();
+    for segment in segments {
+        let chars: Vec<char> = normalized(&segment.text).collect();
+        let step = (segment.end - segment.start) / chars.len().max(1) as f64;
+        for (j, c) in chars.into_iter().enumerate() {
+            let start = segment.start + step * j as f64;
+            heard.push(c);
+            spans.push((start, start + step));
+        }
+    }
+
+    let mut times: Vec<Option<(f64, f64)>> = vec![None; lines.len()];
+    for (i, matched) in align_chars(&script_chars, &heard).into_iter().enumerate() {
+        let Some(j) = matched else {
+            continue;
+        };
+        let (start, end) = spans[j];
+        let time = &mut times[line_of[i]];
+        *time = Some(match *time {
+            Some((first, _)) => (first, end),
+            None => (start, end),
+        });
+    }
+
+    let mut timed = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        let (start, end) = times[i].unwrap_or_else(|| {
+            let previous_end = timed.last().map_or(0.0, |&(_, end, _)| end);
+            let next_start = times[i + 1..]
+                .iter()
+                .find_map(|t| t.map(|(start, _)| start))
+                .filter(|&start| start > previous_end)
+                .unwrap_or(previous_end + 1.0);
+            (previous_end, next_start)
+        });
+        timed.push((start, end, line.to_string()));
+    }
+    timed
+}
+
+fn normalized(text: &str) -> impl Iterator<Item = char> + '_ {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+}
+
+/// For each character of `a`, the character of `b` it lines up with, by
+/// least edits. Only a band around the diagonal is searched, which keeps
+/// episode-length scripts fast.
+fn align_chars(a: &[char], b: &[char]) -> Vec<Option<usize>> {
+    const DIAGONAL: u8 = 0;
+    const UP: u8 = 1;
+    const LEFT: u8 = 2;
+
+    let (n, m) = (a.len(), b.len());
+    if n == 0 || m == 0 {
+        return vec![None; n];
+    }
+    let band = (n.max(m) / 10).clamp(MIN_BAND, MAX_BAND);
+    let lo = |i: usize| (i * m / n).saturating_sub(band);
+    let hi = |i: usize| (i * m / n + band).min(m);
+
+    // Costs of the previous row and moves of every row, each over its band
+    let mut previous: Vec<u32> = (0..=hi(0) as u32).collect();
+    let mut moves: Vec<Vec<u8>> = vec![vec![LEFT; hi(0) + 1]];
+    for i in 1..=n {
+        let (row_lo, row_hi) = (lo(i), hi(i));
+        let (prev_lo, prev_hi) = (lo(i - 1), hi(i - 1));
+        let prev_cost = |j: usize| {
+            (prev_lo..=prev_hi)
+                .contains(&j)
+                .then(|| previous[j - prev_lo])
+        };
+        let mut costs: Vec<u32> = Vec::with_capacity(row_hi - row_lo + 1);
+        let mut row = Vec::with_capacity(row_hi - row_lo + 1);
+        for j in row_lo..=row_hi {
+            let mut best = (u32::MAX, UP);
+            if let Some(cost) = prev_cost(j) {
+                best = (cost.saturating_add(1), UP);
+            }
+            if j > 0 {
+                if let Some(cost) = prev_cost(j - 1) {
+                    let cost = cost.saturating_add(u32::from(a[i - 1] != b[j - 1]));
+                    if cost <= best.0 {
+                        best = (cost, DIAGONAL);
+                    }
+                }
+                if j > row_lo {
+                    let cost = costs[j - 1 - row_lo].saturating_add(1);
+                    if cost < best.0 {
+                        best = (cost, LEFT);
+                    }
+                }
+            }
+            costs.push(best.0);
+            row.push(best.1);
+        }
+        previous = costs;
+        moves.push(row);
+    }
+
+    let mut matched = vec![None; n];
+    let (mut i, mut j) = (n, m);
+    while i > 0 && j > 0 {
+        // Off the band only if the band never reached this far
+        let Some(&step) = moves[i].get(j.wrapping_sub(lo(i))) else {
+            break;
+        };
+        match step {
+            DIAGONAL => {
+                if a[i - 1] == b[j - 1] {
+                    matched[i - 1] = Some(j - 1);
+                }
+                i -= 1;
+                j -= 1;
+            }
+            UP => i -= 1,
+            _ => j -= 1,
+        }
+    }
+    matched
+}
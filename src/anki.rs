@@ -0,0 +1,250 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use tracing::debug;
+
+pub const DEFAULT_ANKICONNECT_URL: &str = "http://127.0.0.1:8765";
+
+pub type AnkiResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Minimal AnkiConnect client (API version 6).
+#[derive(Clone)]
+pub struct AnkiConnect {
+    url: String,
+    client: reqwest::Client,
+}
+
+/// A note to add, with fields already rendered to HTML.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Note {
+    pub deck: String,
+    pub model: String,
+    pub fields: HashMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Add it even if Anki finds its first field in another note already;
+    /// only when asked to add a duplicate
+    #[serde(default)]
+    pub allow_duplicate: bool,
+}
+
+impl AnkiConnect {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn invoke(
+        &self,
+        action: &str,
+        params: serde_json::Value,
+    ) -> AnkiResult<serde_json::Value> {
+        debug!("[anki] {}", action);
+        let body = serde_json::json!({
+            "action": action,
+            "version": 6,
+            "params": params,
+        });
+        let response: serde_json::Value = self
+            .client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        match response.get("error") {
+            Some(serde_json::Value::String(e)) => {
+                Err(format!("AnkiConnect {}: {}", action, e).into())
+            }
+            _ => Ok(response
+                .get("result")
+                .cloned()
+                .unwrap_or(serde_json::Value::Null)),
+        }
+    }
+
+    pub async fn version(&self) -> AnkiResult<u64> {
+        self.invoke("version", serde_json::json!({}))
+            .await?
+            .as_u64()
+            .ok_or_else(|| "AnkiConnect returned a non-numeric version".into())
+    }
+
+    /// Stores `data` in the collection's media folder, returning the name
+    /// Anki actually used.
+    pub async fn store_media_file(&self, filename: &str, data: &[u8]) -> AnkiResult<String> {
+        let result = self
+            .invoke(
+                "storeMediaFile",
+                serde_json::json!({
+                    "filename": filename,
+                    "data": base64::engine::general_purpose::STANDARD.encode(data),
+                }),
+            )
+            .await?;
+        Ok(result.as_str().unwrap_or(filename).to_string())
+    }
+
+    /// Adds `note` and returns its id.
+    pub async fn add_note(&self, note: &Note) -> AnkiResult<u64> {
+        self.invoke(
+            "addNote",
+            serde_json::json!({
+                "note": {
+                    "deckName": note.deck,
+                    "modelName": note.model,
+                    "fields": note.fields,
+                    "tags": note.tags,
+                    "options": { "allowDuplicate": note.allow_duplicate },
+                }
+            }),
+        )
+        .await?
+        .as_u64()
+        .ok_or_else(|| "AnkiConnect did not return a note id".into())
+    }
+
+    /// Ids of the notes matching an Anki search, e.g. `added:1`.
+    pub async fn find_notes(&self, query: &str) -> AnkiResult<Vec<u64>> {
+        let result = self
+            .invoke("findNotes", serde_json::json!({ "query": query }))
+            .await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Opens Anki's browser on the notes matching `query`.
+    pub async fn gui_browse(&self, query: &str) -> AnkiResult<()> {
+        self.invoke("guiBrowse", serde_json::json!({ "query": query }))
+            .await?;
+        Ok(())
+    }
+
+    /// The fields of note `id` as name and HTML content, in the note type's
+    /// order.
+    pub async fn note_fields(&self, id: u64) -> AnkiResult<Vec<(String, String)>> {
+        let result = self
+            .invoke("notesInfo", serde_json::json!({ "notes": [id] }))
+            .await?;
+        let fields = result
+            .get(0)
+            .and_then(|note| note.get("fields"))
+            .and_then(|f| f.as_object())
+            .ok_or_else(|| format!("note {} not found", id))?;
+        let mut fields: Vec<(u64, String, String)> = fields
+            .iter()
+            .map(|(name, field)| {
+                let order = field.get("order").and_then(|o| o.as_u64()).unwrap_or(0);
+                let value = field.get("value").and_then(|v| v.as_str()).unwrap_or("");
+                (order, name.clone(), value.to_string())
+            })
+            .collect();
+        fields.sort();
+        Ok(fields
+            .into_iter()
+            .map(|(_, name, value)| (name, value))
+            .collect())
+    }
+
+    pub async fn model_names(&self) -> AnkiResult<Vec<String>> {
+        let result = self.invoke("modelNames", serde_json::json!({})).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Adds a note type with one card, `front` and `back` being its
+    /// templates.
+    pub async fn create_model(
+        &self,
+        name: &str,
+        fields: &[&str],
+        front: &str,
+        back: &str,
+        css: &str,
+    ) -> AnkiResult<()> {
+        self.invoke(
+            "createModel",
+            serde_json::json!({
+                "modelName": name,
+                "inOrderFields": fields,
+                "css": css,
+                "isCloze": false,
+                "cardTemplates": [{ "Name": "Card 1", "Front": front, "Back": back }],
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn deck_names(&self) -> AnkiResult<Vec<String>> {
+        let result = self.invoke("deckNames", serde_json::json!({})).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Every note type with its field names in order, asked in one request.
+    pub async fn models_with_fields(&self) -> AnkiResult<BTreeMap<String, Vec<String>>> {
+        let names = self.model_names().await?;
+        let actions: Vec<_> = names
+            .iter()
+            .map(|name| {
+                serde_json::json!({
+                    "action": "modelFieldNames",
+                    "version": 6,
+                    "params": { "modelName": name },
+                })
+            })
+            .collect();
+        let results = self
+            .invoke("multi", serde_json::json!({ "actions": actions }))
+            .await?;
+        let mut results = results.as_array().cloned().unwrap_or_default().into_iter();
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                // Each result is the field list, or `{result, error}` from
+                // newer AnkiConnect versions
+                let fields = results
+                    .next()
+                    .map(|r| r.get("result").cloned().unwrap_or(r))
+                    .and_then(|r| serde_json::from_value(r).ok())
+                    .unwrap_or_default();
+                (name, fields)
+            })
+            .collect())
+    }
+
+    /// Replaces the given fields of note `id`, leaving the others alone.
+    pub async fn update_note_fields(
+        &self,
+        id: u64,
+        fields: &HashMap<String, String>,
+    ) -> AnkiResult<()> {
+        self.invoke(
+            "updateNoteFields",
+            serde_json::json!({ "note": { "id": id, "fields": fields } }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Whether `e` means AnkiConnect couldn't be reached at all (Anki closed),
+/// rather than refusing the request. A timeout doesn't count: Anki may have
+/// added the note and been slow to say so, and queueing it would add it
+/// twice.
+pub fn is_unreachable(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect())
+}
+
+/// Field values referencing stored media, in the form Anki expects.
+pub fn sound_tag(filename: &str) -> String {
+    format!("[sound:{}]", filename)
+}
+
+pub fn image_tag(filename: &str) -> String {
+    format!("<img src=\"{}\">", filename)
+}
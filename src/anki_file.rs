@@ -0,0 +1,90 @@
+//! Mining without AnkiConnect, for machines Anki isn't installed on: media
+//! goes straight into a `collection.media` directory and notes into a TSV
+//! file Anki imports with File > Import.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::offline::PreparedNote;
+
+/// Where notes and media go instead of AnkiConnect.
+#[derive(Debug, Clone)]
+pub struct NoteFile {
+    /// The TSV file, created with Anki's import headers and appended to
+    pub path: PathBuf,
+    /// Anki's `collection.media`, or a directory copied into it later
+    pub media_dir: PathBuf,
+}
+
+/// Rows from concurrent mining requests mustn't interleave.
+static WRITING: Mutex<()> = Mutex::new(());
+
+impl NoteFile {
+    /// Writes the media of `prepared` and appends its note as a row, with
+    /// `columns` as the fields in order. Returns an id for the note: the
+    /// millisecond it was written, like Anki's.
+    pub fn add(&self, prepared: &PreparedNote, columns: &[String]) -> io::Result<u64> {
+        fs::create_dir_all(&self.media_dir)?;
+        for (name, data) in &prepared.media {
+            // Named after what was cut, so an existing file is the same
+            let path = self.media_dir.join(name);
+            if !path.exists() {
+                fs::write(path, data)?;
+            }
+        }
+
+        let note = &prepared.note;
+        let mut row: Vec<String> = columns
+            .iter()
+            .map(|column| cell(note.fields.get(column).map_or("", String::as_str)))
+            .collect();
+        row.push(cell(&note.tags.join(" ")));
+
+        let _writing = WRITING.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut text = String::new();
+        if file.metadata()?.len() == 0 {
+            text.push_str(&header(&note.model, &note.deck, columns));
+        }
+        text.push_str(&row.join("\t"));
+        text.push('\n');
+        file.write_all(text.as_bytes())?;
+        Ok(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64)
+    }
+}
+
+/// The headers that let Anki import the file without asking: note type,
+/// deck, and which column holds the tags.
+fn header(model: &str, deck: &str, columns: &[String]) -> String {
+    let mut names: Vec<&str> = columns.iter().map(String::as_str).collect();
+    names.push("Tags");
+    format!(
+        "#separator:tab\n#html:true\n#notetype:{}\n#deck:{}\n#tags column:{}\n#columns:{}\n",
+        model,
+        deck,
+        names.len(),
+        names.join("\t")
+    )
+}
+
+/// `value` as one quoted TSV cell. Fields are HTML, so line breaks become
+/// `<br>`; quoting keeps a sentence that starts with `"` intact.
+fn cell(value: &str) -> String {
+    let value = value
+        .replace("\r\n", "<br>")
+        .replace(['\n', '\r'], "<br>")
+        .replace('"', "\"\"");
+    format!("\"{}\"", value)
+}
@@ -0,0 +1,272 @@
+//! Notes mined while Anki is closed: kept on disk with their media and added
+//! once AnkiConnect answers again, so mining doesn't depend on Anki running.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::anki::{self, AnkiConnect, Note};
+use crate::event_loop::Subtitle;
+use crate::offline::{self, PreparedNote};
+
+/// How often a non-empty queue checks whether Anki is back.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+const NOTE_FILE: &str = "note.json";
+
+/// What `note.json` holds; the media files lie next to it.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    note: Note,
+    media: Vec<String>,
+    /// Missing from notes queued by older versions
+    #[serde(default)]
+    line: Option<MinedLine>,
+}
+
+/// What a queued note was mined from, so the lines can be marked mined and
+/// logged once it is added.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MinedLine {
+    /// The lines that went into the note
+    pub ids: Vec<u64>,
+    /// The line as mined, or the lines combined into one
+    pub sub: Subtitle,
+    pub title: Option<String>,
+}
+
+/// A queued note Anki took.
+pub struct Flushed {
+    pub note_id: u64,
+    pub prepared: PreparedNote,
+    pub line: Option<MinedLine>,
+}
+
+/// A directory of queued notes, one subdirectory each, named so they sort
+/// in the order they were mined. Notes Anki rejected are renamed with a
+/// `.failed` suffix and left for the user.
+#[derive(Debug)]
+pub struct AnkiQueue {
+    dir: PathBuf,
+}
+
+impl AnkiQueue {
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// `mpv-subtitleminer/anki-queue` in the per-user data directory.
+    pub fn default_dir() -> Option<PathBuf> {
+        crate::instance::data_dir().map(|dir| dir.join("anki-queue"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes `prepared`, mined from `line`, to the queue and returns how
+    /// many notes wait.
+    pub fn push(&self, prepared: &PreparedNote, line: MinedLine) -> io::Result<usize> {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = format!("{:013}-{}", now_ms, uuid::Uuid::new_v4().simple());
+        // Written whole, so a crash never leaves half a note to flush
+        let partial = self.dir.join(format!("{}.part", name));
+        fs::create_dir_all(&partial)?;
+        let mut media = Vec::new();
+        for (file, data) in &prepared.media {
+            let file = file_name(file)?;
+            fs::write(partial.join(file), data)?;
+            media.push(file.to_string());
+        }
+        let entry = Entry {
+            note: prepared.note.clone(),
+            media,
+            line: Some(line),
+        };
+        fs::write(
+            partial.join(NOTE_FILE),
+            serde_json::to_vec_pretty(&entry).map_err(io::Error::other)?,
+        )?;
+        fs::rename(&partial, self.dir.join(name))?;
+        Ok(self.len())
+    }
+
+    /// How many notes wait to be added.
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries().is_empty()
+    }
+
+    /// Adds queued notes in order until Anki stops answering, and returns
+    /// those added.
+    pub async fn flush(&self, client: &AnkiConnect) -> Vec<Flushed> {
+        let mut added = Vec::new();
+        for path in self.entries() {
+            let (prepared, line) = match load(&path) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    warn!("[anki-queue] Cannot read {}: {}", path.display(), e);
+                    set_aside(&path);
+                    continue;
+                }
+            };
+            match offline::add_prepared(client, &prepared).await {
+                Ok(note_id) => {
+                    info!("[anki-queue] Added queued note {}", note_id);
+                    if let Err(e) = fs::remove_dir_all(&path) {
+                        warn!("[anki-queue] Cannot remove {}: {}", path.display(), e);
+                    }
+                    added.push(Flushed {
+                        note_id,
+                        prepared,
+                        line,
+                    });
+                }
+                Err(e) if anki::is_unreachable(e.as_ref()) => break,
+                Err(e) => {
+                    warn!(
+                        "[anki-queue] Anki rejected {}, set aside: {}",
+                        path.display(),
+                        e
+                    );
+                    set_aside(&path);
+                }
+            }
+        }
+        added
+    }
+
+    /// Queued notes, oldest first; partial and failed ones are skipped.
+    fn entries(&self) -> Vec<PathBuf> {
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut entries: Vec<_> = dir
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.is_dir()
+                    && path
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .is_some_and(|n| !n.contains('.'))
+            })
+            .collect();
+        entries.sort();
+        entries
+    }
+}
+
+fn load(path: &Path) -> io::Result<(PreparedNote, Option<MinedLine>)> {
+    let entry: Entry =
+        serde_json::from_slice(&fs::read(path.join(NOTE_FILE))?).map_err(io::Error::other)?;
+    let mut media = Vec::new();
+    for file in entry.media {
+        let data = fs::read(path.join(file_name(&file)?))?;
+        media.push((file, data));
+    }
+    let prepared = PreparedNote {
+        note: entry.note,
+        media,
+    };
+    Ok((prepared, entry.line))
+}
+
+/// `name` if it is a bare file name, so a queued note can't reach outside
+/// its directory.
+fn file_name(name: &str) -> io::Result<&str> {
+    match Path::new(name).file_name().and_then(|n| n.to_str()) {
+        Some(file) if file == name => Ok(file),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("bad media file name {:?}", name),
+        )),
+    }
+}
+
+fn set_aside(path: &Path) {
+    let failed = path.with_extension("failed");
+    if let Err(e) = fs::rename(path, &failed) {
+        warn!("[anki-queue] Cannot rename {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prepared(sentence: &str) -> PreparedNote {
+        PreparedNote {
+            note: Note {
+                deck: "Mining".into(),
+                model: "Basic".into(),
+                fields: [("Front".to_string(), sentence.to_string())].into(),
+                tags: vec!["show".into()],
+                allow_duplicate: false,
+            },
+            media: vec![("line.opus".into(), b"audio".to_vec())],
+        }
+    }
+
+    fn mined(id: u64, text: &str) -> MinedLine {
+        MinedLine {
+            ids: vec![id],
+            sub: Subtitle {
+                id,
+                text: text.into(),
+                ..Default::default()
+            },
+            title: Some("Show".into()),
+        }
+    }
+
+    #[test]
+    fn queued_notes_come_back_in_order() {
+        let dir = std::env::temp_dir().join(format!("anki_queue_{}", uuid::Uuid::new_v4()));
+        let queue = AnkiQueue::open(dir.clone()).unwrap();
+        assert!(queue.is_empty());
+        assert_eq!(queue.push(&prepared("一"), mined(1, "一")).unwrap(), 1);
+        // Names sort by the millisecond they were queued in
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(queue.push(&prepared("二"), mined(2, "二")).unwrap(), 2);
+
+        let loaded: Vec<_> = queue
+            .entries()
+            .iter()
+            .map(|path| load(path).unwrap())
+            .collect();
+        let (first, line) = &loaded[0];
+        assert_eq!(first.note.fields["Front"], "一");
+        assert_eq!(first.note.tags, ["show"]);
+        assert_eq!(first.media, [("line.opus".to_string(), b"audio".to_vec())]);
+        let line = line.as_ref().unwrap();
+        assert_eq!(
+            (line.ids.as_slice(), line.sub.text.as_str()),
+            ([1].as_slice(), "一")
+        );
+        assert_eq!(loaded[1].0.note.fields["Front"], "二");
+
+        // Set aside notes aren't flushed again
+        set_aside(&queue.entries()[0]);
+        assert_eq!(queue.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn media_names_stay_in_their_directory() {
+        assert_eq!(file_name("line.opus").unwrap(), "line.opus");
+        assert!(file_name("../line.opus").is_err());
+        assert!(file_name("/tmp/line.opus").is_err());
+        assert!(file_name("").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//! Speech recognition with whisper.cpp, for media without subtitles.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+use tracing::debug;
+
+/// Seconds before and after the playback position transcribed by default.
+pub const DEFAULT_BEFORE: f64 = 20.0;
+pub const DEFAULT_AFTER: f64 = 5.0;
+/// The most either side of the playback position may ask for.
+pub const MAX_WINDOW: f64 = 120.0;
+
+/// Bytes per second of the 16 kHz mono 16-bit audio whisper is given.
+const WAV_RATE: f64 = 32_000.0;
+/// whisper gets this long plus [`TIMEOUT_PER_SECOND`] per second of audio
+/// before it is taken to be stuck and killed.
+const TIMEOUT_BASE: Duration = Duration::from_secs(60);
+const TIMEOUT_PER_SECOND: f64 = 4.0;
+
+/// How whisper.cpp is run.
+#[derive(Debug, Clone)]
+pub struct AsrOptions {
+    /// whisper.cpp's command line program, `whisper-cli` in recent builds
+    pub binary: String,
+    /// A ggml model file, e.g. `ggml-large-v3-turbo.bin`
+    pub model: PathBuf,
+    /// Spoken language, e.g. `ja`, or `auto`
+    pub lang: String,
+}
+
+/// A line whisper heard, with times relative to the start of the audio.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+/// Transcribes `wav` (16 kHz mono, as whisper.cpp wants it). Blocks until
+/// whisper exits, or is killed for taking far longer than the audio lasts.
+pub fn transcribe(wav: &[u8], options: &AsrOptions) -> std::io::Result<Vec<Segment>> {
+    let base = std::env::temp_dir().join(format!("asr_{}", uuid::Uuid::new_v4()));
+    let input = base.with_extension("wav");
+    let output = base.with_extension("json");
+    fs::write(&input, wav)?;
+
+    debug!(
+        "[asr] Transcribing {} with {}",
+        input.display(),
+        options.binary
+    );
+    let mut command = Command::new(&options.binary);
+    command
+        .arg("-m")
+        .arg(&options.model)
+        .args(["-l", &options.lang, "-oj", "-np", "-of"])
+        .arg(&base)
+        .arg("-f")
+        .arg(&input);
+    let timeout =
+        TIMEOUT_BASE + Duration::from_secs_f64(wav.len() as f64 / WAV_RATE * TIMEOUT_PER_SECOND);
+    let result = crate::media::run_killable(command, None, timeout);
+    let _ = fs::remove_file(&input);
+    let json = result.and_then(|out| {
+        if !out.status.success() {
+            return Err(std::io::Error::other(format!(
+                "whisper failed ({}): {}",
+                out.status,
+                String::from_utf8_lossy(&out.stderr).trim()
+            )));
+        }
+        fs::read(&output)
+    });
+    let _ = fs::remove_file(&output);
+
+    let json: serde_json::Value = serde_json::from_slice(&json?)?;
+    let segments = json
+        .get("transcription")
+        .and_then(|t| t.as_array())
+        .map(|segments| {
+            segments
+                .iter()
+                .filter_map(|s| {
+                    let offsets = s.get("offsets")?;
+                    let ms = |key: &str| Some(offsets.get(key)?.as_f64()? / 1000.0);
+                    Some(Segment {
+                        start: ms("from")?,
+                        end: ms("to")?,
+                        text: s.get("text")?.as_str()?.trim().to_string(),
+                    })
+                })
+                .filter(|s| !s.text.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(segments)
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+use crate::event_loop::Subtitle;
+use crate::media::{AudioConfig, FfmpegRequest, ImageConfig};
+
+pub struct BenchResult {
+    pub name: String,
+    pub elapsed: Duration,
+    /// Output size in bytes, `None` if the extraction failed
+    pub size: Option<usize>,
+}
+
+/// The extractions the frontend offers, from cheap to expensive.
+fn presets() -> Vec<(&'static str, Preset)> {
+    let image_with = |format: &str, quality: i32, is_animated: bool, encoder: Option<&str>| {
+        Preset::Image(ImageConfig {
+            format: format.to_string(),
+            quality,
+            is_animated,
+            size: Some("640:-2".to_string()),
+            encoder: encoder.map(str::to_string),
+            ..Default::default()
+        })
+    };
+    let image = |format: &str, quality: i32, is_animated: bool| {
+        image_with(format, quality, is_animated, None)
+    };
+    let audio = |format: &str, quality: i32| {
+        Preset::Audio(AudioConfig {
+            format: format.to_string(),
+            quality,
+            ..Default::default()
+        })
+    };
+    vec![
+        ("jpeg still", image("jpeg", 5, false)),
+        ("webp still", image("webp", 80, false)),
+        ("avif still", image("avif", 30, false)),
+        ("webp animated", image("webp", 60, true)),
+        ("avif animated", image("avif", 40, true)),
+        (
+            "avif animated svt",
+            image_with("avif", 40, true, Some("libsvtav1")),
+        ),
+        ("mp3 128k", audio("mp3", 128)),
+        ("opus 64k", audio("opus", 64)),
+    ]
+}
+
+enum Preset {
+    Image(ImageConfig),
+    Audio(AudioConfig),
+}
+
+/// Runs every preset once against `duration` seconds of `media_path`
+/// starting at `start`, calling `report` after each.
+pub fn run(
+    media_path: &str,
+    start: f64,
+    duration: f64,
+    aid: i64,
+    mut report: impl FnMut(&BenchResult),
+) -> Vec<BenchResult> {
+    let sub = Subtitle {
+        id: 0,
+        text: String::new(),
+        sub_start: start,
+        sub_end: start + duration,
+        media_path: media_path.to_string(),
+        aid,
+        ..Default::default()
+    };
+
+    presets()
+        .into_iter()
+        .map(|(name, preset)| {
+            let req = match preset {
+                Preset::Image(config) => FfmpegRequest::thumbnail(&sub, Some(config)),
+                Preset::Audio(config) => FfmpegRequest::audio(&sub, None, None, Some(config)),
+            };
+            let started = Instant::now();
+            let size = req.run().ok().map(|data| data.len());
+            let result = BenchResult {
+                name: name.to_string(),
+                elapsed: started.elapsed(),
+                size,
+            };
+            report(&result);
+            result
+        })
+        .collect()
+}
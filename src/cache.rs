@@ -0,0 +1,121 @@
+//! On-disk cache of extracted media, so asking for the same thumbnail or
+//! audio again doesn't run ffmpeg again.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use tracing::{debug, info, warn};
+
+/// Eviction stops once the cache is this fraction of its cap, so it doesn't
+/// run again on the very next write.
+const EVICT_TO: f64 = 0.9;
+
+struct MediaCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    used: AtomicU64,
+}
+
+static CACHE: OnceLock<MediaCache> = OnceLock::new();
+
+/// Keeps extracted media in `dir`, evicting the least recently used files
+/// once they take more than `max_bytes`.
+pub fn init(dir: PathBuf, max_bytes: u64) -> io::Result<()> {
+    fs::create_dir_all(&dir)?;
+    let used = entries(&dir)?.iter().map(|e| e.size).sum();
+    info!(
+        "[cache] Using {} ({} of {} MB)",
+        dir.display(),
+        used / 1_000_000,
+        max_bytes / 1_000_000
+    );
+    CACHE
+        .set(MediaCache {
+            dir,
+            max_bytes,
+            used: AtomicU64::new(used),
+        })
+        .ok();
+    Ok(())
+}
+
+/// The cached file for `name`, if there is one.
+pub(crate) fn get(name: &str) -> Option<Vec<u8>> {
+    let cache = CACHE.get()?;
+    let path = cache.dir.join(name);
+    let data = fs::read(&path).ok()?;
+    // Marks it as recently used
+    if let Ok(file) = File::options().write(true).open(&path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+    debug!("[cache] Hit {}", name);
+    Some(data)
+}
+
+/// Stores `data` as `name`, making room if the cache is full.
+pub(crate) fn put(name: &str, data: &[u8]) {
+    let Some(cache) = CACHE.get() else {
+        return;
+    };
+    if let Err(e) = fs::write(cache.dir.join(name), data) {
+        warn!("[cache] Failed to store {}: {}", name, e);
+        return;
+    }
+    let used = cache.used.fetch_add(data.len() as u64, Ordering::Relaxed) + data.len() as u64;
+    if used > cache.max_bytes {
+        cache.evict();
+    }
+}
+
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    used: SystemTime,
+}
+
+fn entries(dir: &PathBuf) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_file() {
+            entries.push(Entry {
+                path: entry.path(),
+                size: meta.len(),
+                used: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+impl MediaCache {
+    fn evict(&self) {
+        let mut entries = match entries(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("[cache] Failed to list {}: {}", self.dir.display(), e);
+                return;
+            }
+        };
+        entries.sort_by_key(|e| e.used);
+
+        let mut used: u64 = entries.iter().map(|e| e.size).sum();
+        let target = (self.max_bytes as f64 * EVICT_TO) as u64;
+        let mut removed = 0;
+        for entry in entries {
+            if used <= target {
+                break;
+            }
+            if fs::remove_file(&entry.path).is_ok() {
+                used -= entry.size;
+                removed += 1;
+            }
+        }
+        self.used.store(used, Ordering::Relaxed);
+        debug!("[cache] Evicted {} files, {} bytes left", removed, used);
+    }
+}
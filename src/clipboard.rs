@@ -0,0 +1,173 @@
+//! Copying new lines to the system clipboard, for lookup tools that watch it
+//! (Yomitan's clipboard monitor and the like).
+//!
+//! Windows' clipboard is set directly. Elsewhere the platform's clipboard
+//! tool does it, as the arboard crate isn't a dependency: `pbcopy` ships
+//! with macOS, while Linux needs `wl-copy`, `xclip` or `xsel` installed.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+use crate::event_loop::ServerEvent;
+
+/// How long a clipboard tool may take to take the text; one stuck waiting
+/// for a display server is killed after this.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How lines are copied.
+#[derive(Debug, Clone, Default)]
+pub struct ClipboardOptions {
+    /// Program reading the text on stdin, instead of the platform's
+    /// clipboard tool
+    pub command: Option<String>,
+}
+
+/// Clipboard tools tried in order when no command is given.
+#[cfg(not(windows))]
+fn platform_commands() -> Vec<Vec<String>> {
+    let commands: &[&[&str]] = if cfg!(target_os = "macos") {
+        &[&["pbcopy"]]
+    } else if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        &[
+            &["wl-copy"],
+            &["xclip", "-selection", "clipboard"],
+            &["xsel", "--clipboard", "--input"],
+        ]
+    } else {
+        &[
+            &["xclip", "-selection", "clipboard"],
+            &["xsel", "--clipboard", "--input"],
+        ]
+    };
+    commands
+        .iter()
+        .map(|c| c.iter().map(|s| s.to_string()).collect())
+        .collect()
+}
+
+/// Puts `text` on the clipboard. Blocks until the tool exits, at most
+/// [`TIMEOUT`] or until the server shuts down.
+pub fn copy(text: &str, options: &ClipboardOptions) -> std::io::Result<()> {
+    let commands = match &options.command {
+        Some(command_line) => vec![
+            shlex::split(command_line)
+                .filter(|args| !args.is_empty())
+                .ok_or_else(|| std::io::Error::other("invalid --clipboard-command"))?,
+        ],
+        #[cfg(windows)]
+        None => return copy_native(text),
+        #[cfg(not(windows))]
+        None => platform_commands(),
+    };
+
+    for args in &commands {
+        // xclip and wl-copy stay around to serve the selection, so their
+        // output must not be waited on
+        let spawned = Command::new(&args[0])
+            .args(&args[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        // Not media::run_killable: that drains stdout, which the process
+        // xclip leaves behind would hold open
+        let status = crate::media::wait_killable(&mut child, &args[0], TIMEOUT)?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "{} failed ({})",
+                args[0], status
+            )));
+        }
+        debug!("[clipboard] Copied with {}", args[0]);
+        return Ok(());
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "no clipboard tool found (wl-copy, xclip or xsel), set --clipboard-command",
+    ))
+}
+
+/// Puts `text` on the Windows clipboard as Unicode text.
+#[cfg(windows)]
+fn copy_native(text: &str) -> std::io::Result<()> {
+    use windows_sys::Win32::System::DataExchange::{
+        CloseClipboard, EmptyClipboard, OpenClipboard, SetClipboardData,
+    };
+    use windows_sys::Win32::System::Memory::{
+        GMEM_MOVEABLE, GlobalAlloc, GlobalFree, GlobalLock, GlobalUnlock,
+    };
+    use windows_sys::Win32::System::Ole::CF_UNICODETEXT;
+
+    let wide: Vec<u16> = text.encode_utf16().chain([0]).collect();
+    // Another program may hold it open for a moment
+    let deadline = std::time::Instant::now() + TIMEOUT;
+    // SAFETY: no owner window is needed to set text; closed again below
+    while unsafe { OpenClipboard(std::ptr::null_mut()) } == 0 {
+        if std::time::Instant::now() > deadline {
+            return Err(std::io::Error::last_os_error());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    // SAFETY: the clipboard is open, and `memory` is sized for `wide`; it
+    // belongs to the clipboard once SetClipboardData succeeds
+    let result = unsafe {
+        let memory = GlobalAlloc(GMEM_MOVEABLE, std::mem::size_of_val(wide.as_slice()));
+        let target = if memory.is_null() {
+            std::ptr::null_mut()
+        } else {
+            GlobalLock(memory).cast::<u16>()
+        };
+        if target.is_null() {
+            let e = std::io::Error::last_os_error();
+            if !memory.is_null() {
+                GlobalFree(memory);
+            }
+            Err(e)
+        } else {
+            std::ptr::copy_nonoverlapping(wide.as_ptr(), target, wide.len());
+            GlobalUnlock(memory);
+            if EmptyClipboard() == 0 || SetClipboardData(CF_UNICODETEXT as u32, memory).is_null() {
+                let e = std::io::Error::last_os_error();
+                GlobalFree(memory);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        }
+    };
+    // SAFETY: opened above
+    unsafe { CloseClipboard() };
+    if result.is_ok() {
+        debug!("[clipboard] Copied");
+    }
+    result
+}
+
+/// Copies every new line in `events` until the miner goes away.
+pub async fn mirror(mut events: broadcast::Receiver<ServerEvent>, options: ClipboardOptions) {
+    loop {
+        let sub = match events.recv().await {
+            Ok(ServerEvent::Subtitle(sub)) => sub,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let options = options.clone();
+        match tokio::task::spawn_blocking(move || copy(&sub.text, &options)).await {
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::Interrupted => return,
+            Ok(Err(e)) => warn!("[clipboard] {}", e),
+            Ok(Ok(())) => {}
+            Err(e) => warn!("[clipboard] Copy panicked: {}", e),
+        }
+    }
+}
@@ -0,0 +1,339 @@
+//! The `--config` file: settings that can change while the server runs.
+//! When named on the command line it is watched for changes (see
+//! [`Watcher`]) and applied without a restart, and clients can change it
+//! with `set_settings`.
+//!
+//! ```json
+//! {
+//!   "filters": ["brackets"],
+//!   "offset_start": 0.25,
+//!   "offset_end": 0.5,
+//!   "image_config": {"format": "webp", "quality": 80},
+//!   "audio_config": {"format": "opus", "quality": 64},
+//!   "anki": {"deck": "Mining", "tags": ["{title}"]},
+//!   "profiles": [{"name": "Drama", "match": "(?i)drama", "offset_end": 1.0}]
+//! }
+//! ```
+
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::filter::TextFilter;
+use crate::offline::{AnkiTarget, TagTemplate};
+use crate::profile::{MediaSettings, Profile, ProfileEntry};
+
+/// How often the file is checked for changes where it can't be watched.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the file must be left alone after a change before it is read,
+/// so a save made of several writes is applied once, complete.
+pub const SETTLE: Duration = Duration::from_millis(200);
+
+/// What the file sets; everything is optional.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Replace `--filter` and filters set by clients
+    pub filters: Option<Vec<TextFilter>>,
+    /// For media requests without their own settings or a matching profile
+    pub media: MediaSettings,
+    pub profiles: Vec<Profile>,
+    /// Changes to the `--anki` settings
+    pub anki: Option<AnkiSettings>,
+    /// The file as read, for clients; `null` without one
+    pub raw: serde_json::Value,
+}
+
+/// Settings of [`AnkiTarget`] that can be changed on the fly; unset ones
+/// keep their command-line value.
+#[derive(Debug, Clone, Default)]
+pub struct AnkiSettings {
+    pub url: Option<String>,
+    pub deck: Option<String>,
+    pub model: Option<String>,
+    pub sentence_field: Option<String>,
+    pub audio_field: Option<String>,
+    pub image_field: Option<String>,
+    pub translation_field: Option<String>,
+    pub reading_field: Option<String>,
+    pub tags: Option<Vec<TagTemplate>>,
+    pub skip_duplicates: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    filters: Option<Vec<String>>,
+    #[serde(flatten)]
+    media: MediaSettings,
+    #[serde(default)]
+    profiles: Vec<ProfileEntry>,
+    anki: Option<AnkiEntry>,
+}
+
+#[derive(Deserialize)]
+struct AnkiEntry {
+    url: Option<String>,
+    deck: Option<String>,
+    model: Option<String>,
+    sentence_field: Option<String>,
+    audio_field: Option<String>,
+    image_field: Option<String>,
+    translation_field: Option<String>,
+    reading_field: Option<String>,
+    tags: Option<Vec<String>>,
+    skip_duplicates: Option<bool>,
+}
+
+impl Config {
+    /// Reads and checks `path`; errors name the file and what is wrong. A
+    /// missing file is an empty one, so settings can be saved to it later.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        serde_json::from_slice(&data)
+            .map_err(|e| e.to_string())
+            .and_then(Self::from_value)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// `mpv-subtitleminer/config.json` in the per-user data directory.
+    pub fn default_path() -> Option<PathBuf> {
+        crate::instance::data_dir().map(|dir| dir.join("config.json"))
+    }
+
+    /// Checks settings as they would be written to the file.
+    pub fn from_value(raw: serde_json::Value) -> Result<Self, String> {
+        let file: ConfigFile = serde_json::from_value(raw.clone()).map_err(|e| e.to_string())?;
+        let filters = file
+            .filters
+            .map(|filters| {
+                filters
+                    .iter()
+                    .map(|spec| {
+                        spec.parse::<TextFilter>()
+                            .map_err(|e| format!("filter {}: {}", spec, e))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+        let profiles = file
+            .profiles
+            .into_iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                entry
+                    .compile()
+                    .map_err(|e| format!("profile {}: {}", i + 1, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let anki = file
+            .anki
+            .map(|entry| {
+                let tags = entry
+                    .tags
+                    .map(|tags| {
+                        tags.iter()
+                            .map(|tag| tag.parse::<TagTemplate>())
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .transpose()?;
+                Ok::<_, String>(AnkiSettings {
+                    url: entry.url,
+                    deck: entry.deck,
+                    model: entry.model,
+                    sentence_field: entry.sentence_field,
+                    audio_field: entry.audio_field,
+                    image_field: entry.image_field,
+                    translation_field: entry.translation_field,
+                    reading_field: entry.reading_field,
+                    tags,
+                    skip_duplicates: entry.skip_duplicates,
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            filters,
+            media: file.media,
+            profiles,
+            anki,
+            raw,
+        })
+    }
+
+    /// Writes `raw` to `path`, beside it first and renamed over it so a
+    /// half-written file is never read.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let part = path.with_extension("json.part");
+        fs::write(&part, serde_json::to_vec_pretty(&self.raw)?)?;
+        fs::rename(part, path)
+    }
+}
+
+impl AnkiSettings {
+    /// `base` with these settings over it.
+    pub fn apply(&self, base: &AnkiTarget) -> AnkiTarget {
+        let mut target = base.clone();
+        let set = |value: &Option<String>, field: &mut Option<String>| {
+            if let Some(value) = value {
+                *field = (!value.is_empty()).then(|| value.clone());
+            }
+        };
+        if let Some(url) = &self.url {
+            target.url = url.clone();
+        }
+        if let Some(deck) = &self.deck {
+            target.deck = deck.clone();
+        }
+        if let Some(model) = &self.model {
+            target.model = model.clone();
+        }
+        set(&self.sentence_field, &mut target.sentence_field);
+        set(&self.audio_field, &mut target.audio_field);
+        set(&self.image_field, &mut target.image_field);
+        set(&self.translation_field, &mut target.translation_field);
+        set(&self.reading_field, &mut target.reading_field);
+        if let Some(tags) = &self.tags {
+            target.tags = tags.clone();
+        }
+        if let Some(skip) = self.skip_duplicates {
+            target.skip_duplicates = skip;
+        }
+        target
+    }
+}
+
+/// Waits for changes to the config file: watched with inotify on Linux, as
+/// the notify crate would, and read every [`POLL_INTERVAL`] elsewhere.
+pub struct Watcher {
+    path: PathBuf,
+    #[cfg(target_os = "linux")]
+    inotify: tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>,
+    #[cfg(not(target_os = "linux"))]
+    last: Option<Vec<u8>>,
+}
+
+impl Watcher {
+    #[cfg(target_os = "linux")]
+    pub fn new(path: &Path) -> io::Result<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+        use std::os::unix::ffi::OsStrExt;
+
+        // Editors often save by renaming a new file over the old one, which
+        // a watch on the file itself would lose track of
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let dir = std::ffi::CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other)?;
+        // SAFETY: plain inotify_init1(2)
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mask = libc::IN_MODIFY
+            | libc::IN_CLOSE_WRITE
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO;
+        // SAFETY: `dir` is a NUL-terminated path that outlives the call
+        if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            inotify: tokio::io::unix::AsyncFd::new(fd)?,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            last: fs::read(path).ok(),
+        })
+    }
+
+    /// Returns once the file changed and then was left alone for
+    /// [`SETTLE`].
+    pub async fn changed(&mut self) -> io::Result<()> {
+        self.next_change().await?;
+        loop {
+            match tokio::time::timeout(SETTLE, self.next_change()).await {
+                Ok(result) => result?,
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn next_change(&mut self) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut guard = self.inotify.readable().await?;
+            let read = guard.try_io(|fd| {
+                // SAFETY: reads at most `buf.len()` bytes into `buf`
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                usize::try_from(n).map_err(|_| io::Error::last_os_error())
+            });
+            match read {
+                Ok(Ok(n)) if self.concerns_file(&buf[..n]) => return Ok(()),
+                Ok(Ok(_)) | Err(_) => continue,
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Whether any of the inotify `events` read from the directory is about
+    /// the file, or may have been: the queue overflowed.
+    #[cfg(target_os = "linux")]
+    fn concerns_file(&self, events: &[u8]) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+
+        let Some(name) = self.path.file_name() else {
+            return true;
+        };
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut rest = events;
+        while rest.len() >= header {
+            // SAFETY: the kernel only hands out whole events; the buffer has
+            // no alignment, hence the unaligned read
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(rest.as_ptr().cast()) };
+            let end = (header + event.len as usize).min(rest.len());
+            // Names are padded with NULs
+            let event_name = rest[header..end].split(|&b| b == 0).next();
+            if event.mask & libc::IN_Q_OVERFLOW != 0 || event_name == Some(name.as_bytes()) {
+                return true;
+            }
+            rest = &rest[end..];
+        }
+        false
+    }
+
+    /// Compares the contents rather than the modification time, which may
+    /// not change between two quick saves.
+    #[cfg(not(target_os = "linux"))]
+    async fn next_change(&mut self) -> io::Result<()> {
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let current = tokio::fs::read(&self.path).await.ok();
+            if current != self.last {
+                self.last = current;
+                return Ok(());
+            }
+        }
+    }
+}
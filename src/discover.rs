@@ -0,0 +1,143 @@
+//! Finding a running mpv's IPC socket when none was given.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, info};
+
+use crate::mpv_stream::MpvStream;
+
+/// Socket path argument that asks for discovery instead of a fixed path.
+pub const AUTO: &str = "auto";
+
+/// Connects to each candidate in turn and returns the first live one.
+pub async fn find_socket() -> std::io::Result<String> {
+    let candidates = candidates();
+    if candidates.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "No mpv IPC socket found, start mpv with --input-ipc-server or pass a socket path",
+        ));
+    }
+
+    for path in &candidates {
+        let path = path.to_string_lossy();
+        match MpvStream::connect(&path).await {
+            Ok(_) => {
+                info!("Using mpv socket {} (auto-detected)", path);
+                return Ok(path.into_owned());
+            }
+            Err(e) => debug!("[discover] Skipping {}: {}", path, e),
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::ConnectionRefused,
+        format!(
+            "Failed to connect to mpv socket, none of the {} candidates is live",
+            candidates.len()
+        ),
+    ))
+}
+
+/// Sockets worth trying: the one configured in mpv.conf first, then anything
+/// mpv-looking in the usual places, most recently created first.
+pub fn candidates() -> Vec<PathBuf> {
+    let mut found: Vec<(PathBuf, Option<SystemTime>)> = Vec::new();
+    for dir in search_dirs() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if !name.to_string_lossy().to_lowercase().contains("mpv") || !is_socket(&entry) {
+                continue;
+            }
+            let modified = entry.metadata().and_then(|m| m.modified()).ok();
+            found.push((entry.path(), modified));
+        }
+    }
+    found.sort_by_key(|(_, modified)| std::cmp::Reverse(*modified));
+
+    let mut paths: Vec<PathBuf> = configured_socket().into_iter().collect();
+    for (path, _) in found {
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    debug!("[discover] Candidates: {:?}", paths);
+    paths
+}
+
+#[cfg(unix)]
+fn search_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("/tmp")];
+    if let Some(runtime) = std::env::var_os("XDG_RUNTIME_DIR") {
+        dirs.push(PathBuf::from(runtime));
+    }
+    dirs
+}
+
+#[cfg(windows)]
+fn search_dirs() -> Vec<PathBuf> {
+    vec![PathBuf::from(r"\\.\pipe\")]
+}
+
+#[cfg(unix)]
+fn is_socket(entry: &std::fs::DirEntry) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    entry.file_type().is_ok_and(|t| t.is_socket())
+}
+
+#[cfg(windows)]
+fn is_socket(_entry: &std::fs::DirEntry) -> bool {
+    // Everything under \\.\pipe\ is a pipe
+    true
+}
+
+/// The `input-ipc-server` from the user's mpv.conf, as the bundled Lua script
+/// reads it.
+fn configured_socket() -> Option<PathBuf> {
+    let content = std::fs::read_to_string(mpv_config_dir()?.join("mpv.conf")).ok()?;
+    content.lines().find_map(|line| {
+        let value = line.trim().strip_prefix("input-ipc-server")?;
+        let value = value.trim_start().strip_prefix('=')?.trim();
+        // mpv.conf allows a `%N%` length prefix
+        let value = match value.strip_prefix('%') {
+            Some(rest) => rest.split_once('%').map_or(value, |(_, v)| v),
+            None => value,
+        };
+        (!value.is_empty()).then(|| Path::new(value).to_path_buf())
+    })
+}
+
+/// mpv's config directory: `$MPV_HOME`, else the platform default. The
+/// Flatpak build's is used when only it exists.
+pub fn mpv_config_dir() -> Option<PathBuf> {
+    if let Some(home) = std::env::var_os("MPV_HOME") {
+        return Some(PathBuf::from(home));
+    }
+    let default = default_config_dir()?;
+    #[cfg(target_os = "linux")]
+    if !default.exists() {
+        let flatpak = std::env::var_os("HOME")
+            .map(|h| Path::new(&h).join(".var/app/io.mpv.Mpv/config/mpv"))
+            .filter(|dir| dir.is_dir());
+        if let Some(flatpak) = flatpak {
+            return Some(flatpak);
+        }
+    }
+    Some(default)
+}
+
+#[cfg(unix)]
+fn default_config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))?;
+    Some(base.join("mpv"))
+}
+
+#[cfg(windows)]
+fn default_config_dir() -> Option<PathBuf> {
+    std::env::var_os("APPDATA").map(|a| Path::new(&a).join("mpv"))
+}
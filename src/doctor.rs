@@ -0,0 +1,241 @@
+//! `doctor`: checks everything the server depends on and reports what is
+//! wrong, before it shows up as a vague failure in the browser.
+
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+use crate::anki::AnkiConnect;
+use crate::discover;
+use crate::media;
+use crate::mpv_stream::MpvStream;
+
+/// How long mpv and AnkiConnect get to answer.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What to check.
+#[derive(Debug, Clone)]
+pub struct DoctorOptions {
+    /// A socket path, or [`discover::AUTO`]
+    pub socket_path: String,
+    pub port: u16,
+    pub ffmpeg_path: String,
+    pub anki_url: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    /// Works, but not fully
+    Warn,
+    Fail,
+}
+
+/// The outcome of one check, with what was found or what went wrong.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self.status {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        write!(f, "[{}] {:<12} {}", label, self.name, self.detail)
+    }
+}
+
+/// Runs every check in turn.
+pub async fn run(options: &DoctorOptions) -> Vec<Check> {
+    media::init_ffmpeg_path(&options.ffmpeg_path);
+    let mut checks = tokio::task::spawn_blocking(|| vec![ffmpeg(), ffprobe(), encoders()])
+        .await
+        .unwrap_or_default();
+    checks.push(mpv(&options.socket_path).await);
+    checks.push(anki(&options.anki_url).await);
+    checks.push(port(options.port).await);
+    checks.push(temp_dir());
+    checks
+}
+
+fn ffmpeg() -> Check {
+    match media::ffmpeg_version() {
+        Ok(version) => Check::new("ffmpeg", Status::Pass, version),
+        Err(e) => Check::new(
+            "ffmpeg",
+            Status::Fail,
+            format!(
+                "{} (install ffmpeg, run `mpv-subtitleminer fetch-ffmpeg` or pass --ffmpeg-path)",
+                e
+            ),
+        ),
+    }
+}
+
+fn ffprobe() -> Check {
+    match media::ffprobe_version() {
+        Ok(version) => Check::new("ffprobe", Status::Pass, version),
+        // Only track listing and offline extraction need it
+        Err(e) => Check::new(
+            "ffprobe",
+            Status::Warn,
+            format!("{} (expected next to ffmpeg)", e),
+        ),
+    }
+}
+
+fn encoders() -> Check {
+    if let Err(e) = media::probe_encoders() {
+        return Check::new("encoders", Status::Fail, format!("ffmpeg -encoders: {}", e));
+    }
+    let missing = media::missing_formats();
+    if missing.is_empty() {
+        let all: Vec<_> = media::IMAGE_FORMATS
+            .iter()
+            .chain(media::AUDIO_FORMATS)
+            .copied()
+            .collect();
+        return Check::new("encoders", Status::Pass, all.join(", "));
+    }
+    let unusable = |formats: &[&str]| formats.iter().all(|f| missing.contains(f));
+    let status = if unusable(media::IMAGE_FORMATS) || unusable(media::AUDIO_FORMATS) {
+        Status::Fail
+    } else {
+        Status::Warn
+    };
+    Check::new(
+        "encoders",
+        status,
+        format!("cannot encode {}", missing.join(", ")),
+    )
+}
+
+async fn mpv(socket_path: &str) -> Check {
+    let path = if socket_path == discover::AUTO {
+        match discover::find_socket().await {
+            Ok(path) => path,
+            Err(e) => return Check::new("mpv", Status::Fail, e.to_string()),
+        }
+    } else {
+        socket_path.to_string()
+    };
+    let asked = async {
+        let mut mpv = MpvStream::connect(&path).await?;
+        mpv.write_all(b"{\"command\":[\"get_property\",\"mpv-version\"],\"request_id\":1}\n")
+            .await?;
+        while let Some(line) = mpv.next_line().await? {
+            let Ok(json) = serde_json::from_str::<serde_json::Value>(&line) else {
+                continue;
+            };
+            if json.get("request_id").and_then(|r| r.as_u64()) == Some(1) {
+                return Ok(json
+                    .get("data")
+                    .and_then(|d| d.as_str())
+                    .unwrap_or("mpv")
+                    .to_string());
+            }
+        }
+        Err(std::io::Error::other("mpv closed the connection"))
+    };
+    match timeout(CHECK_TIMEOUT, asked).await {
+        Ok(Ok(version)) => Check::new("mpv", Status::Pass, format!("{} at {}", version, path)),
+        Ok(Err(e)) => Check::new("mpv", Status::Fail, e.to_string()),
+        Err(_) => Check::new(
+            "mpv",
+            Status::Fail,
+            format!("{} accepted the connection but never answered", path),
+        ),
+    }
+}
+
+async fn anki(url: &str) -> Check {
+    match timeout(CHECK_TIMEOUT, AnkiConnect::new(url).version()).await {
+        Ok(Ok(version)) if !allows_file_pages(url).await => Check::new(
+            "AnkiConnect",
+            Status::Warn,
+            format!(
+                "version {} at {}, but it refuses index.html opened from disk; add \"null\" to webCorsOriginList",
+                version, url
+            ),
+        ),
+        Ok(Ok(version)) => Check::new(
+            "AnkiConnect",
+            Status::Pass,
+            format!("version {} at {}", version, url),
+        ),
+        // Mining through the browser still works without it server-side
+        Ok(Err(e)) => Check::new(
+            "AnkiConnect",
+            Status::Warn,
+            format!("{} ({}; is Anki running with AnkiConnect?)", url, e),
+        ),
+        Err(_) => Check::new(
+            "AnkiConnect",
+            Status::Warn,
+            format!("{} did not answer", url),
+        ),
+    }
+}
+
+/// Whether AnkiConnect lets a page opened from disk (origin `null`) call
+/// it, as the bundled frontend does.
+async fn allows_file_pages(url: &str) -> bool {
+    let response = reqwest::Client::new()
+        .post(url)
+        .header("Origin", "null")
+        .json(&serde_json::json!({ "action": "version", "version": 6 }))
+        .timeout(CHECK_TIMEOUT)
+        .send()
+        .await;
+    response.is_ok_and(|r| r.headers().contains_key("access-control-allow-origin"))
+}
+
+async fn port(port: u16) -> Check {
+    match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(_) => Check::new("port", Status::Pass, format!("{} is free", port)),
+        // The server falls back to the next free port unless --strict-port
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Check::new(
+            "port",
+            Status::Warn,
+            format!(
+                "{} is in use (a running miner? see `mpv-subtitleminer stop`)",
+                port
+            ),
+        ),
+        Err(e) => Check::new("port", Status::Fail, format!("{}: {}", port, e)),
+    }
+}
+
+fn temp_dir() -> Check {
+    let dir = std::env::temp_dir();
+    let probe = dir.join(format!("subtitleminer-doctor-{}", std::process::id()));
+    let written = std::fs::write(&probe, b"ok");
+    let _ = std::fs::remove_file(&probe);
+    match written {
+        Ok(()) => Check::new(
+            "temp dir",
+            Status::Pass,
+            format!("{} is writable", dir.display()),
+        ),
+        Err(e) => Check::new(
+            "temp dir",
+            Status::Fail,
+            format!("{}: {}", dir.display(), e),
+        ),
+    }
+}
@@ -1,366 +1,4130 @@
+use base64::Engine;
 use futures_util::{SinkExt, StreamExt};
-use log::{debug, error, info, warn};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{RwLock, broadcast};
-use tokio::time::{Duration, timeout};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{RwLock, broadcast, mpsc, oneshot};
+use tokio::task::JoinError;
+use tokio::time::{Duration, Instant, interval, timeout};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::{WebSocketStream, accept_hdr_async, connect_async, tungstenite::Message};
+use tracing::{Instrument, Span, debug, debug_span, error, info, info_span, warn};
 
-use crate::media::FfmpegRequest;
+use crate::anki::{self, AnkiConnect};
+use crate::anki_queue::{AnkiQueue, Flushed, MinedLine};
+use crate::config::Config;
+use crate::filter::{SongLines, TextFilter, apply_filters, is_song_chapter};
+use crate::hooks;
+use crate::http::{DOWNLOAD_TTL, Downloads};
+use crate::markdown;
+use crate::media::{
+    AUDIO_FORMATS, AudioConfig, FfmpegError, FfmpegRequest, IMAGE_FORMATS, missing_formats,
+    screenshot_path, sha256_hex, supports_format,
+};
+use crate::metrics::METRICS;
+use crate::miner::{MinerOptions, SubtitleMiner};
+use crate::morph::{self, Token};
 use crate::mpv_stream::MpvStream;
+use crate::ocr;
+use crate::offline::{self, AnkiTarget, ExtractOptions, PreparedNote};
+use crate::profile::{self, MediaSettings, Profile};
+use crate::resume::{FileResume, ResumeStore};
+use crate::stream::{self, StreamSource};
+use crate::subtitle_file::{self, SubtitleFormat};
+use crate::timeline;
+use crate::translate::Translator;
+use crate::{align, asr};
 
-#[derive(Clone)]
+/// This server's version; the Lua script and the frontend report theirs so
+/// a mismatch after a partial upgrade is spotted.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Upper bound for the reconnect backoff in outbound mode.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Request ids at or above this are routed to [`MpvCommand`] replies; the
+/// range below belongs to our own property queries.
+const COMMAND_REQUEST_BASE: u64 = 1 << 40;
+
+/// Lines further apart than this are merged with the gap cut out.
+const MAX_MERGE_GAP: f64 = 1.5;
+
+/// Lines on either side returned by `get_neighbors` by default.
+const DEFAULT_NEIGHBORS: usize = 2;
+
+/// Stills sent for `thumbnail_candidates` unless the client asks otherwise,
+/// and the most it may ask for.
+const DEFAULT_THUMBNAIL_CANDIDATES: usize = 4;
+const MAX_THUMBNAIL_CANDIDATES: usize = 8;
+
+/// Most lines a `search` returns unless asked otherwise.
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+/// How often, and how many times, mpv is asked for the URL it opened for a
+/// network path; its ytdl hook sets it some time after `path` changes.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const STREAM_POLL_ATTEMPTS: u32 = 10;
+
+/// How long running media jobs get to finish when the server shuts down.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+
+/// How often each client is pinged.
+pub(crate) const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// A client that has not answered for this long is considered dead.
+const PONG_TIMEOUT: Duration = Duration::from_secs(45);
+/// WebSocket path where clients get new lines as plain text, the way
+/// texthooker pages, exSTATic and JL expect them.
+pub const TEXTHOOKER_PATH: &str = "/texthooker";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Subtitle {
     pub id: u64,
     pub text: String,
+    /// The line as mpv showed it, when filters changed `text`
+    pub raw_text: Option<String>,
+    /// The line with its ASS override tags (`{\pos(..)}` etc.), when mpv has them
+    pub text_ass: Option<String>,
     pub sub_start: f64,
     pub sub_end: f64,
     pub media_path: String,
     pub aid: i64,
+    /// ffmpeg's index of the selected audio stream, from mpv's `track-list`
+    pub ff_index: Option<i64>,
+    /// No audio track was selected in mpv, so `aid` is the first one
+    pub audio_fallback: bool,
+    /// mpv's subtitle track the line came from
+    pub sid: Option<i64>,
+    /// Language of that track as tagged in the file, e.g. `jpn`
+    pub lang: Option<String>,
+    /// Title of that track, e.g. `Full Subtitles`
+    pub track_title: Option<String>,
+    /// Title of the chapter the line starts in, e.g. `OP` or `Part A`
+    pub chapter: Option<String>,
+    /// Sung in an OP/ED chapter or a song range, see [`SongLines`]
+    pub song: bool,
+    /// How often mpv showed this exact line in a row, e.g. after seeking back
+    pub seen_count: u32,
+    /// Some client added the line to Anki
+    pub mined: bool,
+    /// Session the line was captured in, see [`new_session_id`]
+    pub session: String,
+    /// mpv had no `sub-start`/`sub-end` for this line, so the timing is
+    /// `playback-time` padded by [`MinerOptions::timing_window`]
+    pub estimated_timing: bool,
+    /// Read from an image subtitle by OCR, so the text may have mistakes
+    pub ocr: bool,
+    /// Transcribed from the audio by speech recognition, see [`crate::asr`]
+    pub asr: bool,
+    /// Machine translation of `text`, sent as an update once it arrives
+    pub translation: Option<String>,
+    /// Words of `text` with readings, see [`crate::morph`]
+    pub tokens: Vec<Token>,
+    /// Start times of later lines with the same text, collapsed into this
+    /// one by [`MinerOptions::collapse_duplicates`]
+    pub repeats: Vec<f64>,
+}
+
+impl Subtitle {
+    /// How long the line is shown, in seconds.
+    pub fn duration(&self) -> f64 {
+        (self.sub_end - self.sub_start).max(0.0)
+    }
+
+    /// Characters in the line, not counting whitespace.
+    pub fn char_count(&self) -> usize {
+        self.text.chars().filter(|c| !c.is_whitespace()).count()
+    }
+
+    /// How often the line came up, counting collapsed duplicates.
+    pub fn count(&self) -> usize {
+        1 + self.repeats.len()
+    }
+
+    /// Reading speed in characters per second; none for lines without a
+    /// duration.
+    pub fn cps(&self) -> Option<f64> {
+        let duration = self.duration();
+        (duration > 0.0).then(|| self.char_count() as f64 / duration)
+    }
+}
+
+/// A fresh session id, issued on startup and whenever mpv loads a new file.
+fn new_session_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// Events fanned out to every connected client.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    Subtitle(Subtitle),
+    /// A stored line changed after it was sent, e.g. a client edited it
+    SubtitleUpdated(Subtitle),
+    FileChanged {
+        path: String,
+        title: String,
+        session: String,
+    },
+    /// mpv showed a line again (seek, track toggle) and it was not re-sent
+    SubtitleSeen {
+        id: u64,
+        seen_count: u32,
+    },
+    /// Something failed server-side (e.g. a panicked task) but we kept going.
+    Diagnostic {
+        message: String,
+    },
+    /// The server is about to exit; clients are closed after it is sent.
+    Shutdown,
+    /// A note of a `mine_batch` was added (or found to be a duplicate, or
+    /// failed)
+    MineProgress {
+        batch: u64,
+        /// Notes handled so far, out of `total`
+        done: usize,
+        total: usize,
+        /// Lines that went into the note
+        ids: Vec<u64>,
+        note_id: Option<u64>,
+        duplicate: bool,
+        /// Anki was unreachable, the note waits in the queue
+        queued: bool,
+        error: Option<String>,
+    },
+    /// Every note of a `mine_batch` was handled
+    MineBatchDone {
+        batch: u64,
+        added: usize,
+        duplicates: usize,
+        queued: usize,
+        failed: usize,
+    },
+    /// Notes were queued because Anki was unreachable, or queued ones were
+    /// added now that it is back
+    AnkiQueue {
+        /// Notes still waiting
+        queued: usize,
+        /// Queued notes just added
+        flushed: usize,
+    },
+    /// A file mining was resumed in was opened again: where it left off
+    /// and the settings clients saved for it
+    Resume {
+        path: String,
+        session: String,
+        resume: FileResume,
+    },
+    /// The config file changed and was applied, or couldn't be and `error`
+    /// says why; `config` is what applies
+    ConfigChanged {
+        config: serde_json::Value,
+        error: Option<String>,
+    },
+    /// Another component (`script` or `frontend`) reported a version other
+    /// than [`VERSION`]
+    VersionMismatch {
+        component: String,
+        version: String,
+    },
+}
+
+impl ServerEvent {
+    /// The wire format sent to WebSocket clients.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            ServerEvent::Subtitle(sub) => subtitle_json(sub, "subtitle"),
+            ServerEvent::SubtitleUpdated(sub) => subtitle_json(sub, "subtitle_updated"),
+            ServerEvent::FileChanged {
+                path,
+                title,
+                session,
+            } => serde_json::json!({
+                "type": "file_changed",
+                "path": path,
+                "title": title,
+                "session": session,
+            }),
+            ServerEvent::SubtitleSeen { id, seen_count } => serde_json::json!({
+                "type": "subtitle_seen",
+                "id": id,
+                "seen_count": seen_count,
+            }),
+            ServerEvent::Diagnostic { message } => serde_json::json!({
+                "type": "diagnostic",
+                "level": "error",
+                "message": message,
+            }),
+            ServerEvent::Shutdown => serde_json::json!({ "type": "server_shutdown" }),
+            ServerEvent::MineProgress {
+                batch,
+                done,
+                total,
+                ids,
+                note_id,
+                duplicate,
+                queued,
+                error,
+            } => serde_json::json!({
+                "type": "mine_progress",
+                "batch": batch,
+                "done": done,
+                "total": total,
+                "ids": ids,
+                "note_id": note_id,
+                "duplicate": duplicate,
+                "queued": queued,
+                "error": error,
+            }),
+            ServerEvent::MineBatchDone {
+                batch,
+                added,
+                duplicates,
+                queued,
+                failed,
+            } => serde_json::json!({
+                "type": "mine_batch_done",
+                "batch": batch,
+                "added": added,
+                "duplicates": duplicates,
+                "queued": queued,
+                "failed": failed,
+            }),
+            ServerEvent::AnkiQueue { queued, flushed } => serde_json::json!({
+                "type": "anki_queue",
+                "queued": queued,
+                "flushed": flushed,
+            }),
+            ServerEvent::Resume {
+                path,
+                session,
+                resume,
+            } => serde_json::json!({
+                "type": "resume",
+                "path": path,
+                "session": session,
+                "position": resume.position,
+                "sid": resume.sid,
+                "settings": resume.settings,
+            }),
+            ServerEvent::ConfigChanged { config, error } => serde_json::json!({
+                "type": "config_changed",
+                "config": config,
+                "error": error,
+            }),
+            ServerEvent::VersionMismatch { component, version } => serde_json::json!({
+                "type": "version_mismatch",
+                "component": component,
+                "version": version,
+                "server_version": VERSION,
+            }),
+        }
+    }
+}
+
+/// A line as sent to clients; `kind` is the message type.
+fn subtitle_json(sub: &Subtitle, kind: &str) -> serde_json::Value {
+    serde_json::json!({
+        "type": kind,
+        "id": sub.id,
+        "subtitle": sub.text,
+        "raw_subtitle": sub.raw_text,
+        "subtitle_ass": sub.text_ass,
+        "sub_start": sub.sub_start,
+        "sub_end": sub.sub_end,
+        "duration": sub.duration(),
+        "chars": sub.char_count(),
+        "cps": sub.cps(),
+        "count": sub.count(),
+        "timestamps": std::iter::once(sub.sub_start).chain(sub.repeats.iter().copied()).collect::<Vec<_>>(),
+        "session": sub.session,
+        "audio_fallback": sub.audio_fallback,
+        "sid": sub.sid,
+        "lang": sub.lang,
+        "track_title": sub.track_title,
+        "chapter": sub.chapter,
+        "song": sub.song,
+        "mined": sub.mined,
+        "estimated_timing": sub.estimated_timing,
+        "ocr": sub.ocr,
+        "asr": sub.asr,
+        "translation": sub.translation,
+        "tokens": sub.tokens,
+        "furigana": (!sub.tokens.is_empty()).then(|| morph::furigana(&sub.tokens)),
+    })
+}
+
+/// Logs panics with their location instead of the default stderr dump, so
+/// they end up in the mpv console alongside the rest of our output.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        error!(
+            "Panic in thread '{}' at {}: {}",
+            thread.name().unwrap_or("<unnamed>"),
+            info.location()
+                .map_or_else(|| "unknown location".to_string(), |l| l.to_string()),
+            panic_payload_message(info.payload())
+        );
+    }));
+}
+
+pub(crate) fn panic_payload_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Tells every client that `what` panicked.
+pub(crate) fn report_panic(events: &broadcast::Sender<ServerEvent>, what: &str, err: JoinError) {
+    let message = match err.try_into_panic() {
+        Ok(payload) => format!("{} panicked: {}", what, panic_payload_message(&*payload)),
+        Err(err) => format!("{} failed: {}", what, err),
+    };
+    error!("{}", message);
+    let _ = events.send(ServerEvent::Diagnostic { message });
+}
+
+#[derive(Clone)]
+struct CurrentFile {
+    path: String,
+    title: String,
+    session: String,
+}
+
+/// An IPC command to forward to mpv, e.g. `["show-text", "hi", 2000]`.
+pub(crate) struct MpvCommand {
+    args: serde_json::Value,
+    reply: Option<oneshot::Sender<serde_json::Value>>,
 }
 
-struct SharedState {
-    subtitles: RwLock<HashMap<u64, Subtitle>>,
+pub(crate) struct SharedState {
+    pub(crate) subtitles: RwLock<HashMap<u64, Subtitle>>,
+    pub(crate) events: broadcast::Sender<ServerEvent>,
+    next_subtitle_id: AtomicU64,
+    current_file: RwLock<CurrentFile>,
+    mpv_commands: mpsc::UnboundedSender<MpvCommand>,
+    options: MinerOptions,
+    /// Song time ranges set by clients, per media path
+    song_ranges: RwLock<HashMap<String, Vec<(f64, f64)>>>,
+    /// Applied to new lines, changeable by clients
+    filters: RwLock<Vec<TextFilter>>,
+    /// Files served over HTTP, e.g. `batch_extract` archives
+    pub(crate) downloads: Arc<Downloads>,
+    /// Set when [`MinerOptions::translate`] is
+    translator: Option<Translator>,
+    /// What the Lua script reported in reply to `subtitleminer-hello`
+    script_version: RwLock<Option<String>>,
+    /// Where notes wait while Anki is unreachable, see
+    /// [`MinerOptions::anki_queue_dir`]
+    pub(crate) anki_queue: Option<AnkiQueue>,
+    /// Where mining of each file left off, see [`MinerOptions::resume_file`]
+    resume: Option<Arc<ResumeStore>>,
+    /// What [`MinerOptions::config`] sets, replaced when it changes
+    config: std::sync::RwLock<Arc<Config>>,
+    /// [`MinerOptions::anki`] with the config's changes
+    anki: std::sync::RwLock<Option<Arc<AnkiTarget>>>,
+    /// Held while `set_settings` changes the config, so none is lost
+    config_writes: tokio::sync::Mutex<()>,
 }
 
 impl SharedState {
-    fn new() -> Arc<Self> {
-        Arc::new(Self {
+    pub(crate) fn new(options: MinerOptions) -> (Arc<Self>, mpsc::UnboundedReceiver<MpvCommand>) {
+        let (mpv_commands, commands_rx) = mpsc::unbounded_channel();
+        let config = match &options.config {
+            Some(path) => Config::load(path).unwrap_or_else(|e| {
+                warn!("[config] Ignoring the config file: {}", e);
+                Config::default()
+            }),
+            None => Config::default(),
+        };
+        let (events, _) = broadcast::channel(64);
+        let state = Arc::new(Self {
+            events,
+            next_subtitle_id: AtomicU64::new(1),
+            mpv_commands,
             subtitles: RwLock::new(HashMap::new()),
+            current_file: RwLock::new(CurrentFile {
+                path: String::new(),
+                title: String::new(),
+                session: new_session_id(),
+            }),
+            song_ranges: RwLock::new(HashMap::new()),
+            filters: RwLock::new(
+                config
+                    .filters
+                    .clone()
+                    .unwrap_or_else(|| options.filters.clone()),
+            ),
+            downloads: Arc::default(),
+            translator: options.translate.clone().map(Translator::new),
+            script_version: RwLock::new(None),
+            anki_queue: options
+                .anki
+                .as_ref()
+                .filter(|target| target.file.is_none())
+                .and(options.anki_queue_dir.clone())
+                .and_then(|dir| match AnkiQueue::open(dir) {
+                    Ok(queue) => Some(queue),
+                    Err(e) => {
+                        warn!("[anki-queue] Cannot use the queue, notes are lost while Anki is closed: {}", e);
+                        None
+                    }
+                }),
+            resume: options
+                .resume_file
+                .clone()
+                .and_then(|path| match ResumeStore::open(path) {
+                    Ok(store) => Some(Arc::new(store)),
+                    Err(e) => {
+                        warn!("[resume] Cannot remember where mining left off: {}", e);
+                        None
+                    }
+                }),
+            anki: std::sync::RwLock::new(configured_anki(options.anki.as_ref(), &config)),
+            config: std::sync::RwLock::new(Arc::new(config)),
+            config_writes: tokio::sync::Mutex::new(()),
+            options,
+        });
+        (state, commands_rx)
+    }
+
+    /// What is playing, for clients that join after it started.
+    pub(crate) async fn file_event(&self) -> Option<ServerEvent> {
+        let current = self.current_file.read().await.clone();
+        (!current.path.is_empty()).then_some(ServerEvent::FileChanged {
+            path: current.path,
+            title: current.title,
+            session: current.session,
         })
     }
-}
 
-struct PendingSubtitle {
-    id: u64,
-    text: String,
-    responses: [Option<serde_json::Value>; 4], // sub_start, sub_end, path, aid
-}
+    /// Where notes go, as configured now; `None` when mining is off.
+    pub(crate) fn anki_target(&self) -> Option<Arc<AnkiTarget>> {
+        self.anki.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
 
-impl PendingSubtitle {
-    fn new(id: u64, text: String) -> Self {
-        Self {
-            id,
-            text,
-            responses: Default::default(),
+    fn config(&self) -> Arc<Config> {
+        self.config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Reads the config file again and applies it if it changed, telling
+    /// clients. A file that doesn't parse changes nothing.
+    pub(crate) async fn reload_config(&self) {
+        let Some(path) = &self.options.config else {
+            return;
+        };
+        let config = match Config::load(path) {
+            // Also what `set_settings` just wrote
+            Ok(config) if config.raw == self.config().raw => return,
+            Ok(config) => config,
+            Err(e) => {
+                warn!("[config] Keeping the previous settings: {}", e);
+                let _ = self.events.send(ServerEvent::ConfigChanged {
+                    config: self.config().raw.clone(),
+                    error: Some(e),
+                });
+                return;
+            }
+        };
+        info!("[config] Applying {}", path.display());
+        self.apply_config(config).await;
+    }
+
+    /// Changes the settings keys in `settings` names (`null` removes one)
+    /// and saves them to the config file, if there is one. Returns all
+    /// settings.
+    pub(crate) async fn set_settings(
+        &self,
+        settings: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<serde_json::Value, String> {
+        let _writing = self.config_writes.lock().await;
+        let mut raw = match self.config().raw.clone() {
+            serde_json::Value::Object(raw) => raw,
+            _ => serde_json::Map::new(),
+        };
+        for (key, value) in settings {
+            if value.is_null() {
+                raw.remove(&key);
+            } else {
+                raw.insert(key, value);
+            }
         }
+        let config = Config::from_value(serde_json::Value::Object(raw))?;
+        if let Some(path) = &self.options.config {
+            config
+                .save(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        let raw = config.raw.clone();
+        self.apply_config(config).await;
+        Ok(raw)
+    }
+
+    async fn apply_config(&self, config: Config) {
+        // Without the key, the command line's filters apply again
+        *self.filters.write().await = config
+            .filters
+            .clone()
+            .unwrap_or_else(|| self.options.filters.clone());
+        *self.anki.write().unwrap_or_else(|e| e.into_inner()) =
+            configured_anki(self.options.anki.as_ref(), &config);
+        let raw = config.raw.clone();
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+        let _ = self.events.send(ServerEvent::ConfigChanged {
+            config: raw,
+            error: None,
+        });
     }
 
-    fn set_response(&mut self, index: usize, value: serde_json::Value) {
-        if index < 4 {
-            self.responses[index] = Some(value);
+    /// The profile for line `id`'s file, or the file playing for `None`.
+    async fn profile_for<'a>(&self, config: &'a Config, id: Option<u64>) -> Option<&'a Profile> {
+        if config.profiles.is_empty() {
+            return None;
         }
+        let current = self.current_file.read().await;
+        let (path, session) = match id {
+            Some(id) => {
+                let store = self.subtitles.read().await;
+                let sub = store.get(&id)?;
+                (sub.media_path.clone(), sub.session.clone())
+            }
+            None => (current.path.clone(), current.session.clone()),
+        };
+        // The title is only known for the file playing
+        let title = if session == current.session {
+            current.title.as_str()
+        } else {
+            ""
+        };
+        profile::matching(&config.profiles, &path, title)
     }
 
-    fn is_complete(&self) -> bool {
-        self.responses.iter().all(|r| r.is_some())
+    /// Where mining of `path` left off, if it was mined before.
+    fn resume_event(&self, path: &str, session: &str) -> Option<ServerEvent> {
+        let resume = self.resume.as_ref()?.get(path)?;
+        Some(ServerEvent::Resume {
+            path: path.to_string(),
+            session: session.to_string(),
+            resume,
+        })
     }
 
-    fn into_subtitle(self) -> Subtitle {
-        Subtitle {
-            id: self.id,
-            text: self.text,
-            sub_start: self.responses[0].as_ref().unwrap().as_f64().unwrap(),
-            sub_end: self.responses[1].as_ref().unwrap().as_f64().unwrap(),
-            media_path: self.responses[2]
-                .as_ref()
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string(),
-            aid: self.responses[3].as_ref().unwrap().as_i64().unwrap(),
+    /// [`Self::resume_event`] for what is playing, for clients that join
+    /// after it started.
+    pub(crate) async fn current_resume_event(&self) -> Option<ServerEvent> {
+        let current = self.current_file.read().await;
+        self.resume_event(&current.path, &current.session)
+    }
+
+    /// Selects the subtitle track mining of the current file last used.
+    /// mpv picks tracks while loading, so this waits for `file-loaded`.
+    async fn restore_track(&self) {
+        let path = self.current_file.read().await.path.clone();
+        let Some(sid) = self
+            .resume
+            .as_ref()
+            .and_then(|store| store.get(&path))
+            .and_then(|resume| resume.sid)
+        else {
+            return;
+        };
+        info!("[resume] Selecting subtitle track {} for {}", sid, path);
+        let _ = self.mpv_commands.send(MpvCommand {
+            args: serde_json::json!(["set_property", "sid", sid]),
+            reply: None,
+        });
+    }
+
+    /// Records the version another component reported, warning loudly
+    /// (log, mpv OSD and clients) when it isn't ours.
+    pub(crate) fn check_version(&self, component: &str, version: &str) -> bool {
+        if version == VERSION {
+            debug!("[version] {} {} matches", component, version);
+            return true;
         }
+        warn!(
+            "[version] ******** The {} is version {} but the server is {}. Update them together (`mpv-subtitleminer install-script`) ********",
+            component, version, VERSION
+        );
+        self.show_text(
+            &format!(
+                "mpv-subtitleminer: {} version {} does not match server {}",
+                component, version, VERSION
+            ),
+            5000,
+        );
+        let _ = self.events.send(ServerEvent::VersionMismatch {
+            component: component.to_string(),
+            version: version.to_string(),
+        });
+        false
     }
-}
 
-async fn query_mpv_property(
-    mpv: &mut MpvStream,
-    property: &str,
-    request_id: u64,
-) -> std::io::Result<serde_json::Value> {
-    let cmd = format!(
-        "{{\"command\":[\"get_property\",\"{}\"],\"request_id\":{}}}\n",
-        property, request_id
-    );
-    mpv.write_all(cmd.as_bytes()).await?;
+    /// Sends a command to mpv and waits for its response.
+    pub(crate) async fn mpv_command(&self, args: serde_json::Value) -> Option<serde_json::Value> {
+        let span = debug_span!("mpv_command", command = %args);
+        let (reply, rx) = oneshot::channel();
+        self.mpv_commands
+            .send(MpvCommand {
+                args,
+                reply: Some(reply),
+            })
+            .ok()?;
+        let response = timeout(Duration::from_secs(5), rx)
+            .instrument(span.clone())
+            .await;
+        let _entered = span.enter();
+        match response {
+            Ok(Ok(json)) => {
+                debug!(error = ?json.get("error"), "[mpv] Command answered");
+                Some(json)
+            }
+            _ => {
+                warn!("[mpv] Command got no answer");
+                None
+            }
+        }
+    }
 
-    let mut line = String::new();
-    loop {
-        line.clear();
-        if mpv.read_line(&mut line).await? == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "mpv IPC closed while waiting for property response",
-            ));
+    /// Shows `text` on mpv's OSD for `duration_ms` without waiting.
+    pub(crate) fn show_text(&self, text: &str, duration_ms: u64) {
+        let _ = self.mpv_commands.send(MpvCommand {
+            args: serde_json::json!(["show-text", text, duration_ms]),
+            reply: None,
+        });
+    }
+
+    /// Every captured line in capture order.
+    pub(crate) async fn sorted_subtitles(&self) -> Vec<Subtitle> {
+        let mut subs: Vec<_> = self.subtitles.read().await.values().cloned().collect();
+        subs.sort_by_key(|s| s.id);
+        subs
+    }
+
+    /// Up to `before`/`after` lines around `id` from the same file, including
+    /// `id` itself. `None` if `id` is unknown.
+    pub(crate) async fn neighbors(
+        &self,
+        id: u64,
+        before: usize,
+        after: usize,
+    ) -> Option<Vec<Subtitle>> {
+        let session = self.subtitles.read().await.get(&id)?.session.clone();
+        let subs: Vec<_> = self
+            .sorted_subtitles()
+            .await
+            .into_iter()
+            .filter(|s| s.session == session)
+            .collect();
+        let index = subs.iter().position(|s| s.id == id)?;
+        let from = index.saturating_sub(before);
+        let to = (index + after + 1).min(subs.len());
+        Some(subs[from..to].to_vec())
+    }
+
+    /// Lines of `session` overlapping `start..end` seconds, by start time.
+    pub(crate) async fn range(&self, session: &str, start: f64, end: f64) -> Vec<Subtitle> {
+        let mut subs: Vec<_> = self
+            .sorted_subtitles()
+            .await
+            .into_iter()
+            .filter(|s| s.session == session && s.sub_end > start && s.sub_start < end)
+            .collect();
+        subs.sort_by(|a, b| a.sub_start.total_cmp(&b.sub_start));
+        subs
+    }
+
+    /// The latest `limit` lines whose text `matches`, oldest first.
+    pub(crate) async fn search(
+        &self,
+        matches: impl Fn(&str) -> bool,
+        session: Option<&str>,
+        limit: usize,
+    ) -> Vec<Subtitle> {
+        let mut found: Vec<_> = self
+            .sorted_subtitles()
+            .await
+            .into_iter()
+            .rev()
+            .filter(|s| session.is_none_or(|session| s.session == session))
+            .filter(|s| matches(&s.text) || s.raw_text.as_deref().is_some_and(&matches))
+            .take(limit)
+            .collect();
+        found.reverse();
+        found
+    }
+
+    /// Whether `sub` is lyrics, by its chapter or a song range for its file.
+    async fn is_song(&self, sub: &Subtitle) -> bool {
+        if sub.chapter.as_deref().is_some_and(is_song_chapter) {
+            return true;
         }
-        if let Ok(json) = serde_json::from_str::<serde_json::Value>(&line)
-            && json.get("request_id").and_then(|v| v.as_u64()) == Some(request_id)
+        self.song_ranges
+            .read()
+            .await
+            .get(&sub.media_path)
+            .is_some_and(|ranges| {
+                ranges
+                    .iter()
+                    .any(|&(start, end)| sub.sub_start >= start && sub.sub_start < end)
+            })
+    }
+
+    /// Flags the lines in `ids` for every client and returns their joined text.
+    pub(crate) async fn set_mined(
+        &self,
+        ids: std::ops::RangeInclusive<u64>,
+        mined: bool,
+    ) -> String {
+        let mut store = self.subtitles.write().await;
+        let mut texts = Vec::new();
+        let mut newly_mined = Vec::new();
+        // Only stored lines: a client can name any span, however wide
+        let mut stored: Vec<u64> = store
+            .keys()
+            .copied()
+            .filter(|id| ids.contains(id))
+            .collect();
+        stored.sort_unstable();
+        for id in stored {
+            if let Some(sub) = store.get_mut(&id) {
+                texts.push(sub.text.clone());
+                if sub.mined != mined {
+                    sub.mined = mined;
+                    let _ = self.events.send(ServerEvent::SubtitleUpdated(sub.clone()));
+                    if mined {
+                        newly_mined.push(sub.clone());
+                    }
+                }
+            }
+        }
+        drop(store);
+        if let Some(last) = newly_mined.iter().max_by_key(|s| s.id)
+            && let Some(resume) = self.resume.clone()
         {
-            return Ok(json);
+            let (path, start, sid) = (last.media_path.clone(), last.sub_start, last.sid);
+            tokio::task::spawn_blocking(move || resume.record_mined(&path, start, sid));
+        }
+        if !newly_mined.is_empty() {
+            self.log_markdown(newly_mined).await;
         }
+        texts.join(" ")
     }
-}
 
-async fn query_mpv_property_with_timeout(
-    mpv: &mut MpvStream,
-    property: &str,
-    request_id: u64,
-) -> std::io::Result<serde_json::Value> {
-    timeout(
-        Duration::from_secs(1),
-        query_mpv_property(mpv, property, request_id),
-    )
-    .await
-    .map_err(|_| {
-        std::io::Error::new(
-            std::io::ErrorKind::TimedOut,
-            format!("Timed out querying mpv property '{}'", property),
-        )
-    })?
-}
+    /// Appends newly mined lines to the Markdown log, if configured.
+    async fn log_markdown(&self, subs: Vec<Subtitle>) {
+        let Some(dir) = self.options.markdown_dir.clone() else {
+            return;
+        };
+        let title = {
+            let current = self.current_file.read().await;
+            (!current.title.is_empty()).then(|| (current.session.clone(), current.title.clone()))
+        };
+        tokio::task::spawn_blocking(move || {
+            for sub in subs {
+                let title = title
+                    .as_ref()
+                    .filter(|(session, _)| *session == sub.session)
+                    .map(|(_, title)| title.as_str());
+                if let Err(e) = markdown::append(&dir, &sub, title) {
+                    warn!("[markdown] Failed to log line {}: {}", sub.id, e);
+                }
+            }
+        });
+    }
 
-async fn get_mpv_pid(mpv: &mut MpvStream) -> std::io::Result<u32> {
-    let json = match query_mpv_property_with_timeout(mpv, "pid", 1).await {
-        Ok(json) => json,
-        Err(_) => query_mpv_property_with_timeout(mpv, "process-id", 2).await?,
-    };
-    let status = json.get("error").and_then(|e| e.as_str()).unwrap_or("");
-    if status != "success" {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("mpv returned error querying PID: {}", status),
-        ));
+    /// Counts `sub` as another occurrence of an earlier line with the same
+    /// text in its session, if there is one, and tells clients. Returns the
+    /// earlier line's id.
+    async fn collapse_duplicate(&self, sub: &Subtitle) -> Option<u64> {
+        let mut store = self.subtitles.write().await;
+        let original = store
+            .values_mut()
+            .filter(|s| s.id != sub.id && s.session == sub.session && s.text == sub.text)
+            .min_by_key(|s| s.id)?;
+        // Seeking back shows the same occurrence again
+        let known = std::iter::once(original.sub_start)
+            .chain(original.repeats.iter().copied())
+            .any(|start| (start - sub.sub_start).abs() < 0.1);
+        if !known {
+            original.repeats.push(sub.sub_start);
+        }
+        debug!(
+            "[sub:{}] Collapsed into line {} ({}x)",
+            sub.id,
+            original.id,
+            original.count()
+        );
+        let _ = self
+            .events
+            .send(ServerEvent::SubtitleUpdated(original.clone()));
+        Some(original.id)
     }
 
-    let pid = json
-        .get("data")
-        .and_then(|d| {
-            d.as_u64()
-                .or_else(|| d.as_i64().and_then(|n| u64::try_from(n).ok()))
-        })
-        .ok_or_else(|| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "mpv returned non-integer PID",
-            )
-        })?;
+    /// `text` as it is sent, after the filters; `None` when nothing is left
+    /// of it or it is suppressed.
+    async fn filter_text(&self, text: &str) -> Option<String> {
+        let filtered = apply_filters(&self.filters.read().await, text);
+        if filtered.is_empty() || self.options.suppress.suppresses(&filtered) {
+            debug!("[sub] Filtered out: {}", text);
+            return None;
+        }
+        Some(filtered)
+    }
 
-    u32::try_from(pid)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "mpv PID out of range"))
-}
+    /// Confirms a mined card on mpv's OSD, unless turned off in the options.
+    pub(crate) fn show_mined(&self, text: &str) {
+        let duration_ms = self.options.mined_osd_ms;
+        if duration_ms > 0 {
+            self.show_text(&format!("Card added: {}", text), duration_ms);
+        }
+    }
 
-pub async fn run_server(
-    socket_path: &str,
-    port: u16,
-    expected_mpv_pid: Option<u32>,
-) -> std::io::Result<()> {
-    let mut mpv = MpvStream::connect(socket_path).await?;
-    if let Some(expected) = expected_mpv_pid {
-        let actual = get_mpv_pid(&mut mpv).await?;
-        if actual != expected {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!(
-                    "MPV_IPC_PID_MISMATCH expected={} actual={} socket={}",
-                    expected, actual, socket_path
+    /// Adds subtitle `id` to Anki as configured in [`MinerOptions::anki`],
+    /// confirming on the OSD either way. A line whose sentence is already
+    /// in the deck is not added again unless `allow_duplicate`. With `open`,
+    /// or [`MinerOptions::open_mined`] when not given, the new note is shown
+    /// in Anki's browser.
+    pub(crate) async fn mine(
+        &self,
+        id: u64,
+        allow_duplicate: bool,
+        open: Option<bool>,
+    ) -> Result<Mined, String> {
+        let result = match self.line_to_mine(id).await {
+            Ok((target, sub, title)) => {
+                self.add_note(&target, &[id], &sub, title.as_deref(), allow_duplicate)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let text = self
+            .subtitles
+            .read()
+            .await
+            .get(&id)
+            .map(|s| s.text.clone())
+            .unwrap_or_default();
+        match &result {
+            Ok(Mined::Added(note_id)) => {
+                self.set_mined(id..=id, true).await;
+                self.show_mined(&text);
+                if open.unwrap_or(self.options.open_mined) {
+                    self.open_notes(&[*note_id]).await;
+                }
+            }
+            Ok(Mined::Duplicate(_) | Mined::Skipped(_)) => {
+                self.show_text(&format!("Already in Anki: {}", text), 3000)
+            }
+            Ok(Mined::Queued(waiting)) => self.show_text(
+                &format!(
+                    "Anki is not running, card queued ({} waiting): {}",
+                    waiting, text
                 ),
-            ));
+                3000,
+            ),
+            Err(message) => self.show_text(message, 3000),
         }
+        result
     }
-    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
 
-    println!(
-        "WebSocket server listening on {}",
-        listener
-            .local_addr()
-            .map_or_else(|_| format!("port {}", port), |a| a.to_string())
-    );
+    /// Adds the lines `ids` to Anki, one note each or with `combined` a
+    /// single note for all of them, reporting each step to clients as
+    /// `mine_progress` and the outcome as `mine_batch_done`. With `open` the
+    /// added notes are shown together in Anki's browser at the end.
+    pub(crate) async fn mine_batch(
+        &self,
+        batch: u64,
+        ids: Vec<u64>,
+        combined: bool,
+        allow_duplicate: bool,
+        open: bool,
+    ) {
+        let total = if combined { 1 } else { ids.len() };
+        let (mut added, mut duplicates, mut queued, mut failed) = (0, 0, 0, 0);
+        let mut note_ids = Vec::new();
+        let jobs: Vec<Vec<u64>> = if combined {
+            vec![ids]
+        } else {
+            ids.into_iter().map(|id| vec![id]).collect()
+        };
+        for (done, job) in jobs.into_iter().enumerate() {
+            let result = match self.lines_to_mine(&job).await {
+                Ok((target, sub, title)) => {
+                    self.add_note(&target, &job, &sub, title.as_deref(), allow_duplicate)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            let (note_id, duplicate, was_queued, error) = match result {
+                Ok(Mined::Added(note_id)) => {
+                    added += 1;
+                    note_ids.push(note_id);
+                    for id in &job {
+                        self.set_mined(*id..=*id, true).await;
+                    }
+                    (Some(note_id), false, false, None)
+                }
+                Ok(Mined::Duplicate(note_id) | Mined::Skipped(note_id)) => {
+                    duplicates += 1;
+                    (Some(note_id), true, false, None)
+                }
+                Ok(Mined::Queued(_)) => {
+                    queued += 1;
+                    (None, false, true, None)
+                }
+                Err(e) => {
+                    failed += 1;
+                    (None, false, false, Some(e))
+                }
+            };
+            let _ = self.events.send(ServerEvent::MineProgress {
+                batch,
+                done: done + 1,
+                total,
+                ids: job,
+                note_id,
+                duplicate,
+                queued: was_queued,
+                error,
+            });
+        }
+        info!(
+            "[mine] Batch {}: {} added, {} duplicates, {} queued, {} failed",
+            batch, added, duplicates, queued, failed
+        );
+        if self.options.mined_osd_ms > 0 {
+            self.show_text(
+                &format!(
+                    "Cards added: {}/{}{}{}",
+                    added,
+                    total,
+                    if queued > 0 {
+                        " (some queued for Anki)"
+                    } else {
+                        ""
+                    },
+                    if failed > 0 { " (some failed)" } else { "" }
+                ),
+                self.options.mined_osd_ms,
+            );
+        }
+        let _ = self.events.send(ServerEvent::MineBatchDone {
+            batch,
+            added,
+            duplicates,
+            queued,
+            failed,
+        });
+        if open && !note_ids.is_empty() {
+            self.open_notes(&note_ids).await;
+        }
+    }
 
-    let state = SharedState::new();
-    let (subtitle_tx, _) = broadcast::channel::<Subtitle>(64);
+    /// Shows `note_ids` in Anki's browser for review.
+    async fn open_notes(&self, note_ids: &[u64]) {
+        if self.anki_target().is_some_and(|t| t.file.is_some()) {
+            return;
+        }
+        let ids: Vec<_> = note_ids.iter().map(u64::to_string).collect();
+        if let Err(e) = self
+            .anki_client()
+            .gui_browse(&format!("nid:{}", ids.join(",")))
+            .await
+        {
+            warn!("[mine] Could not open the new notes in Anki: {}", e);
+        }
+    }
 
-    let mpv_state = state.clone();
-    let mpv_tx = subtitle_tx.clone();
-    tokio::spawn(async move {
-        if let Err(e) = handle_mpv(mpv, mpv_state, mpv_tx).await {
-            error!("MPV handler error: {}", e);
+    /// Adds `sub` as a note unless it is a duplicate (see [`Self::mine`]),
+    /// queueing it when Anki is unreachable.
+    async fn add_note(
+        &self,
+        target: &AnkiTarget,
+        ids: &[u64],
+        sub: &Subtitle,
+        title: Option<&str>,
+        allow_duplicate: bool,
+    ) -> Result<Mined, String> {
+        // Mined before its words arrived
+        let tokenized;
+        let sub = if sub.tokens.is_empty() && self.options.morph.is_some() {
+            tokenized = Subtitle {
+                tokens: tokenize(self, &sub.text).await,
+                ..sub.clone()
+            };
+            &tokenized
+        } else {
+            sub
+        };
+        if let Some(file) = &target.file {
+            let prepared = offline::prepare_line(target, sub, title)
+                .await
+                .map_err(mining_failed)?;
+            let note_id = file
+                .add(&prepared, &target.field_names())
+                .map_err(mining_failed)?;
+            info!(
+                "[mine] Wrote subtitle {} to {}",
+                sub.id,
+                file.path.display()
+            );
+            self.card_mined(sub, title, note_id, &prepared);
+            return Ok(Mined::Added(note_id));
         }
-        info!("MPV connection closed, shutting down.");
-        std::process::exit(0);
-    });
+        let client = AnkiConnect::new(&target.url);
+        if !allow_duplicate {
+            match crate::offline::find_duplicate(&client, target, sub).await {
+                Ok(Some(note_id)) => {
+                    info!("[mine] Subtitle {} is already note {}", sub.id, note_id);
+                    return Ok(if target.skip_duplicates {
+                        Mined::Skipped(note_id)
+                    } else {
+                        Mined::Duplicate(note_id)
+                    });
+                }
+                Ok(None) => {}
+                Err(e) => warn!("[mine] Duplicate check failed: {}", e),
+            }
+        }
+        let mut prepared = offline::prepare_line(target, sub, title)
+            .await
+            .map_err(mining_failed)?;
+        prepared.note.allow_duplicate = allow_duplicate;
+        match offline::add_prepared(&client, &prepared).await {
+            Ok(note_id) => {
+                info!("[mine] Added note {} for subtitle {}", note_id, sub.id);
+                self.card_mined(sub, title, note_id, &prepared);
+                Ok(Mined::Added(note_id))
+            }
+            Err(e) if anki::is_unreachable(e.as_ref()) && self.anki_queue.is_some() => {
+                let line = MinedLine {
+                    ids: ids.to_vec(),
+                    sub: sub.clone(),
+                    title: title.map(str::to_string),
+                };
+                self.queue_note(&prepared, line, e.to_string())
+            }
+            Err(e) => Err(mining_failed(e)),
+        }
+    }
 
-    let mut client_id = 0u64;
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        client_id += 1;
-        let id = client_id;
+    /// Runs the `card_mined` hook, if configured.
+    fn card_mined(
+        &self,
+        sub: &Subtitle,
+        title: Option<&str>,
+        note_id: u64,
+        prepared: &PreparedNote,
+    ) {
+        if let Some(command) = &self.options.hooks.on_mined {
+            hooks::card_mined(command.clone(), sub, title, note_id, prepared);
+        }
+    }
+
+    /// AnkiConnect at `--anki-url` when mining is configured, at its
+    /// default address otherwise.
+    fn anki_client(&self) -> AnkiConnect {
+        AnkiConnect::new(
+            self.anki_target()
+                .as_ref()
+                .map_or(anki::DEFAULT_ANKICONNECT_URL, |target| &target.url),
+        )
+    }
+
+    /// Keeps `prepared` for when Anki is back; `error` is why it couldn't
+    /// be added now.
+    fn queue_note(
+        &self,
+        prepared: &PreparedNote,
+        line: MinedLine,
+        error: String,
+    ) -> Result<Mined, String> {
+        let Some(queue) = &self.anki_queue else {
+            return Err(format!("Mining failed: {}", error));
+        };
+        match queue.push(prepared, line) {
+            Ok(waiting) => {
+                info!(
+                    "[anki-queue] Anki is unreachable ({}), queued the note ({} waiting)",
+                    error, waiting
+                );
+                let _ = self.events.send(ServerEvent::AnkiQueue {
+                    queued: waiting,
+                    flushed: 0,
+                });
+                Ok(Mined::Queued(waiting))
+            }
+            Err(e) => {
+                let message = format!("Mining failed: {}, and queueing failed: {}", error, e);
+                warn!("[mine] {}", message);
+                Err(message)
+            }
+        }
+    }
+
+    /// Adds the notes queued while Anki was unreachable if it answers again,
+    /// telling clients and the OSD.
+    pub(crate) async fn flush_anki_queue(&self) {
+        let (Some(queue), Some(target)) = (&self.anki_queue, self.anki_target()) else {
+            return;
+        };
+        if queue.is_empty() {
+            return;
+        }
+        let client = AnkiConnect::new(&target.url);
+        if client.version().await.is_err() {
+            return;
+        }
+        let added = queue.flush(&client).await;
+        if added.is_empty() {
+            return;
+        }
+        let flushed = added.len();
+        for note in added {
+            self.queued_note_added(note).await;
+        }
+        let queued = queue.len();
+        info!(
+            "[anki-queue] Anki is back, added {} queued notes ({} still waiting)",
+            flushed, queued
+        );
+        self.show_text(&format!("Queued cards added to Anki: {}", flushed), 3000);
+        let _ = self.events.send(ServerEvent::AnkiQueue { queued, flushed });
+    }
+
+    /// Marks the lines of a queued note mined and runs the `card_mined`
+    /// hook, now that Anki took it.
+    async fn queued_note_added(&self, added: Flushed) {
+        let Some(line) = added.line else {
+            return;
+        };
+        self.card_mined(
+            &line.sub,
+            line.title.as_deref(),
+            added.note_id,
+            &added.prepared,
+        );
+        // Ids start over every run, only lines of the same session are ours
+        let stored: Vec<u64> = {
+            let store = self.subtitles.read().await;
+            line.ids
+                .iter()
+                .copied()
+                .filter(|id| store.get(id).is_some_and(|s| s.session == line.sub.session))
+                .collect()
+        };
+        if stored.is_empty() {
+            self.log_markdown(vec![line.sub]).await;
+        }
+        for id in stored {
+            self.set_mined(id..=id, true).await;
+        }
+    }
+
+    /// Puts subtitle `id` into the note added last, if that was within
+    /// [`MinerOptions::last_card_window`], confirming on the OSD either way.
+    pub(crate) async fn mine_to_last_card(&self, id: u64) -> Result<u64, String> {
+        let (target, sub, title) = self.line_to_mine(id).await?;
+        if target.file.is_some() {
+            let message = "Updating the last card needs AnkiConnect, not --anki-file".to_string();
+            self.show_text(&message, 3000);
+            return Err(message);
+        }
+        let client = AnkiConnect::new(&target.url);
+        let window = self.options.last_card_window;
+        match crate::offline::update_last_note(&client, &target, &sub, title.as_deref(), window)
+            .await
+        {
+            Ok(note_id) => {
+                info!("[mine] Updated note {} with subtitle {}", note_id, id);
+                self.set_mined(id..=id, true).await;
+                if self.options.mined_osd_ms > 0 {
+                    self.show_text(
+                        &format!("Card updated: {}", sub.text),
+                        self.options.mined_osd_ms,
+                    );
+                }
+                Ok(note_id)
+            }
+            Err(e) => {
+                let message = format!("Updating the last card failed: {}", e);
+                warn!("[mine] {}", message);
+                self.show_text(&message, 3000);
+                Err(message)
+            }
+        }
+    }
+
+    /// Like [`Self::line_to_mine`] for several lines of one file as one:
+    /// their text joined line by line, their audio from the first start to
+    /// the last end.
+    async fn lines_to_mine(
+        &self,
+        ids: &[u64],
+    ) -> Result<(Arc<AnkiTarget>, Subtitle, Option<String>), String> {
+        let (&first, rest) = ids.split_first().ok_or("No lines given")?;
+        let (target, mut combined, title) = self.line_to_mine(first).await?;
+        for &id in rest {
+            let (_, sub, _) = self.line_to_mine(id).await?;
+            if sub.media_path != combined.media_path {
+                return Err(format!(
+                    "Line {} is from another file, lines mined as one note must share it",
+                    id
+                ));
+            }
+            combined.text = format!("{}\n{}", combined.text, sub.text);
+            combined.translation = match (combined.translation, sub.translation) {
+                (Some(a), Some(b)) => Some(format!("{}\n{}", a, b)),
+                (a, b) => a.or(b),
+            };
+            combined.tokens.extend(sub.tokens);
+            combined.sub_start = combined.sub_start.min(sub.sub_start);
+            combined.sub_end = combined.sub_end.max(sub.sub_end);
+        }
+        Ok((target, combined, title))
+    }
+
+    /// The target, subtitle `id` (translated if a field wants it) and the
+    /// title of its file, for mining.
+    async fn line_to_mine(
+        &self,
+        id: u64,
+    ) -> Result<(Arc<AnkiTarget>, Subtitle, Option<String>), String> {
+        if crate::media::is_dry_run() {
+            return Err("dry run (--dry-run), nothing is mined".into());
+        }
+        let target = self
+            .anki_target()
+            .ok_or("Anki is not configured, start the server with --anki")?;
+        let mut sub = self
+            .subtitles
+            .read()
+            .await
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| format!("No subtitle {}", id))?;
+        // Mined before its translation arrived
+        if let (Some(translator), None, true) = (
+            &self.translator,
+            &sub.translation,
+            target.wants_translation(),
+        ) {
+            match translator.translate(&sub.text).await {
+                Ok(translation) => sub.translation = Some(translation),
+                Err(e) => warn!("[translate] Line {}: {}", id, e),
+            }
+        }
+
+        let title = {
+            let current = self.current_file.read().await;
+            (current.session == sub.session && !current.title.is_empty())
+                .then(|| current.title.clone())
+        };
+        Ok((target, sub, title))
+    }
+}
+
+/// `anki` with `config`'s changes over it.
+fn configured_anki(anki: Option<&AnkiTarget>, config: &Config) -> Option<Arc<AnkiTarget>> {
+    match (anki, &config.anki) {
+        (Some(target), Some(settings)) => Some(Arc::new(settings.apply(target))),
+        (Some(target), None) => Some(Arc::new(target.clone())),
+        (None, Some(_)) => {
+            debug!("[config] Ignoring the anki settings, mining needs --anki or --anki-file");
+            None
+        }
+        (None, None) => None,
+    }
+}
+
+/// Logs why a note couldn't be added and returns it for the client.
+fn mining_failed(e: impl std::fmt::Display) -> String {
+    let message = format!("Mining failed: {}", e);
+    warn!("[mine] {}", message);
+    message
+}
+
+/// Numbers `mine_batch` requests, so their progress events can be told apart.
+static NEXT_BATCH: AtomicU64 = AtomicU64::new(1);
+
+/// What [`SharedState::mine`] did.
+pub(crate) enum Mined {
+    Added(u64),
+    /// A note in the deck already has the sentence, so none was added; the
+    /// client may ask to add it anyway
+    Duplicate(u64),
+    /// The same, with `--skip-duplicates`: not worth asking about
+    Skipped(u64),
+    /// Anki was unreachable, so the note was queued; how many now wait
+    Queued(usize),
+}
+
+/// Latest values of the mpv properties a subtitle is built from, kept up to
+/// date with `observe_property` so a new line needs no round trip.
+#[derive(Default)]
+struct Observed {
+    sub_start: Option<f64>,
+    sub_end: Option<f64>,
+    aid: Option<i64>,
+    sid: Option<i64>,
+    track_list: serde_json::Value,
+    chapter: Option<i64>,
+    chapter_list: serde_json::Value,
+    text_ass: Option<String>,
+}
+
+impl Observed {
+    /// Records a property change, returning false for properties not tracked here.
+    fn update(&mut self, name: &str, data: &serde_json::Value) -> bool {
+        match name {
+            "sub-start" => self.sub_start = data.as_f64(),
+            "sub-end" => self.sub_end = data.as_f64(),
+            // `aid` is `false`/"no" when audio is disabled in mpv
+            "aid" => self.aid = data.as_i64(),
+            "sid" => self.sid = data.as_i64(),
+            "track-list" => self.track_list = data.clone(),
+            "chapter" => self.chapter = data.as_i64(),
+            "chapter-list" => self.chapter_list = data.clone(),
+            "sub-text-ass" => {
+                self.text_ass = data.as_str().filter(|s| !s.is_empty()).map(str::to_string)
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The chapter containing `sub-start`, or mpv's current chapter when the
+    /// line has no timing.
+    fn chapter_title(&self) -> Option<String> {
+        let chapters = self.chapter_list.as_array()?;
+        let index = match self.sub_start {
+            Some(start) => chapters
+                .iter()
+                .rposition(|c| c.get("time").and_then(|t| t.as_f64()) <= Some(start))?,
+            None => usize::try_from(self.chapter?).ok()?,
+        };
+        chapters
+            .get(index)?
+            .get("title")
+            .and_then(|t| t.as_str())
+            .map(str::to_string)
+    }
+
+    /// ffmpeg's index of the selected subtitle track if it is image-based,
+    /// and the video size its pictures are drawn for.
+    fn image_sub_track(&self) -> Option<(i64, i64, i64)> {
+        let track = find_track(&self.track_list, "sub", self.sid?)?;
+        if !ocr::is_image_codec(track.get("codec")?.as_str()?) {
+            return None;
+        }
+        let video = self.track_list.as_array()?.iter().find(|t| {
+            t.get("type").and_then(|t| t.as_str()) == Some("video")
+                && t.get("selected").and_then(|s| s.as_bool()) == Some(true)
+        });
+        let dimension = |key: &str, default: i64| {
+            video
+                .and_then(|v| v.get(key))
+                .and_then(|d| d.as_i64())
+                .unwrap_or(default)
+        };
+        Some((
+            track.get("ff-index")?.as_i64()?,
+            dimension("demux-w", 1920),
+            dimension("demux-h", 1080),
+        ))
+    }
+
+    fn subtitle(&self, id: u64, text: String, media_path: String, session: String) -> Subtitle {
+        let aid = self
+            .aid
+            .unwrap_or_else(|| first_audio_track(&self.track_list).unwrap_or(1));
+        let track = self
+            .sid
+            .and_then(|sid| find_track(&self.track_list, "sub", sid));
+        let track_str = |key: &str| {
+            track
+                .and_then(|t| t.get(key))
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        };
+        Subtitle {
+            id,
+            text,
+            raw_text: None,
+            text_ass: self.text_ass.clone(),
+            sub_start: self.sub_start.unwrap_or_default(),
+            sub_end: self.sub_end.unwrap_or_default(),
+            media_path,
+            aid,
+            ff_index: audio_ff_index(&self.track_list, aid),
+            audio_fallback: self.aid.is_none(),
+            sid: self.sid,
+            lang: track_str("lang"),
+            track_title: track_str("title"),
+            chapter: self.chapter_title(),
+            song: false,
+            seen_count: 1,
+            mined: false,
+            session,
+            // Signs and image subs may come without timing
+            estimated_timing: self.sub_start.is_none() || self.sub_end.is_none(),
+            ocr: false,
+            asr: false,
+            translation: None,
+            tokens: Vec::new(),
+            repeats: Vec::new(),
+        }
+    }
+}
+
+/// A line without timing, waiting for `playback-time` to estimate it.
+struct Untimed {
+    sub: Subtitle,
+    sub_start: Option<f64>,
+    sub_end: Option<f64>,
+}
+
+impl Untimed {
+    fn into_subtitle(mut self, playback_time: f64, timing_window: f64) -> Subtitle {
+        self.sub.sub_start = self
+            .sub_start
+            .unwrap_or((playback_time - timing_window).max(0.0));
+        self.sub.sub_end = self.sub_end.unwrap_or(playback_time + timing_window);
+        self.sub
+    }
+}
+
+/// Finds out what ffmpeg has to open for the network path `path`: the URL,
+/// headers and cookies mpv uses if it plays a plain HTTP stream, yt-dlp's
+/// answer otherwise (e.g. when mpv stitched separate video and audio into
+/// EDL).
+async fn resolve_stream(state: Arc<SharedState>, path: String) {
+    let get = |name: &'static str| {
+        let state = state.clone();
+        async move {
+            state
+                .mpv_command(serde_json::json!(["get_property", name]))
+                .await
+                .and_then(|r| r.get("data").cloned())
+        }
+    };
+
+    let mut opened = None;
+    for _ in 0..STREAM_POLL_ATTEMPTS {
+        tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+        opened = get("stream-open-filename")
+            .await
+            .and_then(|v| v.as_str().map(str::to_string));
+        if opened.as_ref().is_some_and(|url| *url != path) {
+            break;
+        }
+    }
+
+    // mpv only sends its cookie file along with --cookies
+    let cookies_file = match get("options/cookies").await.and_then(|v| v.as_bool()) {
+        Some(true) => get("options/cookies-file")
+            .await
+            .and_then(|v| v.as_str().map(PathBuf::from))
+            .filter(|file| !file.as_os_str().is_empty()),
+        _ => None,
+    };
+
+    let direct = opened.filter(|url| url.starts_with("http://") || url.starts_with("https://"));
+    let source = match direct {
+        Some(video) => StreamSource {
+            video,
+            audio: None,
+            headers: get("http-header-fields")
+                .await
+                .and_then(|v| serde_json::from_value(v).ok())
+                .unwrap_or_default(),
+            user_agent: get("user-agent")
+                .await
+                .and_then(|v| v.as_str().map(str::to_string)),
+            cookies: match &cookies_file {
+                Some(file) => match stream::read_cookies_file(file) {
+                    Ok(cookies) => Some(cookies),
+                    Err(e) => {
+                        warn!("[stream] Can't read cookies from {}: {}", file.display(), e);
+                        None
+                    }
+                },
+                None => None,
+            },
+        },
+        None => {
+            let url = path.clone();
+            match tokio::task::spawn_blocking(move || {
+                stream::resolve_with_ytdl(&url, cookies_file.as_deref())
+            })
+            .await
+            {
+                Ok(Ok(source)) => source,
+                Ok(Err(e)) => {
+                    warn!("[stream] Can't resolve {}: {}", path, e);
+                    return;
+                }
+                Err(_) => return,
+            }
+        }
+    };
+    stream::register(&path, source);
+}
+
+/// Passes the disc device mpv plays `path` from on to the mpv that cuts
+/// media from it, which doesn't read the user's config.
+async fn resolve_device(state: Arc<SharedState>, path: String, option: &'static str) {
+    let device = state
+        .mpv_command(serde_json::json!([
+            "get_property",
+            format!("options/{}", option)
+        ]))
+        .await
+        .and_then(|r| r.get("data")?.as_str().map(str::to_string))
+        .filter(|d| !d.is_empty());
+    if let Some(device) = device {
+        stream::register_mpv_options(&path, vec![format!("--{}={}", option, device)]);
+    }
+}
+
+/// Checks whether the current file plays its chapters out of order, so
+/// media is cut from where mpv actually is in the file, see [`timeline`].
+async fn resolve_timeline(state: Arc<SharedState>, chapter_list: serde_json::Value) {
+    let path = state.current_file.read().await.path.clone();
+    let starts: Vec<f64> = chapter_list
+        .as_array()
+        .map(|chapters| {
+            chapters
+                .iter()
+                .filter_map(|c| c.get("time")?.as_f64())
+                .collect()
+        })
+        .unwrap_or_default();
+    if starts.is_empty()
+        || path.is_empty()
+        || stream::is_network(&path)
+        || stream::is_mpv_only(&path)
+    {
+        return;
+    }
+
+    let probed = path.clone();
+    let chapters =
+        match tokio::task::spawn_blocking(move || crate::media::probe_chapters(&probed)).await {
+            Ok(Ok(chapters)) => chapters,
+            Ok(Err(e)) => {
+                debug!("[timeline] Can't read chapters of {}: {}", path, e);
+                return;
+            }
+            Err(_) => return,
+        };
+    if !timeline::register(&path, &starts, &chapters) {
+        stream::require_mpv(&path);
+    }
+}
+
+/// Reads the text of the image subtitle `sub` and publishes it like any
+/// other line, flagged as OCR.
+async fn read_image_line(
+    state: Arc<SharedState>,
+    mut sub: Subtitle,
+    stream: i64,
+    width: i64,
+    height: i64,
+) {
+    let Some(options) = state.options.ocr.clone() else {
+        return;
+    };
+    let request = FfmpegRequest::subtitle_image(&sub, stream, width, height);
+    let result = tokio::task::spawn_blocking(move || {
+        let png = request.run().map_err(|e| e.to_string())?;
+        ocr::recognize(&png, &options).map_err(|e| e.to_string())
+    })
+    .await;
+    let text = match result {
+        Ok(Ok(text)) if !text.is_empty() => text,
+        Ok(Ok(_)) => {
+            debug!("[ocr] No text at {:.3}", sub.sub_start);
+            return;
+        }
+        Ok(Err(e)) => {
+            warn!("[ocr] Can't read line at {:.3}: {}", sub.sub_start, e);
+            return;
+        }
+        Err(_) => return,
+    };
+
+    let Some(filtered) = state.filter_text(&text).await else {
+        return;
+    };
+    sub.id = state.next_subtitle_id.fetch_add(1, Ordering::Relaxed);
+    info!("[sub:{}] {} (OCR)", sub.id, filtered);
+    if filtered != text {
+        sub.raw_text = Some(text);
+    }
+    sub.text = filtered;
+    sub.ocr = true;
+    publish_subtitle(&state, sub).await;
+}
+
+/// Stores `sub` and sends it to every client, unless it is hidden lyrics.
+/// Its words and translation follow as updates. Returns the id the line is
+/// stored under: its own, or that of the earlier line it was collapsed into.
+async fn publish_subtitle(state: &Arc<SharedState>, mut sub: Subtitle) -> Option<u64> {
+    if state.options.song_lines != SongLines::Keep && state.is_song(&sub).await {
+        if state.options.song_lines == SongLines::Hide {
+            debug!("[sub:{}] Hiding song line", sub.id);
+            return None;
+        }
+        sub.song = true;
+    }
+    if state.options.collapse_duplicates
+        && let Some(original) = state.collapse_duplicate(&sub).await
+    {
+        return Some(original);
+    }
+    if sub.audio_fallback {
+        warn!(
+            "[sub:{}] No audio track selected in mpv, using track {}",
+            sub.id, sub.aid
+        );
+    }
+    if sub.estimated_timing {
+        debug!(
+            "[sub:{}] No timing from mpv, estimated {:.2}-{:.2}",
+            sub.id, sub.sub_start, sub.sub_end
+        );
+    }
+    debug!("[sub:{}] Broadcasting", sub.id);
+    state.subtitles.write().await.insert(sub.id, sub.clone());
+    METRICS.subtitle_captured();
+    if state.options.morph.is_some() {
+        tokio::spawn(tokenize_line(state.clone(), sub.id, sub.text.clone()));
+    }
+    if state.translator.is_some() {
+        tokio::spawn(translate_line(state.clone(), sub.id, sub.text.clone()));
+    }
+    let id = sub.id;
+    let _ = state.events.send(ServerEvent::Subtitle(sub));
+    Some(id)
+}
+
+/// Tokenizes line `id` and sends it again with its words, unless its text
+/// changed in the meantime.
+async fn tokenize_line(state: Arc<SharedState>, id: u64, text: String) {
+    let tokens = tokenize(&state, &text).await;
+    if tokens.is_empty() {
+        return;
+    }
+    let mut store = state.subtitles.write().await;
+    if let Some(sub) = store.get_mut(&id).filter(|sub| sub.text == text) {
+        debug!("[sub:{}] Tokenized into {} words", id, tokens.len());
+        sub.tokens = tokens;
+        let _ = state.events.send(ServerEvent::SubtitleUpdated(sub.clone()));
+    }
+}
+
+/// The words of `text`, if lines are tokenized at all.
+async fn tokenize(state: &SharedState, text: &str) -> Vec<Token> {
+    let Some(options) = state.options.morph.clone() else {
+        return Vec::new();
+    };
+    let text = text.to_string();
+    match tokio::task::spawn_blocking(move || morph::tokenize(&text, &options)).await {
+        Ok(Ok(tokens)) => tokens,
+        Ok(Err(e)) => {
+            warn!("[morph] {}", e);
+            Vec::new()
+        }
+        Err(e) => {
+            report_panic(&state.events, "Tokenizer", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Translates line `id` and sends it again with the translation, unless its
+/// text changed in the meantime.
+async fn translate_line(state: Arc<SharedState>, id: u64, text: String) {
+    let Some(translator) = &state.translator else {
+        return;
+    };
+    let translation = match translator.translate(&text).await {
+        Ok(translation) => translation,
+        Err(e) => {
+            warn!("[translate] Line {}: {}", id, e);
+            return;
+        }
+    };
+    let mut store = state.subtitles.write().await;
+    if let Some(sub) = store.get_mut(&id).filter(|sub| sub.text == text) {
+        debug!("[sub:{}] Translated: {}", id, translation);
+        sub.translation = Some(translation);
+        let _ = state.events.send(ServerEvent::SubtitleUpdated(sub.clone()));
+    }
+}
+
+/// The `track-list` entry of the given type (`audio`, `sub`, ..) and id.
+fn find_track<'a>(
+    track_list: &'a serde_json::Value,
+    kind: &str,
+    id: i64,
+) -> Option<&'a serde_json::Value> {
+    track_list.as_array()?.iter().find(|track| {
+        track.get("type").and_then(|t| t.as_str()) == Some(kind)
+            && track.get("id").and_then(|i| i.as_i64()) == Some(id)
+    })
+}
+
+fn first_audio_track(track_list: &serde_json::Value) -> Option<i64> {
+    track_list.as_array()?.iter().find_map(|track| {
+        (track.get("type").and_then(|t| t.as_str()) == Some("audio"))
+            .then(|| track.get("id").and_then(|i| i.as_i64()))
+            .flatten()
+    })
+}
+
+/// Looks up ffmpeg's stream index for mpv audio track `aid`. mpv numbers
+/// tracks per type, which only matches ffmpeg's `0:a:N` ordering for simple
+/// layouts. External audio files have their own index space and are skipped.
+fn audio_ff_index(track_list: &serde_json::Value, aid: i64) -> Option<i64> {
+    let track = find_track(track_list, "audio", aid)?;
+    if track
+        .get("external")
+        .and_then(|e| e.as_bool())
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    track.get("ff-index").and_then(|i| i.as_i64())
+}
+
+async fn query_mpv_property(
+    mpv: &mut MpvStream,
+    property: &str,
+    request_id: u64,
+) -> std::io::Result<serde_json::Value> {
+    let cmd = format!(
+        "{{\"command\":[\"get_property\",\"{}\"],\"request_id\":{}}}\n",
+        property, request_id
+    );
+    mpv.write_all(cmd.as_bytes()).await?;
+
+    loop {
+        let Some(line) = mpv.next_line().await? else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "mpv IPC closed while waiting for property response",
+            ));
+        };
+        if let Ok(json) = serde_json::from_str::<serde_json::Value>(&line)
+            && json.get("request_id").and_then(|v| v.as_u64()) == Some(request_id)
+        {
+            return Ok(json);
+        }
+    }
+}
+
+async fn query_mpv_property_with_timeout(
+    mpv: &mut MpvStream,
+    property: &str,
+    request_id: u64,
+) -> std::io::Result<serde_json::Value> {
+    timeout(
+        Duration::from_secs(1),
+        query_mpv_property(mpv, property, request_id),
+    )
+    .instrument(debug_span!("mpv_get_property", property, request_id))
+    .await
+    .map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("Timed out querying mpv property '{}'", property),
+        )
+    })?
+}
+
+pub(crate) async fn get_mpv_pid(mpv: &mut MpvStream) -> std::io::Result<u32> {
+    let json = match query_mpv_property_with_timeout(mpv, "pid", 1).await {
+        Ok(json) => json,
+        Err(_) => query_mpv_property_with_timeout(mpv, "process-id", 2).await?,
+    };
+    let status = json.get("error").and_then(|e| e.as_str()).unwrap_or("");
+    if status != "success" {
+        return Err(std::io::Error::other(format!(
+            "mpv returned error querying PID: {}",
+            status
+        )));
+    }
+
+    let pid = json
+        .get("data")
+        .and_then(|d| {
+            d.as_u64()
+                .or_else(|| d.as_i64().and_then(|n| u64::try_from(n).ok()))
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "mpv returned non-integer PID",
+            )
+        })?;
+
+    u32::try_from(pid)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "mpv PID out of range"))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    pub socket_path: String,
+    pub port: u16,
+    pub miner: MinerOptions,
+    /// Dial out to this URL instead of listening
+    pub connect_url: Option<String>,
+    /// Required from clients as `?token=` or `Authorization: Bearer`
+    pub auth_token: Option<String>,
+    /// Lets clients in read-only, see [`SPECTATOR_QUERY`]. Only checked
+    /// along with `auth_token`; without one every client has full control
+    pub spectator_token: Option<String>,
+    /// Print a QR code of the connection URL on startup
+    pub qr: bool,
+    /// Also show the QR code on mpv's OSD
+    pub qr_osd: bool,
+    /// Also listen here for texthooker clients, see [`TEXTHOOKER_PATH`]
+    pub texthooker_port: Option<u16>,
+    /// Announce the server on the LAN under this name, see [`crate::mdns`]
+    pub mdns_name: Option<String>,
+    /// Listen on this Unix domain socket instead of the TCP port
+    pub listen_unix: Option<PathBuf>,
+    /// Browser pages allowed to connect, see [`OriginPolicy`]
+    pub origins: OriginPolicy,
+    /// Removed on shutdown, see [`crate::instance::lock`]
+    pub pid_file: Option<PathBuf>,
+    /// Fail when `port` is taken instead of listening on another one
+    pub strict_port: bool,
+    /// Where the port actually listened on is written, see
+    /// [`crate::instance::write_discovery`]; removed on shutdown unless
+    /// another server wrote it since
+    pub discovery_file: Option<PathBuf>,
+}
+
+/// Ports after the configured one tried when it is taken, before leaving
+/// the choice to the OS; the frontend looks for servers on these.
+const FALLBACK_PORTS: u16 = 4;
+
+/// Which web pages may open the WebSocket or read the HTTP endpoints, so a
+/// site visited in the same browser can't pull lines or media from the
+/// miner. Clients that send no `Origin` aren't browsers and are always let
+/// in, as are the miner's own pages (e.g. `/overlay`). Browsers must also
+/// address the miner by a name it knows is its own, so a site can't get in
+/// by pointing its domain at 127.0.0.1 (DNS rebinding).
+#[derive(Debug, Clone, Default)]
+pub struct OriginPolicy {
+    allowed: Vec<String>,
+}
+
+impl OriginPolicy {
+    /// Allows `origins`, e.g. `http://localhost:5173` or `null` for
+    /// `index.html` opened from disk; `*` allows any page.
+    pub fn new(origins: Vec<String>) -> Self {
+        let allowed = origins
+            .iter()
+            .map(|o| o.trim_end_matches('/').to_string())
+            .collect();
+        Self { allowed }
+    }
+
+    /// Whether a request with these headers may be answered. `browser`
+    /// says it comes from a browser even without an `Origin`, and `local`
+    /// is the address it reached the miner on (`None` for the Unix socket).
+    pub(crate) fn allows(
+        &self,
+        origin: Option<&str>,
+        host: Option<&str>,
+        browser: bool,
+        local: Option<SocketAddr>,
+    ) -> bool {
+        if !browser && origin.is_none() {
+            return true;
+        }
+        if !host.is_some_and(|host| self.is_own_host(host, local)) {
+            return false;
+        }
+        let Some(origin) = origin else {
+            return true;
+        };
+        let same_origin = origin
+            .split_once("://")
+            .is_some_and(|(_, authority)| self.is_own_origin(authority, local));
+        same_origin
+            || self
+                .allowed
+                .iter()
+                .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    /// Whether `authority` (`host[:port]`) names this computer: localhost,
+    /// a loopback address, the address the client connected to, or a host
+    /// of an allowed origin.
+    fn is_own_host(&self, authority: &str, local: Option<SocketAddr>) -> bool {
+        let host = split_authority(authority).0.to_ascii_lowercase();
+        if host == "localhost" || host.ends_with(".localhost") {
+            return true;
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return ip.is_loopback() || local.is_some_and(|local| local.ip() == ip);
+        }
+        self.allowed.iter().any(|allowed| {
+            allowed
+                .split_once("://")
+                .is_some_and(|(_, a)| split_authority(a).0.eq_ignore_ascii_case(&host))
+        })
+    }
+
+    /// Whether a page at `authority` is served by the miner itself.
+    fn is_own_origin(&self, authority: &str, local: Option<SocketAddr>) -> bool {
+        let port = split_authority(authority).1.unwrap_or(80);
+        self.is_own_host(authority, local) && local.is_none_or(|local| local.port() == port)
+    }
+
+    /// Rejects the handshake if `req` comes from a page that isn't allowed.
+    #[allow(clippy::result_large_err)] // signature dictated by tungstenite
+    pub fn check(&self, req: &Request, local: Option<SocketAddr>) -> Result<(), ErrorResponse> {
+        let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+        let origin = header("origin");
+        if self.allows(origin, header("host"), false, local) {
+            return Ok(());
+        }
+        warn!(
+            "Rejected a connection from {}, allow it with --allowed-origin",
+            origin.unwrap_or_default()
+        );
+        let mut err = ErrorResponse::new(Some("origin not allowed".into()));
+        *err.status_mut() = StatusCode::FORBIDDEN;
+        Err(err)
+    }
+}
+
+/// `host[:port]` split into its parts, with IPv6 brackets removed.
+fn split_authority(authority: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, port) = rest.split_once(']').unwrap_or((rest, ""));
+        return (host, port.strip_prefix(':').and_then(|p| p.parse().ok()));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()),
+        None => (authority, None),
+    }
+}
+
+/// Query parameter making a client read-only: it gets every broadcast and
+/// can look lines up, but can't ask for media or touch mpv and the lines.
+/// Clients presenting the spectator token get the same.
+pub const SPECTATOR_QUERY: &str = "role=spectator";
+
+/// Handshake callback rejecting clients that don't present `token`.
+#[allow(clippy::result_large_err)] // signature dictated by tungstenite
+pub fn check_token(
+    token: Option<String>,
+) -> impl FnOnce(&Request, Response) -> Result<Response, ErrorResponse> {
+    move |req, resp| {
+        let Some(expected) = token else {
+            return Ok(resp);
+        };
+        let authorization = req
+            .headers()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok());
+        if presented_token(req.uri().query(), authorization)
+            .is_some_and(|presented| tokens_match(&presented, &expected))
+        {
+            return Ok(resp);
+        }
+        let mut err = ErrorResponse::new(Some("invalid or missing token".into()));
+        *err.status_mut() = StatusCode::UNAUTHORIZED;
+        Err(err)
+    }
+}
+
+/// The token a client sent as `?token=` (URL-encoded) or
+/// `Authorization: Bearer`.
+pub(crate) fn presented_token(query: Option<&str>, authorization: Option<&str>) -> Option<String> {
+    let from_query = query.and_then(|q| {
+        q.split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .map(|token| {
+                // `+` is how forms encode a space
+                let token = token.replace('+', " ");
+                percent_encoding::percent_decode_str(&token)
+                    .decode_utf8_lossy()
+                    .into_owned()
+            })
+    });
+    let from_header = authorization
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string);
+    from_query.or(from_header)
+}
+
+/// Compares tokens in time that doesn't depend on where they differ, so
+/// timing replies can't guess a token byte by byte.
+fn tokens_match(presented: &str, expected: &str) -> bool {
+    let (presented, expected) = (presented.as_bytes(), expected.as_bytes());
+    presented.len() == expected.len()
+        && presented
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Runs the miner behind a WebSocket server. Exits the process once mpv goes
+/// away; embedders wanting control over that should use [`SubtitleMiner`].
+pub async fn run_server(options: &ServerOptions) -> std::io::Result<()> {
+    let port = options.port;
+    let mut miner = SubtitleMiner::start(&options.socket_path, options.miner.clone()).await?;
+    let state = miner.state.clone();
+    let event_tx = state.events.clone();
+
+    // Files that only mean something while we run
+    let leftovers: Vec<PathBuf> = options
+        .listen_unix
+        .iter()
+        .chain(&options.pid_file)
+        .cloned()
+        .collect();
+    let discovery_file = options.discovery_file.clone();
+    let closed_state = state.clone();
+    let closed_leftovers = leftovers.clone();
+    let closed_discovery = discovery_file.clone();
+    tokio::spawn(async move {
+        if let Err(e) = miner.closed().await {
+            error!("MPV handler error: {}", e);
+        }
+        info!("MPV connection closed, shutting down.");
+        shut_down(
+            &closed_state,
+            &closed_leftovers,
+            closed_discovery.as_deref(),
+        )
+        .await;
+    });
+    let signal_state = state.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Interrupted, shutting down.");
+        shut_down(&signal_state, &leftovers, discovery_file.as_deref()).await;
+    });
+
+    if let Some(url) = options.connect_url.as_deref() {
+        run_outbound(url, state, event_tx).await;
+        return Ok(());
+    }
+
+    if let Some(texthooker_port) = options.texthooker_port {
+        let listener = TcpListener::bind(("0.0.0.0", texthooker_port)).await?;
+        println!("Texthooker WebSocket on port {}", texthooker_port);
+        tokio::spawn(run_texthooker(
+            listener,
+            event_tx.clone(),
+            options.auth_token.clone(),
+        ));
+    }
+
+    if let Some(path) = options.listen_unix.as_deref() {
+        return run_unix(path, state, event_tx, options.origins.clone()).await;
+    }
+
+    let listener = bind_port(port, options.strict_port).await?;
+    let port = listener.local_addr().map_or(port, |a| a.port());
+    if port != options.port {
+        println!(
+            "Port {} is in use, listening on {} instead",
+            options.port, port
+        );
+    }
+    if let Some(path) = &options.discovery_file
+        && let Err(e) = crate::instance::write_discovery(path, port, &options.socket_path)
+    {
+        warn!("Could not write {}: {}", path.display(), e);
+    }
+
+    println!(
+        "WebSocket server listening on {}",
+        listener
+            .local_addr()
+            .map_or_else(|_| format!("port {}", port), |a| a.to_string())
+    );
+
+    if options.qr || options.qr_osd {
+        let url = crate::qr::connection_url(port, options.auth_token.as_deref());
+        match crate::qr::render(&url) {
+            Some(code) => {
+                println!("Scan to connect to {}\n{}", url, code);
+                if options.qr_osd {
+                    state.show_text(&crate::qr::osd_text(&code), 15000);
+                }
+            }
+            None => warn!("Could not encode {} as a QR code", url),
+        }
+    }
+
+    if let Some(name) = options.mdns_name.clone() {
+        tokio::spawn(crate::mdns::announce(crate::mdns::Announcement {
+            name,
+            port,
+            auth: options.auth_token.is_some(),
+        }));
+    }
+
+    let mut client_id = 0u64;
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        client_id += 1;
+        let id = client_id;
+
+        let client_state = state.clone();
+        let client_rx = event_tx.subscribe();
+        let access = Access {
+            token: options.auth_token.clone(),
+            spectator_token: options.spectator_token.clone(),
+            origins: options.origins.clone(),
+        };
+
+        let span = info_span!("client", id, %addr);
+        let client = tokio::spawn(
+            async move {
+                if !crate::http::is_websocket(&stream).await {
+                    debug!("[http] Request from {}", addr);
+                    if let Err(e) = crate::http::serve(stream, &client_state, &access).await {
+                        debug!("[http] {}: {}", addr, e);
+                    }
+                    return;
+                }
+                info!("[client:{}] Connected from {}", id, addr);
+                let local = stream.local_addr().ok();
+                serve_websocket(stream, id, client_state, client_rx, access, local).await;
+            }
+            .instrument(span),
+        );
+        let events = event_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = client.await {
+                report_panic(&events, &format!("Handler for client {}", id), e);
+            }
+        });
+    }
+}
+
+/// Listens on `port`, or when it is taken (and `strict` isn't set) on one
+/// of the [`FALLBACK_PORTS`] after it, or any free port.
+async fn bind_port(port: u16, strict: bool) -> std::io::Result<TcpListener> {
+    let err = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => return Ok(listener),
+        Err(e) if strict || e.kind() != std::io::ErrorKind::AddrInUse => return Err(e),
+        Err(e) => e,
+    };
+    let next = (1..=FALLBACK_PORTS).filter_map(|i| port.checked_add(i));
+    for candidate in next.chain([0]) {
+        match TcpListener::bind(("0.0.0.0", candidate)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) => debug!("Port {} is not free either: {}", candidate, e),
+        }
+    }
+    Err(err)
+}
+
+/// Close frame telling clients the server is going away.
+fn going_away() -> CloseFrame {
+    CloseFrame {
+        code: CloseCode::Away,
+        reason: "server shutting down".into(),
+    }
+}
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Can't handle SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Lets running media jobs finish, killing whatever is left after
+/// [`SHUTDOWN_GRACE`] (the jobs remove their temp files either way), tells
+/// clients the server is going away and closes them, removes `leftovers`,
+/// then exits.
+async fn shut_down(state: &SharedState, leftovers: &[PathBuf], discovery_file: Option<&Path>) -> ! {
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    if crate::media::running_jobs() > 0 {
+        info!(
+            "Waiting for {} media job(s) to finish",
+            crate::media::running_jobs()
+        );
+    }
+    while crate::media::running_jobs() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    if crate::media::running_jobs() > 0 {
+        warn!(
+            "Cancelling {} media job(s) still running",
+            crate::media::running_jobs()
+        );
+    }
+    // Also ends whisper, OCR, tokenizer, hook and clipboard runs, which
+    // aren't media jobs
+    crate::media::cancel_jobs();
+    let running = || crate::media::running_jobs() + crate::media::running_processes();
+    if running() > 0 {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while running() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    let _ = state.events.send(ServerEvent::Shutdown);
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while METRICS.connected_clients() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    for path in leftovers {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(path) = discovery_file {
+        crate::instance::remove_discovery(path);
+    }
+    std::process::exit(0)
+}
+
+/// Who may connect, checked by both the WebSocket and the HTTP side.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Access {
+    pub token: Option<String>,
+    pub spectator_token: Option<String>,
+    pub origins: OriginPolicy,
+}
+
+/// What a client may do, by the token it presented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Full,
+    /// Sees everything but can't change anything, see [`SPECTATOR_QUERY`]
+    Spectator,
+}
+
+impl Access {
+    /// The role the `presented` token grants, or `None` if it grants none:
+    /// full control with the auth token or when there is none, read-only
+    /// with the spectator token.
+    pub fn role(&self, presented: Option<&str>) -> Option<Role> {
+        let Some(token) = &self.token else {
+            return Some(Role::Full);
+        };
+        let presented = presented?;
+        if tokens_match(presented, token) {
+            Some(Role::Full)
+        } else if self
+            .spectator_token
+            .as_deref()
+            .is_some_and(|spectator| tokens_match(presented, spectator))
+        {
+            Some(Role::Spectator)
+        } else {
+            None
+        }
+    }
+}
+
+/// Runs the WebSocket handshake on `stream` and hands the client to the
+/// texthooker or the regular handler, by the path it asked for. `local` is
+/// the address the client connected to, see [`OriginPolicy`].
+async fn serve_websocket<S>(
+    stream: S,
+    id: u64,
+    state: Arc<SharedState>,
+    event_rx: broadcast::Receiver<ServerEvent>,
+    access: Access,
+    local: Option<SocketAddr>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut path = String::new();
+    let mut read_only = false;
+    #[allow(clippy::result_large_err)] // signature dictated by tungstenite
+    let handshake = accept_hdr_async(stream, |req: &Request, resp| {
+        path = req.uri().path().to_string();
+        // Texthookers only get the text of lines, and many run on pages
+        // of their own
+        if path != TEXTHOOKER_PATH {
+            access.origins.check(req, local)?;
+        }
+        let query = req.uri().query();
+        let authorization = req
+            .headers()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok());
+        let Some(role) = access.role(presented_token(query, authorization).as_deref()) else {
+            let mut err = ErrorResponse::new(Some("invalid or missing token".into()));
+            *err.status_mut() = StatusCode::UNAUTHORIZED;
+            return Err(err);
+        };
+        // Anyone may also give up control by asking
+        read_only = role == Role::Spectator
+            || query.is_some_and(|q| q.split('&').any(|p| p == SPECTATOR_QUERY));
+        Ok(resp)
+    });
+    let result = match handshake.await {
+        Ok(ws) if path == TEXTHOOKER_PATH => handle_texthooker(ws, id, event_rx).await,
+        Ok(ws) => handle_client(ws, id, state, event_rx, read_only).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        debug!("[client:{}] Disconnected: {}", id, e);
+    } else {
+        debug!("[client:{}] Disconnected", id);
+    }
+}
+
+/// Accepts WebSocket clients on a Unix domain socket at `path`. Only the
+/// owner may connect, so no token is asked for. There is no HTTP on the
+/// socket, only WebSocket.
+#[cfg(unix)]
+async fn run_unix(
+    path: &Path,
+    state: Arc<SharedState>,
+    event_tx: broadcast::Sender<ServerEvent>,
+    origins: OriginPolicy,
+) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::{UnixListener, UnixStream};
+
+    if path.exists() {
+        // A socket left behind by a miner that didn't shut down cleanly
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("{} is in use by another server", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    // Owner-only before it listens, so there is no moment in which anyone
+    // else could connect; the process's umask is left alone
+    let socket = socket2::Socket::new(socket2::Domain::UNIX, socket2::Type::STREAM, None)?;
+    socket.bind(&socket2::SockAddr::unix(path)?)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    let listener = UnixListener::from_std(std::os::unix::net::UnixListener::from(
+        std::os::fd::OwnedFd::from(socket),
+    ))?;
+    println!("WebSocket server listening on {}", path.display());
+
+    let mut client_id = 0u64;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        client_id += 1;
+        let id = client_id;
+
+        let client_state = state.clone();
+        let client_rx = event_tx.subscribe();
+        let access = Access {
+            origins: origins.clone(),
+            ..Default::default()
+        };
+        let span = info_span!("client", id, addr = "unix");
+        let client = tokio::spawn(
+            async move {
+                info!("[client:{}] Connected over the Unix socket", id);
+                serve_websocket(stream, id, client_state, client_rx, access, None).await;
+            }
+            .instrument(span),
+        );
+        let events = event_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = client.await {
+                report_panic(&events, &format!("Handler for client {}", id), e);
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+async fn run_unix(
+    _path: &Path,
+    _state: Arc<SharedState>,
+    _event_tx: broadcast::Sender<ServerEvent>,
+    _origins: OriginPolicy,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/// Accepts texthooker clients on a port of their own, for pages that can't
+/// be given a path.
+async fn run_texthooker(
+    listener: TcpListener,
+    event_tx: broadcast::Sender<ServerEvent>,
+    token: Option<String>,
+) {
+    let mut client_id = 0u64;
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("[texthooker] Accept failed: {}", e);
+                continue;
+            }
+        };
+        client_id += 1;
+        let id = client_id;
+        let rx = event_tx.subscribe();
+        let token = token.clone();
+        let span = info_span!("texthooker", id, %addr);
+        tokio::spawn(
+            async move {
+                info!("[texthooker:{}] Connected from {}", id, addr);
+                let result = match accept_hdr_async(stream, check_token(token)).await {
+                    Ok(ws) => handle_texthooker(ws, id, rx).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    debug!("[texthooker:{}] Disconnected: {}", id, e);
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Sends the text of every new line and nothing else. Whatever the client
+/// sends is ignored.
+async fn handle_texthooker<S>(
+    ws: WebSocketStream<S>,
+    id: u64,
+    mut event_rx: broadcast::Receiver<ServerEvent>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let _connected = METRICS.client_connected();
+    debug!("[texthooker:{}] Sending plain lines", id);
+    loop {
+        tokio::select! {
+            Ok(event) = event_rx.recv() => match event {
+                ServerEvent::Subtitle(sub) => ws_tx.send(Message::Text(sub.text.into())).await?,
+                ServerEvent::Shutdown => {
+                    ws_tx.send(Message::Close(Some(going_away()))).await?;
+                    return Ok(());
+                }
+                _ => {}
+            },
+
+            Some(msg) = ws_rx.next() => {
+                if msg?.is_close() {
+                    return Ok(());
+                }
+            }
+
+            else => return Ok(()),
+        }
+    }
+}
+
+/// Dials out to `url` instead of listening, reconnecting with backoff for as
+/// long as mpv is alive.
+async fn run_outbound(
+    url: &str,
+    state: Arc<SharedState>,
+    event_tx: broadcast::Sender<ServerEvent>,
+) {
+    let mut delay = Duration::from_secs(1);
+    let mut client_id = 0u64;
+    loop {
+        client_id += 1;
+        match connect_async(url).await {
+            Ok((ws, _)) => {
+                info!("[client:{}] Connected out to {}", client_id, url);
+                delay = Duration::from_secs(1);
+                let rx = event_tx.subscribe();
+                let span = info_span!("client", id = client_id, url);
+                let result = handle_client(ws, client_id, state.clone(), rx, false)
+                    .instrument(span)
+                    .await;
+                if let Err(e) = result {
+                    warn!("[client:{}] Outbound connection lost: {}", client_id, e);
+                } else {
+                    info!("[client:{}] Outbound connection closed", client_id);
+                }
+            }
+            Err(e) => warn!("Failed to connect to {}: {}", url, e),
+        }
+
+        info!("Reconnecting to {} in {}s", url, delay.as_secs());
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+pub(crate) async fn handle_mpv(
+    mut mpv: MpvStream,
+    state: Arc<SharedState>,
+    commands: &mut mpsc::UnboundedReceiver<MpvCommand>,
+) -> std::io::Result<()> {
+    let tx = &state.events;
+    mpv.write_all(
+        // mpv reports changes in observation order, so the timing of a
+        // line is already known when its `sub-text` arrives
+        concat!(
+            "{\"command\":[\"observe_property\",1,\"sub-start\"]}\n",
+            "{\"command\":[\"observe_property\",2,\"sub-end\"]}\n",
+            "{\"command\":[\"observe_property\",3,\"path\"]}\n",
+            "{\"command\":[\"observe_property\",4,\"media-title\"]}\n",
+            "{\"command\":[\"observe_property\",5,\"aid\"]}\n",
+            "{\"command\":[\"observe_property\",6,\"sid\"]}\n",
+            "{\"command\":[\"observe_property\",7,\"track-list\"]}\n",
+            "{\"command\":[\"observe_property\",8,\"chapter\"]}\n",
+            "{\"command\":[\"observe_property\",9,\"chapter-list\"]}\n",
+            "{\"command\":[\"observe_property\",10,\"sub-text-ass\"]}\n",
+            "{\"command\":[\"observe_property\",11,\"sub-text\"]}\n"
+        )
+        .as_bytes(),
+    )
+    .await?;
+    // The Lua script answers with `subtitleminer-script-version`
+    let hello =
+        serde_json::json!({ "command": ["script-message", "subtitleminer-hello", VERSION] });
+    mpv.write_all(format!("{}\n", hello).as_bytes()).await?;
+    info!("Connected to mpv, observing subtitle changes");
+
+    let mut observed = Observed::default();
+    let mut untimed: HashMap<u64, Untimed> = HashMap::new();
+    let mut next_request_id = 10u64;
+    let mut replies: HashMap<u64, oneshot::Sender<serde_json::Value>> = HashMap::new();
+    let mut next_command_id = COMMAND_REQUEST_BASE;
+    // Text and start of the last line, and the id it was sent as
+    let mut last_line: Option<(String, Option<f64>, Option<u64>)> = None;
+    // Start of the last image line sent to OCR
+    let mut last_ocr: Option<f64> = None;
+
+    loop {
+        let line = tokio::select! {
+            line = mpv.next_line() => match line? {
+                Some(line) => line,
+                None => return Ok(()), // EOF
+            },
+            Some(cmd) = commands.recv() => {
+                let request_id = next_command_id;
+                next_command_id += 1;
+                debug!(request_id, command = %cmd.args, "[mpv] Sending command");
+                let msg = serde_json::json!({ "command": cmd.args, "request_id": request_id });
+                mpv.write_all(format!("{}\n", msg).as_bytes()).await?;
+                if let Some(reply) = cmd.reply {
+                    replies.insert(request_id, reply);
+                }
+                continue;
+            }
+        };
+
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(&line) else {
+            continue;
+        };
+
+        // Responses to commands issued through `SharedState::mpv_command`
+        if let Some(request_id) = json.get("request_id").and_then(|r| r.as_u64())
+            && request_id >= COMMAND_REQUEST_BASE
+        {
+            if let Some(reply) = replies.remove(&request_id) {
+                let _ = reply.send(json);
+            }
+            continue;
+        }
+
+        // `playback-time` for lines without timing
+        if let Some(request_id) = json.get("request_id").and_then(|r| r.as_u64()) {
+            if let Some(line) = untimed.remove(&request_id) {
+                let now = json
+                    .get("data")
+                    .and_then(|d| d.as_f64())
+                    .unwrap_or_default();
+                let sub = line.into_subtitle(now, state.options.timing_window);
+                let id = sub.id;
+                let stored = publish_subtitle(&state, sub).await;
+                if let Some(line) = &mut last_line
+                    && line.2 == Some(id)
+                {
+                    line.2 = stored;
+                }
+            }
+            continue;
+        }
+
+        // `script-message subtitleminer-mine` from the Lua script or input.conf
+        if json.get("event") == Some(&serde_json::json!("client-message")) {
+            let args = json.get("args").and_then(|a| a.as_array());
+            let command = args.and_then(|a| a.first()).and_then(|c| c.as_str());
+            if command == Some("subtitleminer-script-version") {
+                let version = args
+                    .and_then(|a| a.get(1))
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string();
+                state.check_version("Lua script", &version);
+                *state.script_version.write().await = Some(version);
+            } else if let Some(to_last_card) = match command {
+                Some("subtitleminer-mine") => Some(false),
+                Some("subtitleminer-mine-to-last-card") => Some(true),
+                _ => None,
+            } {
+                // The line on screen as stored, which may be the earlier id
+                // it repeats; hidden lines and ones still waiting for a time
+                // have none
+                let latest = last_line
+                    .as_ref()
+                    .and_then(|line| line.2)
+                    .filter(|id| !untimed.values().any(|line| line.sub.id == *id));
+                if let Some(latest) = latest {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if to_last_card {
+                            let _ = state.mine_to_last_card(latest).await;
+                        } else {
+                            let _ = state.mine(latest, false, None).await;
+                        }
+                    });
+                } else {
+                    state.show_text("No subtitle to mine yet", 2000);
+                }
+            }
+            continue;
+        }
+
+        if json.get("event") == Some(&serde_json::json!("file-loaded")) {
+            state.restore_track().await;
+            continue;
+        }
+
+        if json.get("event") != Some(&serde_json::json!("property-change")) {
+            continue;
+        }
+
+        let name = json.get("name").and_then(|n| n.as_str()).unwrap_or("");
+        let data = json.get("data").cloned().unwrap_or_default();
+        if observed.update(name, &data) {
+            if name == "chapter-list" {
+                tokio::spawn(resolve_timeline(state.clone(), data));
+            }
+            // Image subtitles leave `sub-text` empty; their timing is all
+            // there is to go by. `sub-end` comes right after `sub-start`.
+            if name == "sub-end"
+                && state.options.ocr.is_some()
+                && observed.sub_end.is_some()
+                && observed.sub_start != last_ocr
+                && let Some((stream, width, height)) = observed.image_sub_track()
+            {
+                last_ocr = observed.sub_start;
+                let (path, session) = {
+                    let current = state.current_file.read().await;
+                    (current.path.clone(), current.session.clone())
+                };
+                let sub = observed.subtitle(0, String::new(), path, session);
+                tokio::spawn(read_image_line(state.clone(), sub, stream, width, height));
+            }
+            continue;
+        }
+
+        // Handle file changes (next episode, playlist navigation)
+        if name == "path" || name == "media-title" {
+            let Some(value) = json.get("data").and_then(|d| d.as_str()) else {
+                continue;
+            };
+            let mut current = state.current_file.write().await;
+            let changed = if name == "path" {
+                let previous = std::mem::replace(&mut current.path, value.to_string());
+                let changed = previous != value;
+                if changed {
+                    stream::forget(&previous);
+                    current.session = new_session_id();
+                    if stream::is_network(value) {
+                        tokio::spawn(resolve_stream(state.clone(), value.to_string()));
+                    } else if let Some(option) = stream::device_option(value) {
+                        tokio::spawn(resolve_device(state.clone(), value.to_string(), option));
+                    }
+                }
+                changed
+            } else {
+                std::mem::replace(&mut current.title, value.to_string()) != value
+            };
+            if changed && !current.path.is_empty() {
+                info!(
+                    "[file] {} ({}) session {}",
+                    current.title, current.path, current.session
+                );
+                let _ = tx.send(ServerEvent::FileChanged {
+                    path: current.path.clone(),
+                    title: current.title.clone(),
+                    session: current.session.clone(),
+                });
+                if name == "path"
+                    && let Some(resume) = state.resume_event(&current.path, &current.session)
+                {
+                    let _ = tx.send(resume);
+                }
+            }
+            continue;
+        }
+
+        // Handle subtitle property changes
+        if name == "sub-text"
+            && let Some(text) = json
+                .get("data")
+                .and_then(|d| d.as_str())
+                .filter(|s| !s.is_empty())
+        {
+            // mpv re-fires `sub-text` on seeks and track toggles
+            if let Some((last_text, last_start, last_id)) = &last_line
+                && last_text == text
+                && *last_start == observed.sub_start
+            {
+                if let Some(id) = *last_id
+                    && let Some(sub) = state.subtitles.write().await.get_mut(&id)
+                {
+                    sub.seen_count += 1;
+                    debug!("[sub:{}] Seen again ({}x)", id, sub.seen_count);
+                    let _ = tx.send(ServerEvent::SubtitleSeen {
+                        id,
+                        seen_count: sub.seen_count,
+                    });
+                }
+                continue;
+            }
+            last_line = Some((text.to_string(), observed.sub_start, None));
+
+            let Some(filtered) = state.filter_text(text).await else {
+                continue;
+            };
+            let subtitle_id = state.next_subtitle_id.fetch_add(1, Ordering::Relaxed);
+            if let Some(line) = &mut last_line {
+                line.2 = Some(subtitle_id);
+            }
+            info!("[sub:{}] {}", subtitle_id, text);
+
+            let (path, session) = {
+                let current = state.current_file.read().await;
+                (current.path.clone(), current.session.clone())
+            };
+            let mut sub = observed.subtitle(subtitle_id, filtered, path, session);
+            if sub.text != text {
+                sub.raw_text = Some(text.to_string());
+            }
+            if sub.estimated_timing {
+                let request_id = next_request_id;
+                next_request_id += 1;
+                let cmd = format!(
+                    "{{\"command\":[\"get_property\",\"playback-time\"],\"request_id\":{}}}\n",
+                    request_id
+                );
+                mpv.write_all(cmd.as_bytes()).await?;
+                untimed.insert(
+                    request_id,
+                    Untimed {
+                        sub,
+                        sub_start: observed.sub_start,
+                        sub_end: observed.sub_end,
+                    },
+                );
+            } else {
+                let stored = publish_subtitle(&state, sub).await;
+                if let Some(line) = &mut last_line {
+                    line.2 = stored;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_client<S>(
+    ws: WebSocketStream<S>,
+    id: u64,
+    state: Arc<SharedState>,
+    mut event_rx: broadcast::Receiver<ServerEvent>,
+    read_only: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let _connected = METRICS.client_connected();
+    if read_only {
+        info!("[client:{}] Read-only", id);
+    }
+
+    // Late joiners still need to know what is playing
+    if let Some(hello) = state.file_event().await {
+        ws_tx
+            .send(Message::Text(hello.to_json().to_string().into()))
+            .await?;
+    }
+    if let Some(resume) = state.current_resume_event().await {
+        ws_tx
+            .send(Message::Text(resume.to_json().to_string().into()))
+            .await?;
+    }
+    let mut capabilities = capabilities_json(&state);
+    capabilities["read_only"] = read_only.into();
+    capabilities["script_version"] = state.script_version.read().await.clone().into();
+    ws_tx
+        .send(Message::Text(capabilities.to_string().into()))
+        .await?;
+
+    let mut ping_timer = interval(PING_INTERVAL);
+    ping_timer.tick().await;
+    let mut last_seen = Instant::now();
+    // Replies of requests that run in the background, e.g. `transcribe`
+    let (replies, mut replies_rx) = mpsc::unbounded_channel::<String>();
+    // Requests are answered one at a time, in order, but off this loop so
+    // pongs are still read while a slow one such as `mine` runs
+    let (requests, mut requests_rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn({
+        let state = state.clone();
+        let replies = replies.clone();
+        async move {
+            while let Some(text) = requests_rx.recv().await {
+                if let Some(response) = handle_request(&text, id, &state, read_only, &replies).await
+                    && replies.send(response).is_err()
+                {
+                    break;
+                }
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            _ = ping_timer.tick() => {
+                if last_seen.elapsed() > PONG_TIMEOUT {
+                    warn!(
+                        "[client:{}] No pong for {}s, reaping connection",
+                        id,
+                        last_seen.elapsed().as_secs()
+                    );
+                    return Ok(());
+                }
+                ws_tx.send(Message::Ping(Vec::new().into())).await?;
+            }
+
+            Ok(event) = event_rx.recv() => {
+                ws_tx.send(Message::Text(event.to_json().to_string().into())).await?;
+                if matches!(event, ServerEvent::Shutdown) {
+                    ws_tx.send(Message::Close(Some(going_away()))).await?;
+                    return Ok(());
+                }
+            }
+
+            Some(reply) = replies_rx.recv() => {
+                ws_tx.send(Message::Text(reply.into())).await?;
+            }
+
+            Some(msg) = ws_rx.next() => {
+                let msg = msg?;
+                last_seen = Instant::now();
+                if let Message::Text(text) = msg {
+                    let _ = requests.send(text.to_string());
+                } else if msg.is_close() {
+                    return Ok(());
+                }
+            }
+
+            else => return Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+enum ProtocolRequest {
+    Thumbnail {
+        id: u64,
+        end_id: Option<u64>,
+        image_config: Option<crate::media::ImageConfig>,
+    },
+    /// Stills spread over the line to pick from, see
+    /// [`FfmpegRequest::thumbnail_candidates`]
+    ThumbnailCandidates {
+        id: u64,
+        end_id: Option<u64>,
+        count: Option<usize>,
+        image_config: Option<crate::media::ImageConfig>,
+    },
+    /// A still at `time` seconds of the file line `id` came from, or of
+    /// the current file without one
+    FrameAt {
+        id: Option<u64>,
+        time: f64,
+        image_config: Option<crate::media::ImageConfig>,
+    },
+    /// Lines transcribed from the audio around the playback position, for
+    /// media without subtitles
+    Transcribe {
+        /// Seconds before the playback position, at most [`asr::MAX_WINDOW`]
+        before: Option<f64>,
+        /// Seconds after it, at most [`asr::MAX_WINDOW`]
+        after: Option<f64>,
+    },
+    /// Lines of a plain-text script, timed against the current file's
+    /// speech
+    Align {
+        text: String,
+    },
+    /// The frame mpv is showing, exactly, from its own screenshot rather
+    /// than ffmpeg seeking to the same time
+    CurrentFrame {
+        /// Include rendered subtitles
+        #[serde(default)]
+        subtitles: bool,
+        image_config: Option<crate::media::ImageConfig>,
+    },
+    Audio {
+        id: u64,
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+        audio_config: Option<crate::media::AudioConfig>,
+    },
+    /// Quick, low-bitrate audio for listening, see [`AudioConfig::preview`]
+    AudioPreview {
+        id: u64,
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+    },
+    AudioRange {
+        start_id: u64,
+        end_id: u64,
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+        audio_config: Option<crate::media::AudioConfig>,
+    },
+    Session,
+    /// Lines around `id` in the same file
+    GetNeighbors {
+        id: u64,
+        before: Option<usize>,
+        after: Option<usize>,
+    },
+    /// Lines between two timestamps, in the current file unless `session`
+    /// is given
+    GetRange {
+        start: f64,
+        end: f64,
+        session: Option<String>,
+    },
+    /// Lines containing `query` (case-insensitive), or matching it as a regex
+    Search {
+        query: String,
+        #[serde(default)]
+        regex: bool,
+        session: Option<String>,
+        limit: Option<usize>,
+    },
+    /// The current file's lines (or `session`'s) as a subtitle file
+    Export {
+        format: String,
+        session: Option<String>,
+    },
+    /// One sentence spread over several lines: joined text and audio
+    Merge {
+        ids: Vec<u64>,
+        /// Cut out the time between lines; by default only when a gap is
+        /// longer than [`MAX_MERGE_GAP`]
+        gapless: Option<bool>,
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+        audio_config: Option<crate::media::AudioConfig>,
+    },
+    /// Treat lines between `start` and `end` seconds of the current file as
+    /// lyrics, see [`SongLines`]
+    SongRange {
+        start: f64,
+        end: f64,
+    },
+    /// Correct the text of line `id`, keeping the original in `raw_text`
+    EditSubtitle {
+        id: u64,
+        text: String,
+    },
+    /// Media for many lines at once, as a zip of `NNNN.<ext>` files hosted
+    /// over HTTP on this port, or sent as base64 with `inline`
+    BatchExtract {
+        ids: Vec<u64>,
+        /// `audio` and/or `image`
+        media: Vec<String>,
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+        audio_config: Option<crate::media::AudioConfig>,
+        image_config: Option<crate::media::ImageConfig>,
+        #[serde(default)]
+        inline: bool,
+    },
+    /// Replace the text filters, each a preset name or a regex
+    SetFilters {
+        filters: Vec<String>,
+    },
+    /// Add line `id` to Anki server-side. When its sentence is already in
+    /// the deck, answered with `duplicate` instead unless `allow_duplicate`,
+    /// or with `skipped` set under `--skip-duplicates`
+    Mine {
+        id: u64,
+        #[serde(default)]
+        allow_duplicate: bool,
+        /// Show the new note in Anki's browser; `--open-mined` by default
+        open: Option<bool>,
+    },
+    /// Add lines `ids` to Anki in the background, one note each or one for
+    /// all of them with `combined`; answered right away with the batch's
+    /// number, progress follows as `mine_progress` events
+    MineBatch {
+        ids: Vec<u64>,
+        #[serde(default)]
+        combined: bool,
+        #[serde(default)]
+        allow_duplicate: bool,
+        /// Show the new notes in Anki's browser at the end; never by
+        /// default, whatever `--open-mined` says
+        #[serde(default)]
+        open: bool,
+    },
+    /// Show note `note_id` in Anki's browser, e.g. the one a `duplicate`
+    /// named
+    OpenNote {
+        note_id: u64,
+    },
+    /// The decks in Anki, asked through the server so pages don't need
+    /// AnkiConnect to allow their origin
+    AnkiDecks,
+    /// The note types in Anki with their fields, like [`Self::AnkiDecks`]
+    AnkiModels,
+    /// Create note type `model` (the configured one by default) with
+    /// [`offline::DEFAULT_MODEL_FIELDS`] unless Anki has it
+    CreateModel {
+        model: Option<String>,
+    },
+    /// Put line `id` into the note added last, see
+    /// [`SharedState::mine_to_last_card`]
+    MineToLastCard {
+        id: u64,
+    },
+    /// The client added lines `id..=end_id` to Anki, or with `mined: false`
+    /// wants them unmarked
+    Mined {
+        id: u64,
+        end_id: Option<u64>,
+        mined: Option<bool>,
+    },
+    /// Remember `settings` (e.g. offsets, image and audio settings) for the
+    /// current file, sent back in `resume` when it is opened again
+    FileSettings {
+        settings: serde_json::Map<String, serde_json::Value>,
+    },
+    /// The settings of [`crate::config`], as in the file
+    GetSettings,
+    /// Change top-level keys of the settings, saving them to the config file
+    /// and applying them; `null` removes a key
+    SetSettings {
+        settings: serde_json::Map<String, serde_json::Value>,
+    },
+    /// The last `limit` lines the server logged, oldest first
+    GetLogs {
+        limit: Option<usize>,
+    },
+    /// The frontend's version, answered with the server's and the Lua
+    /// script's
+    Hello {
+        version: String,
+    },
+}
+
+impl ProtocolRequest {
+    /// For media requests, the line whose file picks the profile; `Some(None)`
+    /// for requests about the file playing.
+    fn media_line(&self) -> Option<Option<u64>> {
+        match self {
+            Self::Thumbnail { id, .. }
+            | Self::ThumbnailCandidates { id, .. }
+            | Self::Audio { id, .. }
+            | Self::AudioPreview { id, .. }
+            | Self::AudioRange { start_id: id, .. } => Some(Some(*id)),
+            Self::Merge { ids, .. } | Self::BatchExtract { ids, .. } => {
+                ids.first().map(|id| Some(*id))
+            }
+            Self::FrameAt { id, .. } => Some(*id),
+            Self::CurrentFrame { .. } => Some(None),
+            _ => None,
+        }
+    }
+
+    /// Fills the offsets and media settings the client left out from
+    /// `settings`.
+    fn with_settings(mut self, settings: &MediaSettings) -> Self {
+        let (offsets, image, audio) = match &mut self {
+            Self::Thumbnail { image_config, .. }
+            | Self::ThumbnailCandidates { image_config, .. }
+            | Self::FrameAt { image_config, .. }
+            | Self::CurrentFrame { image_config, .. } => (None, Some(image_config), None),
+            Self::Audio {
+                offset_start,
+                offset_end,
+                audio_config,
+                ..
+            }
+            | Self::AudioRange {
+                offset_start,
+                offset_end,
+                audio_config,
+                ..
+            }
+            | Self::Merge {
+                offset_start,
+                offset_end,
+                audio_config,
+                ..
+            } => (Some((offset_start, offset_end)), None, Some(audio_config)),
+            Self::AudioPreview {
+                offset_start,
+                offset_end,
+                ..
+            } => (Some((offset_start, offset_end)), None, None),
+            Self::BatchExtract {
+                offset_start,
+                offset_end,
+                audio_config,
+                image_config,
+                ..
+            } => (
+                Some((offset_start, offset_end)),
+                Some(image_config),
+                Some(audio_config),
+            ),
+            _ => (None, None, None),
+        };
+        if let Some((start, end)) = offsets {
+            *start = start.or(settings.offset_start);
+            *end = end.or(settings.offset_end);
+        }
+        if let Some(config) = image
+            && config.is_none()
+        {
+            *config = settings.image_config.clone();
+        }
+        if let Some(config) = audio
+            && config.is_none()
+        {
+            *config = settings.audio_config.clone();
+        }
+        self
+    }
+
+    /// Whether the request only reads stored lines, so read-only clients may
+    /// make it.
+    fn is_lookup(&self) -> bool {
+        matches!(
+            self,
+            Self::Session
+                | Self::Hello { .. }
+                | Self::GetNeighbors { .. }
+                | Self::GetRange { .. }
+                | Self::Search { .. }
+                | Self::Export { .. }
+        )
+    }
+
+    /// Whether the request adds or changes Anki notes.
+    fn is_mining(&self) -> bool {
+        matches!(
+            self,
+            Self::Mine { .. } | Self::MineBatch { .. } | Self::MineToLastCard { .. }
+        )
+    }
+}
+
+/// Which formats the local ffmpeg can write; requests for the `missing` ones
+/// fall back along [`IMAGE_FORMATS`] and [`AUDIO_FORMATS`].
+fn capabilities_json(state: &SharedState) -> serde_json::Value {
+    let available = |formats: &[&'static str]| {
+        formats
+            .iter()
+            .copied()
+            .filter(|f| supports_format(f))
+            .collect::<Vec<_>>()
+    };
+    serde_json::json!({
+        "type": "capabilities",
+        "version": VERSION,
+        "image_formats": available(IMAGE_FORMATS),
+        "audio_formats": available(AUDIO_FORMATS),
+        "missing": missing_formats(),
+        "transcribe": state.options.asr.is_some(),
+        "anki_queued": state.anki_queue.as_ref().map(AnkiQueue::len),
+    })
+}
+
+/// Transcribes the audio around mpv's playback position and publishes what
+/// was said as lines, returning their ids. Speech already transcribed is
+/// skipped, so asking again only adds what is new.
+async fn transcribe(
+    state: &Arc<SharedState>,
+    before: Option<f64>,
+    after: Option<f64>,
+) -> Result<Vec<u64>, String> {
+    let options = asr_options(state)?;
+    let time = get_property(state, "time-pos")
+        .await
+        .and_then(|t| t.as_f64())
+        .ok_or("no file is playing")?;
+    let window = |seconds: Option<f64>, default: f64| {
+        let seconds = seconds.filter(|s| s.is_finite()).unwrap_or(default);
+        seconds.clamp(0.0, asr::MAX_WINDOW)
+    };
+    let start = (time - window(before, asr::DEFAULT_BEFORE)).max(0.0);
+    let end = time + window(after, asr::DEFAULT_AFTER);
+    let range = Subtitle {
+        asr: true,
+        ..playing_audio(state, start, end).await?
+    };
+    let current = state.current_file.read().await.clone();
+    info!("[asr] Transcribing {:.3}-{:.3}", start, end);
+    let request = FfmpegRequest::speech(&range);
+    let segments = tokio::task::spawn_blocking(move || {
+        let wav = request.run().map_err(|e| e.to_string())?;
+        asr::transcribe(&wav, &options).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|_| "transcription panicked".to_string())??;
+
+    let transcribed: Vec<(f64, f64)> = state
+        .subtitles
+        .read()
+        .await
+        .values()
+        .filter(|s| s.asr && s.session == current.session)
+        .map(|s| (s.sub_start, s.sub_end))
+        .collect();
+    let mut ids = Vec::new();
+    for segment in segments {
+        let (sub_start, sub_end) = (start + segment.start, start + segment.end);
+        let middle = (sub_start + sub_end) / 2.0;
+        if transcribed.iter().any(|&(s, e)| s <= middle && middle <= e) {
+            continue;
+        }
+        let Some(filtered) = state.filter_text(&segment.text).await else {
+            continue;
+        };
+        let id = state.next_subtitle_id.fetch_add(1, Ordering::Relaxed);
+        info!("[sub:{}] {} (ASR)", id, filtered);
+        let sub = Subtitle {
+            id,
+            raw_text: (filtered != segment.text).then_some(segment.text),
+            text: filtered,
+            sub_start,
+            sub_end,
+            ..range.clone()
+        };
+        ids.extend(publish_subtitle(state, sub).await);
+    }
+    Ok(ids)
+}
+
+/// Times the lines of a plain-text `script` against the current file's
+/// speech and publishes them, returning their ids.
+async fn align_script(state: &Arc<SharedState>, script: String) -> Result<Vec<u64>, String> {
+    let options = asr_options(state)?;
+    let duration = get_property(state, "duration")
+        .await
+        .and_then(|d| d.as_f64())
+        .ok_or("no file is playing")?;
+    let range = playing_audio(state, 0.0, duration).await?;
+    info!("[align] Aligning a script to {}", range.media_path);
+    let lines = tokio::task::spawn_blocking(move || align::align_media(&range, &script, &options))
+        .await
+        .map_err(|_| "alignment panicked".to_string())??;
+
+    let mut ids = Vec::new();
+    for line in lines {
+        let Some(filtered) = state.filter_text(&line.text).await else {
+            continue;
+        };
+        let id = state.next_subtitle_id.fetch_add(1, Ordering::Relaxed);
+        info!("[sub:{}] {} (aligned)", id, filtered);
+        let sub = Subtitle {
+            id,
+            raw_text: (filtered != line.text).then_some(line.text),
+            text: filtered,
+            ..line
+        };
+        ids.extend(publish_subtitle(state, sub).await);
+    }
+    Ok(ids)
+}
+
+fn asr_options(state: &SharedState) -> Result<asr::AsrOptions, String> {
+    state
+        .options
+        .asr
+        .clone()
+        .ok_or_else(|| "transcription is not enabled (--whisper-model)".to_string())
+}
+
+async fn get_property(state: &SharedState, name: &str) -> Option<serde_json::Value> {
+    state
+        .mpv_command(serde_json::json!(["get_property", name]))
+        .await
+        .and_then(|r| r.get("data").cloned())
+}
+
+/// A line spanning `start` to `end` of the current file's selected audio
+/// track, for cutting speech from.
+async fn playing_audio(state: &SharedState, start: f64, end: f64) -> Result<Subtitle, String> {
+    let track = get_property(state, "current-tracks/audio")
+        .await
+        .ok_or("no audio track is selected")?;
+    let current = state.current_file.read().await.clone();
+    Ok(Subtitle {
+        sub_start: start,
+        sub_end: end,
+        media_path: current.path,
+        aid: track.get("id").and_then(|i| i.as_i64()).unwrap_or(1),
+        ff_index: track.get("ff-index").and_then(|i| i.as_i64()),
+        session: current.session,
+        seen_count: 1,
+        ..Default::default()
+    })
+}
+
+/// Runs an ffmpeg job off the async runtime and returns its output, or
+/// with `dry_run` just the commands it would run.
+/// A panicking job is reported to clients and treated as a failed extraction.
+async fn run_media(
+    state: &SharedState,
+    req: FfmpegRequest,
+    dry_run: bool,
+) -> Result<Vec<u8>, FfmpegError> {
+    if dry_run {
+        return Err(req.dry_run());
+    }
+    let span = Span::current();
+    match tokio::task::spawn_blocking(move || span.in_scope(|| req.run())).await {
+        Ok(data) => data,
+        Err(e) => {
+            report_panic(&state.events, "Media job", e);
+            Err(FfmpegError::Panicked)
+        }
+    }
+}
+
+/// Counts a media request in the metrics; dry runs are neither successes
+/// nor failures.
+fn count_media_request(dry_run: bool, kind: &str, ok: bool) {
+    if !dry_run {
+        METRICS.media_request(kind, ok);
+    }
+}
+
+/// Sets `data` (base64), its `format` and `sha256` on a media response, or
+/// `error` and, with [`MinerOptions::ffmpeg_stderr`], ffmpeg's last lines as
+/// `stderr`. A dry run sets `commands` instead of `error`.
+fn set_media_result(
+    response: &mut serde_json::Value,
+    format: &str,
+    result: Result<Vec<u8>, FfmpegError>,
+    state: &SharedState,
+) {
+    match result {
+        Ok(data) => {
+            response["sha256"] = sha256_hex(&data).into();
+            response["data"] = base64::engine::general_purpose::STANDARD
+                .encode(&data)
+                .into();
+            response["format"] = format.into();
+        }
+        Err(FfmpegError::DryRun(commands)) => {
+            response["data"] = serde_json::Value::Null;
+            response["dry_run"] = true.into();
+            response["commands"] = commands.into();
+        }
+        Err(e) => {
+            response["data"] = serde_json::Value::Null;
+            response["error"] = e.to_string().into();
+            if state.options.ffmpeg_stderr
+                && let Some(stderr) = e.stderr()
+            {
+                response["stderr"] = stderr.into();
+            }
+        }
+    }
+}
+
+/// Answers `text`, a request from client `client_id`. Requests that take
+/// long reply through `replies` once done instead.
+async fn handle_request(
+    text: &str,
+    client_id: u64,
+    state: &Arc<SharedState>,
+    read_only: bool,
+    replies: &mpsc::UnboundedSender<String>,
+) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    let kind = value
+        .get("request")
+        .and_then(|r| r.as_str())
+        .unwrap_or_default()
+        .to_string();
+    // Any media request can ask for its ffmpeg commands instead of output
+    let dry_run =
+        value.get("dry_run").and_then(|d| d.as_bool()) == Some(true) || crate::media::is_dry_run();
+    let request: ProtocolRequest = serde_json::from_value(value).ok()?;
+    if read_only && !request.is_lookup() {
+        debug!(
+            "[client:{}] Refused {} from a read-only client",
+            client_id, kind
+        );
+        return Some(serde_json::json!({ "type": kind, "error": "read-only client" }).to_string());
+    }
+    // Notes would go to Anki without their media
+    if dry_run && request.is_mining() {
+        debug!("[client:{}] Refused {} in a dry run", client_id, kind);
+        return Some(
+            serde_json::json!({ "type": kind, "error": "dry run, nothing is mined" }).to_string(),
+        );
+    }
+    let request = match request.media_line() {
+        Some(line) => {
+            let config = state.config();
+            let request = match state.profile_for(&config, line).await {
+                Some(profile) => {
+                    debug!("[profile] Using {} for {}", profile.name, kind);
+                    request.with_settings(&profile.settings)
+                }
+                None => request,
+            };
+            request.with_settings(&config.media)
+        }
+        None => request,
+    };
+    dispatch_request(request, client_id, state, dry_run, replies)
+        .instrument(debug_span!("request", %kind))
+        .await
+}
+
+async fn dispatch_request(
+    request: ProtocolRequest,
+    client_id: u64,
+    state: &Arc<SharedState>,
+    dry_run: bool,
+    replies: &mpsc::UnboundedSender<String>,
+) -> Option<String> {
+    match request {
+        ProtocolRequest::Hello { version } => {
+            let matches = version == VERSION;
+            if !matches {
+                warn!(
+                    "[version] ******** Client {} runs frontend {} but the server is {}. Reload the page or update index.html ********",
+                    client_id, version, VERSION
+                );
+            }
+            Some(
+                serde_json::json!({
+                    "type": "hello",
+                    "server_version": VERSION,
+                    "script_version": *state.script_version.read().await,
+                    "matches": matches,
+                })
+                .to_string(),
+            )
+        }
+        ProtocolRequest::Session => {
+            let subs = state.sorted_subtitles().await;
+            let current = state.current_file.read().await.clone();
+            Some(
+                serde_json::json!({
+                    "type": "session",
+                    "path": current.path,
+                    "title": current.title,
+                    "session": current.session,
+                    "subtitles": subs,
+                })
+                .to_string(),
+            )
+        }
+        ProtocolRequest::GetNeighbors { id, before, after } => {
+            let subs = state
+                .neighbors(
+                    id,
+                    before.unwrap_or(DEFAULT_NEIGHBORS),
+                    after.unwrap_or(DEFAULT_NEIGHBORS),
+                )
+                .await;
+            Some(
+                serde_json::json!({
+                    "type": "neighbors",
+                    "id": id,
+                    "subtitles": subs.unwrap_or_default(),
+                })
+                .to_string(),
+            )
+        }
+        ProtocolRequest::GetRange {
+            start,
+            end,
+            session,
+        } => {
+            let session = match session {
+                Some(session) => session,
+                None => state.current_file.read().await.session.clone(),
+            };
+            let subs = state.range(&session, start, end).await;
+            Some(
+                serde_json::json!({
+                    "type": "range",
+                    "start": start,
+                    "end": end,
+                    "session": session,
+                    "subtitles": subs,
+                })
+                .to_string(),
+            )
+        }
+        ProtocolRequest::Search {
+            query,
+            regex,
+            session,
+            limit,
+        } => {
+            let pattern = if regex {
+                regex::Regex::new(&query)
+            } else {
+                regex::RegexBuilder::new(&regex::escape(&query))
+                    .case_insensitive(true)
+                    .build()
+            };
+            let response = match pattern {
+                Ok(pattern) => {
+                    let subs = state
+                        .search(
+                            |text| pattern.is_match(text),
+                            session.as_deref(),
+                            limit.unwrap_or(DEFAULT_SEARCH_LIMIT),
+                        )
+                        .await;
+                    debug!(
+                        "[client:{}] Search '{}' found {} lines",
+                        client_id,
+                        query,
+                        subs.len()
+                    );
+                    serde_json::json!({ "type": "search", "query": query, "subtitles": subs })
+                }
+                Err(e) => serde_json::json!({
+                    "type": "search",
+                    "query": query,
+                    "error": e.to_string(),
+                }),
+            };
+            Some(response.to_string())
+        }
+        ProtocolRequest::Export { format, session } => {
+            let session = match session {
+                Some(session) => session,
+                None => state.current_file.read().await.session.clone(),
+            };
+            let response = match format.parse::<SubtitleFormat>() {
+                Ok(parsed) => {
+                    let subs: Vec<_> = state
+                        .sorted_subtitles()
+                        .await
+                        .into_iter()
+                        .filter(|s| s.session == session)
+                        .collect();
+                    info!(
+                        "[client:{}] Exporting {} lines as {}",
+                        client_id,
+                        subs.len(),
+                        format
+                    );
+                    serde_json::json!({
+                        "type": "export",
+                        "format": format,
+                        "session": session,
+                        "content": subtitle_file::write(&subs, parsed),
+                    })
+                }
+                Err(e) => serde_json::json!({ "type": "export", "format": format, "error": e }),
+            };
+            Some(response.to_string())
+        }
+        ProtocolRequest::Merge {
+            ids,
+            gapless,
+            offset_start,
+            offset_end,
+            audio_config,
+        } => {
+            let store = state.subtitles.read().await;
+            let mut subs = ids
+                .iter()
+                .map(|id| store.get(id).cloned())
+                .collect::<Option<Vec<_>>>()?;
+            drop(store);
+            subs.sort_by(|a, b| a.sub_start.total_cmp(&b.sub_start));
+            let first = subs.first()?;
+            let sub_end = subs.iter().map(|s| s.sub_end).fold(f64::MIN, f64::max);
+
+            let gapless = gapless.unwrap_or_else(|| {
+                subs.windows(2)
+                    .any(|w| w[1].sub_start - w[0].sub_end > MAX_MERGE_GAP)
+            });
+            info!(
+                "[client:{}] Merging {} lines{}",
+                client_id,
+                subs.len(),
+                if gapless { " without gaps" } else { "" }
+            );
+            let ffmpeg_req = if gapless {
+                FfmpegRequest::audio_gapless(&subs, offset_start, offset_end, audio_config)
+            } else {
+                FfmpegRequest::audio_range(first, sub_end, offset_start, offset_end, audio_config)
+            };
+            let text = subs
+                .iter()
+                .map(|s| s.text.as_str())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut response = serde_json::json!({
+                "type": "merge",
+                "ids": ids,
+                "text": text,
+                "sub_start": first.sub_start,
+                "sub_end": sub_end,
+                "session": first.session,
+                "gapless": gapless,
+            });
+
+            let format = ffmpeg_req.extension().to_string();
+            let data = run_media(state, ffmpeg_req, dry_run).await;
+            count_media_request(dry_run, "merge", data.is_ok());
+            set_media_result(&mut response, &format, data, state);
+            Some(response.to_string())
+        }
+        ProtocolRequest::BatchExtract {
+            ids,
+            media,
+            offset_start,
+            offset_end,
+            audio_config,
+            image_config,
+            inline,
+        } => {
+            let mut options = ExtractOptions {
+                offset_start,
+                offset_end,
+                ..Default::default()
+            };
+            for kind in &media {
+                match kind.as_str() {
+                    "audio" => options.audio = Some(audio_config.clone().unwrap_or_default()),
+                    "image" => options.image = Some(image_config.clone().unwrap_or_default()),
+                    _ => {
+                        return Some(
+                            serde_json::json!({
+                                "type": "batch_extract",
+                                "error": format!("unknown media type '{}'", kind),
+                            })
+                            .to_string(),
+                        );
+                    }
+                }
+            }
+            let store = state.subtitles.read().await;
+            let subs: Vec<_> = ids.iter().filter_map(|id| store.get(id).cloned()).collect();
+            drop(store);
+            info!(
+                "[client:{}] Extracting {} for {} lines",
+                client_id,
+                media.join("+"),
+                subs.len()
+            );
 
-        let client_state = state.clone();
-        let client_rx = subtitle_tx.subscribe();
+            let found: Vec<_> = subs.iter().map(|s| s.id).collect();
+            if dry_run {
+                let commands = offline::extract_commands(&subs, &options);
+                return Some(
+                    serde_json::json!({
+                        "type": "batch_extract",
+                        "ids": found,
+                        "dry_run": true,
+                        "commands": commands,
+                    })
+                    .to_string(),
+                );
+            }
+            let span = Span::current();
+            let archive = tokio::task::spawn_blocking(move || {
+                span.in_scope(|| offline::extract_zip(&subs, &options))
+            })
+            .await;
+            count_media_request(
+                dry_run,
+                "batch_extract",
+                archive
+                    .as_ref()
+                    .is_ok_and(|a| a.as_ref().is_ok_and(|(_, n)| *n > 0)),
+            );
+            let (data, complete) = match archive {
+                Ok(Ok(archive)) => archive,
+                Ok(Err(e)) => {
+                    warn!("[media] Failed to build archive: {}", e);
+                    return Some(
+                        serde_json::json!({ "type": "batch_extract", "error": e.to_string() })
+                            .to_string(),
+                    );
+                }
+                Err(e) => {
+                    report_panic(&state.events, "Batch extraction", e);
+                    return None;
+                }
+            };
 
-        tokio::spawn(async move {
-            info!("[client:{}] Connected from {}", id, addr);
-            if let Err(e) = handle_client(stream, id, client_state, client_rx).await {
-                debug!("[client:{}] Disconnected: {}", id, e);
+            let mut response = serde_json::json!({
+                "type": "batch_extract",
+                "ids": found,
+                "complete": complete,
+                "size": data.len(),
+                "sha256": sha256_hex(&data),
+            });
+            if inline {
+                response["data"] = base64::engine::general_purpose::STANDARD
+                    .encode(&data)
+                    .into();
             } else {
-                debug!("[client:{}] Disconnected", id);
+                let path = state
+                    .downloads
+                    .host("lines.zip", "application/zip", data)
+                    .await;
+                response["path"] = path.into();
+                response["expires_in"] = DOWNLOAD_TTL.as_secs().into();
             }
-        });
-    }
-}
-
-async fn handle_mpv(
-    mut mpv: MpvStream,
-    state: Arc<SharedState>,
-    tx: broadcast::Sender<Subtitle>,
-) -> std::io::Result<()> {
-    mpv.write_all(b"{\"command\":[\"observe_property\",1,\"sub-text\"]}\n")
-        .await?;
-    info!("Connected to mpv, observing subtitle changes");
-
-    let mut pending: HashMap<u64, PendingSubtitle> = HashMap::new();
-    let mut next_subtitle_id = 1u64;
-    let mut next_request_id = 10u64;
-    let mut line = String::new();
-
-    loop {
-        line.clear();
-        if mpv.read_line(&mut line).await? == 0 {
-            return Ok(()); // EOF
+            Some(response.to_string())
         }
-
-        let Ok(json) = serde_json::from_str::<serde_json::Value>(&line) else {
-            continue;
-        };
-
-        // Handle property responses (request_id encodes: base_id + property_index)
-        if let Some(request_id) = json.get("request_id").and_then(|r| r.as_u64()) {
-            let base_id = request_id / 10 * 10; // Round down to base
-            let prop_idx = (request_id % 10) as usize;
-
-            if let Some(p) = pending.get_mut(&base_id)
-                && let Some(data) = json.get("data").cloned()
-            {
-                p.set_response(prop_idx, data);
+        ProtocolRequest::SongRange { start, end } => {
+            let path = state.current_file.read().await.path.clone();
+            info!(
+                "[client:{}] Song range {:.1}-{:.1} for {}",
+                client_id, start, end, path
+            );
+            let mut song_ranges = state.song_ranges.write().await;
+            let ranges = song_ranges.entry(path.clone()).or_default();
+            ranges.push((start, end));
+            Some(
+                serde_json::json!({
+                    "type": "song_range",
+                    "path": path,
+                    "ranges": ranges,
+                })
+                .to_string(),
+            )
+        }
+        ProtocolRequest::EditSubtitle { id, text } => {
+            let tokens = tokenize(state, &text).await;
+            let mut store = state.subtitles.write().await;
+            let sub = store.get_mut(&id)?;
+            info!("[client:{}] Edited subtitle {}: {}", client_id, id, text);
+            let original = std::mem::replace(&mut sub.text, text.clone());
+            sub.raw_text.get_or_insert(original);
+            sub.translation = None;
+            sub.tokens = tokens;
+            let _ = state.events.send(ServerEvent::SubtitleUpdated(sub.clone()));
+            if state.translator.is_some() {
+                tokio::spawn(translate_line(state.clone(), id, text));
             }
-
-            // Try to complete pending subtitles
-            let completed: Vec<_> = pending
+            None
+        }
+        ProtocolRequest::SetFilters { filters } => {
+            let response = match filters
                 .iter()
-                .filter(|(_, p)| p.is_complete())
-                .map(|(id, _)| *id)
-                .collect();
-
-            for base_id in completed {
-                let sub = pending.remove(&base_id).unwrap().into_subtitle();
-                debug!("[sub:{}] Broadcasting", sub.id);
-                state.subtitles.write().await.insert(sub.id, sub.clone());
-                let _ = tx.send(sub);
+                .map(|f| f.parse::<TextFilter>())
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(filters) => {
+                    let specs: Vec<_> = filters.iter().map(|f| f.spec.clone()).collect();
+                    info!("[client:{}] Filters set to {:?}", client_id, specs);
+                    *state.filters.write().await = filters;
+                    serde_json::json!({ "type": "filters", "filters": specs })
+                }
+                Err(e) => serde_json::json!({ "type": "filters", "error": e.to_string() }),
+            };
+            Some(response.to_string())
+        }
+        ProtocolRequest::Mine {
+            id,
+            allow_duplicate,
+            open,
+        } => {
+            info!("[client:{}] Mining subtitle {}", client_id, id);
+            let (note_id, error) = match state.mine(id, allow_duplicate, open).await {
+                Ok(Mined::Added(note_id)) => (Some(note_id), None),
+                Ok(Mined::Queued(waiting)) => {
+                    return Some(
+                        serde_json::json!({
+                            "type": "mine",
+                            "id": id,
+                            "note_id": null,
+                            "queued": true,
+                            "waiting": waiting,
+                        })
+                        .to_string(),
+                    );
+                }
+                Ok(Mined::Duplicate(note_id)) => {
+                    return Some(
+                        serde_json::json!({
+                            "type": "duplicate",
+                            "id": id,
+                            "note_id": note_id,
+                        })
+                        .to_string(),
+                    );
+                }
+                Ok(Mined::Skipped(note_id)) => {
+                    return Some(
+                        serde_json::json!({
+                            "type": "mine",
+                            "id": id,
+                            "note_id": note_id,
+                            "skipped": true,
+                        })
+                        .to_string(),
+                    );
+                }
+                Err(e) => (None, Some(e)),
+            };
+            Some(
+                serde_json::json!({
+                    "type": "mine",
+                    "id": id,
+                    "note_id": note_id,
+                    "error": error,
+                })
+                .to_string(),
+            )
+        }
+        ProtocolRequest::MineBatch {
+            ids,
+            combined,
+            allow_duplicate,
+            open,
+        } => {
+            if state.anki_target().is_none() {
+                return Some(
+                    serde_json::json!({
+                        "type": "mine_batch",
+                        "error": "Anki is not configured, start the server with --anki",
+                    })
+                    .to_string(),
+                );
             }
-            continue;
+            let batch = NEXT_BATCH.fetch_add(1, Ordering::Relaxed);
+            let total = if combined { 1 } else { ids.len() };
+            info!(
+                "[client:{}] Mining {} lines as batch {}",
+                client_id,
+                ids.len(),
+                batch
+            );
+            let state = state.clone();
+            tokio::spawn(
+                async move {
+                    state
+                        .mine_batch(batch, ids, combined, allow_duplicate, open)
+                        .await
+                }
+                .instrument(Span::current()),
+            );
+            Some(
+                serde_json::json!({
+                    "type": "mine_batch",
+                    "batch": batch,
+                    "total": total,
+                })
+                .to_string(),
+            )
         }
-
-        // Handle subtitle property changes
-        if json.get("event") == Some(&serde_json::json!("property-change"))
-            && let Some(text) = json
-                .get("data")
-                .and_then(|d| d.as_str())
-                .filter(|s| !s.is_empty())
-        {
-            let subtitle_id = next_subtitle_id;
-            next_subtitle_id += 1;
-
-            let base_id = next_request_id;
-            next_request_id += 10;
-
-            // Query all properties we need
-            let cmd = format!(
-                concat!(
-                    "{{\"command\":[\"get_property\",\"sub-start\"],\"request_id\":{0}}}\n",
-                    "{{\"command\":[\"get_property\",\"sub-end\"],\"request_id\":{1}}}\n",
-                    "{{\"command\":[\"get_property\",\"path\"],\"request_id\":{2}}}\n",
-                    "{{\"command\":[\"get_property\",\"aid\"],\"request_id\":{3}}}\n"
-                ),
-                base_id,
-                base_id + 1,
-                base_id + 2,
-                base_id + 3
+        ProtocolRequest::OpenNote { note_id } => {
+            let error = match state.anki_target() {
+                Some(target) => AnkiConnect::new(&target.url)
+                    .gui_browse(&format!("nid:{}", note_id))
+                    .await
+                    .err()
+                    .map(|e| e.to_string()),
+                None => Some("Anki is not configured, start the server with --anki".into()),
+            };
+            Some(
+                serde_json::json!({
+                    "type": "open_note",
+                    "note_id": note_id,
+                    "error": error,
+                })
+                .to_string(),
+            )
+        }
+        ProtocolRequest::AnkiDecks => {
+            let response = match state.anki_client().deck_names().await {
+                Ok(decks) => serde_json::json!({ "type": "anki_decks", "decks": decks }),
+                Err(e) => serde_json::json!({ "type": "anki_decks", "error": e.to_string() }),
+            };
+            Some(response.to_string())
+        }
+        ProtocolRequest::AnkiModels => {
+            let response = match state.anki_client().models_with_fields().await {
+                Ok(models) => serde_json::json!({ "type": "anki_models", "models": models }),
+                Err(e) => serde_json::json!({ "type": "anki_models", "error": e.to_string() }),
+            };
+            Some(response.to_string())
+        }
+        ProtocolRequest::CreateModel { model } => {
+            let model = model
+                .or_else(|| state.anki_target().map(|t| t.model.clone()))
+                .unwrap_or_else(|| offline::DEFAULT_MODEL_NAME.to_string());
+            let (created, error) =
+                match offline::ensure_model(&state.anki_client(), &model, true).await {
+                    Ok(created) => {
+                        if created {
+                            info!("[client:{}] Created note type \"{}\"", client_id, model);
+                        }
+                        (created, None)
+                    }
+                    Err(e) => (false, Some(e.to_string())),
+                };
+            Some(
+                serde_json::json!({
+                    "type": "create_model",
+                    "model": model,
+                    "fields": offline::DEFAULT_MODEL_FIELDS,
+                    "created": created,
+                    "error": error,
+                })
+                .to_string(),
+            )
+        }
+        ProtocolRequest::MineToLastCard { id } => {
+            info!(
+                "[client:{}] Mining subtitle {} to the last card",
+                client_id, id
             );
-
-            mpv.write_all(cmd.as_bytes()).await?;
-            pending.insert(base_id, PendingSubtitle::new(subtitle_id, text.to_string()));
-            info!("[sub:{}] {}", subtitle_id, text);
+            let (note_id, error) = match state.mine_to_last_card(id).await {
+                Ok(note_id) => (Some(note_id), None),
+                Err(e) => (None, Some(e)),
+            };
+            Some(
+                serde_json::json!({
+                    "type": "mine_to_last_card",
+                    "id": id,
+                    "note_id": note_id,
+                    "error": error,
+                })
+                .to_string(),
+            )
         }
-    }
-}
-
-async fn handle_client(
-    stream: TcpStream,
-    id: u64,
-    state: Arc<SharedState>,
-    mut subtitle_rx: broadcast::Receiver<Subtitle>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let ws = accept_async(stream).await?;
-    let (mut ws_tx, mut ws_rx) = ws.split();
-
-    loop {
-        tokio::select! {
-            Ok(sub) = subtitle_rx.recv() => {
-                let msg = serde_json::json!({
-                    "type": "subtitle",
-                    "id": sub.id,
-                    "subtitle": sub.text,
-                    "sub_start": sub.sub_start,
-                    "sub_end": sub.sub_end,
-                });
-                ws_tx.send(Message::Text(msg.to_string().into())).await?;
+        ProtocolRequest::Mined { id, end_id, mined } => {
+            let mined = mined.unwrap_or(true);
+            let text = state.set_mined(id..=end_id.unwrap_or(id), mined).await;
+            info!(
+                "[client:{}] Marked subtitle {} as {}",
+                client_id,
+                id,
+                if mined { "mined" } else { "not mined" }
+            );
+            if mined {
+                state.show_mined(&text);
             }
-
-            Some(msg) = ws_rx.next() => {
-                let msg = msg?;
-                if let Message::Text(text) = msg {
-                    if let Some(response) = handle_request(&text, id, &state).await {
-                        ws_tx.send(Message::Text(response.into())).await?;
-                    }
-                } else if msg.is_close() {
-                    return Ok(());
+            None
+        }
+        ProtocolRequest::GetSettings => Some(
+            serde_json::json!({
+                "type": "settings",
+                "settings": state.config().raw,
+                "path": state.options.config,
+            })
+            .to_string(),
+        ),
+        ProtocolRequest::GetLogs { limit } => Some(
+            serde_json::json!({
+                "type": "logs",
+                "lines": crate::logs::recent(limit.unwrap_or(crate::logs::RECENT_LINES)),
+            })
+            .to_string(),
+        ),
+        ProtocolRequest::SetSettings { settings } => {
+            let keys: Vec<_> = settings.keys().cloned().collect();
+            let response = match state.set_settings(settings).await {
+                Ok(settings) => {
+                    info!("[client:{}] Changed settings {:?}", client_id, keys);
+                    serde_json::json!({ "type": "set_settings", "settings": settings })
                 }
-            }
-
-            else => return Ok(()),
+                Err(e) => {
+                    warn!("[client:{}] Settings not changed: {}", client_id, e);
+                    serde_json::json!({ "type": "set_settings", "error": e })
+                }
+            };
+            Some(response.to_string())
+        }
+        ProtocolRequest::FileSettings { settings } => {
+            let path = state.current_file.read().await.path.clone();
+            let error = match state.resume.clone() {
+                _ if path.is_empty() => Some("no file is playing".to_string()),
+                Some(store) => {
+                    let file = path.clone();
+                    tokio::task::spawn_blocking(move || store.set_settings(&file, settings))
+                        .await
+                        .map_err(|e| e.to_string())
+                        .and_then(|saved| saved.map_err(|e| e.to_string()))
+                        .err()
+                }
+                None => Some("remembering files is turned off (--no-resume)".to_string()),
+            };
+            Some(
+                serde_json::json!({
+                    "type": "file_settings",
+                    "path": path,
+                    "error": error,
+                })
+                .to_string(),
+            )
         }
-    }
-}
-
-#[derive(Deserialize)]
-#[serde(tag = "request", rename_all = "snake_case")]
-enum ProtocolRequest {
-    Thumbnail {
-        id: u64,
-        end_id: Option<u64>,
-        image_config: Option<crate::media::ImageConfig>,
-    },
-    Audio {
-        id: u64,
-        offset_start: Option<f64>,
-        offset_end: Option<f64>,
-        audio_config: Option<crate::media::AudioConfig>,
-    },
-    AudioRange {
-        start_id: u64,
-        end_id: u64,
-        offset_start: Option<f64>,
-        offset_end: Option<f64>,
-        audio_config: Option<crate::media::AudioConfig>,
-    },
-}
-
-async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) -> Option<String> {
-    let request: ProtocolRequest = serde_json::from_str(text).ok()?;
-
-    match request {
         ProtocolRequest::AudioRange {
             start_id,
             end_id,
@@ -371,11 +4135,10 @@ async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) ->
             let store = state.subtitles.read().await;
             let start = store.get(&start_id)?;
             let end = store.get(&end_id)?;
+            let session = start.session.clone();
             let ffmpeg_req = FfmpegRequest::audio_range(
-                start.sub_start,
+                start,
                 end.sub_end,
-                &start.media_path,
-                start.aid,
                 offset_start,
                 offset_end,
                 audio_config,
@@ -387,34 +4150,207 @@ async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) ->
                 client_id, start_id, end_id
             );
 
-            let data = tokio::task::spawn_blocking(move || ffmpeg_req.execute())
-                .await
-                .ok()?;
+            let format = ffmpeg_req.extension().to_string();
+            let data = run_media(state, ffmpeg_req, dry_run).await;
+            count_media_request(dry_run, "audio_range", data.is_ok());
+
+            let mut response = serde_json::json!({
+                "type": "audio_range",
+                "start_id": start_id,
+                "end_id": end_id,
+                "session": session,
+            });
+            set_media_result(&mut response, &format, data, state);
+            Some(response.to_string())
+        }
+        ProtocolRequest::ThumbnailCandidates {
+            id,
+            end_id,
+            count,
+            image_config,
+        } => {
+            let store = state.subtitles.read().await;
+            let mut sub = store.get(&id)?.clone();
+            if let Some(eid) = end_id
+                && let Some(end_sub) = store.get(&eid)
+            {
+                sub.sub_end = end_sub.sub_end;
+            }
+            drop(store);
+
+            let count = count
+                .unwrap_or(DEFAULT_THUMBNAIL_CANDIDATES)
+                .clamp(1, MAX_THUMBNAIL_CANDIDATES);
+            info!(
+                "[client:{}] Requesting {} thumbnail candidates for subtitle {}",
+                client_id, count, id
+            );
+
+            let jobs = FfmpegRequest::thumbnail_candidates(&sub, count, image_config)
+                .into_iter()
+                .map(|(time, ffmpeg_req)| async move {
+                    let format = ffmpeg_req.extension().to_string();
+                    let data = run_media(state, ffmpeg_req, dry_run).await;
+                    let time = (time * 1000.0).round() / 1000.0;
+                    let mut candidate = serde_json::json!({ "time": time });
+                    set_media_result(&mut candidate, &format, data, state);
+                    candidate
+                });
+            let candidates = futures_util::future::join_all(jobs).await;
+            let ok = candidates.iter().any(|c| !c["data"].is_null());
+            count_media_request(dry_run, "thumbnail_candidates", ok);
 
             Some(
                 serde_json::json!({
-                    "type": "audio_range",
-                    "start_id": start_id,
-                    "end_id": end_id,
-                    "data": data,
+                    "type": "thumbnail_candidates",
+                    "id": id,
+                    "session": sub.session,
+                    "candidates": candidates,
                 })
                 .to_string(),
             )
         }
+        ProtocolRequest::FrameAt {
+            id,
+            time,
+            image_config,
+        } => {
+            // The picker may stay open after mpv moved on to the next file
+            let (path, session) = match id {
+                Some(id) => match state.subtitles.read().await.get(&id) {
+                    Some(sub) => (sub.media_path.clone(), sub.session.clone()),
+                    None => {
+                        return Some(
+                            serde_json::json!({
+                                "type": "frame_at",
+                                "id": id,
+                                "time": time,
+                                "data": null,
+                                "error": "subtitle not found",
+                            })
+                            .to_string(),
+                        );
+                    }
+                },
+                None => {
+                    let current = state.current_file.read().await;
+                    (current.path.clone(), current.session.clone())
+                }
+            };
+            let mut response = serde_json::json!({
+                "type": "frame_at",
+                "id": id,
+                "time": time,
+                "session": session,
+            });
+            if path.is_empty() || !time.is_finite() || time < 0.0 {
+                response["data"] = serde_json::Value::Null;
+                response["error"] = "no file loaded or invalid time".into();
+                return Some(response.to_string());
+            }
+
+            info!("[client:{}] Requesting frame at {:.3}", client_id, time);
+            let ffmpeg_req = FfmpegRequest::frame_at(&path, time, image_config);
+            let format = ffmpeg_req.extension().to_string();
+            let data = run_media(state, ffmpeg_req, dry_run).await;
+            count_media_request(dry_run, "frame_at", data.is_ok());
+            set_media_result(&mut response, &format, data, state);
+            Some(response.to_string())
+        }
+        ProtocolRequest::Transcribe { before, after } => {
+            // whisper takes a while; the client's other requests go on meanwhile
+            let state = state.clone();
+            let replies = replies.clone();
+            tokio::spawn(
+                async move {
+                    let response = match transcribe(&state, before, after).await {
+                        Ok(ids) => serde_json::json!({ "type": "transcribe", "ids": ids }),
+                        Err(e) => serde_json::json!({ "type": "transcribe", "error": e }),
+                    };
+                    let _ = replies.send(response.to_string());
+                }
+                .instrument(Span::current()),
+            );
+            None
+        }
+        ProtocolRequest::Align { text } => {
+            // Transcribes the whole file, which takes minutes
+            let state = state.clone();
+            let replies = replies.clone();
+            tokio::spawn(
+                async move {
+                    let response = match align_script(&state, text).await {
+                        Ok(ids) => serde_json::json!({ "type": "align", "ids": ids }),
+                        Err(e) => serde_json::json!({ "type": "align", "error": e }),
+                    };
+                    let _ = replies.send(response.to_string());
+                }
+                .instrument(Span::current()),
+            );
+            None
+        }
+        ProtocolRequest::CurrentFrame {
+            subtitles,
+            image_config,
+        } => {
+            let session = state.current_file.read().await.session.clone();
+            let mut response = serde_json::json!({
+                "type": "current_frame",
+                "session": session,
+            });
+
+            info!("[client:{}] Requesting mpv's current frame", client_id);
+            let screenshot = screenshot_path();
+            let flags = if subtitles { "subtitles" } else { "video" };
+            let answer = state
+                .mpv_command(serde_json::json!([
+                    "screenshot-to-file",
+                    screenshot.display().to_string(),
+                    flags
+                ]))
+                .await;
+            let error = match answer {
+                Some(answer) => answer
+                    .get("error")
+                    .and_then(|e| e.as_str())
+                    .filter(|e| *e != "success")
+                    .map(|e| format!("mpv screenshot failed: {}", e)),
+                None => Some("mpv did not answer".to_string()),
+            };
+            if let Some(error) = error {
+                count_media_request(dry_run, "current_frame", false);
+                response["data"] = serde_json::Value::Null;
+                response["error"] = error.into();
+                return Some(response.to_string());
+            }
+
+            let ffmpeg_req = FfmpegRequest::encode_image(&screenshot, image_config);
+            let format = ffmpeg_req.extension().to_string();
+            let data = run_media(state, ffmpeg_req, dry_run).await;
+            let _ = std::fs::remove_file(&screenshot);
+            count_media_request(dry_run, "current_frame", data.is_ok());
+            set_media_result(&mut response, &format, data, state);
+            Some(response.to_string())
+        }
         _ => {
-            let (subtitle_id, media_type, ffmpeg_req) = match request {
-                ProtocolRequest::Thumbnail { id, end_id, image_config } => {
+            let (subtitle_id, media_type, session, ffmpeg_req) = match request {
+                ProtocolRequest::Thumbnail {
+                    id,
+                    end_id,
+                    image_config,
+                } => {
                     let store = state.subtitles.read().await;
                     let mut sub = store.get(&id)?.clone();
-                    if let Some(eid) = end_id {
-                        if let Some(end_sub) = store.get(&eid) {
-                            sub.sub_end = end_sub.sub_end;
-                        }
+                    if let Some(eid) = end_id
+                        && let Some(end_sub) = store.get(&eid)
+                    {
+                        sub.sub_end = end_sub.sub_end;
                     }
                     drop(store);
                     (
                         id,
                         "thumbnail",
+                        sub.session.clone(),
                         FfmpegRequest::thumbnail(&sub, image_config),
                     )
                 }
@@ -430,9 +4366,30 @@ async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) ->
                     (
                         id,
                         "audio",
+                        sub.session.clone(),
                         FfmpegRequest::audio(&sub, offset_start, offset_end, audio_config),
                     )
                 }
+                ProtocolRequest::AudioPreview {
+                    id,
+                    offset_start,
+                    offset_end,
+                } => {
+                    let store = state.subtitles.read().await;
+                    let sub = store.get(&id)?.clone();
+                    drop(store);
+                    (
+                        id,
+                        "audio_preview",
+                        sub.session.clone(),
+                        FfmpegRequest::audio(
+                            &sub,
+                            offset_start,
+                            offset_end,
+                            Some(AudioConfig::preview()),
+                        ),
+                    )
+                }
                 _ => unreachable!(),
             };
 
@@ -442,11 +4399,11 @@ async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) ->
             );
 
             let req_type = media_type.to_string();
-            let data = tokio::task::spawn_blocking(move || ffmpeg_req.execute())
-                .await
-                .ok()?;
+            let format = ffmpeg_req.extension().to_string();
+            let data = run_media(state, ffmpeg_req, dry_run).await;
+            count_media_request(dry_run, media_type, data.is_ok());
 
-            if data.is_some() {
+            if data.is_ok() {
                 debug!("[media] {} ready for subtitle {}", req_type, subtitle_id);
             } else {
                 warn!(
@@ -455,14 +4412,154 @@ async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) ->
                 );
             }
 
-            Some(
-                serde_json::json!({
-                    "type": req_type,
-                    "id": subtitle_id,
-                    "data": data,
-                })
-                .to_string(),
-            )
+            let mut response = serde_json::json!({
+                "type": req_type,
+                "id": subtitle_id,
+                "session": session,
+            });
+            set_media_result(&mut response, &format, data, state);
+            Some(response.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lan() -> Option<SocketAddr> {
+        Some("192.168.1.5:61777".parse().unwrap())
+    }
+
+    #[test]
+    fn query_tokens_are_url_decoded() {
+        assert_eq!(
+            presented_token(Some("token=a%2Bb%2Fc%3Dd%26e"), None).as_deref(),
+            Some("a+b/c=d&e")
+        );
+        assert_eq!(
+            presented_token(Some("role=spectator&token=a+b"), None).as_deref(),
+            Some("a b")
+        );
+        assert_eq!(
+            presented_token(Some("token=caf%C3%A9"), None).as_deref(),
+            Some("café")
+        );
+        assert_eq!(presented_token(Some("role=spectator"), None), None);
+    }
+
+    #[test]
+    fn bearer_header_is_used_without_query_token() {
+        assert_eq!(
+            presented_token(None, Some("Bearer secret")).as_deref(),
+            Some("secret")
+        );
+        assert_eq!(
+            presented_token(Some("token=query"), Some("Bearer header")).as_deref(),
+            Some("query")
+        );
+        assert_eq!(presented_token(None, Some("Basic c2VjcmV0")), None);
+    }
+
+    #[test]
+    fn tokens_match_only_whole_tokens() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "secrets"));
+        assert!(!tokens_match("secrets", "secret"));
+        assert!(!tokens_match("Secret", "secret"));
+        assert!(!tokens_match("", "secret"));
+    }
+
+    #[test]
+    fn access_roles() {
+        let access = Access {
+            token: Some("full".into()),
+            spectator_token: Some("friend".into()),
+            ..Default::default()
+        };
+        assert_eq!(access.role(Some("full")), Some(Role::Full));
+        assert_eq!(access.role(Some("friend")), Some(Role::Spectator));
+        assert_eq!(access.role(Some("wrong")), None);
+        assert_eq!(access.role(None), None);
+        assert_eq!(Access::default().role(None), Some(Role::Full));
+    }
+
+    #[test]
+    fn clients_without_origin_are_let_in() {
+        let policy = OriginPolicy::default();
+        assert!(policy.allows(None, Some("evil.example:61777"), false, lan()));
+        assert!(policy.allows(None, None, false, lan()));
+    }
+
+    #[test]
+    fn own_pages_are_allowed() {
+        let policy = OriginPolicy::default();
+        for (origin, host) in [
+            ("http://localhost:61777", "localhost:61777"),
+            ("http://127.0.0.1:61777", "127.0.0.1:61777"),
+            ("http://[::1]:61777", "[::1]:61777"),
+            ("http://192.168.1.5:61777", "192.168.1.5:61777"),
+        ] {
+            assert!(
+                policy.allows(Some(origin), Some(host), true, lan()),
+                "{}",
+                origin
+            );
+        }
+        // A browser leaving out Origin, addressing the miner by its name
+        assert!(policy.allows(None, Some("localhost:61777"), true, lan()));
+    }
+
+    #[test]
+    fn other_pages_need_allowing() {
+        let policy = OriginPolicy::default();
+        assert!(!policy.allows(
+            Some("https://evil.example"),
+            Some("localhost:61777"),
+            true,
+            lan()
+        ));
+        // Another server on this computer is another origin
+        assert!(!policy.allows(
+            Some("http://localhost:5173"),
+            Some("localhost:61777"),
+            true,
+            lan()
+        ));
+        let policy = OriginPolicy::new(vec!["http://localhost:5173/".into()]);
+        assert!(policy.allows(
+            Some("http://localhost:5173"),
+            Some("localhost:61777"),
+            true,
+            lan()
+        ));
+        let policy = OriginPolicy::new(vec!["*".into()]);
+        assert!(policy.allows(
+            Some("https://evil.example"),
+            Some("localhost:61777"),
+            true,
+            lan()
+        ));
+    }
+
+    #[test]
+    fn rebound_names_are_refused() {
+        // Even `*` doesn't let a page reach the miner under its own domain
+        for policy in [OriginPolicy::default(), OriginPolicy::new(vec!["*".into()])] {
+            assert!(!policy.allows(
+                Some("http://evil.example:61777"),
+                Some("evil.example:61777"),
+                true,
+                lan()
+            ));
+            assert!(!policy.allows(None, Some("evil.example:61777"), true, lan()));
         }
+        let policy = OriginPolicy::new(vec!["http://miner.lan:61777".into()]);
+        assert!(policy.allows(
+            Some("http://miner.lan:61777"),
+            Some("miner.lan:61777"),
+            true,
+            lan()
+        ));
     }
 }
@@ -0,0 +1,185 @@
+use futures_util::{SinkExt, StreamExt};
+use std::path::{Path, PathBuf};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::event_loop::Subtitle;
+
+type ExportResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Fetches the captured session from a running server as JSON.
+pub async fn fetch_session(url: &str) -> ExportResult<serde_json::Value> {
+    request(url, serde_json::json!({ "request": "session" }), "session").await
+}
+
+/// Fetches the current file's lines from a running server as an SRT or
+/// WebVTT file.
+pub async fn fetch_subtitles(url: &str, format: &str) -> ExportResult<String> {
+    let json = request(
+        url,
+        serde_json::json!({ "request": "export", "format": format }),
+        "export",
+    )
+    .await?;
+    if let Some(error) = json.get("error").and_then(|e| e.as_str()) {
+        return Err(error.into());
+    }
+    Ok(json
+        .get("content")
+        .and_then(|c| c.as_str())
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Sends `request` and waits for the first response of type `response_type`.
+async fn request(
+    url: &str,
+    request: serde_json::Value,
+    response_type: &str,
+) -> ExportResult<serde_json::Value> {
+    let (mut ws, _) = connect_async(url).await?;
+    ws.send(Message::Text(request.to_string().into())).await?;
+
+    while let Some(msg) = ws.next().await {
+        if let Message::Text(text) = msg?
+            && let Ok(json) = serde_json::from_str::<serde_json::Value>(&text)
+            && json.get("type").and_then(|t| t.as_str()) == Some(response_type)
+        {
+            let _ = ws.close(None).await;
+            return Ok(json);
+        }
+    }
+    Err(format!(
+        "server closed the connection before sending the {}",
+        response_type
+    )
+    .into())
+}
+
+/// Spreadsheet formats, one row per line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    Csv,
+    Tsv,
+}
+
+impl std::str::FromStr for TableFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "tsv" => Ok(Self::Tsv),
+            _ => Err(format!("unknown table format '{}'", s)),
+        }
+    }
+}
+
+/// Audio and image cut for one line ahead of time.
+#[derive(Debug, Default)]
+pub struct MediaFiles {
+    pub audio: Option<PathBuf>,
+    pub image: Option<PathBuf>,
+}
+
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "opus", "ogg", "m4a", "aac", "flac", "wav"];
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp", "avif"];
+
+/// Looks up `NNNN.<ext>` in `dir`, as written by `extract` and `mine --out-dir`.
+pub fn find_media(dir: &Path, id: u64) -> MediaFiles {
+    let mut files = MediaFiles::default();
+    for ext in AUDIO_EXTENSIONS {
+        let path = dir.join(format!("{:04}.{}", id, ext));
+        if files.audio.is_none() && path.is_file() {
+            files.audio = Some(path);
+        }
+    }
+    for ext in IMAGE_EXTENSIONS {
+        let path = dir.join(format!("{:04}.{}", id, ext));
+        if files.image.is_none() && path.is_file() {
+            files.image = Some(path);
+        }
+    }
+    files
+}
+
+/// Adds `audio_file`/`image_file` to every line of a session dump that has
+/// media in `dir`.
+pub fn attach_media(session: &mut serde_json::Value, dir: &Path) {
+    let Some(subs) = session.get_mut("subtitles").and_then(|s| s.as_array_mut()) else {
+        return;
+    };
+    for sub in subs {
+        let Some(id) = sub.get("id").and_then(|id| id.as_u64()) else {
+            continue;
+        };
+        let files = find_media(dir, id);
+        if let Some(obj) = sub.as_object_mut() {
+            for (key, path) in [("audio_file", files.audio), ("image_file", files.image)] {
+                if let Some(path) = path {
+                    obj.insert(key.into(), path.display().to_string().into());
+                }
+            }
+        }
+    }
+}
+
+/// Writes one row per line with its text, timings and file, plus the paths of
+/// its media in `media_dir` when given.
+pub fn write_table(subs: &[Subtitle], format: TableFormat, media_dir: Option<&Path>) -> String {
+    let mut header = vec![
+        "id", "file", "start", "end", "text", "chapter", "mined", "count",
+    ];
+    if media_dir.is_some() {
+        header.extend(["audio", "image"]);
+    }
+
+    let mut out = String::new();
+    push_row(&mut out, format, header.into_iter().map(str::to_string));
+    for sub in subs {
+        let mut row = vec![
+            sub.id.to_string(),
+            sub.media_path.clone(),
+            format!("{:.3}", sub.sub_start),
+            format!("{:.3}", sub.sub_end),
+            sub.text.clone(),
+            sub.chapter.clone().unwrap_or_default(),
+            sub.mined.to_string(),
+            sub.count().to_string(),
+        ];
+        if let Some(dir) = media_dir {
+            let files = find_media(dir, sub.id);
+            for path in [files.audio, files.image] {
+                row.push(path.map(|p| p.display().to_string()).unwrap_or_default());
+            }
+        }
+        push_row(&mut out, format, row.into_iter());
+    }
+    out
+}
+
+fn push_row(out: &mut String, format: TableFormat, fields: impl Iterator<Item = String>) {
+    let fields: Vec<String> = match format {
+        TableFormat::Csv => fields
+            .map(|f| {
+                if f.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", f.replace('"', "\"\""))
+                } else {
+                    f
+                }
+            })
+            .collect(),
+        // TSV has no quoting, so line breaks and tabs become spaces
+        TableFormat::Tsv => fields.map(|f| f.replace(['\t', '\r', '\n'], " ")).collect(),
+    };
+    let separator = if format == TableFormat::Csv {
+        ","
+    } else {
+        "\t"
+    };
+    out.push_str(&fields.join(separator));
+    out.push_str(if format == TableFormat::Csv {
+        "\r\n"
+    } else {
+        "\n"
+    });
+}
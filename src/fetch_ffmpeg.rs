@@ -0,0 +1,211 @@
+//! `fetch-ffmpeg`: downloads a static ffmpeg build into the data directory,
+//! where [`crate::media`] looks for ffmpeg before the system's, so there is
+//! nothing else to install or put on PATH.
+
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+/// Release the builds and their `checksums.sha256` are downloaded from.
+///
+/// The checksum is taken from the same rolling release as the build, so it
+/// only shows the download arrived intact, not that the build is genuine;
+/// that still rests on HTTPS and the release's owner. Pass `--url` and
+/// `--sha256` for a build checked against a hash from elsewhere.
+const RELEASE_URL: &str = "https://github.com/BtbN/FFmpeg-Builds/releases/download/latest";
+
+/// A download to install instead of the build for this platform.
+#[derive(Debug, Clone)]
+pub struct Source {
+    /// A `.zip` or `.tar.*` archive with ffmpeg (and ffprobe) anywhere in it
+    pub url: String,
+    /// Its SHA-256, in hex
+    pub sha256: String,
+}
+
+/// Where the fetched ffmpeg and ffprobe go.
+pub fn install_dir() -> Option<PathBuf> {
+    crate::instance::data_dir().map(|dir| dir.join("ffmpeg"))
+}
+
+/// The archive in [`RELEASE_URL`] for this platform.
+fn build_name() -> Option<&'static str> {
+    match (env::consts::OS, env::consts::ARCH) {
+        ("windows", "x86_64") => Some("ffmpeg-master-latest-win64-gpl.zip"),
+        ("windows", "aarch64") => Some("ffmpeg-master-latest-winarm64-gpl.zip"),
+        ("linux", "x86_64") => Some("ffmpeg-master-latest-linux64-gpl.tar.xz"),
+        ("linux", "aarch64") => Some("ffmpeg-master-latest-linuxarm64-gpl.tar.xz"),
+        _ => None,
+    }
+}
+
+/// Downloads `source` (the build for this platform when `None`), checks its
+/// checksum and unpacks ffmpeg and ffprobe into [`install_dir`], replacing
+/// any fetched before. Returns the installed ffmpeg.
+pub async fn fetch(source: Option<Source>) -> io::Result<PathBuf> {
+    let dir = install_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no data directory to put it in"))?;
+    let client = reqwest::Client::new();
+    let source = match source {
+        Some(source) => source,
+        None => release_source(&client).await?,
+    };
+
+    let work = dir.join(".download");
+    let _ = fs::remove_dir_all(&work);
+    fs::create_dir_all(&work)?;
+    let result = install(&client, &source, &dir, &work).await;
+    let _ = fs::remove_dir_all(&work);
+    result
+}
+
+async fn release_source(client: &reqwest::Client) -> io::Result<Source> {
+    let name = build_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "no ffmpeg build to fetch for {} on {}; install ffmpeg with your package manager (e.g. `brew install ffmpeg`) or pass --url and --sha256",
+                env::consts::OS,
+                env::consts::ARCH
+            ),
+        )
+    })?;
+    let checksums = get(client, &format!("{}/checksums.sha256", RELEASE_URL))
+        .await?
+        .text()
+        .await
+        .map_err(io::Error::other)?;
+    // `<hex>  <file name>` per line, as sha256sum writes them
+    let sha256 = checksums
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, file)| file.trim().trim_start_matches('*') == name)
+        .map(|(hex, _)| hex.to_string())
+        .ok_or_else(|| io::Error::other(format!("no checksum listed for {}", name)))?;
+    Ok(Source {
+        url: format!("{}/{}", RELEASE_URL, name),
+        sha256,
+    })
+}
+
+async fn install(
+    client: &reqwest::Client,
+    source: &Source,
+    dir: &Path,
+    work: &Path,
+) -> io::Result<PathBuf> {
+    let name = source
+        .url
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or("ffmpeg.zip");
+    let archive = work.join(name);
+    info!("[fetch-ffmpeg] Downloading {}", source.url);
+    let sha256 = download(client, &source.url, &archive).await?;
+    if !sha256.eq_ignore_ascii_case(source.sha256.trim()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "checksum mismatch for {}: expected {}, got {}",
+                name, source.sha256, sha256
+            ),
+        ));
+    }
+
+    // bsdtar, which ships with Windows and macOS, unpacks zips as well
+    let unpacked = work.join("unpacked");
+    fs::create_dir_all(&unpacked)?;
+    let status = tokio::process::Command::new("tar")
+        .arg("-xf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&unpacked)
+        .status()
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("tar failed to start: {}", e)))?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "tar couldn't unpack {} ({})",
+            name, status
+        )));
+    }
+
+    let ffmpeg = executable("ffmpeg");
+    let found = find(&unpacked, &ffmpeg)?
+        .ok_or_else(|| io::Error::other(format!("{} has no {}", name, ffmpeg)))?;
+    fs::create_dir_all(dir)?;
+    replace(&found, &dir.join(&ffmpeg))?;
+    // Only track listing and offline extraction need it
+    let ffprobe = executable("ffprobe");
+    match find(&unpacked, &ffprobe)? {
+        Some(found) => replace(&found, &dir.join(&ffprobe))?,
+        None => warn!(
+            "[fetch-ffmpeg] {} has no {}, track listing won't work",
+            name, ffprobe
+        ),
+    }
+    Ok(dir.join(ffmpeg))
+}
+
+/// Streams `url` into `path` and returns its SHA-256.
+async fn download(client: &reqwest::Client, url: &str, path: &Path) -> io::Result<String> {
+    let mut response = get(client, url).await?;
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = response.chunk().await.map_err(io::Error::other)? {
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+        size += chunk.len();
+    }
+    file.flush().await?;
+    info!(
+        "[fetch-ffmpeg] Downloaded {:.1} MB",
+        size as f64 / 1_000_000.0
+    );
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+async fn get(client: &reqwest::Client, url: &str) -> io::Result<reqwest::Response> {
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(io::Error::other)
+}
+
+fn executable(name: &str) -> String {
+    format!("{}{}", name, env::consts::EXE_SUFFIX)
+}
+
+/// The file called `name` anywhere under `dir`.
+fn find(dir: &Path, name: &str) -> io::Result<Option<PathBuf>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if let Some(found) = find(&path, name)? {
+                return Ok(Some(found));
+            }
+        } else if path.file_name().is_some_and(|n| n == name) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Moves `from` to `to`, over any file already there.
+fn replace(from: &Path, to: &Path) -> io::Result<()> {
+    // Windows won't rename over a file
+    let _ = fs::remove_file(to);
+    fs::rename(from, to)
+}
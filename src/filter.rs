@@ -0,0 +1,108 @@
+//! Server-side rules deciding how captured lines reach clients.
+
+use regex::Regex;
+use std::str::FromStr;
+
+/// What to do with lyrics, i.e. lines in OP/ED chapters or in a song range
+/// set for the file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SongLines {
+    /// Treat them like any other line
+    Keep,
+    /// Send them with `"song": true`
+    #[default]
+    Flag,
+    /// Don't capture them at all
+    Hide,
+}
+
+impl FromStr for SongLines {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "flag" => Ok(Self::Flag),
+            "hide" => Ok(Self::Hide),
+            _ => Err(format!("expected keep, flag or hide, got '{}'", s)),
+        }
+    }
+}
+
+/// Whether a chapter title names an opening or ending, e.g. `OP`, `ED2`,
+/// `Opening` or `Ending Song`.
+pub fn is_song_chapter(title: &str) -> bool {
+    let word = title
+        .split(|c: char| !c.is_alphanumeric())
+        .find(|w| !w.is_empty())
+        .unwrap_or_default()
+        .to_lowercase();
+    let name = word.trim_end_matches(|c: char| c.is_ascii_digit());
+    matches!(name, "op" | "ed" | "opening" | "ending")
+}
+
+/// Built-in filters, usable by name wherever a filter regex is accepted.
+const PRESETS: &[(&str, &str)] = &[
+    ("parentheses", r"\([^)]*\)|（[^）]*）"),
+    ("brackets", r"\[[^\]]*\]|［[^］]*］|【[^】]*】"),
+    ("speaker", r"(?m)^[^\s:：]{1,20}[:：]\s*"),
+    ("furigana", r"\{[^}]*\}|《[^》]*》"),
+];
+
+/// A regex whose matches are removed from captured lines.
+#[derive(Debug, Clone)]
+pub struct TextFilter {
+    /// Preset name or the regex as given
+    pub spec: String,
+    regex: Regex,
+}
+
+impl FromStr for TextFilter {
+    type Err = regex::Error;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let pattern = PRESETS
+            .iter()
+            .find(|(name, _)| *name == spec)
+            .map_or(spec, |(_, pattern)| pattern);
+        Ok(Self {
+            spec: spec.to_string(),
+            regex: Regex::new(pattern)?,
+        })
+    }
+}
+
+/// Removes every filter's matches from `text` in order and trims what is
+/// left, dropping lines that end up blank.
+pub fn apply_filters(filters: &[TextFilter], text: &str) -> String {
+    let mut text = text.to_string();
+    for filter in filters {
+        text = filter.regex.replace_all(&text, "").into_owned();
+    }
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Lines that are dropped altogether rather than sent, checked after the
+/// filters ran.
+#[derive(Debug, Clone, Default)]
+pub struct Suppression {
+    /// Lines with fewer characters than this, not counting whitespace
+    pub min_chars: usize,
+    /// Lines without a letter or digit, e.g. `♪～` or `……！？`
+    pub symbols_only: bool,
+    /// Lines matching any of these
+    pub patterns: Vec<Regex>,
+}
+
+impl Suppression {
+    /// Whether `text` should not be sent at all.
+    pub fn suppresses(&self, text: &str) -> bool {
+        text.chars().filter(|c| !c.is_whitespace()).count() < self.min_chars
+            || (self.symbols_only && !text.chars().any(char::is_alphanumeric))
+            || self.patterns.iter().any(|re| re.is_match(text))
+    }
+}
@@ -0,0 +1,206 @@
+//! User commands run on mining events, to wire the miner into other tools:
+//! appending to a Markdown log, re-recording audio with TTS and so on.
+
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+use crate::event_loop::{ServerEvent, Subtitle};
+use crate::offline::PreparedNote;
+
+/// Commands to run, each split into arguments like a shell would but run
+/// without one, so line text can't inject anything. See [`PLACEHOLDERS`].
+///
+/// Placeholders are filled in before the command is split off, so a hook
+/// that needs a shell should read the environment instead:
+/// `sh -c 'echo "$SUBTITLEMINER_TEXT" >> log'`, not `sh -c 'echo {text}'`,
+/// where the line's text would be parsed by the shell.
+#[derive(Debug, Clone, Default)]
+pub struct HookOptions {
+    /// Run for every new line (`subtitle_captured`)
+    pub on_subtitle: Option<String>,
+    /// Run for every note added (`card_mined`)
+    pub on_mined: Option<String>,
+}
+
+/// What hook arguments can use, each written as `{name}`, and what every
+/// hook also gets as `SUBTITLEMINER_<NAME>` in its environment. `title`,
+/// `note_id`, `audio` and `image` (paths to the card's media, removed once
+/// the command exits) are only set for `card_mined`.
+pub const PLACEHOLDERS: &[&str] = &[
+    "id",
+    "text",
+    "translation",
+    "file",
+    "start",
+    "end",
+    "chapter",
+    "title",
+    "note_id",
+    "audio",
+    "image",
+];
+
+/// How long a hook may run before it is killed.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// `subtitle_captured` hooks running at once; further lines wait for one
+/// to finish.
+const MAX_RUNNING: usize = 4;
+
+/// Runs `command_line` for `event` and waits for it, killing it after
+/// [`TIMEOUT`] or on shutdown.
+pub fn run(event: &str, command_line: &str, values: &HashMap<&str, String>) -> std::io::Result<()> {
+    let args: Vec<String> = shlex::split(command_line)
+        .filter(|args| !args.is_empty())
+        .ok_or_else(|| std::io::Error::other(format!("invalid hook command: {}", command_line)))?
+        .iter()
+        .map(|arg| fill(arg, values))
+        .collect();
+    let mut command = Command::new(&args[0]);
+    command.args(&args[1..]).env("SUBTITLEMINER_EVENT", event);
+    for name in PLACEHOLDERS {
+        let value = values.get(name).map_or("", String::as_str);
+        command.env(format!("SUBTITLEMINER_{}", name.to_uppercase()), value);
+    }
+    let output = crate::media::run_killable(command, None, TIMEOUT)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(format!(
+            "{} exited with {}: {}",
+            args[0],
+            output.status,
+            stderr.lines().last().unwrap_or_default()
+        )));
+    }
+    debug!("[hooks] {} ran {}", event, args[0]);
+    Ok(())
+}
+
+/// `arg` with known placeholders filled; other braces (awk, jq) are kept.
+fn fill(arg: &str, values: &HashMap<&str, String>) -> String {
+    static PLACEHOLDER: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
+    PLACEHOLDER
+        .get_or_init(|| Regex::new(r"\{([a-z_]+)\}").expect("valid regex"))
+        .replace_all(arg, |caps: &regex::Captures| {
+            if PLACEHOLDERS.contains(&&caps[1]) {
+                values.get(&caps[1]).cloned().unwrap_or_default()
+            } else {
+                caps[0].to_string()
+            }
+        })
+        .into_owned()
+}
+
+fn line_values(sub: &Subtitle) -> HashMap<&'static str, String> {
+    let mut values = HashMap::from([
+        ("id", sub.id.to_string()),
+        ("text", sub.text.clone()),
+        ("file", sub.media_path.clone()),
+        ("start", format!("{:.3}", sub.sub_start)),
+        ("end", format!("{:.3}", sub.sub_end)),
+    ]);
+    values.extend(sub.translation.clone().map(|t| ("translation", t)));
+    values.extend(sub.chapter.clone().map(|c| ("chapter", c)));
+    values
+}
+
+/// Runs `command_line` for every new line in `events`, up to
+/// [`MAX_RUNNING`] at a time in the order they came, until the miner goes
+/// away. Lines that come faster than the hooks finish are skipped with a
+/// warning.
+pub async fn on_subtitles(mut events: broadcast::Receiver<ServerEvent>, command_line: String) {
+    let running = Arc::new(Semaphore::new(MAX_RUNNING));
+    loop {
+        let sub = match events.recv().await {
+            Ok(ServerEvent::Subtitle(sub)) => sub,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    "[hooks] subtitle_captured is falling behind, skipped {} event(s)",
+                    skipped
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Ok(permit) = running.clone().acquire_owned().await else {
+            return;
+        };
+        let command_line = command_line.clone();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            if let Err(e) = run("subtitle_captured", &command_line, &line_values(&sub)) {
+                warn!("[hooks] subtitle_captured: {}", e);
+            }
+        });
+    }
+}
+
+/// Runs `command_line` in the background for note `note_id`, made from
+/// `sub`, with its media written to a temporary directory.
+pub fn card_mined(
+    command_line: String,
+    sub: &Subtitle,
+    title: Option<&str>,
+    note_id: u64,
+    prepared: &PreparedNote,
+) {
+    let mut values = line_values(sub);
+    values.insert("note_id", note_id.to_string());
+    values.extend(title.map(|t| ("title", t.to_string())));
+    let media = prepared.media.clone();
+    let sounds: Vec<_> = media
+        .iter()
+        .map(|(name, _)| {
+            let tag = crate::anki::sound_tag(name);
+            prepared.note.fields.values().any(|v| v.contains(&tag))
+        })
+        .collect();
+    tokio::task::spawn_blocking(move || {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("subtitleminer-hook-{}", uuid::Uuid::new_v4()));
+        let written = std::fs::create_dir_all(&dir).and_then(|()| {
+            for ((name, data), is_sound) in media.iter().zip(sounds) {
+                let path = dir.join(name);
+                std::fs::write(&path, data)?;
+                let key = if is_sound { "audio" } else { "image" };
+                values.insert(key, path.display().to_string());
+            }
+            Ok(())
+        });
+        let result = written.and_then(|()| run("card_mined", &command_line, &values));
+        let _ = std::fs::remove_dir_all(&dir);
+        if let Err(e) = result {
+            warn!("[hooks] card_mined: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_known_placeholders() {
+        let values = HashMap::from([("id", "7".to_string()), ("text", "a \"b\"".to_string())]);
+        assert_eq!(fill("{text}", &values), "a \"b\"");
+        assert_eq!(fill("line {id}: {text}", &values), "line 7: a \"b\"");
+        // Known but not set for this event
+        assert_eq!(fill("[{translation}]", &values), "[]");
+    }
+
+    #[test]
+    fn keeps_other_braces() {
+        let values = HashMap::from([("text", "x".to_string())]);
+        assert_eq!(fill("{print $1}", &values), "{print $1}");
+        assert_eq!(fill("{print}", &values), "{print}");
+        assert_eq!(fill(".{Text}", &values), ".{Text}");
+    }
+}
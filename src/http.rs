@@ -0,0 +1,322 @@
+//! Plain HTTP on the WebSocket port, for the bundled page, `/metrics`, the
+//! `/overlay` page, the `/events` stream and files too big to push through
+//! the JSON protocol.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::RwLock;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info};
+
+use crate::event_loop::{Access, PING_INTERVAL, Role, ServerEvent, SharedState};
+use crate::metrics::METRICS;
+
+/// How long a hosted download stays available.
+pub const DOWNLOAD_TTL: Duration = Duration::from_secs(600);
+
+/// Requests with bigger headers are not ours.
+const MAX_HEAD: usize = 8192;
+
+/// How long a client gets to send its request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The current line on a transparent background, for OBS browser sources.
+const OVERLAY_HTML: &str = include_str!("overlay.html");
+
+struct Download {
+    name: String,
+    content_type: &'static str,
+    data: Arc<Vec<u8>>,
+}
+
+/// Files handed out under `/downloads/<id>/<name>` for [`DOWNLOAD_TTL`].
+#[derive(Default)]
+pub struct Downloads(RwLock<HashMap<String, Download>>);
+
+impl Downloads {
+    /// Hosts `data` and returns the path it can be fetched from.
+    pub async fn host(
+        self: &Arc<Self>,
+        name: &str,
+        content_type: &'static str,
+        data: Vec<u8>,
+    ) -> String {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let path = format!("/downloads/{}/{}", id, name);
+        self.0.write().await.insert(
+            id.clone(),
+            Download {
+                name: name.to_string(),
+                content_type,
+                data: Arc::new(data),
+            },
+        );
+
+        let downloads = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(DOWNLOAD_TTL).await;
+            if downloads.0.write().await.remove(&id).is_some() {
+                debug!("[http] Download {} expired", id);
+            }
+        });
+        path
+    }
+}
+
+/// Whether the connection is a WebSocket handshake rather than a plain HTTP
+/// request. Only peeks, so the handshake can still read everything.
+pub async fn is_websocket(stream: &TcpStream) -> bool {
+    let mut buf = vec![0u8; MAX_HEAD];
+    let deadline = tokio::time::Instant::now() + HEAD_TIMEOUT;
+    loop {
+        let n = match stream.peek(&mut buf).await {
+            Ok(n) => n,
+            // Let the handshake report it
+            Err(_) => return true,
+        };
+        let head = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+        if head.contains("\r\n\r\n") || n == 0 || n == MAX_HEAD {
+            return n == 0 || head.contains("upgrade: websocket");
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Answers one plain HTTP request and closes the connection.
+pub(crate) async fn serve(
+    mut stream: TcpStream,
+    state: &SharedState,
+    access: &Access,
+) -> std::io::Result<()> {
+    let head = read_head(&mut stream).await?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    let header = |wanted: &str| {
+        headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| *value)
+    };
+
+    // Browsers send Sec-Fetch-* even where they leave out Origin
+    let origin = header("origin");
+    let browser = header("sec-fetch-site").is_some();
+    let local = stream.local_addr().ok();
+    if !access
+        .origins
+        .allows(origin, header("host"), browser, local)
+    {
+        debug!(
+            "[http] Refused {} from {}",
+            path,
+            origin.unwrap_or("a browser")
+        );
+        return respond(&mut stream, "403 Forbidden", "text/plain", b"", None).await;
+    }
+    // Only the page that asked may read the answer
+    let cors = origin;
+
+    if method != "GET" {
+        return respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"",
+            cors,
+        )
+        .await;
+    }
+    let presented = crate::event_loop::presented_token(Some(query), header("authorization"));
+    let Some(role) = access.role(presented.as_deref()) else {
+        return respond(&mut stream, "401 Unauthorized", "text/plain", b"", cors).await;
+    };
+
+    if path == "/" || path == "/index.html" {
+        return match bundled_page() {
+            Some(page) => {
+                respond(
+                    &mut stream,
+                    "200 OK",
+                    "text/html; charset=utf-8",
+                    &page,
+                    cors,
+                )
+                .await
+            }
+            None => respond(&mut stream, "404 Not Found", "text/plain", b"", cors).await,
+        };
+    }
+
+    if path == "/metrics" {
+        let body = METRICS.render();
+        return respond(
+            &mut stream,
+            "200 OK",
+            "text/plain; version=0.0.4",
+            body.as_bytes(),
+            cors,
+        )
+        .await;
+    }
+
+    if path == "/overlay" {
+        return respond(
+            &mut stream,
+            "200 OK",
+            "text/html; charset=utf-8",
+            OVERLAY_HTML.as_bytes(),
+            cors,
+        )
+        .await;
+    }
+
+    if path == "/events" {
+        return stream_events(&mut stream, state, cors).await;
+    }
+
+    let download = path
+        .strip_prefix("/downloads/")
+        .and_then(|rest| rest.split_once('/'));
+    let Some((id, name)) = download else {
+        return respond(&mut stream, "404 Not Found", "text/plain", b"", cors).await;
+    };
+    // Spectators can't ask for media, so none is theirs to fetch
+    if role == Role::Spectator {
+        return respond(&mut stream, "403 Forbidden", "text/plain", b"", cors).await;
+    }
+    let found = state
+        .downloads
+        .0
+        .read()
+        .await
+        .get(id)
+        .filter(|d| d.name == name)
+        .map(|d| (d.content_type, d.data.clone()));
+    let Some((content_type, data)) = found else {
+        return respond(&mut stream, "404 Not Found", "text/plain", b"", cors).await;
+    };
+
+    info!("[http] Serving {} ({} bytes)", name, data.len());
+    respond(&mut stream, "200 OK", content_type, &data, cors).await
+}
+
+/// The frontend's `index.html` next to the executable, where releases and
+/// `install-script` put it. Opened from here it is one of the miner's own
+/// pages and needs no `--allowed-origin`.
+fn bundled_page() -> Option<Vec<u8>> {
+    let exe = std::env::current_exe().ok()?;
+    std::fs::read(exe.parent()?.join("index.html")).ok()
+}
+
+/// `Access-Control-Allow-Origin` for `origin`, an allowed page, if any.
+fn cors_header(origin: Option<&str>) -> String {
+    origin.map_or_else(String::new, |origin| {
+        format!(
+            "Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n",
+            origin
+        )
+    })
+}
+
+/// Sends the same messages WebSocket clients get, as Server-Sent Events,
+/// for browsers and networks where WebSockets don't get through. Runs until
+/// the client goes away. Requests still need the WebSocket.
+async fn stream_events(
+    stream: &mut TcpStream,
+    state: &SharedState,
+    cors: Option<&str>,
+) -> std::io::Result<()> {
+    let mut events = state.events.subscribe();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+         Cache-Control: no-cache\r\n{}\r\n",
+        cors_header(cors)
+    );
+    stream.write_all(head.as_bytes()).await?;
+    let _connected = METRICS.client_connected();
+    info!("[http] Streaming events");
+    if let Some(hello) = state.file_event().await {
+        stream
+            .write_all(format!("data: {}\n\n", hello.to_json()).as_bytes())
+            .await?;
+    }
+    if let Some(resume) = state.current_resume_event().await {
+        stream
+            .write_all(format!("data: {}\n\n", resume.to_json()).as_bytes())
+            .await?;
+    }
+
+    let mut keepalive = tokio::time::interval(PING_INTERVAL);
+    keepalive.tick().await;
+    loop {
+        let chunk = tokio::select! {
+            _ = keepalive.tick() => ": ping\n\n".to_string(),
+            event = events.recv() => match event {
+                Ok(ServerEvent::Shutdown) => {
+                    let last = format!("data: {}\n\n", ServerEvent::Shutdown.to_json());
+                    stream.write_all(last.as_bytes()).await?;
+                    return stream.shutdown().await;
+                }
+                Ok(event) => format!("data: {}\n\n", event.to_json()),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        };
+        // Fails once the client has gone
+        stream.write_all(chunk.as_bytes()).await?;
+    }
+}
+
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    tokio::time::timeout(HEAD_TIMEOUT, async {
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 || head.len() + n > MAX_HEAD {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "incomplete HTTP request",
+                ));
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "HTTP request timed out"))??;
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+    cors: Option<&str>,
+) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         {}Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len(),
+        cors_header(cors)
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
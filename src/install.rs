@@ -0,0 +1,182 @@
+//! `install-script`: puts the bundled Lua script, the binary and the config
+//! it needs into the user's mpv config directory, which otherwise means
+//! copying folders and merging mpv.conf by hand.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The Lua script this binary was built with, so the two always match.
+const SCRIPT: &str = include_str!("../mpv/mpv-subtitleminer.lua");
+const SCRIPT_NAME: &str = "mpv-subtitleminer.lua";
+const SCRIPT_OPTS_NAME: &str = "mpv-subtitleminer.conf";
+
+/// The name the Lua script looks for.
+const BINARY_NAME: &str = if cfg!(windows) {
+    "mpv-subtitleminer.exe"
+} else {
+    "mpv-subtitleminer"
+};
+
+/// The socket the packaged mpv.conf configures. mpv maps it to a named pipe
+/// on Windows.
+const DEFAULT_SOCKET: &str = "/tmp/mpv-socket";
+
+/// Ports the Lua script tries by default.
+const DEFAULT_PORTS: &str = "61777,61778,61779,61780,61781";
+
+/// What happened to one installed file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Installed,
+    Updated,
+    UpToDate,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Outcome::Installed => "installed",
+            Outcome::Updated => "updated",
+            Outcome::UpToDate => "up to date",
+        })
+    }
+}
+
+/// Installs into `config_dir` (the detected mpv config directory when
+/// `None`) and returns each file touched with what happened to it.
+/// `server_args` replaces the script's `server_args` option when given.
+pub fn install(
+    config_dir: Option<&Path>,
+    server_args: Option<&str>,
+) -> std::io::Result<Vec<(PathBuf, Outcome)>> {
+    let config_dir = match config_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => crate::discover::mpv_config_dir().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "could not find the mpv config directory, pass --config-dir",
+            )
+        })?,
+    };
+    fs::create_dir_all(config_dir.join("scripts"))?;
+    fs::create_dir_all(config_dir.join("script-opts"))?;
+
+    let mut done = Vec::new();
+    let script = config_dir.join("scripts").join(SCRIPT_NAME);
+    done.push((
+        script.clone(),
+        write_if_changed(&script, SCRIPT.as_bytes())?,
+    ));
+
+    // The script starts the binary from next to mpv.conf, the frontend
+    // ships beside it
+    let exe = std::env::current_exe()?;
+    let binary = config_dir.join(BINARY_NAME);
+    if !same_file(&exe, &binary) {
+        done.push((binary.clone(), copy_if_changed(&exe, &binary)?));
+        if let Some(page) = exe.parent().map(|dir| dir.join("index.html"))
+            && page.is_file()
+        {
+            let target = config_dir.join("index.html");
+            done.push((target.clone(), copy_if_changed(&page, &target)?));
+        }
+    }
+
+    let conf = config_dir.join("mpv.conf");
+    if let Some(outcome) = ensure_ipc_server(&conf)? {
+        done.push((conf, outcome));
+    }
+
+    let opts = config_dir.join("script-opts").join(SCRIPT_OPTS_NAME);
+    done.push((opts.clone(), write_script_opts(&opts, server_args)?));
+    Ok(done)
+}
+
+fn write_if_changed(path: &Path, content: &[u8]) -> std::io::Result<Outcome> {
+    let outcome = match fs::read(path) {
+        Ok(existing) if existing == content => return Ok(Outcome::UpToDate),
+        Ok(_) => Outcome::Updated,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Outcome::Installed,
+        Err(e) => return Err(e),
+    };
+    fs::write(path, content)?;
+    Ok(outcome)
+}
+
+fn copy_if_changed(from: &Path, to: &Path) -> std::io::Result<Outcome> {
+    let outcome = write_if_changed(to, &fs::read(from)?)?;
+    #[cfg(unix)]
+    if outcome != Outcome::UpToDate {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(to, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(outcome)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// The script needs mpv to open its IPC socket; adds the packaged setting
+/// to mpv.conf unless one is already there. Returns `None` if untouched.
+fn ensure_ipc_server(conf: &Path) -> std::io::Result<Option<Outcome>> {
+    let existing = match fs::read_to_string(conf) {
+        Ok(content) => Some(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let content = existing.as_deref().unwrap_or_default();
+    if content
+        .lines()
+        .any(|line| line.trim_start().starts_with("input-ipc-server"))
+    {
+        return Ok(None);
+    }
+    let mut updated = content.to_string();
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    updated.push_str(&format!("input-ipc-server={}\n", DEFAULT_SOCKET));
+    fs::write(conf, updated)?;
+    Ok(Some(if existing.is_some() {
+        Outcome::Updated
+    } else {
+        Outcome::Installed
+    }))
+}
+
+/// Writes the script's options with their defaults, keeping whatever the
+/// user already set; only `server_args` is replaced, when given.
+fn write_script_opts(path: &Path, server_args: Option<&str>) -> std::io::Result<Outcome> {
+    let existing = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let mut lines: Vec<String> = existing.lines().map(str::to_string).collect();
+    let is_option = |line: &str, key: &str| {
+        line.trim_start()
+            .strip_prefix(key)
+            .is_some_and(|rest| rest.trim_start().starts_with('='))
+    };
+
+    for (key, default) in [
+        ("ports", DEFAULT_PORTS),
+        ("auto_start", "yes"),
+        ("server_args", ""),
+    ] {
+        let position = lines.iter().position(|line| is_option(line, key));
+        match (key, server_args, position) {
+            ("server_args", Some(args), Some(i)) => lines[i] = format!("server_args={}", args),
+            ("server_args", Some(args), None) => lines.push(format!("server_args={}", args)),
+            (_, _, None) => lines.push(format!("{}={}", key, default)),
+            (_, _, Some(_)) => {}
+        }
+    }
+    let mut content = lines.join("\n");
+    content.push('\n');
+    write_if_changed(path, content.as_bytes())
+}
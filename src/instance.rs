@@ -0,0 +1,308 @@
+//! One miner per mpv socket: a pidfile per socket path keeps a second miner
+//! from broadcasting every line twice, and lets `stop` find the running one.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Set for the process [`daemonize`] starts, which then runs as a regular
+/// server.
+pub const DAEMON_ENV: &str = "MPV_SUBTITLEMINER_DAEMON";
+
+/// How long `stop` waits for a miner to finish its graceful shutdown.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where pidfiles live: the per-user runtime directory when there is one.
+pub fn runtime_dir() -> PathBuf {
+    #[cfg(unix)]
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        return PathBuf::from(dir);
+    }
+    std::env::temp_dir()
+}
+
+/// `mpv-subtitleminer` in the per-user data directory, where state kept
+/// across runs goes.
+pub fn data_dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        std::env::var_os("HOME").map(|home| Path::new(&home).join("Library/Application Support"))
+    } else {
+        std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".local/share")))
+    }?;
+    Some(base.join("mpv-subtitleminer"))
+}
+
+/// The pidfile of the miner serving `socket_path`.
+pub fn pid_file(socket_path: &str) -> PathBuf {
+    let canonical = fs::canonicalize(socket_path)
+        .map_or_else(|_| socket_path.to_string(), |p| p.display().to_string());
+    let hash = crate::media::sha256_hex(canonical.as_bytes());
+    runtime_dir().join(format!("subtitleminer-{}.pid", &hash[..16]))
+}
+
+/// Claims `socket_path` for this process, returning the pidfile to remove on
+/// exit. Fails if a live miner already holds it; pidfiles of miners that
+/// died are taken over.
+pub fn lock(socket_path: &str) -> std::io::Result<PathBuf> {
+    let path = pid_file(socket_path);
+    for _ in 0..2 {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                writeln!(file, "{}", std::process::id())?;
+                debug!("[instance] Locked {} with {}", socket_path, path.display());
+                return Ok(path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+        match read_pid(&path) {
+            Some(pid) if is_running(pid) && is_miner(pid) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!(
+                        "a miner (pid {}) is already serving {}, stop it with `mpv-subtitleminer stop`",
+                        pid, socket_path
+                    ),
+                ));
+            }
+            _ => {
+                debug!("[instance] Removing stale {}", path.display());
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+    Err(std::io::Error::other(format!(
+        "could not create {}",
+        path.display()
+    )))
+}
+
+/// Stops the miner serving `socket_path`, or every running miner, and waits
+/// for each to exit. Returns the pids stopped.
+pub fn stop(socket_path: Option<&str>) -> std::io::Result<Vec<u32>> {
+    let files = match socket_path {
+        Some(socket_path) => vec![pid_file(socket_path)],
+        None => fs::read_dir(runtime_dir())?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with("subtitleminer-") && n.ends_with(".pid"))
+            })
+            .collect(),
+    };
+
+    let mut stopped = Vec::new();
+    for file in files {
+        let Some(pid) = read_pid(&file) else {
+            continue;
+        };
+        // A pid left behind by a miner that crashed may belong to anything now
+        if !is_running(pid) || !is_miner(pid) {
+            let _ = fs::remove_file(&file);
+            continue;
+        }
+        terminate(pid)?;
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while is_running(pid) {
+            if Instant::now() >= deadline {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("pid {} did not exit in time", pid),
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let _ = fs::remove_file(&file);
+        stopped.push(pid);
+    }
+    Ok(stopped)
+}
+
+/// The default discovery file: the last server started writes where it
+/// listens here.
+pub fn discovery_file() -> PathBuf {
+    runtime_dir().join("mpv-subtitleminer.json")
+}
+
+/// Writes where this server listens to `path` as JSON (`port`, `url`,
+/// `pid` and the mpv `socket`), so the Lua script and other tools can find
+/// it when the configured port was taken.
+pub fn write_discovery(path: &Path, port: u16, socket_path: &str) -> std::io::Result<()> {
+    let info = serde_json::json!({
+        "port": port,
+        "url": format!("ws://127.0.0.1:{}", port),
+        "pid": std::process::id(),
+        "socket": socket_path,
+    });
+    // Written whole, so readers never see half a file
+    let partial = path.with_extension("json.part");
+    fs::write(&partial, info.to_string())?;
+    fs::rename(&partial, path)
+}
+
+/// Removes the discovery file at `path`, unless another server wrote it
+/// after us: the default one is shared, and the server that wrote it last
+/// is still running.
+pub fn remove_discovery(path: &Path) {
+    let pid = fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice::<serde_json::Value>(&data).ok())
+        .and_then(|info| info.get("pid")?.as_u64());
+    if pid == Some(u64::from(std::process::id())) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(windows)]
+fn quietly(command: &mut Command) -> std::io::Result<std::process::Output> {
+    command.stdin(Stdio::null()).stderr(Stdio::null()).output()
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // It exists but belongs to someone else
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn is_running(pid: u32) -> bool {
+    quietly(Command::new("tasklist").args(["/FI", &format!("PID eq {}", pid), "/NH"]))
+        .is_ok_and(|out| String::from_utf8_lossy(&out.stdout).contains(&pid.to_string()))
+}
+
+/// Whether `pid` runs this program, so a pid reused since its miner died is
+/// left alone. Only the file names are compared: the binary may have been
+/// replaced by an update while the miner ran.
+fn is_miner(pid: u32) -> bool {
+    let Some(ours) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.file_name().map(|n| n.to_string_lossy().into_owned()))
+    else {
+        return false;
+    };
+    process_name(pid).is_some_and(|name| name == ours)
+}
+
+#[cfg(target_os = "linux")]
+fn process_name(pid: u32) -> Option<String> {
+    let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok()?;
+    let name = exe.file_name()?.to_string_lossy().into_owned();
+    Some(name.trim_end_matches(" (deleted)").to_string())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_name(pid: u32) -> Option<String> {
+    let out = Command::new("ps")
+        .args(["-o", "comm=", "-p", &pid.to_string()])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let comm = String::from_utf8_lossy(&out.stdout).trim().to_string();
+    Path::new(&comm)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+}
+
+#[cfg(windows)]
+fn process_name(pid: u32) -> Option<String> {
+    // `"name.exe","pid",...`
+    let out = quietly(Command::new("tasklist").args([
+        "/FI",
+        &format!("PID eq {}", pid),
+        "/FO",
+        "CSV",
+        "/NH",
+    ]))
+    .ok()?;
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let name = stdout.lines().next()?.split(',').next()?.trim_matches('"');
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Asks `pid` to shut down; on Unix that is SIGTERM, which the server
+/// handles gracefully.
+#[cfg(unix)]
+fn terminate(pid: u32) -> std::io::Result<()> {
+    let pid = libc::pid_t::try_from(pid).map_err(std::io::Error::other)?;
+    // SAFETY: plain kill(2), `pid` was checked to be a miner
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) -> std::io::Result<()> {
+    let out = quietly(Command::new("taskkill").args(["/PID", &pid.to_string()]))?;
+    if !out.status.success() {
+        return Err(std::io::Error::other(format!("could not stop pid {}", pid)));
+    }
+    Ok(())
+}
+
+/// Starts this program again with `args`, detached from the terminal and
+/// logging to `log_file`, and returns its pid once it survived startup.
+pub fn daemonize(args: &[String], log_file: &Path) -> std::io::Result<u32> {
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .env(DAEMON_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Out of the terminal's process group, so Ctrl+C and hangups there
+        // don't reach it
+        command.process_group(0);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x0000_0008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+    let mut child = command.spawn()?;
+
+    // Most mistakes (a held lock, an unreachable mpv) end it right away
+    std::thread::sleep(Duration::from_millis(800));
+    if let Some(status) = child.try_wait()? {
+        return Err(std::io::Error::other(format!(
+            "the miner exited with {}, see {}",
+            status,
+            log_file.display()
+        )));
+    }
+    Ok(child.id())
+}
@@ -0,0 +1,82 @@
+//! Starting mpv ourselves on a private IPC socket.
+
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::mpv_stream::MpvStream;
+
+/// How long mpv gets to create its IPC socket.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An mpv process started by [`spawn`].
+pub struct SpawnedMpv {
+    pub socket_path: String,
+    pub pid: u32,
+}
+
+/// Runs `command_line` (e.g. `mpv --fs video.mkv`) with an added
+/// `--input-ipc-server` and waits until the socket accepts connections.
+pub async fn spawn(command_line: &str) -> std::io::Result<SpawnedMpv> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+    let args = shlex::split(command_line).ok_or_else(|| invalid("Unbalanced quotes in --spawn"))?;
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| invalid("--spawn needs a command"))?;
+
+    let socket_path = private_socket_path();
+    let mut child = Command::new(program)
+        .args(args)
+        .arg(format!("--input-ipc-server={}", socket_path))
+        .spawn()
+        .map_err(|e| {
+            std::io::Error::new(e.kind(), format!("Failed to start {}: {}", program, e))
+        })?;
+    let pid = child
+        .id()
+        .ok_or_else(|| std::io::Error::other("mpv exited immediately"))?;
+    info!("Started {} (pid {}) on {}", program, pid, socket_path);
+
+    let deadline = tokio::time::Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        if MpvStream::connect(&socket_path).await.is_ok() {
+            break;
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(std::io::Error::other(format!(
+                "{} exited with {} before opening its IPC socket",
+                program, status
+            )));
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("{} did not open {} in time", program, socket_path),
+            ));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // Reap it; the miner notices the socket closing on its own
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) => info!("mpv exited with {}", status),
+            Err(e) => warn!("Lost track of mpv: {}", e),
+        }
+    });
+
+    Ok(SpawnedMpv { socket_path, pid })
+}
+
+#[cfg(unix)]
+fn private_socket_path() -> String {
+    std::env::temp_dir()
+        .join(format!("mpv-subtitleminer-{}.sock", std::process::id()))
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(windows)]
+fn private_socket_path() -> String {
+    format!(r"\\.\pipe\mpv-subtitleminer-{}", std::process::id())
+}
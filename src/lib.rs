@@ -0,0 +1,48 @@
+//! Subtitle capture and media extraction for mpv.
+//!
+//! [`SubtitleMiner`] connects to mpv's IPC socket and can be embedded
+//! directly; [`run_server`] wraps it in the WebSocket server used by the
+//! bundled frontend.
+
+pub mod align;
+pub mod anki;
+pub mod anki_file;
+pub mod anki_queue;
+pub mod asr;
+pub mod bench;
+pub mod cache;
+pub mod clipboard;
+pub mod config;
+pub mod discover;
+pub mod doctor;
+pub mod event_loop;
+pub mod export;
+pub mod fetch_ffmpeg;
+pub mod filter;
+pub mod hooks;
+pub mod http;
+pub mod install;
+pub mod instance;
+pub mod launch;
+pub mod logs;
+pub mod markdown;
+pub mod mdns;
+pub mod media;
+pub mod metrics;
+pub mod miner;
+pub mod morph;
+pub mod mpv_stream;
+pub mod ocr;
+pub mod offline;
+pub mod profile;
+pub mod qr;
+pub mod relay;
+pub mod resume;
+pub mod stream;
+pub mod subtitle_file;
+pub mod timeline;
+pub mod translate;
+
+pub use event_loop::{ServerEvent, ServerOptions, Subtitle, run_server};
+pub use media::{AudioConfig, ImageConfig};
+pub use miner::{MinerOptions, SubtitleMiner};
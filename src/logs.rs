@@ -0,0 +1,140 @@
+//! Where logs go besides stderr: a file started anew once it grows too
+//! large, and the recent lines clients fetch with `get_logs`, so a bug
+//! report can carry them without running the server in a terminal.
+
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Lines [`recent`] keeps.
+pub const RECENT_LINES: usize = 1000;
+
+/// Older log files kept next to the current one, as `<file>.1` (newest) to
+/// `<file>.3`.
+pub const KEPT_FILES: usize = 3;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// The last `limit` log lines, oldest first.
+pub fn recent(limit: usize) -> Vec<String> {
+    let recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    recent
+        .iter()
+        .skip(recent.len().saturating_sub(limit))
+        .cloned()
+        .collect()
+}
+
+/// Writer for a log layer that feeds [`recent`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Recent;
+
+impl Write for Recent {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            if recent.len() == RECENT_LINES {
+                recent.pop_front();
+            }
+            recent.push_back(line.to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Recent {
+    type Writer = Recent;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        *self
+    }
+}
+
+/// A log file that is moved to `<file>.1` (shifting older ones up to
+/// [`KEPT_FILES`]) once writing to it would pass `max_size` bytes.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    /// The open file and its size
+    file: Mutex<(File, u64)>,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_size: u64) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    fn rotate(&self) -> io::Result<File> {
+        for i in (1..KEPT_FILES).rev() {
+            let from = numbered(&self.path, i);
+            if from.exists() {
+                fs::rename(from, numbered(&self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, numbered(&self.path, 1))?;
+        append(&self.path)
+    }
+}
+
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut guard = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        let (file, size) = &mut *guard;
+        if *size > 0 && *size + buf.len() as u64 > self.max_size {
+            // Keep logging to the full file rather than not at all
+            if let Ok(fresh) = self.rotate() {
+                *file = fresh;
+                *size = 0;
+            }
+        }
+        let written = file.write(buf)?;
+        *size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .0
+            .flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = &'a RotatingFile;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// `<path>.<n>`
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
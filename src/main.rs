@@ -1,16 +1,486 @@
-mod event_loop;
-mod media;
-mod mpv_stream;
-
-use clap::Parser;
-use event_loop::run_server;
+use clap::{Args, Parser, Subcommand};
+use mpv_subtitleminer::anki_file::NoteFile;
+use mpv_subtitleminer::anki_queue::AnkiQueue;
+use mpv_subtitleminer::asr::AsrOptions;
+use mpv_subtitleminer::clipboard::ClipboardOptions;
+use mpv_subtitleminer::config::Config;
+use mpv_subtitleminer::doctor::{self, DoctorOptions};
+use mpv_subtitleminer::event_loop::OriginPolicy;
+use mpv_subtitleminer::hooks::HookOptions;
+use mpv_subtitleminer::media::{AudioConfig, ImageConfig};
+use mpv_subtitleminer::morph::{self, MorphOptions};
+use mpv_subtitleminer::ocr::OcrOptions;
+use mpv_subtitleminer::offline::{self, AnkiTarget, ExtractOptions, FieldTemplate, TagTemplate};
+use mpv_subtitleminer::resume::ResumeStore;
+use mpv_subtitleminer::subtitle_file::SubtitleFormat;
+use mpv_subtitleminer::translate::{Provider, TranslateOptions};
+use mpv_subtitleminer::{
+    MinerOptions, ServerOptions, align, anki, bench, cache, discover, event_loop, export,
+    fetch_ffmpeg, filter, install, instance, launch, logs, mdns, media, relay, run_server, stream,
+    subtitle_file,
+};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    /// Path to the mpv IPC socket
-    #[cfg_attr(unix, arg(default_value = "/tmp/mpv-socket"))]
-    #[cfg_attr(windows, arg(default_value = r"\\.\pipe\mpv-socket"))]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Arguments for `serve`, which runs when no subcommand is given
+    #[command(flatten)]
+    serve: ServeArgs,
+
+    /// Log output: text, or json for one object per event
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "text")]
+    log_format: LogFormat,
+
+    /// What to log: error, warn, info, debug or trace, or per module like
+    /// RUST_LOG (e.g. `info,mpv_subtitleminer::media=debug`) [default:
+    /// RUST_LOG, else info]
+    #[arg(long, global = true, value_name = "LEVEL", value_parser = parse_log_level)]
+    log_level: Option<String>,
+
+    /// Also log to this file, moved to FILE.1 once it reaches
+    /// --log-max-size; the last 3 are kept. A `--daemon` miner logs only
+    /// here [default for --daemon: mpv-subtitleminer.log in the runtime or
+    /// temp directory]
+    #[arg(long, global = true, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Size in MB at which --log-file starts a new file
+    #[arg(long, global = true, value_name = "MB", default_value_t = 10)]
+    log_max_size: u64,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Connect to mpv and serve subtitles over WebSocket (default)
+    Serve(Box<ServeArgs>),
+
+    /// List the audio and subtitle tracks of a media file via ffprobe
+    Probe {
+        file: String,
+
+        /// Path to ffmpeg binary; ffprobe is expected next to it
+        #[arg(long, default_value = "ffmpeg")]
+        ffmpeg_path: String,
+    },
+
+    /// Cut audio and images for each line of a subtitle file, without mpv
+    Extract(ExtractArgs),
+
+    /// Batch-produce audio/image pairs from a subtitle file, optionally adding
+    /// them to Anki, without mpv
+    Mine(Box<MineArgs>),
+
+    /// Time a plain-text script (one line per subtitle) against a media
+    /// file's speech and write it as a subtitle file
+    Align(AlignArgs),
+
+    /// Time representative extractions to see which formats this machine handles
+    Bench {
+        /// Media file to cut from
+        file: String,
+
+        /// Start of the test segment in seconds
+        #[arg(long, default_value_t = 60.0)]
+        at: f64,
+
+        /// Length of the test segment in seconds
+        #[arg(long, default_value_t = 3.0)]
+        duration: f64,
+
+        /// mpv-style audio track number (1-based)
+        #[arg(long, default_value_t = 1)]
+        aid: i64,
+
+        /// Path to ffmpeg binary
+        #[arg(long, default_value = "ffmpeg")]
+        ffmpeg_path: String,
+    },
+
+    /// Dump the session captured by a running server as JSON, as a CSV/TSV
+    /// spreadsheet, or the current file's lines as SRT/WebVTT
+    Export {
+        /// WebSocket URL of the running server
+        #[arg(long, default_value = "ws://127.0.0.1:61777")]
+        url: String,
+
+        /// json, csv, tsv, srt or vtt
+        #[arg(long, default_value = "json")]
+        format: String,
+
+        /// Reference media cut beforehand (`NNNN.<ext>`, as written by
+        /// `extract`) from this directory. json, csv and tsv only
+        #[arg(long, value_name = "DIR")]
+        media_dir: Option<PathBuf>,
+
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Stop the miner serving an mpv socket, e.g. one started with
+    /// `--daemon`
+    Stop {
+        /// mpv IPC socket the miner serves; every running miner when omitted
+        socket_path: Option<String>,
+    },
+
+    /// Install or update the bundled Lua script, this binary and the config
+    /// they need in mpv's config directory
+    InstallScript {
+        /// mpv config directory [default: $MPV_HOME or the platform's]
+        #[arg(long, value_name = "DIR")]
+        config_dir: Option<PathBuf>,
+
+        /// Extra arguments for the server, written to the script's
+        /// `server_args` option
+        #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+        server_args: Option<String>,
+    },
+
+    /// Check ffmpeg, mpv, AnkiConnect, the port and the temp directory and
+    /// report what is wrong
+    Doctor {
+        /// mpv IPC socket to try, or `auto` to look for one
+        #[arg(default_value = discover::AUTO)]
+        socket_path: String,
+
+        /// Port the server would listen on
+        #[arg(default_value_t = 61777)]
+        port: u16,
+
+        /// Path to ffmpeg binary; ffprobe is expected next to it
+        #[arg(long, default_value = "ffmpeg")]
+        ffmpeg_path: String,
+
+        #[arg(long, default_value = anki::DEFAULT_ANKICONNECT_URL)]
+        anki_url: String,
+    },
+
+    /// Download a static ffmpeg build into the data directory, where it is
+    /// used when no ffmpeg path is given
+    FetchFfmpeg {
+        /// Archive to download instead of the build for this platform
+        #[arg(long, requires = "sha256")]
+        url: Option<String>,
+
+        /// SHA-256 of the --url archive
+        #[arg(long, requires = "url")]
+        sha256: Option<String>,
+    },
+}
+
+/// Media and subtitle file shared by the offline subcommands.
+#[derive(Args, Debug)]
+struct SourceArgs {
+    /// Media file to cut from
+    media: PathBuf,
+
+    /// SRT or WebVTT file with the timings
+    subs: PathBuf,
+
+    /// Only these lines, e.g. `12` or `10-20` (1-based, inclusive)
+    #[arg(long)]
+    lines: Option<String>,
+
+    /// Only lines matching this regex
+    #[arg(long = "match", value_name = "REGEX")]
+    pattern: Option<String>,
+
+    /// Audio format: mp3, opus, aac, vorbis or flac
+    #[arg(long)]
+    audio: Option<String>,
+
+    /// Image format, e.g. jpeg, webp or avif
+    #[arg(long)]
+    image: Option<String>,
+
+    /// Cut animated images (webp/avif) spanning the whole line
+    #[arg(long)]
+    animated: bool,
+
+    /// Pick a representative, non-black frame instead of the midpoint one
+    #[arg(long)]
+    smart_frame: bool,
+
+    /// mpv-style audio track number (1-based)
+    #[arg(long, default_value_t = 1)]
+    aid: i64,
+
+    /// Path to ffmpeg binary
+    #[arg(long, default_value = "ffmpeg")]
+    ffmpeg_path: String,
+}
+
+impl SourceArgs {
+    /// Loads the subtitle file and applies `--lines`/`--match`.
+    fn load(&self) -> std::io::Result<Vec<mpv_subtitleminer::Subtitle>> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+
+        let range = match &self.lines {
+            Some(range) => Some(
+                offline::parse_line_range(range)
+                    .ok_or_else(|| invalid(format!("Invalid line range '{}'", range)))?,
+            ),
+            None => None,
+        };
+        let pattern = match &self.pattern {
+            Some(p) => Some(Regex::new(p).map_err(|e| invalid(e.to_string()))?),
+            None => None,
+        };
+
+        let mut subs = offline::load_subtitles(&self.subs, &self.media, self.aid)?;
+        offline::select(&mut subs, range, pattern.as_ref());
+        Ok(subs)
+    }
+
+    fn extract_options(&self) -> ExtractOptions {
+        ExtractOptions {
+            audio: self.audio.clone().map(|format| AudioConfig {
+                format,
+                ..Default::default()
+            }),
+            image: self.image.clone().map(|format| ImageConfig {
+                format,
+                is_animated: self.animated,
+                smart_frame: self.smart_frame,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct ExtractArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+
+    /// Output directory
+    #[arg(short, long, default_value = ".")]
+    out_dir: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct AlignArgs {
+    /// Media file with the speech
+    media: PathBuf,
+
+    /// Text file with one line per subtitle
+    script: PathBuf,
+
+    /// SRT or WebVTT file to write, by extension. Defaults to the media
+    /// file's name with `.srt`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// mpv-style audio track number (1-based)
+    #[arg(long, default_value_t = 1)]
+    aid: i64,
+
+    /// Path to ffmpeg binary; ffprobe is expected next to it
+    #[arg(long, default_value = "ffmpeg")]
+    ffmpeg_path: String,
+
+    #[command(flatten)]
+    whisper: WhisperArgs,
+}
+
+#[derive(Args, Debug)]
+struct MineArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+
+    /// Also write the cut files to this directory
+    #[arg(short, long)]
+    out_dir: Option<PathBuf>,
+
+    /// Tokenizer printing MeCab's format, for --reading-field and
+    /// {reading}; gets the line on stdin
+    #[arg(long, value_name = "COMMAND", default_value = "mecab")]
+    tokenizer_command: String,
+
+    #[command(flatten)]
+    anki: AnkiArgs,
+}
+
+/// Speech recognition, shared by `align` and `serve`.
+#[derive(Args, Debug)]
+struct WhisperArgs {
+    /// whisper.cpp model, e.g. ggml-large-v3-turbo.bin
+    #[arg(long, value_name = "FILE")]
+    whisper_model: Option<PathBuf>,
+
+    /// whisper.cpp's command line program
+    #[arg(long, value_name = "PATH", default_value = "whisper-cli")]
+    whisper_path: String,
+
+    /// Spoken language, or `auto`
+    #[arg(long, value_name = "LANG", default_value = "ja")]
+    whisper_lang: String,
+}
+
+impl WhisperArgs {
+    /// The configured recognizer, if `--whisper-model` was given.
+    fn options(&self) -> Option<AsrOptions> {
+        self.whisper_model.as_ref().map(|model| AsrOptions {
+            binary: self.whisper_path.clone(),
+            model: model.clone(),
+            lang: self.whisper_lang.clone(),
+        })
+    }
+}
+
+/// Where notes go, shared by `mine` and `serve`.
+#[derive(Args, Debug)]
+struct AnkiArgs {
+    /// Push notes to AnkiConnect
+    #[arg(long)]
+    anki: bool,
+
+    #[arg(long, default_value = anki::DEFAULT_ANKICONNECT_URL)]
+    anki_url: String,
+
+    #[arg(long, default_value = "Default")]
+    deck: String,
+
+    /// Note type
+    #[arg(long, default_value = "Basic")]
+    model: String,
+
+    /// [default: Front, unless --field is given]
+    #[arg(long)]
+    sentence_field: Option<String>,
+
+    #[arg(long)]
+    audio_field: Option<String>,
+
+    #[arg(long)]
+    image_field: Option<String>,
+
+    /// Gets the line's machine translation (`serve --translate`)
+    #[arg(long)]
+    translation_field: Option<String>,
+
+    /// Gets the line with furigana (`serve --tokenize`, or
+    /// `mine --tokenizer-command`)
+    #[arg(long)]
+    reading_field: Option<String>,
+
+    /// Fill a field from a template, e.g. `Notes={title} {timestamp}`
+    /// (repeatable). Placeholders: {sentence}, {translation}, {reading},
+    /// {audio}, {image}, {title}, {timestamp}, {chapter}, {file}
+    #[arg(long = "field", value_name = "FIELD=TEMPLATE")]
+    fields: Vec<FieldTemplate>,
+
+    /// When updating an existing note (`mine_to_last_card`), add to this
+    /// field's content instead of replacing it (repeatable)
+    #[arg(long = "append-field", value_name = "FIELD")]
+    append_fields: Vec<String>,
+
+    /// Create the note type if Anki doesn't have it, with the fields
+    /// Sentence, Audio, Image, Translation and Source. They are filled
+    /// unless other field options are given
+    #[arg(long)]
+    create_model: bool,
+
+    /// Skip lines whose sentence is already in the deck; without it, the
+    /// server asks the client whether to add them again
+    #[arg(long)]
+    skip_duplicates: bool,
+
+    /// Write notes to this TSV file for File > Import in Anki, instead of
+    /// sending them to AnkiConnect; implies --anki
+    #[arg(long, value_name = "FILE", requires = "anki_media_dir")]
+    anki_file: Option<PathBuf>,
+
+    /// Where --anki-file puts the media: Anki's `collection.media`, or a
+    /// directory to copy into it
+    #[arg(long, value_name = "DIR", requires = "anki_file")]
+    anki_media_dir: Option<PathBuf>,
+
+    /// Tag for every note (repeatable), e.g. `subtitleminer::{media_title}`.
+    /// Placeholders: {title}, {media_title}, {file}, {chapter}, {season},
+    /// {episode}
+    #[arg(long = "tag", value_name = "TAG")]
+    tags: Vec<TagTemplate>,
+}
+
+impl AnkiArgs {
+    /// The configured target, if `--anki` or `--anki-file` was given.
+    fn target(&self) -> Option<AnkiTarget> {
+        if !self.anki && self.anki_file.is_none() {
+            return None;
+        }
+        let file = self
+            .anki_file
+            .clone()
+            .zip(self.anki_media_dir.clone())
+            .map(|(path, media_dir)| NoteFile { path, media_dir });
+        let no_fields = self.sentence_field.is_none()
+            && self.audio_field.is_none()
+            && self.image_field.is_none()
+            && self.translation_field.is_none()
+            && self.reading_field.is_none()
+            && self.fields.is_empty();
+        if self.create_model && no_fields {
+            let [sentence, audio, image, translation, source] = offline::DEFAULT_MODEL_FIELDS;
+            return Some(AnkiTarget {
+                url: self.anki_url.clone(),
+                deck: self.deck.clone(),
+                model: self.model.clone(),
+                sentence_field: Some(sentence.to_string()),
+                audio_field: Some(audio.to_string()),
+                image_field: Some(image.to_string()),
+                translation_field: Some(translation.to_string()),
+                fields: vec![
+                    format!("{}={{title}} {{timestamp}}", source)
+                        .parse()
+                        .expect("valid template"),
+                ],
+                append_fields: self.append_fields.clone(),
+                skip_duplicates: self.skip_duplicates,
+                tags: self.tags.clone(),
+                file,
+                ..Default::default()
+            });
+        }
+        Some(AnkiTarget {
+            url: self.anki_url.clone(),
+            deck: self.deck.clone(),
+            model: self.model.clone(),
+            sentence_field: self
+                .sentence_field
+                .clone()
+                .or_else(|| self.fields.is_empty().then(|| "Front".to_string())),
+            audio_field: self.audio_field.clone(),
+            image_field: self.image_field.clone(),
+            translation_field: self.translation_field.clone(),
+            reading_field: self.reading_field.clone(),
+            fields: self.fields.clone(),
+            append_fields: self.append_fields.clone(),
+            skip_duplicates: self.skip_duplicates,
+            tags: self.tags.clone(),
+            file,
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// Path to the mpv IPC socket, or `auto` to find a running mpv
+    #[arg(default_value = discover::AUTO)]
     socket_path: String,
 
     /// WebSocket server port
@@ -21,22 +491,837 @@ struct Args {
     #[arg(default_value = "ffmpeg")]
     ffmpeg_path: String,
 
+    /// Kill ffmpeg jobs running longer than this
+    #[arg(long, value_name = "SECONDS", default_value = "60", value_parser = parse_timeout)]
+    ffmpeg_timeout: Duration,
+
+    /// Don't run ffmpeg or mpv to cut media; media requests answer with
+    /// the commands they would run instead, and so does the log
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Hardware-accelerated decoding for animated images: auto, vaapi,
+    /// videotoolbox, d3d11va, ... (see `ffmpeg -hwaccels`)
+    #[arg(long, value_name = "METHOD")]
+    hwaccel: Option<String>,
+
+    /// mpv binary, for cutting sources ffmpeg can't open (ordered
+    /// chapters, edl:// playlists, ...) before encoding them
+    #[arg(long, value_name = "PATH", default_value = "mpv")]
+    mpv_path: String,
+
+    /// yt-dlp binary, for resolving network streams mpv doesn't expose a
+    /// direct URL for
+    #[arg(long, value_name = "PATH", default_value = "yt-dlp")]
+    ytdl_path: String,
+
+    /// Send the last lines of ffmpeg's stderr to clients when a media request
+    /// fails, e.g. to diagnose missing encoders
+    #[arg(long)]
+    ffmpeg_stderr: bool,
+
+    /// Keep extracted media in this directory and reuse it for identical
+    /// requests
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Evict the least recently used media once the cache is bigger than this
+    #[arg(long, value_name = "MB", default_value_t = 500, requires = "cache_dir")]
+    cache_size: u64,
+
     /// Validate that the IPC socket belongs to this mpv PID
     #[arg(long)]
     expected_mpv_pid: Option<u32>,
+
+    /// Start mpv with this command line on a private IPC socket, e.g.
+    /// `--spawn "mpv --fs video.mkv"`
+    #[arg(long, value_name = "COMMAND", conflicts_with_all = ["relay", "expected_mpv_pid"])]
+    spawn: Option<String>,
+
+    /// Seconds either side of the playback position assumed for lines that
+    /// mpv reports without timing
+    #[arg(long, value_name = "SECONDS", default_value_t = mpv_subtitleminer::miner::DEFAULT_TIMING_WINDOW)]
+    timing_window: f64,
+
+    /// Refuse `mine_to_last_card` when the last note was added longer ago
+    /// than this, so unrelated cards aren't overwritten
+    #[arg(long, value_name = "SECONDS", default_value_t = mpv_subtitleminer::miner::DEFAULT_LAST_CARD_WINDOW.as_secs())]
+    last_card_window: u64,
+
+    /// Keep notes mined while Anki is closed here and add them once it is
+    /// back [default: mpv-subtitleminer/anki-queue in the user data directory]
+    #[arg(long, value_name = "DIR")]
+    anki_queue_dir: Option<PathBuf>,
+
+    /// Fail mining while Anki is closed instead of queueing the notes
+    #[arg(long, conflicts_with = "anki_queue_dir")]
+    no_anki_queue: bool,
+
+    /// JSON file of settings applied without a restart whenever it changes:
+    /// text filters, default offsets and media settings, per-show profiles
+    /// and Anki settings. Clients' `set_settings` are saved to it [default:
+    /// mpv-subtitleminer/config.json in the user data directory]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Deprecated: the old name of --config, whose `profiles` key takes the
+    /// same file
+    #[arg(long, value_name = "FILE", hide = true, conflicts_with = "config")]
+    profiles: Option<PathBuf>,
+
+    /// Remember where mining of each file left off, its subtitle track and
+    /// the settings clients chose for it here, and restore them when the
+    /// file is opened again [default: mpv-subtitleminer/resume.json in the
+    /// user data directory]
+    #[arg(long, value_name = "FILE")]
+    resume_file: Option<PathBuf>,
+
+    /// Don't remember anything per file
+    #[arg(long, conflicts_with = "resume_file")]
+    no_resume: bool,
+
+    /// Open each note mined with the hotkey or a `mine` request in Anki's
+    /// browser, to review or edit it right away
+    #[arg(long)]
+    open_mined: bool,
+
+    /// Milliseconds to show "Card added" on mpv's OSD after mining, 0 to disable
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    mined_osd_duration: u64,
+
+    /// Read image subtitles (PGS, VobSub) with Tesseract, so Blu-ray and DVD
+    /// subtitles can be mined too
+    #[arg(long)]
+    ocr: bool,
+
+    /// Tesseract language(s) for --ocr, e.g. `jpn` or `jpn_vert+eng`
+    #[arg(long, value_name = "LANG", default_value = "jpn", requires = "ocr")]
+    ocr_lang: String,
+
+    /// OCR program to run instead of Tesseract; gets the image path as its
+    /// last argument and prints the text
+    #[arg(long, value_name = "COMMAND", requires = "ocr")]
+    ocr_command: Option<String>,
+
+    /// Transcribes media without subtitles on request
+    #[command(flatten)]
+    whisper: WhisperArgs,
+
+    /// Send every line's words with their readings and dictionary forms
+    #[arg(long)]
+    tokenize: bool,
+
+    /// Tokenizer printing MeCab's format, e.g. `lindera tokenize`; gets the
+    /// line on stdin
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        default_value = "mecab",
+        requires = "tokenize"
+    )]
+    tokenizer_command: String,
+
+    /// Translate every line with deepl, google, libretranslate or command
+    #[arg(long, value_name = "PROVIDER")]
+    translate: Option<Provider>,
+
+    /// Language to translate into
+    #[arg(
+        long,
+        value_name = "LANG",
+        default_value = "en",
+        requires = "translate"
+    )]
+    translate_to: String,
+
+    /// Language of the lines, detected when not given
+    #[arg(long, value_name = "LANG", requires = "translate")]
+    translate_from: Option<String>,
+
+    /// API key for DeepL, Google or LibreTranslate
+    #[arg(long, value_name = "KEY", requires = "translate")]
+    translate_key: Option<String>,
+
+    /// Server to use instead of the provider's, e.g. a self-hosted
+    /// LibreTranslate
+    #[arg(long, value_name = "URL", requires = "translate")]
+    translate_url: Option<String>,
+
+    /// Program for `--translate command`; reads the line on stdin and
+    /// prints the translation
+    #[arg(long, value_name = "COMMAND", requires = "translate")]
+    translate_command: Option<String>,
+
+    /// Lines in OP/ED chapters: keep, flag (`"song": true`) or hide
+    #[arg(long, value_name = "MODE", default_value = "flag")]
+    song_lines: filter::SongLines,
+
+    /// Remove matches from every line (repeatable). A regex or one of the
+    /// presets parentheses, brackets, speaker, furigana
+    #[arg(long = "filter", value_name = "REGEX")]
+    filters: Vec<filter::TextFilter>,
+
+    /// Don't send lines shorter than this many characters (after filters,
+    /// not counting spaces)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    min_chars: usize,
+
+    /// Don't send lines made only of symbols, e.g. `♪～`
+    #[arg(long)]
+    drop_symbol_lines: bool,
+
+    /// Don't send lines matching this regex (repeatable)
+    #[arg(long = "drop", value_name = "REGEX")]
+    drop_patterns: Vec<Regex>,
+
+    /// Send a line already seen in the file only as a higher count of its
+    /// first occurrence
+    #[arg(long)]
+    collapse_duplicates: bool,
+
+    /// Dial out to this WebSocket URL instead of listening for clients
+    #[arg(long, value_name = "URL")]
+    connect_url: Option<String>,
+
+    /// Listen on this Unix domain socket instead of the TCP port, for local
+    /// clients only
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["connect_url", "qr", "qr_osd"])]
+    listen_unix: Option<PathBuf>,
+
+    /// Require clients to present this token (`?token=` or `Authorization: Bearer`)
+    #[arg(long, value_name = "TOKEN")]
+    auth_token: Option<String>,
+
+    /// Let clients presenting this token in read-only: they see the lines
+    /// but can't request media or control mpv. Needs --auth-token, which
+    /// everyone else has to present
+    #[arg(long, value_name = "TOKEN", requires = "auth_token")]
+    spectator_token: Option<String>,
+
+    /// Web page origin allowed to connect besides the server's own pages,
+    /// e.g. `http://localhost:5173`, or `null` for index.html opened from
+    /// disk (which any site can send too); repeatable, `*` allows any page
+    #[arg(long = "allowed-origin", value_name = "ORIGIN")]
+    allowed_origins: Vec<String>,
+
+    /// Print a QR code with the connection URL on startup
+    #[arg(long)]
+    qr: bool,
+
+    /// Also show the QR code on mpv's OSD
+    #[arg(long)]
+    qr_osd: bool,
+
+    /// Copy every new line to the clipboard, e.g. for Yomitan's clipboard
+    /// monitor
+    #[arg(long)]
+    clipboard: bool,
+
+    /// Program to copy with instead of the system clipboard (pbcopy,
+    /// wl-copy, xclip or xsel outside Windows); gets the line on stdin
+    #[arg(long, value_name = "COMMAND", requires = "clipboard")]
+    clipboard_command: Option<String>,
+
+    /// Run this for every new line, e.g. `--on-subtitle 'notify-send {text}'`.
+    /// Placeholders: {id}, {text}, {translation}, {file}, {start}, {end},
+    /// {chapter}; also in the environment as SUBTITLEMINER_<NAME>, which is
+    /// what `sh -c` scripts should use
+    #[arg(long, value_name = "COMMAND")]
+    on_subtitle: Option<String>,
+
+    /// Run this for every note added, like --on-subtitle plus {title},
+    /// {note_id} and the card's media as {audio} and {image}
+    #[arg(long, value_name = "COMMAND")]
+    on_mined: Option<String>,
+
+    /// Log mined lines with their audio and a screenshot to a Markdown file
+    /// per show in this directory, e.g. an Obsidian vault
+    #[arg(long, value_name = "DIR")]
+    markdown_dir: Option<PathBuf>,
+
+    /// Also send lines as plain text to texthooker pages on this port
+    /// (e.g. 6677); any port serves them at /texthooker
+    #[arg(long, value_name = "PORT")]
+    texthooker_port: Option<u16>,
+
+    /// Announce the server on the LAN as `_subtitleminer._tcp` (mDNS), so
+    /// frontends can find it without an address
+    #[arg(long, conflicts_with = "listen_unix")]
+    mdns: bool,
+
+    /// Name shown to frontends browsing the LAN [default: Subtitle Miner on
+    /// <hostname>]
+    #[arg(long, value_name = "NAME", requires = "mdns")]
+    mdns_name: Option<String>,
+
+    /// Relay another miner instance at this WebSocket URL instead of reading mpv
+    #[arg(long, value_name = "URL", conflicts_with = "connect_url")]
+    relay: Option<String>,
+
+    /// Token presented to the upstream instance in relay mode
+    #[arg(long, value_name = "TOKEN", requires = "relay")]
+    upstream_token: Option<String>,
+
+    /// Fail when the port is taken instead of listening on the next free one
+    #[arg(long)]
+    strict_port: bool,
+
+    /// Write the port actually listened on here as JSON [default:
+    /// mpv-subtitleminer.json in the runtime or temp directory]
+    #[arg(long, value_name = "PATH")]
+    discovery_file: Option<PathBuf>,
+
+    /// Detach from the terminal and keep running in the background; stop
+    /// it with the `stop` subcommand
+    #[arg(long)]
+    daemon: bool,
+
+    /// Lets clients and the mpv hotkey (`script-message subtitleminer-mine`)
+    /// add notes server-side
+    #[command(flatten)]
+    anki: AnkiArgs,
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+    init_logging(&cli);
 
-    let args = Args::parse();
-
-    media::init_ffmpeg_path(&args.ffmpeg_path);
-    log::info!("Using ffmpeg: {}", args.ffmpeg_path);
+    let result = match cli.command {
+        None => serve(cli.serve, cli.log_file).await,
+        Some(Command::Serve(args)) => serve(*args, cli.log_file).await,
+        Some(Command::Probe { file, ffmpeg_path }) => probe(&file, &ffmpeg_path),
+        Some(Command::Extract(args)) => extract(args),
+        Some(Command::Mine(args)) => mine(*args).await,
+        Some(Command::Align(args)) => align(args),
+        Some(Command::Bench {
+            file,
+            at,
+            duration,
+            aid,
+            ffmpeg_path,
+        }) => {
+            bench(&file, at, duration, aid, &ffmpeg_path);
+            Ok(())
+        }
+        Some(Command::Export {
+            url,
+            format,
+            media_dir,
+            output,
+        }) => export_session(&url, &format, media_dir.as_deref(), output).await,
+        Some(Command::Stop { socket_path }) => stop(socket_path.as_deref()),
+        Some(Command::InstallScript {
+            config_dir,
+            server_args,
+        }) => install_script(config_dir.as_deref(), server_args.as_deref()),
+        Some(Command::Doctor {
+            socket_path,
+            port,
+            ffmpeg_path,
+            anki_url,
+        }) => {
+            doctor(DoctorOptions {
+                socket_path,
+                port,
+                ffmpeg_path,
+                anki_url,
+            })
+            .await
+        }
+        Some(Command::FetchFfmpeg { url, sha256 }) => {
+            let source = url
+                .zip(sha256)
+                .map(|(url, sha256)| fetch_ffmpeg::Source { url, sha256 });
+            fetch(source).await
+        }
+    };
 
-    if let Err(e) = run_server(&args.socket_path, args.port, args.expected_mpv_pid).await {
+    if let Err(e) = result {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
 }
+
+/// Logs to stderr, `--log-file` and the recent lines kept for `get_logs`,
+/// filtered by `--log-level` or `RUST_LOG` (default `info`). Spans log their
+/// timings when they close, so slow requests and ffmpeg jobs stand out.
+fn init_logging(cli: &Cli) {
+    use tracing_subscriber::prelude::*;
+    use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+    let filter = match &cli.log_level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> =
+        vec![log_layer(cli.log_format, logs::Recent, false)];
+    let file = cli.log_file.as_ref().and_then(|path| {
+        logs::RotatingFile::open(path.clone(), cli.log_max_size.max(1) * 1024 * 1024)
+            .inspect_err(|e| eprintln!("Cannot log to {}: {}", path.display(), e))
+            .ok()
+    });
+    // A daemon's stderr already goes to the file
+    let daemon = std::env::var_os(instance::DAEMON_ENV).is_some();
+    if !(daemon && file.is_some()) {
+        let ansi = std::io::IsTerminal::is_terminal(&std::io::stderr());
+        layers.push(log_layer(cli.log_format, std::io::stderr, ansi));
+    }
+    if let Some(file) = file {
+        layers.push(log_layer(cli.log_format, file, false));
+    }
+    tracing_subscriber::registry()
+        .with(layers.with_filter(filter))
+        .init();
+}
+
+fn log_layer<W>(
+    format: LogFormat,
+    writer: W,
+    ansi: bool,
+) -> Box<dyn tracing_subscriber::Layer<tracing_subscriber::Registry> + Send + Sync>
+where
+    W: for<'a> tracing_subscriber::fmt::MakeWriter<'a> + Send + Sync + 'static,
+{
+    use tracing_subscriber::Layer;
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    let layer = tracing_subscriber::fmt::layer()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).boxed(),
+    }
+}
+
+fn parse_log_level(level: &str) -> Result<String, String> {
+    tracing_subscriber::EnvFilter::try_new(level)
+        .map(|_| level.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// A positive number of seconds that fits a [`Duration`].
+fn parse_timeout(seconds: &str) -> Result<Duration, String> {
+    let seconds: f64 = seconds
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+    if seconds.is_nan() || seconds <= 0.0 {
+        return Err("must be positive".to_string());
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())
+}
+
+async fn serve(args: ServeArgs, log_file: Option<PathBuf>) -> std::io::Result<()> {
+    if args.daemon && std::env::var_os(instance::DAEMON_ENV).is_none() {
+        return daemonize(log_file);
+    }
+    event_loop::install_panic_hook();
+    media::init_ffmpeg_path(&args.ffmpeg_path);
+    media::init_ffmpeg_timeout(args.ffmpeg_timeout);
+    media::set_dry_run(args.dry_run);
+    if let Some(method) = &args.hwaccel {
+        media::init_hwaccel(method);
+    }
+    media::init_mpv_path(&args.mpv_path);
+    stream::init_ytdl_path(&args.ytdl_path);
+    let translate = args.translate.map(|provider| TranslateOptions {
+        provider,
+        target_lang: args.translate_to.clone(),
+        source_lang: args.translate_from.clone(),
+        api_key: args.translate_key.clone(),
+        url: args.translate_url.clone(),
+        command: args.translate_command.clone(),
+    });
+    if let Some(Err(e)) = translate.as_ref().map(TranslateOptions::check) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--translate: {}", e),
+        ));
+    }
+    if let Some(dir) = &args.cache_dir {
+        cache::init(dir.clone(), args.cache_size * 1_000_000)?;
+    }
+    tracing::info!("Using ffmpeg: {}", media::ffmpeg_path());
+
+    if let Some(upstream) = args.relay {
+        relay::run_relay(
+            &upstream,
+            args.upstream_token.as_deref(),
+            args.port,
+            args.auth_token,
+            OriginPolicy::new(args.allowed_origins),
+        )
+        .await
+    } else {
+        match media::probe_encoders() {
+            Ok(()) => {
+                let missing = media::missing_formats();
+                if !missing.is_empty() {
+                    tracing::warn!(
+                        "ffmpeg can't encode {:?}, requests for them fall back to other formats",
+                        missing
+                    );
+                }
+            }
+            Err(e) => tracing::warn!("Could not list ffmpeg encoders: {}", e),
+        }
+        if args.profiles.is_some() {
+            tracing::warn!("--profiles is deprecated, use --config with the same file");
+        }
+        // Only a file given on the command line is watched for changes
+        let watch_config = args.config.is_some() || args.profiles.is_some();
+        let config = args.config.or(args.profiles).or_else(Config::default_path);
+        // A broken default file is only warned about once the miner reads it
+        if watch_config && let Some(path) = &config {
+            Config::load(path).map_err(std::io::Error::other)?;
+        }
+        let mut expected_mpv_pid = args.expected_mpv_pid;
+        let socket_path = if let Some(command) = &args.spawn {
+            let mpv = launch::spawn(command).await?;
+            expected_mpv_pid = Some(mpv.pid);
+            mpv.socket_path
+        } else if args.socket_path == discover::AUTO {
+            discover::find_socket().await?
+        } else {
+            args.socket_path
+        };
+        let pid_file = instance::lock(&socket_path)?;
+        let anki = args.anki.target();
+        if let Some(target) = anki.as_ref().filter(|t| t.file.is_none())
+            && let Err(e) = check_model(target, args.anki.create_model).await
+        {
+            tracing::warn!("{}", e);
+        }
+        let result = run_server(&ServerOptions {
+            socket_path,
+            port: args.port,
+            miner: MinerOptions {
+                expected_mpv_pid,
+                timing_window: args.timing_window,
+                mined_osd_ms: args.mined_osd_duration,
+                anki,
+                open_mined: args.open_mined,
+                anki_queue_dir: if args.no_anki_queue {
+                    None
+                } else {
+                    args.anki_queue_dir.or_else(AnkiQueue::default_dir)
+                },
+                config,
+                watch_config,
+                resume_file: if args.no_resume {
+                    None
+                } else {
+                    args.resume_file.or_else(ResumeStore::default_path)
+                },
+                last_card_window: Duration::from_secs(args.last_card_window),
+                song_lines: args.song_lines,
+                filters: args.filters,
+                suppress: filter::Suppression {
+                    min_chars: args.min_chars,
+                    symbols_only: args.drop_symbol_lines,
+                    patterns: args.drop_patterns,
+                },
+                collapse_duplicates: args.collapse_duplicates,
+                clipboard: args.clipboard.then_some(ClipboardOptions {
+                    command: args.clipboard_command,
+                }),
+                hooks: HookOptions {
+                    on_subtitle: args.on_subtitle,
+                    on_mined: args.on_mined,
+                },
+                markdown_dir: args.markdown_dir,
+                ffmpeg_stderr: args.ffmpeg_stderr,
+                ocr: args.ocr.then_some(OcrOptions {
+                    lang: args.ocr_lang,
+                    command: args.ocr_command,
+                }),
+                asr: args.whisper.options(),
+                translate,
+                morph: args.tokenize.then_some(MorphOptions {
+                    command: args.tokenizer_command,
+                }),
+            },
+            connect_url: args.connect_url,
+            auth_token: args.auth_token,
+            spectator_token: args.spectator_token,
+            qr: args.qr,
+            qr_osd: args.qr_osd,
+            texthooker_port: args.texthooker_port,
+            mdns_name: args.mdns.then(|| {
+                args.mdns_name
+                    .unwrap_or_else(mdns::Announcement::default_name)
+            }),
+            listen_unix: args.listen_unix,
+            origins: OriginPolicy::new(args.allowed_origins),
+            pid_file: Some(pid_file.clone()),
+            strict_port: args.strict_port,
+            discovery_file: Some(args.discovery_file.unwrap_or_else(instance::discovery_file)),
+        })
+        .await;
+        let _ = std::fs::remove_file(&pid_file);
+        result
+    }
+}
+
+/// Runs the same command line again in the background and returns once it
+/// is up.
+fn daemonize(log_file: Option<PathBuf>) -> std::io::Result<()> {
+    let given = log_file.is_some();
+    let log_file =
+        log_file.unwrap_or_else(|| instance::runtime_dir().join("mpv-subtitleminer.log"));
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if !given {
+        // So it rotates the file it logs to
+        args.extend(["--log-file".into(), log_file.display().to_string()]);
+    }
+    let pid = instance::daemonize(&args, &log_file)?;
+    println!(
+        "Running in the background (pid {}), logging to {}",
+        pid,
+        log_file.display()
+    );
+    Ok(())
+}
+
+fn stop(socket_path: Option<&str>) -> std::io::Result<()> {
+    let stopped = instance::stop(socket_path)?;
+    if stopped.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no running miner found",
+        ));
+    }
+    for pid in stopped {
+        println!("Stopped pid {}", pid);
+    }
+    Ok(())
+}
+
+fn install_script(config_dir: Option<&Path>, server_args: Option<&str>) -> std::io::Result<()> {
+    for (path, outcome) in install::install(config_dir, server_args)? {
+        println!("{}: {}", path.display(), outcome);
+    }
+    Ok(())
+}
+
+async fn fetch(source: Option<fetch_ffmpeg::Source>) -> std::io::Result<()> {
+    let ffmpeg = fetch_ffmpeg::fetch(source).await?;
+    media::init_ffmpeg_path(&ffmpeg.display().to_string());
+    println!("{}", media::ffmpeg_version()?);
+    println!(
+        "Installed to {}, used whenever ffmpeg is not found next to mpv-subtitleminer",
+        ffmpeg.display()
+    );
+    Ok(())
+}
+
+async fn doctor(options: DoctorOptions) -> std::io::Result<()> {
+    let checks = doctor::run(&options).await;
+    for check in &checks {
+        println!("{}", check);
+    }
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == doctor::Status::Fail)
+        .count();
+    if failed > 0 {
+        return Err(std::io::Error::other(format!(
+            "{} of {} checks failed",
+            failed,
+            checks.len()
+        )));
+    }
+    Ok(())
+}
+
+fn probe(file: &str, ffmpeg_path: &str) -> std::io::Result<()> {
+    media::init_ffmpeg_path(ffmpeg_path);
+    let streams = media::probe_streams(file)?;
+    let mut audio_n = 0;
+    let mut sub_n = 0;
+    for s in streams {
+        // mpv numbers tracks per type starting at 1
+        let label = match s.codec_type.as_str() {
+            "audio" => {
+                audio_n += 1;
+                format!("aid={}", audio_n)
+            }
+            "subtitle" => {
+                sub_n += 1;
+                format!("sid={}", sub_n)
+            }
+            _ => continue,
+        };
+        println!(
+            "#{:<3} {:<6} {:<10} {:<5} {}",
+            s.index,
+            label,
+            s.codec_name,
+            s.language.as_deref().unwrap_or("-"),
+            s.title.as_deref().unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+fn extract(args: ExtractArgs) -> std::io::Result<()> {
+    media::init_ffmpeg_path(&args.source.ffmpeg_path);
+
+    let subs = args.source.load()?;
+    let options = args.source.extract_options();
+    if options.audio.is_none() && options.image.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Nothing to extract, pass --audio and/or --image",
+        ));
+    }
+
+    let ok = offline::extract(&subs, &args.out_dir, &options)?;
+    println!(
+        "Extracted {}/{} lines to {}",
+        ok,
+        subs.len(),
+        args.out_dir.display()
+    );
+    Ok(())
+}
+
+/// Makes sure `target`'s note type exists before mining, creating it with
+/// `create`. Anki being closed is not an error here.
+async fn check_model(target: &AnkiTarget, create: bool) -> anki::AnkiResult<()> {
+    let client = anki::AnkiConnect::new(&target.url);
+    match offline::ensure_model(&client, &target.model, create).await {
+        Ok(true) => {
+            tracing::info!("Created note type \"{}\" in Anki", target.model);
+            Ok(())
+        }
+        Ok(false) => Ok(()),
+        Err(e) if anki::is_unreachable(e.as_ref()) => {
+            tracing::debug!("Not checking the note type, Anki is not running");
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn mine(args: MineArgs) -> std::io::Result<()> {
+    media::init_ffmpeg_path(&args.source.ffmpeg_path);
+
+    let mut subs = args.source.load()?;
+    let mut options = args.source.extract_options();
+    if options.audio.is_none() && options.image.is_none() {
+        options.audio = Some(AudioConfig::default());
+        options.image = Some(ImageConfig::default());
+    }
+
+    let target = args.anki.target();
+    if let Some(target) = target.as_ref().filter(|t| t.file.is_none()) {
+        check_model(target, args.anki.create_model)
+            .await
+            .map_err(std::io::Error::other)?;
+    }
+    if target.is_none() && args.out_dir.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Nowhere to put results, pass --anki and/or --out-dir",
+        ));
+    }
+
+    if target.as_ref().is_some_and(AnkiTarget::wants_reading) {
+        let morph = MorphOptions {
+            command: args.tokenizer_command.clone(),
+        };
+        for sub in &mut subs {
+            sub.tokens = morph::tokenize(&sub.text, &morph)?;
+        }
+    }
+
+    let ok = offline::mine(&subs, args.out_dir.as_deref(), &options, target.as_ref()).await?;
+    println!("Mined {}/{} lines", ok, subs.len());
+    Ok(())
+}
+
+fn align(args: AlignArgs) -> std::io::Result<()> {
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+    media::init_ffmpeg_path(&args.ffmpeg_path);
+
+    let options = args
+        .whisper
+        .options()
+        .ok_or_else(|| invalid("Pass --whisper-model".into()))?;
+    let output = args
+        .output
+        .unwrap_or_else(|| args.media.with_extension("srt"));
+    let format: SubtitleFormat = output
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .parse()
+        .map_err(invalid)?;
+    let script = std::fs::read_to_string(&args.script)?;
+    let media_path = args.media.display().to_string();
+    let range = mpv_subtitleminer::Subtitle {
+        sub_end: media::probe_duration(&media_path)?,
+        media_path,
+        aid: args.aid,
+        ..Default::default()
+    };
+
+    let lines = align::align_media(&range, &script, &options).map_err(std::io::Error::other)?;
+    std::fs::write(&output, subtitle_file::write(&lines, format))?;
+    println!("Aligned {} lines to {}", lines.len(), output.display());
+    Ok(())
+}
+
+fn bench(file: &str, at: f64, duration: f64, aid: i64, ffmpeg_path: &str) {
+    media::init_ffmpeg_path(ffmpeg_path);
+    println!("Benchmarking {:.1}s of {} at {:.1}s", duration, file, at);
+    println!("{:<16} {:>10} {:>10}", "preset", "time", "size");
+    bench::run(file, at, duration, aid, |r| {
+        let size = r.size.map_or_else(
+            || "FAILED".to_string(),
+            |s| format!("{:.1} KiB", s as f64 / 1024.0),
+        );
+        println!("{:<16} {:>8}ms {:>10}", r.name, r.elapsed.as_millis(), size);
+    });
+}
+
+async fn export_session(
+    url: &str,
+    format: &str,
+    media_dir: Option<&Path>,
+    output: Option<PathBuf>,
+) -> std::io::Result<()> {
+    let text = if format == "json" {
+        let mut session = export::fetch_session(url)
+            .await
+            .map_err(std::io::Error::other)?;
+        if let Some(dir) = media_dir {
+            export::attach_media(&mut session, dir);
+        }
+        serde_json::to_string_pretty(&session)?
+    } else if let Ok(table) = format.parse::<export::TableFormat>() {
+        let mut session = export::fetch_session(url)
+            .await
+            .map_err(std::io::Error::other)?;
+        let subs: Vec<mpv_subtitleminer::Subtitle> =
+            serde_json::from_value(session["subtitles"].take())?;
+        export::write_table(&subs, table, media_dir)
+    } else {
+        export::fetch_subtitles(url, format)
+            .await
+            .map_err(std::io::Error::other)?
+    };
+    match output {
+        Some(path) => std::fs::write(path, text),
+        None => {
+            print!("{}", text);
+            if !text.ends_with('\n') {
+                println!();
+            }
+            Ok(())
+        }
+    }
+}
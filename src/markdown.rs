@@ -0,0 +1,139 @@
+//! A Markdown log of mined lines, one file per show in a notes vault
+//! (Obsidian and the like), for immersion notes kept outside Anki.
+
+use regex::Regex;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::debug;
+
+use crate::event_loop::Subtitle;
+use crate::offline;
+
+/// Where media the log links to goes, inside the vault directory.
+const MEDIA_DIR: &str = "media";
+
+/// Entries from lines mined at once mustn't interleave.
+static WRITING: Mutex<()> = Mutex::new(());
+
+/// Appends `sub` to the log of its show in `dir`, with its audio and a
+/// screenshot cut into `dir/media`. `title` is mpv's `media-title`, when
+/// known. Returns the file written. Blocks while ffmpeg runs.
+pub fn append(dir: &Path, sub: &Subtitle, title: Option<&str>) -> io::Result<PathBuf> {
+    let stem = Path::new(&sub.media_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    let title = title.unwrap_or(stem);
+    let show = show_name(title);
+    let path = dir.join(format!("{}.md", show));
+
+    let (audio, image) = offline::cut_files(sub);
+    let media_dir = dir.join(MEDIA_DIR);
+    let mut links = Vec::new();
+    for (name, data) in [image, audio].into_iter().flatten() {
+        fs::create_dir_all(&media_dir)?;
+        let file = media_dir.join(&name);
+        if !file.exists() {
+            fs::write(file, data)?;
+        }
+        links.push(format!("![]({}/{})", MEDIA_DIR, url_escape(&name)));
+    }
+
+    let mut entry = format!("\n## {} {}\n\n", timestamp(sub.sub_start), title);
+    for line in sub.text.lines() {
+        entry.push_str(&format!("> {}\n", line));
+    }
+    if let Some(translation) = &sub.translation {
+        entry.push('\n');
+        for line in translation.lines() {
+            entry.push_str(&format!("*{}*\n", line.trim()));
+        }
+    }
+    if !links.is_empty() {
+        entry.push('\n');
+        entry.push_str(&links.join("\n"));
+        entry.push('\n');
+    }
+
+    let _writing = WRITING.lock().unwrap_or_else(|e| e.into_inner());
+    fs::create_dir_all(dir)?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    if file.metadata()?.len() == 0 {
+        entry.insert_str(0, &format!("# {}\n", show));
+    }
+    file.write_all(entry.as_bytes())?;
+    debug!("[markdown] Logged line {} to {}", sub.id, path.display());
+    Ok(path)
+}
+
+/// The show an episode belongs to: its title or file name without release
+/// groups, tags and what follows the episode number, e.g. `Show` for
+/// `[Group] Show - S01E05 [1080p]`.
+fn show_name(title: &str) -> String {
+    static PATTERNS: std::sync::OnceLock<[Regex; 2]> = std::sync::OnceLock::new();
+    let [tags, episode] = PATTERNS.get_or_init(|| {
+        [
+            Regex::new(r"\[[^\]]*\]|\([^)]*\)").expect("valid regex"),
+            Regex::new(
+                r"(?i)\bS\d{1,2}\s?E\d{1,4}|\b\d{1,2}x\d{2,3}\b|\s-\s\d{1,4}\b|\bEp(?:isode)?\.?\s?\d{1,4}\b",
+            )
+            .expect("valid regex"),
+        ]
+    });
+    let mut name = tags.replace_all(title, " ").into_owned();
+    // Scene names use dots or underscores for spaces
+    if !name.trim().contains(' ') {
+        name = name.replace(['.', '_'], " ");
+    }
+    if let Some(found) = episode.find(&name) {
+        name.truncate(found.start());
+    }
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => ' ',
+            c => c,
+        })
+        .collect();
+    let name = name
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_matches(|c: char| c == '-' || c == '.' || c == '_')
+        .trim()
+        .to_string();
+    if name.is_empty() {
+        "Mined lines".to_string()
+    } else {
+        name
+    }
+}
+
+/// `seconds` as `HH:MM:SS`.
+fn timestamp(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// `name` usable in a Markdown link, which spaces and parentheses end.
+fn url_escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
@@ -0,0 +1,470 @@
+//! Announcing the server on the LAN over multicast DNS, so frontends on
+//! phones and tablets can find it as `_subtitleminer._tcp` instead of being
+//! told an address.
+//!
+//! This is a minimal responder: it answers questions about the service and
+//! its host and announces them on startup, without probing for conflicts.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+/// The service type frontends browse for.
+pub const SERVICE_TYPE: &str = "_subtitleminer._tcp.local";
+
+/// What DNS-SD browsers ask to list every service type.
+const SERVICES_META: &str = "_services._dns-sd._udp.local";
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on records only we answer for, so caches replace older copies.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// TTLs from RFC 6762: records naming a host expire sooner.
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+
+/// The instance this server announces.
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    /// Shown to users browsing for servers, e.g. `Subtitle Miner on desk`
+    pub name: String,
+    pub port: u16,
+    /// Whether clients must present a token
+    pub auth: bool,
+}
+
+impl Announcement {
+    /// The default name: the program on this computer's hostname.
+    pub fn default_name() -> String {
+        format!("Subtitle Miner on {}", hostname())
+    }
+
+    fn instance(&self) -> String {
+        // DNS labels hold at most 63 bytes
+        let mut name = self.name.replace('.', " ");
+        while name.len() > 63 {
+            name.pop();
+        }
+        format!("{}.{}", name, SERVICE_TYPE)
+    }
+
+    /// A host name of our own, so the address record can't clash with the
+    /// one the OS may publish.
+    fn host(ip: Ipv4Addr) -> String {
+        format!("subtitleminer-{}.local", ip.to_string().replace('.', "-"))
+    }
+
+    /// The full answer: the service, its port and host, and the host's
+    /// address.
+    fn response(&self, ip: Ipv4Addr) -> Vec<u8> {
+        let instance = self.instance();
+        let host = Self::host(ip);
+        let txt = [
+            format!("version={}", env!("CARGO_PKG_VERSION")),
+            format!("auth={}", if self.auth { 1 } else { 0 }),
+            "path=/".to_string(),
+        ];
+
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&self.port.to_be_bytes());
+        write_name(&mut srv, &host);
+        let mut txt_data = Vec::new();
+        for entry in &txt {
+            txt_data.push(entry.len() as u8);
+            txt_data.extend_from_slice(entry.as_bytes());
+        }
+        let mut ptr = Vec::new();
+        write_name(&mut ptr, &instance);
+        let mut meta = Vec::new();
+        write_name(&mut meta, SERVICE_TYPE);
+
+        let records: [(&str, u16, u16, u32, Vec<u8>); 5] = [
+            (SERVICE_TYPE, TYPE_PTR, CLASS_IN, OTHER_TTL, ptr),
+            (SERVICES_META, TYPE_PTR, CLASS_IN, OTHER_TTL, meta),
+            (&instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, HOST_TTL, srv),
+            (
+                &instance,
+                TYPE_TXT,
+                CLASS_IN | CACHE_FLUSH,
+                OTHER_TTL,
+                txt_data,
+            ),
+            (
+                &host,
+                TYPE_A,
+                CLASS_IN | CACHE_FLUSH,
+                HOST_TTL,
+                ip.octets().to_vec(),
+            ),
+        ];
+
+        // Header: id 0, authoritative response, answers only
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, records.len() as u8, 0, 0, 0, 0];
+        for (name, rtype, class, ttl, data) in records {
+            write_name(&mut packet, name);
+            packet.extend_from_slice(&rtype.to_be_bytes());
+            packet.extend_from_slice(&class.to_be_bytes());
+            packet.extend_from_slice(&ttl.to_be_bytes());
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&data);
+        }
+        packet
+    }
+
+    /// Whether a query asks about anything we answer for.
+    fn is_asked(&self, packet: &[u8], ip: Ipv4Addr) -> bool {
+        let ours = [
+            SERVICE_TYPE.to_string(),
+            SERVICES_META.to_string(),
+            self.instance(),
+            Self::host(ip),
+        ];
+        questions(packet).is_some_and(|questions| {
+            questions.iter().any(|(name, qtype)| {
+                matches!(*qtype, TYPE_A | TYPE_PTR | TYPE_TXT | TYPE_SRV | TYPE_ANY)
+                    && ours.iter().any(|o| o.eq_ignore_ascii_case(name))
+            })
+        })
+    }
+}
+
+/// Answers mDNS questions about `announcement` until the process exits.
+/// Gives up with a warning if the mDNS port can't be joined.
+pub async fn announce(announcement: Announcement) {
+    let ip = match crate::qr::lan_ip() {
+        IpAddr::V4(ip) if !ip.is_loopback() => ip,
+        _ => {
+            warn!("[mdns] No LAN address, not announcing");
+            return;
+        }
+    };
+    let socket = match bind(ip) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("[mdns] Could not join multicast DNS: {}", e);
+            return;
+        }
+    };
+    let response = announcement.response(ip);
+    let group = SocketAddr::from((MDNS_ADDR, MDNS_PORT));
+    info!(
+        "Announcing '{}' as {} on {}:{}",
+        announcement.name, SERVICE_TYPE, ip, announcement.port
+    );
+
+    // Unsolicited announcements, repeated as RFC 6762 asks
+    for delay in [0, 1] {
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+        if let Err(e) = socket.send_to(&response, group).await {
+            warn!("[mdns] Announcement failed: {}", e);
+        }
+    }
+
+    let mut buf = [0u8; 9000];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("[mdns] {}", e);
+                continue;
+            }
+        };
+        if !announcement.is_asked(&buf[..len], ip) {
+            continue;
+        }
+        debug!("[mdns] Answering {}", from);
+        // Legacy resolvers ask from a port other than 5353, only listen
+        // there and match the answer by the query's id
+        let result = if from.port() == MDNS_PORT {
+            socket.send_to(&response, group).await
+        } else {
+            let mut answer = response.clone();
+            answer[..2].copy_from_slice(&buf[..2]);
+            socket.send_to(&answer, from).await
+        };
+        if let Err(e) = result {
+            debug!("[mdns] Answer to {} failed: {}", from, e);
+        }
+    }
+}
+
+/// A socket on the mDNS port shared with any other responder on the
+/// machine (Avahi, Bonjour).
+fn bind(ip: Ipv4Addr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &ip)?;
+    socket.set_multicast_if_v4(&ip)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// The `(name, type)` of every question in a query, or `None` for
+/// responses and anything malformed.
+fn questions(packet: &[u8]) -> Option<Vec<(String, u16)>> {
+    if packet.len() < 12 || packet[2] & 0x80 != 0 {
+        return None;
+    }
+    let count = u16::from_be_bytes([packet[4], packet[5]]);
+    let mut pos = 12;
+    let mut questions = Vec::new();
+    for _ in 0..count {
+        let (name, next) = read_name(packet, pos)?;
+        let qtype = u16::from_be_bytes([*packet.get(next)?, *packet.get(next + 1)?]);
+        questions.push((name, qtype));
+        // Type and class
+        pos = next + 4;
+    }
+    Some(questions)
+}
+
+/// The name at `pos` and the position after it, following compression
+/// pointers.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds pointer loops
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xC0 == 0xC0 {
+            let target = ((len & 0x3F) << 8) | *packet.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            pos = target;
+            continue;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    None
+}
+
+fn hostname() -> String {
+    let from_env = std::env::var("HOSTNAME").or_else(|_| std::env::var("COMPUTERNAME"));
+    from_env
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .map(|h| h.split('.').next().unwrap_or(&h).to_string())
+        .unwrap_or_else(|| "this computer".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A query with one question per `(name, type)`.
+    fn query(questions: &[(&str, u16)]) -> Vec<u8> {
+        let mut packet = vec![0, 0, 0, 0, 0, questions.len() as u8, 0, 0, 0, 0, 0, 0];
+        for (name, qtype) in questions {
+            write_name(&mut packet, name);
+            packet.extend_from_slice(&qtype.to_be_bytes());
+            packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        }
+        packet
+    }
+
+    /// `(name, type, class, ttl, data)` of every answer in a response.
+    fn answers(packet: &[u8]) -> Vec<(String, u16, u16, u32, Vec<u8>)> {
+        let u16_at = |pos: usize| u16::from_be_bytes([packet[pos], packet[pos + 1]]);
+        let count = u16_at(6);
+        let mut pos = 12;
+        let mut records = Vec::new();
+        for _ in 0..count {
+            let (name, next) = read_name(packet, pos).expect("valid name");
+            let ttl = u32::from_be_bytes(packet[next + 4..next + 8].try_into().unwrap());
+            let len = u16_at(next + 8) as usize;
+            let data = packet[next + 10..next + 10 + len].to_vec();
+            records.push((name, u16_at(next), u16_at(next + 2), ttl, data));
+            pos = next + 10 + len;
+        }
+        assert_eq!(pos, packet.len(), "trailing bytes");
+        records
+    }
+
+    fn announcement() -> Announcement {
+        Announcement {
+            name: "Subtitle Miner on desk".into(),
+            port: 61777,
+            auth: true,
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let mut packet = Vec::new();
+        write_name(
+            &mut packet,
+            "Subtitle Miner on desk._subtitleminer._tcp.local",
+        );
+        assert_eq!(
+            read_name(&packet, 0),
+            Some((
+                "Subtitle Miner on desk._subtitleminer._tcp.local".to_string(),
+                packet.len()
+            ))
+        );
+    }
+
+    #[test]
+    fn follows_compression_pointers() {
+        // "_subtitleminer" then a pointer to "_tcp.local" at 0
+        let mut packet = Vec::new();
+        write_name(&mut packet, "_tcp.local");
+        let start = packet.len();
+        packet.push(14);
+        packet.extend_from_slice(b"_subtitleminer");
+        packet.extend_from_slice(&[0xC0, 0]);
+        assert_eq!(
+            read_name(&packet, start),
+            Some(("_subtitleminer._tcp.local".to_string(), packet.len()))
+        );
+    }
+
+    #[test]
+    fn rejects_pointer_loops_and_truncated_names() {
+        assert_eq!(read_name(&[0xC0, 0], 0), None);
+        assert_eq!(read_name(&[5, b'l', b'o'], 0), None);
+        assert_eq!(read_name(&[], 0), None);
+    }
+
+    #[test]
+    fn reads_questions_but_not_responses() {
+        let packet = query(&[(SERVICE_TYPE, TYPE_PTR), ("desk.local", TYPE_A)]);
+        assert_eq!(
+            questions(&packet),
+            Some(vec![
+                (SERVICE_TYPE.to_string(), TYPE_PTR),
+                ("desk.local".to_string(), TYPE_A)
+            ])
+        );
+        let ip = Ipv4Addr::new(192, 168, 1, 20);
+        assert_eq!(questions(&announcement().response(ip)), None);
+        assert_eq!(questions(&packet[..11]), None);
+    }
+
+    #[test]
+    fn answers_only_questions_about_us() {
+        let ip = Ipv4Addr::new(192, 168, 1, 20);
+        let ours = announcement();
+        for asked in [
+            query(&[(SERVICE_TYPE, TYPE_PTR)]),
+            query(&[("_SUBTITLEMINER._tcp.local", TYPE_PTR)]),
+            query(&[(SERVICES_META, TYPE_PTR)]),
+            query(&[(&ours.instance(), TYPE_SRV)]),
+            query(&[("subtitleminer-192-168-1-20.local", TYPE_A)]),
+            query(&[("desk.local", TYPE_A), (SERVICE_TYPE, TYPE_ANY)]),
+        ] {
+            assert!(ours.is_asked(&asked, ip));
+        }
+        for other in [
+            query(&[("_http._tcp.local", TYPE_PTR)]),
+            query(&[("subtitleminer-192-168-1-21.local", TYPE_A)]),
+            // AAAA
+            query(&[(SERVICE_TYPE, 28)]),
+        ] {
+            assert!(!ours.is_asked(&other, ip));
+        }
+    }
+
+    #[test]
+    fn response_round_trips() {
+        let ip = Ipv4Addr::new(192, 168, 1, 20);
+        let ours = announcement();
+        let instance = "Subtitle Miner on desk._subtitleminer._tcp.local";
+        let host = "subtitleminer-192-168-1-20.local";
+        let records = answers(&ours.response(ip));
+        assert_eq!(records.len(), 5);
+
+        let (name, rtype, class, ttl, data) = &records[0];
+        assert_eq!(
+            (name.as_str(), *rtype, *class, *ttl),
+            (SERVICE_TYPE, TYPE_PTR, CLASS_IN, OTHER_TTL)
+        );
+        assert_eq!(
+            read_name(data, 0).map(|(name, _)| name).as_deref(),
+            Some(instance)
+        );
+
+        let (name, rtype, _, _, data) = &records[1];
+        assert_eq!((name.as_str(), *rtype), (SERVICES_META, TYPE_PTR));
+        assert_eq!(
+            read_name(data, 0).map(|(name, _)| name).as_deref(),
+            Some(SERVICE_TYPE)
+        );
+
+        let (name, rtype, class, ttl, data) = &records[2];
+        assert_eq!(
+            (name.as_str(), *rtype, *class, *ttl),
+            (instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, HOST_TTL)
+        );
+        assert_eq!(u16::from_be_bytes([data[4], data[5]]), 61777);
+        assert_eq!(
+            read_name(data, 6).map(|(name, _)| name).as_deref(),
+            Some(host)
+        );
+
+        let (name, rtype, _, _, data) = &records[3];
+        assert_eq!((name.as_str(), *rtype), (instance, TYPE_TXT));
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = data[pos] as usize;
+            entries.push(String::from_utf8(data[pos + 1..pos + 1 + len].to_vec()).unwrap());
+            pos += 1 + len;
+        }
+        assert_eq!(
+            entries,
+            [
+                format!("version={}", env!("CARGO_PKG_VERSION")),
+                "auth=1".to_string(),
+                "path=/".to_string()
+            ]
+        );
+
+        let (name, rtype, _, ttl, data) = &records[4];
+        assert_eq!((name.as_str(), *rtype, *ttl), (host, TYPE_A, HOST_TTL));
+        assert_eq!(data, &[192, 168, 1, 20]);
+    }
+
+    #[test]
+    fn instance_names_fit_a_label() {
+        let long = Announcement {
+            name: format!("my.server {}", "x".repeat(80)),
+            ..announcement()
+        };
+        let instance = long.instance();
+        let label = instance
+            .strip_suffix(&format!(".{}", SERVICE_TYPE))
+            .unwrap();
+        assert_eq!(label.len(), 63);
+        assert!(label.starts_with("my server "));
+    }
+}
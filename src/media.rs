@@ -1,57 +1,488 @@
-use base64::Engine;
-use log::{debug, info, warn};
-use serde::Deserialize;
-use std::process::{Command, Stdio};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
 use std::sync::OnceLock;
-use std::{env, fs, path::PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use tracing::{debug, info, info_span, warn};
 use uuid::Uuid;
 
+use crate::cache;
 use crate::event_loop::Subtitle;
+use crate::metrics::METRICS;
+use crate::stream;
+use crate::timeline;
 
 const DEFAULT_AUDIO_OFFSET: f64 = 0.25;
 
+/// Frames per second considered when picking a smart frame, and how many of
+/// them at most; the `thumbnail` filter holds them all in memory.
+const SMART_FRAME_RATE: f64 = 4.0;
+const SMART_FRAME_MAX: usize = 40;
+
+/// Generous for any single line; only a stuck ffmpeg takes this long.
+pub const DEFAULT_FFMPEG_TIMEOUT: Duration = Duration::from_secs(60);
+
 static FFMPEG_PATH: OnceLock<String> = OnceLock::new();
+static FFMPEG_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static ENCODERS: OnceLock<HashSet<String>> = OnceLock::new();
+static HWACCEL: OnceLock<String> = OnceLock::new();
+static MPV_PATH: OnceLock<String> = OnceLock::new();
+
+/// Jobs between starting and having cleaned up their temp files.
+static RUNNING_JOBS: AtomicUsize = AtomicUsize::new(0);
+/// Set on shutdown: running processes are killed and no new ones start.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+static RUNNING_PROCESSES: AtomicUsize = AtomicUsize::new(0);
+/// Jobs report their commands instead of running them, see [`set_dry_run`].
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+/// ffmpeg and ffprobe run on the host through `flatpak-spawn`, see
+/// [`resolve_ffmpeg_path`].
+static HOST_SPAWN: AtomicBool = AtomicBool::new(false);
+
+/// Extra source cut around what a job reads when it goes through mpv, so
+/// keyframe-less seeking can't clip it.
+const MPV_DUMP_MARGIN: f64 = 1.0;
+
+/// Encoders for the built-in formats; any one of them will do.
+const FORMAT_ENCODERS: &[(&str, &str)] = &[
+    ("jpeg", "mjpeg"),
+    ("webp", "libwebp"),
+    ("avif", "libaom-av1"),
+    ("avif", "libsvtav1"),
+    ("png", "png"),
+    ("mp3", "libmp3lame"),
+    ("opus", "libopus"),
+    ("flac", "flac"),
+    ("aac", "aac"),
+    ("vorbis", "libvorbis"),
+];
+
+/// Image formats from best to most widely available; a format the local
+/// ffmpeg can't encode falls back to the next one.
+pub const IMAGE_FORMATS: &[&str] = &["avif", "webp", "jpeg"];
+/// Same for audio.
+pub const AUDIO_FORMATS: &[&str] = &["opus", "mp3"];
 
 pub fn init_ffmpeg_path(path: &str) {
-    let resolved = resolve_ffmpeg_path(path);
-    if resolved != path {
-        debug!("[media] Resolved ffmpeg '{}' -> '{}'", path, resolved);
-    }
-    FFMPEG_PATH.set(resolved).ok();
+    FFMPEG_PATH.set(resolve_ffmpeg_path(path)).ok();
 }
 
 fn ffmpeg() -> &'static str {
     FFMPEG_PATH.get().map(|s| s.as_str()).unwrap_or("ffmpeg")
 }
 
+/// The ffmpeg jobs run, as found by [`init_ffmpeg_path`].
+pub fn ffmpeg_path() -> String {
+    if HOST_SPAWN.load(Ordering::Relaxed) {
+        return format!("{} (on the host)", ffmpeg());
+    }
+    ffmpeg().to_string()
+}
+
+/// A command for ffmpeg or ffprobe, run on the host from inside Flatpak.
+fn ffmpeg_tool(program: impl AsRef<OsStr>) -> Command {
+    if HOST_SPAWN.load(Ordering::Relaxed) {
+        let mut command = Command::new("flatpak-spawn");
+        // A timeout kills flatpak-spawn, which can't forward SIGKILL; with
+        // --watch-bus the host process dies along with it
+        command.args(["--host", "--watch-bus"]).arg(program);
+        return command;
+    }
+    Command::new(program)
+}
+
+/// Sets the mpv binary used for sources ffmpeg can't open, see
+/// [`FfmpegRequest::through_mpv`].
+pub fn init_mpv_path(path: &str) {
+    MPV_PATH.set(path.to_string()).ok();
+}
+
+fn mpv() -> &'static str {
+    MPV_PATH.get().map(|s| s.as_str()).unwrap_or("mpv")
+}
+
+/// Sets how long a single ffmpeg job may run before it is killed.
+pub fn init_ffmpeg_timeout(timeout: Duration) {
+    FFMPEG_TIMEOUT.set(timeout).ok();
+}
+
+/// Makes every job fail with [`FfmpegError::DryRun`] and the commands it
+/// would have run, for debugging `advanced_args` and filters.
+pub fn set_dry_run(on: bool) {
+    DRY_RUN.store(on, Ordering::SeqCst);
+}
+
+/// Whether [`set_dry_run`] turned running jobs off.
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+/// Runs `ffmpeg -encoders` and remembers which of the built-in formats this
+/// build can write. Until this succeeds every format is assumed to work.
+pub fn probe_encoders() -> std::io::Result<()> {
+    let output = ffmpeg_tool(ffmpeg())
+        .args(["-hide_banner", "-encoders"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    // Encoder lines look like ` V....D libwebp    libwebp WebP image`
+    let encoders: HashSet<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1).map(str::to_string))
+        .collect();
+    if encoders.is_empty() {
+        return Err(std::io::Error::other("ffmpeg -encoders listed nothing"));
+    }
+    ENCODERS.set(encoders).ok();
+    Ok(())
+}
+
+/// The first line of `ffmpeg -version`, e.g. `ffmpeg version 6.1.1 ...`.
+pub fn ffmpeg_version() -> std::io::Result<String> {
+    version_line(Path::new(ffmpeg()))
+}
+
+/// Same for the ffprobe next to ffmpeg.
+pub fn ffprobe_version() -> std::io::Result<String> {
+    version_line(&ffprobe())
+}
+
+fn version_line(program: &Path) -> std::io::Result<String> {
+    let output = ffmpeg_tool(program)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", program.display(), e)))?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .filter(|line| output.status.success() && !line.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| std::io::Error::other(format!("{} -version failed", program.display())))
+}
+
+/// Whether ffmpeg can write `format` (`jpeg`, `webp`, `avif`, `png`, `mp3`,
+/// `opus`, `flac`, `aac` or `vorbis`). Unknown formats and an unprobed
+/// ffmpeg get the benefit of the doubt.
+pub fn supports_format(format: &str) -> bool {
+    let format = if format == "jpg" { "jpeg" } else { format };
+    let Some(encoders) = ENCODERS.get() else {
+        return true;
+    };
+    let mut candidates = FORMAT_ENCODERS
+        .iter()
+        .filter(|(f, _)| *f == format)
+        .peekable();
+    candidates.peek().is_none() || candidates.any(|(_, encoder)| encoders.contains(*encoder))
+}
+
+/// Whether ffmpeg lists `encoder`. Unlike [`supports_format`] an unprobed
+/// ffmpeg is assumed not to have it.
+pub fn has_encoder(encoder: &str) -> bool {
+    ENCODERS.get().is_some_and(|e| e.contains(encoder))
+}
+
+/// Built-in formats ffmpeg can't write.
+pub fn missing_formats() -> Vec<&'static str> {
+    IMAGE_FORMATS
+        .iter()
+        .chain(AUDIO_FORMATS)
+        .copied()
+        .filter(|f| !supports_format(f))
+        .collect()
+}
+
+/// The first format from `format` on in `chain` that ffmpeg can write, or
+/// `format` itself if it isn't part of the chain or nothing is available.
+fn fallback_format(format: &str, chain: &[&'static str]) -> Option<&'static str> {
+    let format = if format == "jpg" { "jpeg" } else { format };
+    let start = chain.iter().position(|f| *f == format)?;
+    let found = chain[start..]
+        .iter()
+        .copied()
+        .find(|f| supports_format(f))?;
+    if found != format {
+        warn!(
+            "[media] ffmpeg can't encode {}, using {} instead",
+            format, found
+        );
+    }
+    Some(found)
+}
+
+/// Decodes video for animated images with `-hwaccel <method>`, e.g. `auto`,
+/// `vaapi`, `videotoolbox` or `d3d11va`.
+pub fn init_hwaccel(method: &str) {
+    HWACCEL.set(method.to_string()).ok();
+}
+
+fn ffmpeg_timeout() -> Duration {
+    FFMPEG_TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_FFMPEG_TIMEOUT)
+}
+
+/// ffprobe is looked up next to the resolved ffmpeg binary.
+fn ffprobe() -> PathBuf {
+    let ffmpeg = Path::new(ffmpeg());
+    let name = ffmpeg
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("ffmpeg")
+        .replace("ffmpeg", "ffprobe");
+    ffmpeg.with_file_name(name)
+}
+
+/// Finds a bare program name like the default `ffmpeg` in the places it is
+/// usually installed, since mpv or a desktop launcher may start the miner
+/// with a sparse PATH. Paths with a directory are used as given.
 fn resolve_ffmpeg_path(path: &str) -> String {
     let trimmed = path.trim();
     if trimmed.is_empty() {
         return "ffmpeg".to_string();
     }
+    if Path::new(trimmed).components().count() > 1 {
+        return trimmed.to_string();
+    }
 
-    #[cfg(target_os = "macos")]
+    if let Some((found, place)) = ffmpeg_candidates(trimmed)
+        .into_iter()
+        .find(|(candidate, _)| candidate.is_file())
     {
-        if trimmed == "ffmpeg" {
-            for candidate in [
-                "/opt/homebrew/bin/ffmpeg",
-                "/usr/local/bin/ffmpeg",
-                "/usr/bin/ffmpeg",
-            ] {
-                if fs::metadata(candidate).is_ok() {
-                    return candidate.to_string();
-                }
-            }
+        info!("[media] Found {} {}: {}", trimmed, place, found.display());
+        return found.display().to_string();
+    }
+    if runs_on_host(trimmed) {
+        info!(
+            "[media] Found {} on the host, running it through flatpak-spawn",
+            trimmed
+        );
+        HOST_SPAWN.store(true, Ordering::Relaxed);
+        return trimmed.to_string();
+    }
+    debug!(
+        "[media] {} not found next to the executable, in PATH or the usual places",
+        trimmed
+    );
+    trimmed.to_string()
+}
+
+/// Where to look for the program `name`, in order, each with how to
+/// describe it in the log.
+fn ffmpeg_candidates(name: &str) -> Vec<(PathBuf, &'static str)> {
+    let file = if name.ends_with(env::consts::EXE_SUFFIX) {
+        name.to_string()
+    } else {
+        format!("{}{}", name, env::consts::EXE_SUFFIX)
+    };
+    let mut dirs: Vec<(PathBuf, &'static str)> = Vec::new();
+    // A portable install bundles it
+    if let Some(dir) = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    {
+        dirs.push((dir, "next to the executable"));
+    }
+    if let Some(dir) = crate::fetch_ffmpeg::install_dir() {
+        dirs.push((dir, "in the fetch-ffmpeg directory"));
+    }
+    if let Some(path) = env::var_os("PATH") {
+        dirs.extend(env::split_paths(&path).map(|dir| (dir, "in PATH")));
+    }
+
+    #[cfg(windows)]
+    {
+        let var = |name: &str| env::var_os(name).map(PathBuf::from);
+        dirs.push((PathBuf::from(r"C:\ffmpeg\bin"), r"in C:\ffmpeg\bin"));
+        if let Some(scoop) =
+            var("SCOOP").or_else(|| var("USERPROFILE").map(|home| home.join("scoop")))
+        {
+            dirs.push((scoop.join("shims"), "in scoop's shims"));
+        }
+        let choco =
+            var("ChocolateyInstall").unwrap_or_else(|| PathBuf::from(r"C:\ProgramData\chocolatey"));
+        dirs.push((choco.join("bin"), "in Chocolatey's shims"));
+        if let Some(local) = var("LOCALAPPDATA") {
+            dirs.push((local.join(r"Microsoft\WinGet\Links"), "in winget's links"));
         }
     }
+    #[cfg(target_os = "macos")]
+    dirs.extend(
+        ["/opt/homebrew/bin", "/usr/local/bin", "/usr/bin"]
+            .map(|dir| (PathBuf::from(dir), "in a Homebrew or system directory")),
+    );
+    #[cfg(all(unix, not(target_os = "macos")))]
+    dirs.extend(
+        ["/usr/local/bin", "/usr/bin", "/snap/bin"]
+            .map(|dir| (PathBuf::from(dir), "in a system directory")),
+    );
 
-    trimmed.to_string()
+    dirs.into_iter()
+        .map(|(dir, place)| (dir.join(&file), place))
+        .collect()
+}
+
+/// Whether the miner runs inside a Flatpak sandbox and `name` runs on the
+/// host. Its temp files must be visible to both, e.g. with
+/// `--filesystem=/tmp`.
+fn runs_on_host(name: &str) -> bool {
+    Path::new("/.flatpak-info").exists()
+        && Command::new("flatpak-spawn")
+            .args(["--host", name, "-version"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
 }
 
 fn temp_path(prefix: &str, ext: &str) -> PathBuf {
     env::temp_dir().join(format!("{}_{}.{}", prefix, Uuid::new_v4(), ext))
 }
 
+/// Hex SHA-256 of `data`, sent along with media so clients can verify and
+/// deduplicate it.
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Where to have mpv save a lossless screenshot for [`FfmpegRequest::encode_image`].
+pub fn screenshot_path() -> PathBuf {
+    temp_path("screenshot", "png")
+}
+
+/// A stream as reported by ffprobe.
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamInfo {
+    /// Absolute stream index, usable as `-map 0:<index>`
+    pub index: u64,
+    pub codec_type: String,
+    pub codec_name: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+/// Lists the streams of `media_path` using ffprobe.
+pub fn probe_streams(media_path: &str) -> std::io::Result<Vec<StreamInfo>> {
+    let out = ffmpeg_tool(ffprobe())
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "stream=index,codec_type,codec_name:stream_tags=language,title",
+            "-of",
+            "json",
+            media_path,
+        ])
+        .stdin(Stdio::null())
+        .output()?;
+    if !out.status.success() {
+        return Err(std::io::Error::other(format!(
+            "ffprobe failed ({}): {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    let streams = json
+        .get("streams")
+        .and_then(|s| s.as_array())
+        .map(|streams| {
+            streams
+                .iter()
+                .map(|s| {
+                    let str_field = |v: Option<&serde_json::Value>| {
+                        v.and_then(|v| v.as_str()).map(str::to_string)
+                    };
+                    let tags = s.get("tags");
+                    StreamInfo {
+                        index: s.get("index").and_then(|i| i.as_u64()).unwrap_or(0),
+                        codec_type: str_field(s.get("codec_type")).unwrap_or_default(),
+                        codec_name: str_field(s.get("codec_name")).unwrap_or_default(),
+                        language: str_field(tags.and_then(|t| t.get("language"))),
+                        title: str_field(tags.and_then(|t| t.get("title"))),
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(streams)
+}
+
+/// Length of `media_path` in seconds, using ffprobe.
+pub fn probe_duration(media_path: &str) -> std::io::Result<f64> {
+    let out = ffmpeg_tool(ffprobe())
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            media_path,
+        ])
+        .stdin(Stdio::null())
+        .output()?;
+    if !out.status.success() {
+        return Err(std::io::Error::other(format!(
+            "ffprobe failed ({}): {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    String::from_utf8_lossy(&out.stdout)
+        .trim()
+        .parse()
+        .map_err(|_| std::io::Error::other(format!("{} has no duration", media_path)))
+}
+
+/// Start and end of each chapter of `media_path` in the file itself, which
+/// for ordered chapters is not where mpv plays them.
+pub fn probe_chapters(media_path: &str) -> std::io::Result<Vec<(f64, f64)>> {
+    let out = ffmpeg_tool(ffprobe())
+        .args(["-v", "error", "-show_chapters", "-of", "json", media_path])
+        .stdin(Stdio::null())
+        .output()?;
+    if !out.status.success() {
+        return Err(std::io::Error::other(format!(
+            "ffprobe failed ({}): {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    let time = |c: &serde_json::Value, key: &str| {
+        c.get(key)
+            .and_then(|t| t.as_str())
+            .and_then(|t| t.parse().ok())
+    };
+    let chapters = json
+        .get("chapters")
+        .and_then(|c| c.as_array())
+        .map(|chapters| {
+            chapters
+                .iter()
+                .filter_map(|c| Some((time(c, "start_time")?, time(c, "end_time")?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(chapters)
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct ImageConfig {
@@ -59,6 +490,14 @@ pub struct ImageConfig {
     pub quality: i32,
     pub is_animated: bool,
     pub size: Option<String>,
+    /// AV1 encoder for avif, `libsvtav1` or `libaom-av1`. By default the much
+    /// faster SVT-AV1 is used when ffmpeg has it.
+    pub encoder: Option<String>,
+    /// Pick a representative, non-black frame from the line instead of the
+    /// one at its midpoint. Stills only.
+    pub smart_frame: bool,
+    /// ffmpeg video filters run ahead of scaling, e.g. `eq=brightness=0.1`
+    pub filters: Option<String>,
     pub advanced_args: Option<String>,
 }
 
@@ -69,12 +508,48 @@ impl Default for ImageConfig {
             quality: 5,
             is_animated: false,
             size: None,
+            encoder: None,
+            smart_frame: false,
+            filters: None,
             advanced_args: None,
         }
     }
 }
 
 impl ImageConfig {
+    /// Switches to the next format in [`IMAGE_FORMATS`] if ffmpeg can't write
+    /// the configured one. Advanced arguments are left to the user.
+    pub fn with_available_format(mut self) -> Self {
+        if self.advanced_args.is_none()
+            && let Some(format) = fallback_format(&self.format, IMAGE_FORMATS)
+            && !supports_format(&self.format)
+        {
+            // Quality scales differ per encoder
+            self.quality = match format {
+                "webp" => 80,
+                _ => ImageConfig::default().quality,
+            };
+            self.format = format.to_string();
+        }
+        self
+    }
+
+    /// The configured AV1 encoder, or SVT-AV1 if ffmpeg has it.
+    fn av1_encoder(&self) -> &str {
+        match self.encoder.as_deref() {
+            Some(encoder) => encoder,
+            None if has_encoder("libsvtav1") => "libsvtav1",
+            None => "libaom-av1",
+        }
+    }
+
+    fn uses_smart_frame(&self, sub: &Subtitle) -> bool {
+        self.smart_frame
+            && !self.is_animated
+            && self.advanced_args.is_none()
+            && sub.sub_end > sub.sub_start
+    }
+
     pub fn get_extension(&self) -> &str {
         let fmt = self.format.trim_start_matches('.');
         if fmt.is_empty() {
@@ -84,6 +559,7 @@ impl ImageConfig {
             "jpeg" | "jpg" => "jpg",
             "avif" | "avif_animated" => "avif",
             "webp" | "webp_animated" => "webp",
+            "png" => "png",
             other => other,
         }
     }
@@ -105,10 +581,32 @@ impl ImageConfig {
             args.extend(["-vframes".into(), "1".into()]);
         }
 
-        if let Some(size) = &self.size {
-            if !size.trim().is_empty() {
-                args.extend(["-vf".into(), format!("scale={}", size)]);
-            }
+        let mut filters = Vec::new();
+        if self.uses_smart_frame(sub) {
+            let (start, end) = smart_frame_window(sub);
+            let frames = ((end - start) * SMART_FRAME_RATE).ceil() as usize;
+            // blackframe tags every frame with how black it is, metadata
+            // drops the mostly black ones and thumbnail keeps the frame
+            // closest to the average, which rules out cuts and blur
+            filters.extend([
+                format!("fps={}", SMART_FRAME_RATE),
+                "blackframe=amount=0:threshold=32".to_string(),
+                "metadata=select:key=lavfi.blackframe.pblack:value=90:function=less".to_string(),
+                format!("thumbnail={}", frames.clamp(1, SMART_FRAME_MAX)),
+            ]);
+        }
+        if let Some(f) = &self.filters
+            && !f.trim().is_empty()
+        {
+            filters.push(f.clone());
+        }
+        if let Some(size) = &self.size
+            && !size.trim().is_empty()
+        {
+            filters.push(format!("scale={}", size));
+        }
+        if !filters.is_empty() {
+            args.extend(["-vf".into(), filters.join(",")]);
         }
 
         match self.format.as_str() {
@@ -120,6 +618,37 @@ impl ImageConfig {
                     format!("{}", self.quality.clamp(1, 31)),
                 ]);
             }
+            "png" => {
+                // Lossless, so quality only trades size for speed
+                args.extend([
+                    "-compression_level".into(),
+                    format!("{}", self.quality.clamp(0, 9)),
+                ]);
+                if self.is_animated {
+                    args.extend([
+                        "-c:v".into(),
+                        "apng".into(),
+                        "-plays".into(),
+                        "0".into(),
+                        "-f".into(),
+                        "apng".into(),
+                    ]);
+                } else {
+                    args.extend(["-c:v".into(), "png".into()]);
+                }
+            }
+            "avif" if self.av1_encoder() == "libsvtav1" => {
+                args.extend([
+                    "-c:v".into(),
+                    "libsvtav1".into(),
+                    "-crf".into(),
+                    format!("{}", self.quality.clamp(0, 63)),
+                    "-preset".into(),
+                    "10".into(),
+                    "-pix_fmt".into(),
+                    "yuv420p".into(),
+                ]);
+            }
             "avif" => {
                 args.extend([
                     "-c:v".into(),
@@ -156,6 +685,13 @@ pub struct AudioConfig {
     pub format: String,
     pub quality: i32,
     pub filters: Option<String>,
+    /// `stereo` or `mono` to downmix, or `center` for only the center
+    /// channel, where 5.1 mixes put the dialog
+    pub channels: Option<String>,
+    /// Boost (or cut) in dB, for quiet sources
+    pub gain_db: Option<f64>,
+    /// Playback speed keeping the pitch, e.g. `0.85` for fast speakers
+    pub tempo: Option<f64>,
     pub advanced_args: Option<String>,
 }
 
@@ -165,12 +701,58 @@ impl Default for AudioConfig {
             format: "mp3".to_string(),
             quality: 128,
             filters: None,
+            channels: None,
+            gain_db: None,
+            tempo: None,
             advanced_args: None,
         }
     }
 }
 
 impl AudioConfig {
+    /// Switches to the next format in [`AUDIO_FORMATS`] if ffmpeg can't write
+    /// the configured one. Advanced arguments are left to the user.
+    pub fn with_available_format(mut self) -> Self {
+        if self.advanced_args.is_none()
+            && let Some(format) = fallback_format(&self.format, AUDIO_FORMATS)
+            && !supports_format(&self.format)
+        {
+            self.format = format.to_string();
+        }
+        self
+    }
+
+    /// Cheap settings for listening in the browser: low-bitrate mono opus,
+    /// or mp3 where ffmpeg lacks libopus.
+    pub fn preview() -> Self {
+        let config = Self {
+            format: "opus".to_string(),
+            quality: 24,
+            filters: None,
+            channels: Some("mono".to_string()),
+            gain_db: None,
+            tempo: None,
+            advanced_args: None,
+        }
+        .with_available_format();
+        match config.format.as_str() {
+            "mp3" => Self {
+                quality: 48,
+                ..config
+            },
+            _ => config,
+        }
+    }
+
+    /// A mono downmix to retry with when a source without a center channel
+    /// was asked for only that.
+    fn center_fallback(&self) -> Option<Self> {
+        (self.channels.as_deref() == Some("center")).then(|| Self {
+            channels: Some("mono".to_string()),
+            ..self.clone()
+        })
+    }
+
     pub fn get_extension(&self) -> &str {
         let fmt = self.format.trim_start_matches('.');
         if fmt.is_empty() {
@@ -179,52 +761,224 @@ impl AudioConfig {
         match fmt {
             "mp3" => "mp3",
             "opus" => "opus",
+            "flac" => "flac",
+            "aac" => "m4a",
+            "vorbis" => "ogg",
             other => other,
         }
     }
 
     pub fn apply_to_args(&self, args: &mut Vec<String>) {
+        self.apply_to_args_with_filters(args, Vec::new());
+    }
+
+    /// Like [`apply_to_args`](Self::apply_to_args), running `filters` ahead
+    /// of the configured ones.
+    fn apply_to_args_with_filters(&self, args: &mut Vec<String>, mut filters: Vec<String>) {
         if let Some(advanced) = &self.advanced_args {
+            if !filters.is_empty() {
+                args.extend(["-af".into(), filters.join(",")]);
+            }
             args.extend(advanced.split_whitespace().map(|s| s.to_string()));
             return;
         }
 
-        if self.format == "mp3" {
-            args.extend([
-                "-c:a".into(),
-                "libmp3lame".into(),
-                "-b:a".into(),
-                format!("{}k", self.quality.clamp(8, 320)),
-            ]);
-        } else {
-            args.extend([
-                "-c:a".into(),
-                "libopus".into(),
-                "-b:a".into(),
-                format!("{}k", self.quality.clamp(8, 512)),
-            ]);
+        match self.format.as_str() {
+            "mp3" => {
+                args.extend([
+                    "-c:a".into(),
+                    "libmp3lame".into(),
+                    "-b:a".into(),
+                    format!("{}k", self.quality.clamp(8, 320)),
+                ]);
+            }
+            // Lossless, so the bitrate doesn't apply
+            "flac" => args.extend(["-c:a".into(), "flac".into()]),
+            "aac" => {
+                args.extend([
+                    "-c:a".into(),
+                    "aac".into(),
+                    "-b:a".into(),
+                    format!("{}k", self.quality.clamp(8, 512)),
+                    "-movflags".into(),
+                    "+faststart".into(),
+                ]);
+            }
+            "vorbis" => {
+                args.extend([
+                    "-c:a".into(),
+                    "libvorbis".into(),
+                    "-b:a".into(),
+                    format!("{}k", self.quality.clamp(32, 500)),
+                ]);
+            }
+            _ => {
+                args.extend([
+                    "-c:a".into(),
+                    "libopus".into(),
+                    "-b:a".into(),
+                    format!("{}k", self.quality.clamp(8, 512)),
+                ]);
+            }
         }
 
-        let mut filters = vec!["afade=t=in:d=0.005".to_string()];
-        if let Some(f) = &self.filters {
-            if !f.trim().is_empty() {
-                filters.push(f.clone());
-            }
+        match self.channels.as_deref() {
+            Some("stereo") => args.extend(["-ac".into(), "2".into()]),
+            Some("mono") => args.extend(["-ac".into(), "1".into()]),
+            // Fails without a center channel, see `center_fallback`
+            Some("center") => filters.insert(0, "pan=mono|c0=FC".to_string()),
+            _ => {}
+        }
+
+        filters.push("afade=t=in:d=0.005".to_string());
+        if let Some(gain) = self.gain_db
+            && gain.is_finite()
+            && gain != 0.0
+        {
+            filters.push(format!("volume={:.1}dB", gain.clamp(-30.0, 30.0)));
+        }
+        if let Some(tempo) = self.tempo
+            && tempo.is_finite()
+            && tempo != 1.0
+        {
+            filters.extend(atempo_chain(tempo.clamp(0.25, 4.0)));
+        }
+        if let Some(f) = &self.filters
+            && !f.trim().is_empty()
+        {
+            filters.push(f.clone());
         }
         args.extend(["-af".into(), filters.join(",")]);
     }
 }
 
+/// `atempo` filters for `tempo`, chained since older ffmpeg only takes
+/// 0.5 to 2 per filter.
+fn atempo_chain(mut tempo: f64) -> Vec<String> {
+    let mut chain = Vec::new();
+    while tempo < 0.5 {
+        chain.push("atempo=0.5".to_string());
+        tempo /= 0.5;
+    }
+    while tempo > 2.0 {
+        chain.push("atempo=2.0".to_string());
+        tempo /= 2.0;
+    }
+    chain.push(format!("atempo={:.4}", tempo));
+    chain
+}
+
+/// `-map` argument selecting the subtitle's audio stream: the exact stream
+/// when mpv told us its ffmpeg index, otherwise the nth audio stream.
+fn audio_map(sub: &Subtitle) -> String {
+    if stream::has_separate_audio(&sub.media_path) {
+        return "0:a:0".into();
+    }
+    match sub.ff_index {
+        Some(index) => format!("0:{}", index),
+        None => format!("0:a:{}", (sub.aid - 1).max(0)),
+    }
+}
+
+/// Part of the line a smart frame is picked from, leaving out its edges
+/// where scene cuts tend to be.
+fn smart_frame_window(sub: &Subtitle) -> (f64, f64) {
+    let margin = ((sub.sub_end - sub.sub_start) * 0.1).clamp(0.0, 0.25);
+    (sub.sub_start + margin, sub.sub_end - margin)
+}
+
 #[derive(Debug, Clone)]
 pub struct FfmpegRequest {
     output_path: PathBuf,
     args: Vec<String>,
+    /// Tried when this request fails or produces nothing
+    fallback: Option<Box<FfmpegRequest>>,
+    /// Whether the same arguments always give the same output, see
+    /// [`crate::cache`]
+    cacheable: bool,
+    /// Segment mpv has to cut before ffmpeg can run
+    dump: Option<MpvDump>,
+}
+
+/// A segment of a source cut by a headless mpv into a file ffmpeg can read.
+#[derive(Debug, Clone)]
+struct MpvDump {
+    source: String,
+    start: f64,
+    length: f64,
+    /// Audio track to keep, or video only when `None`
+    aid: Option<i64>,
+    path: PathBuf,
+}
+
+/// Size and modification time of the local file `path`, empty for URLs and
+/// anything else that can't be read.
+fn file_stamp(path: &str) -> String {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return String::new();
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|since| since.as_nanos())
+        .unwrap_or_default();
+    format!("{} {}", metadata.len(), modified)
+}
+
+impl MpvDump {
+    fn command(&self) -> Command {
+        let mut command = Command::new(mpv());
+        command.args(self.args());
+        command
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--no-config".into(),
+            "--no-terminal".into(),
+            format!("--start={:.3}", self.start),
+            format!("--length={:.3}", self.length),
+            format!("--o={}", self.path.display()),
+        ];
+        match self.aid {
+            Some(aid) => args.extend([
+                "--no-video".into(),
+                format!("--aid={}", aid),
+                "--of=wav".into(),
+                "--oac=pcm_s16le".into(),
+            ]),
+            None => args.extend([
+                "--no-audio".into(),
+                "--sid=no".into(),
+                "--of=matroska".into(),
+                "--ovc=ffv1".into(),
+            ]),
+        }
+        args.extend(stream::mpv_options(&self.source));
+        args.push(self.source.clone());
+        args
+    }
 }
 
 impl FfmpegRequest {
     pub fn thumbnail(sub: &Subtitle, config: Option<ImageConfig>) -> Self {
-        let config = config.unwrap_or_default();
+        let start = sub.sub_start - MPV_DUMP_MARGIN;
+        let end = sub.sub_end + MPV_DUMP_MARGIN;
+        let job = Self::thumbnail_job(&timeline::on_file(sub), config.clone());
+        let through_mpv = Self::through_mpv(std::slice::from_ref(sub), start, end, false, |subs| {
+            Self::thumbnail_job(&subs[0], config)
+        });
+        if stream::is_mpv_only(&sub.media_path) {
+            return through_mpv;
+        }
+        job.or_else(through_mpv)
+    }
+
+    fn thumbnail_job(sub: &Subtitle, config: Option<ImageConfig>) -> Self {
+        let config = config.unwrap_or_default().with_available_format();
         let is_animated = config.is_animated;
+        let smart_frame = config.uses_smart_frame(sub);
 
         let ext = config.get_extension();
         let output = temp_path("thumb", ext);
@@ -235,14 +989,34 @@ impl FfmpegRequest {
             config.format, mid_time, sub.media_path
         );
 
-        let ss = if is_animated { sub.sub_start } else { mid_time };
+        let ss = if is_animated {
+            sub.sub_start
+        } else if smart_frame {
+            smart_frame_window(sub).0
+        } else {
+            mid_time
+        };
 
-        let mut args = vec![
-            "-ss".into(),
-            format!("{:.3}", ss),
-            "-i".into(),
-            sub.media_path.clone(),
-        ];
+        let mut args = Vec::new();
+        // Worth its setup cost only when decoding many frames
+        if is_animated && let Some(method) = HWACCEL.get() {
+            args.extend(["-hwaccel".into(), method.clone()]);
+        }
+        args.extend(["-ss".into(), format!("{:.3}", ss)]);
+        if smart_frame {
+            let (start, end) = smart_frame_window(sub);
+            args.extend(["-t".into(), format!("{:.3}", end - start)]);
+        }
+        args.extend(stream::input_args(&sub.media_path, false));
+
+        // All frames might be black, e.g. during a fade
+        let fallback = smart_frame.then(|| {
+            let midpoint = ImageConfig {
+                smart_frame: false,
+                ..config.clone()
+            };
+            Box::new(Self::thumbnail_job(sub, Some(midpoint)))
+        });
 
         config.apply_to_args(&mut args, sub);
 
@@ -250,118 +1024,812 @@ impl FfmpegRequest {
         Self {
             args,
             output_path: output,
+            fallback,
+            cacheable: true,
+            dump: None,
         }
     }
 
+    /// `count` stills spread evenly over the line, each with the time it is
+    /// taken at, for picking the best one by hand.
+    pub fn thumbnail_candidates(
+        sub: &Subtitle,
+        count: usize,
+        config: Option<ImageConfig>,
+    ) -> Vec<(f64, Self)> {
+        let config = config.unwrap_or_default();
+        let step = (sub.sub_end - sub.sub_start).max(0.0) / (count + 1) as f64;
+        (1..=count)
+            .map(|i| {
+                let time = sub.sub_start + step * i as f64;
+                (time, Self::still(sub, time, config.clone()))
+            })
+            .collect()
+    }
+
+    /// A still of `media_path` at `time` seconds, regardless of any line.
+    pub fn frame_at(media_path: &str, time: f64, config: Option<ImageConfig>) -> Self {
+        let sub = Subtitle {
+            media_path: media_path.to_string(),
+            ..Default::default()
+        };
+        Self::still(&sub, time, config.unwrap_or_default())
+    }
+
+    /// Re-encodes an existing image, e.g. an mpv screenshot, with `config`.
+    /// `input` is left in place.
+    pub fn encode_image(input: &Path, config: Option<ImageConfig>) -> Self {
+        let config = ImageConfig {
+            is_animated: false,
+            smart_frame: false,
+            ..config.unwrap_or_default()
+        }
+        .with_available_format();
+        let output = temp_path("thumb", config.get_extension());
+
+        let mut args = vec!["-i".into(), input.display().to_string()];
+        config.apply_to_args(&mut args, &Subtitle::default());
+        args.extend(["-y".into(), output.display().to_string()]);
+        Self {
+            args,
+            output_path: output,
+            fallback: None,
+            // The input is a one-off screenshot
+            cacheable: false,
+            dump: None,
+        }
+    }
+
+    /// The image subtitle in ffmpeg's stream `stream` at the middle of
+    /// `sub`, dark on a light `width`x`height` canvas as OCR prefers.
+    pub fn subtitle_image(sub: &Subtitle, stream: i64, width: i64, height: i64) -> Self {
+        let output = temp_path("subimage", "png");
+        let sub = timeline::on_file(sub);
+        let mid_time = (sub.sub_start + sub.sub_end) / 2.0;
+        // Starts early enough to catch the picture being drawn
+        let seek = (sub.sub_start - 1.0).max(0.0);
+
+        let mut args = vec!["-ss".into(), format!("{:.3}", seek)];
+        args.extend(stream::input_args(&sub.media_path, false));
+        args.extend([
+            "-filter_complex".into(),
+            format!(
+                "color=c=black:s={}x{}[bg];[bg][0:{}]overlay=shortest=1,format=gray,negate",
+                width, height, stream
+            ),
+            "-ss".into(),
+            format!("{:.3}", mid_time - seek),
+            "-frames:v".into(),
+            "1".into(),
+            "-c:v".into(),
+            "png".into(),
+            "-y".into(),
+            output.display().to_string(),
+        ]);
+        Self {
+            args,
+            output_path: output,
+            fallback: None,
+            // Only read once, by OCR
+            cacheable: false,
+            dump: None,
+        }
+    }
+
+    /// Audio of `sub` as 16 kHz mono WAV, the input speech recognition
+    /// expects, see [`crate::asr`].
+    pub fn speech(sub: &Subtitle) -> Self {
+        let output = temp_path("speech", "wav");
+        let sub = timeline::on_file(sub);
+        let mut args = vec![
+            "-ss".into(),
+            format!("{:.3}", sub.sub_start),
+            "-t".into(),
+            format!("{:.3}", sub.sub_end - sub.sub_start),
+        ];
+        args.extend(stream::input_args(&sub.media_path, true));
+        args.extend([
+            "-map".into(),
+            audio_map(&sub),
+            "-vn".into(),
+            "-ac".into(),
+            "1".into(),
+            "-ar".into(),
+            "16000".into(),
+            "-c:a".into(),
+            "pcm_s16le".into(),
+            "-y".into(),
+            output.display().to_string(),
+        ]);
+        Self {
+            args,
+            output_path: output,
+            fallback: None,
+            // Only read once, by whisper
+            cacheable: false,
+            dump: None,
+        }
+    }
+
+    fn still(sub: &Subtitle, time: f64, config: ImageConfig) -> Self {
+        let config = ImageConfig {
+            is_animated: false,
+            smart_frame: false,
+            ..config
+        };
+        // A still is taken at the line's midpoint
+        let at = Subtitle {
+            sub_start: time,
+            sub_end: time,
+            ..sub.clone()
+        };
+        Self::thumbnail(&at, Some(config))
+    }
+
     pub fn audio(
         sub: &Subtitle,
         offset_start: Option<f64>,
         offset_end: Option<f64>,
         config: Option<AudioConfig>,
     ) -> Self {
-        Self::audio_range(
-            sub.sub_start,
-            sub.sub_end,
-            &sub.media_path,
-            sub.aid,
+        Self::audio_range(sub, sub.sub_end, offset_start, offset_end, config)
+    }
+
+    /// Audio from the start of `sub` to `sub_end`, which may belong to a later
+    /// line.
+    pub fn audio_range(
+        sub: &Subtitle,
+        sub_end: f64,
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+        config: Option<AudioConfig>,
+    ) -> Self {
+        let start = sub.sub_start - offset_start.unwrap_or(DEFAULT_AUDIO_OFFSET) - MPV_DUMP_MARGIN;
+        let end = sub_end + offset_end.unwrap_or(DEFAULT_AUDIO_OFFSET) + MPV_DUMP_MARGIN;
+        let on_file = timeline::on_file(sub);
+        let shift = on_file.sub_start - sub.sub_start;
+        let job = Self::audio_range_job(
+            &on_file,
+            sub_end + shift,
             offset_start,
             offset_end,
-            config,
-        )
+            config.clone(),
+        );
+        let through_mpv = Self::through_mpv(std::slice::from_ref(sub), start, end, true, |subs| {
+            let shift = subs[0].sub_start - sub.sub_start;
+            Self::audio_range_job(&subs[0], sub_end + shift, offset_start, offset_end, config)
+        });
+        // Ends in a chapter moved by a different amount
+        let moved_apart =
+            (timeline::to_file(&sub.media_path, sub_end) - sub_end - shift).abs() > 0.001;
+        if stream::is_mpv_only(&sub.media_path) || moved_apart {
+            return through_mpv;
+        }
+        job.or_else(through_mpv)
     }
 
-    pub fn audio_range(
-        sub_start: f64,
+    fn audio_range_job(
+        sub: &Subtitle,
         sub_end: f64,
-        media_path: &str,
-        aid: i64,
         offset_start: Option<f64>,
         offset_end: Option<f64>,
         config: Option<AudioConfig>,
     ) -> Self {
-        let config = config.unwrap_or_default();
+        let config = config.unwrap_or_default().with_available_format();
         let ext = config.get_extension();
         let output = temp_path("audio", ext);
         let start_offset = offset_start.unwrap_or(DEFAULT_AUDIO_OFFSET);
         let end_offset = offset_end.unwrap_or(DEFAULT_AUDIO_OFFSET);
-        let start = (sub_start - start_offset).max(0.0);
-        let duration = sub_end - sub_start + start_offset + end_offset;
+        let start = (sub.sub_start - start_offset).max(0.0);
+        let duration = sub_end - sub.sub_start + start_offset + end_offset;
 
         debug!(
             "[media] Audio ({}) {:.3}-{:.3} from {}",
             config.format,
             start,
             start + duration,
-            media_path
+            sub.media_path
         );
 
+        // Limits the input, so a slower tempo can't cut the end
         let mut args = vec![
             "-ss".into(),
             format!("{:.3}", start),
-            "-i".into(),
-            media_path.to_string(),
             "-t".into(),
             format!("{:.3}", duration),
-            "-map".into(),
-            format!("0:a:{}", (aid - 1).max(0)),
-            "-vn".into(),
         ];
+        args.extend(stream::input_args(&sub.media_path, true));
+        args.extend(["-map".into(), audio_map(sub), "-vn".into()]);
 
         config.apply_to_args(&mut args);
 
         args.extend(["-y".into(), output.display().to_string()]);
 
+        let fallback = config.center_fallback().map(|mono| {
+            Box::new(Self::audio_range_job(
+                sub,
+                sub_end,
+                offset_start,
+                offset_end,
+                Some(mono),
+            ))
+        });
         Self {
             args,
             output_path: output,
+            fallback,
+            cacheable: true,
+            dump: None,
         }
     }
 
-    pub fn execute(self) -> Option<String> {
-        info!("[media] Running: {} {}", ffmpeg(), self.args.join(" "));
+    /// Audio of several lines back to back, with the silence between them
+    /// cut out. `subs` must be sorted by start time and non-empty.
+    pub fn audio_gapless(
+        subs: &[Subtitle],
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+        config: Option<AudioConfig>,
+    ) -> Self {
+        let start =
+            subs[0].sub_start - offset_start.unwrap_or(DEFAULT_AUDIO_OFFSET) - MPV_DUMP_MARGIN;
+        let end = subs.iter().map(|s| s.sub_end).fold(f64::MIN, f64::max)
+            + offset_end.unwrap_or(DEFAULT_AUDIO_OFFSET)
+            + MPV_DUMP_MARGIN;
+        let on_file: Vec<_> = subs.iter().map(timeline::on_file).collect();
+        let job = Self::audio_gapless_job(&on_file, offset_start, offset_end, config.clone());
+        let through_mpv = Self::through_mpv(subs, start, end, true, |subs| {
+            Self::audio_gapless_job(subs, offset_start, offset_end, config)
+        });
+        // Lines from chapters moved by different amounts aren't one range
+        // of the file
+        let shift = |i: usize| on_file[i].sub_start - subs[i].sub_start;
+        let moved_apart = (1..subs.len()).any(|i| (shift(i) - shift(0)).abs() > 0.001);
+        if stream::is_mpv_only(&subs[0].media_path) || moved_apart {
+            return through_mpv;
+        }
+        job.or_else(through_mpv)
+    }
 
-        let result = Command::new(ffmpeg())
-            .args(&self.args)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::piped())
-            .output();
+    fn audio_gapless_job(
+        subs: &[Subtitle],
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+        config: Option<AudioConfig>,
+    ) -> Self {
+        let config = config.unwrap_or_default().with_available_format();
+        let ext = config.get_extension();
+        let output = temp_path("audio", ext);
+        let first = &subs[0];
+        let start_offset = offset_start.unwrap_or(DEFAULT_AUDIO_OFFSET);
+        let end_offset = offset_end.unwrap_or(DEFAULT_AUDIO_OFFSET);
+        let start = (first.sub_start - start_offset).max(0.0);
+        let end = subs.iter().map(|s| s.sub_end).fold(f64::MIN, f64::max) + end_offset;
 
-        let cleanup = || {
-            let _ = fs::remove_file(&self.output_path);
-        };
+        // Timestamps restart at 0 after input seeking
+        let last = subs.len() - 1;
+        let segments: Vec<_> = subs
+            .iter()
+            .enumerate()
+            .map(|(i, sub)| {
+                let from = if i == 0 { start } else { sub.sub_start };
+                let to = if i == last {
+                    sub.sub_end + end_offset
+                } else {
+                    sub.sub_end
+                };
+                format!("between(t,{:.3},{:.3})", from - start, to - start)
+            })
+            .collect();
+
+        debug!(
+            "[media] Gapless audio ({}) of {} lines {:.3}-{:.3} from {}",
+            config.format,
+            subs.len(),
+            start,
+            end,
+            first.media_path
+        );
+
+        let mut args = vec![
+            "-ss".into(),
+            format!("{:.3}", start),
+            "-t".into(),
+            format!("{:.3}", end - start),
+        ];
+        args.extend(stream::input_args(&first.media_path, true));
+        args.extend(["-map".into(), audio_map(first), "-vn".into()]);
+
+        let select = vec![
+            format!("aselect='{}'", segments.join("+")),
+            "asetpts=N/SR/TB".to_string(),
+        ];
+        config.apply_to_args_with_filters(&mut args, select);
+
+        args.extend(["-y".into(), output.display().to_string()]);
+
+        let fallback = config.center_fallback().map(|mono| {
+            Box::new(Self::audio_gapless_job(
+                subs,
+                offset_start,
+                offset_end,
+                Some(mono),
+            ))
+        });
+        Self {
+            args,
+            output_path: output,
+            fallback,
+            cacheable: true,
+            dump: None,
+        }
+    }
 
-        match result {
-            Ok(out) if out.status.success() => match fs::read(&self.output_path) {
-                Ok(data) if !data.is_empty() => {
-                    cleanup();
-                    Some(base64::engine::general_purpose::STANDARD.encode(&data))
+    /// The job `build` makes for `subs`, run on the part of their source
+    /// between `start` and `end` as cut by a headless mpv: the last resort
+    /// for sources only mpv can play, like ordered chapters or `edl://`.
+    /// `build` gets the lines with their times shifted into that cut.
+    fn through_mpv(
+        subs: &[Subtitle],
+        start: f64,
+        end: f64,
+        audio: bool,
+        build: impl FnOnce(&[Subtitle]) -> Self,
+    ) -> Self {
+        let start = start.max(0.0);
+        let path = temp_path("dump", if audio { "wav" } else { "mkv" });
+        let shifted: Vec<_> = subs
+            .iter()
+            .map(|sub| Subtitle {
+                sub_start: sub.sub_start - start,
+                sub_end: sub.sub_end - start,
+                media_path: path.display().to_string(),
+                // The cut has just the one track
+                aid: 1,
+                ff_index: None,
+                ..sub.clone()
+            })
+            .collect();
+        let mut job = build(&shifted);
+        job.dump = Some(MpvDump {
+            source: subs[0].media_path.clone(),
+            start,
+            length: end - start,
+            aid: audio.then_some(subs[0].aid.max(1)),
+            path,
+        });
+        job
+    }
+
+    /// Appends `last` to the end of the fallback chain.
+    fn or_else(mut self, last: Self) -> Self {
+        let mut tail = &mut self;
+        while tail.fallback.is_some() {
+            tail = tail.fallback.as_mut().unwrap();
+        }
+        tail.fallback = Some(Box::new(last));
+        self
+    }
+
+    /// Short hash of everything that determines the output: the source and
+    /// when it last changed, the times and the encoding settings. Cutting
+    /// the same media again gives the same key; replacing the file (a new
+    /// release under the same name) gives a new one.
+    pub fn content_key(&self) -> String {
+        let mut hasher = Sha256::new();
+        // A cut by mpv goes to a random path as well, so it counts by what
+        // it cuts instead
+        let dump_path = self.dump.as_ref().map(|dump| {
+            hasher.update(
+                format!(
+                    "{} {:.3} {:.3} {:?} {}",
+                    dump.source,
+                    dump.start,
+                    dump.length,
+                    dump.aid,
+                    file_stamp(&dump.source)
+                )
+                .as_bytes(),
+            );
+            dump.path.display().to_string()
+        });
+        // All but the random output path
+        let args = &self.args[..self.args.len() - 1];
+        for (i, arg) in args.iter().enumerate() {
+            if Some(arg) != dump_path.as_ref() {
+                hasher.update(arg.as_bytes());
+                if i > 0 && args[i - 1] == "-i" {
+                    hasher.update(file_stamp(arg).as_bytes());
                 }
-                _ => {
-                    warn!(
-                        "[media] ffmpeg succeeded but output file is empty or missing: {}",
-                        self.output_path.display()
-                    );
-                    cleanup();
-                    None
+            }
+            hasher.update([0]);
+        }
+        hasher.finalize()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Extension of the file ffmpeg writes, i.e. the format actually used
+    /// after any fallback.
+    pub fn extension(&self) -> &str {
+        self.output_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+    }
+
+    /// The command lines this job runs, shell-quoted: the first attempt
+    /// (mpv's cut and ffmpeg joined by `&&` when it goes through mpv), then
+    /// each fallback in the order they are tried.
+    pub fn commands(&self) -> Vec<String> {
+        let mut commands = Vec::new();
+        let mut job = Some(self);
+        while let Some(current) = job {
+            let ffmpeg = command_line(&current.ffmpeg_command());
+            commands.push(match &current.dump {
+                Some(dump) => format!("{} && {}", command_line(&dump.command()), ffmpeg),
+                None => ffmpeg,
+            });
+            job = current.fallback.as_deref();
+        }
+        commands
+    }
+
+    /// Runs ffmpeg and returns the raw output bytes. Jobs running longer than
+    /// the configured timeout (see [`init_ffmpeg_timeout`]) are killed.
+    /// Served from the media cache instead if it has this output.
+    pub fn run(self) -> Result<Vec<u8>, FfmpegError> {
+        if DRY_RUN.load(Ordering::SeqCst) {
+            return Err(self.dry_run());
+        }
+        let _running = JobGuard::new();
+        let cache_name = self
+            .cacheable
+            .then(|| format!("{}.{}", self.content_key(), self.extension()));
+        if let Some(data) = cache_name.as_deref().and_then(cache::get) {
+            METRICS.cache_hit();
+            return Ok(data);
+        }
+
+        let result = self.run_uncached();
+        if let (Some(name), Ok(data)) = (&cache_name, &result) {
+            cache::put(name, data);
+        }
+        result
+    }
+
+    fn run_uncached(mut self) -> Result<Vec<u8>, FfmpegError> {
+        if let Some(dump) = self.dump.take() {
+            return self.run_on_dump(dump);
+        }
+        let span = info_span!("ffmpeg", format = self.extension()).entered();
+        info!("[media] Running: {}", command_line(&self.ffmpeg_command()));
+
+        let started = Instant::now();
+        let result = self.run_ffmpeg();
+        let _ = fs::remove_file(&self.output_path);
+        METRICS.ffmpeg_run(started.elapsed(), result.is_ok());
+        match &result {
+            Err(e @ (FfmpegError::EmptyOutput | FfmpegError::Failed { .. }))
+                if self.fallback.is_some() =>
+            {
+                info!("[media] {}, falling back to a plainer job", e);
+            }
+            Err(e @ FfmpegError::Failed { stderr, .. }) => warn!("[media] {}: {}", e, stderr),
+            Err(e) => warn!("[media] {}", e),
+            Ok(_) => {}
+        }
+        drop(span);
+        match (result, self.fallback) {
+            (Err(e @ (FfmpegError::EmptyOutput | FfmpegError::Failed { .. })), Some(fallback)) => {
+                // Worth reporting what ffmpeg said rather than mpv
+                let last_resort = fallback.dump.is_some();
+                match fallback.run_uncached() {
+                    Err(_) if last_resort => Err(e),
+                    result => result,
                 }
-            },
-            Ok(out) => {
-                let stderr = String::from_utf8_lossy(&out.stderr);
-                let errors: Vec<_> = stderr.lines().rev().take(10).collect();
-                warn!(
-                    "[media] ffmpeg failed ({}): {}",
-                    out.status,
-                    errors.into_iter().rev().collect::<Vec<_>>().join(" | ")
-                );
-                cleanup();
-                None
             }
+            (result, _) => result,
+        }
+    }
+
+    /// Has mpv cut `dump`, then runs this job (and its fallbacks) on it.
+    fn run_on_dump(self, dump: MpvDump) -> Result<Vec<u8>, FfmpegError> {
+        let command = dump.command();
+        info!("[media] Running: {}", command_line(&command));
+        let result = run_process(command).and_then(|()| match fs::metadata(&dump.path) {
+            Ok(meta) if meta.len() > 0 => Ok(()),
+            _ => Err(FfmpegError::EmptyOutput),
+        });
+        let result = match result {
+            Ok(()) => self.run_uncached(),
             Err(e) => {
-                warn!("[media] ffmpeg failed to start: {}", e);
-                cleanup();
-                None
+                // The error's own wording is about ffmpeg
+                let detail = match &e {
+                    FfmpegError::Spawn(e) => e.to_string(),
+                    FfmpegError::Failed { status, stderr } => format!("{} ({})", stderr, status),
+                    FfmpegError::EmptyOutput => "no output".into(),
+                    other => other.to_string(),
+                };
+                warn!("[media] mpv couldn't cut {}: {}", dump.source, detail);
+                Err(e)
+            }
+        };
+        let _ = fs::remove_file(&dump.path);
+        result
+    }
+
+    /// [`FfmpegError::DryRun`] with [`Self::commands`], which are logged.
+    pub fn dry_run(&self) -> FfmpegError {
+        let commands = self.commands();
+        for command in &commands {
+            info!("[media] Dry run: {}", command);
+        }
+        FfmpegError::DryRun(commands)
+    }
+
+    fn ffmpeg_command(&self) -> Command {
+        let mut command = ffmpeg_tool(ffmpeg());
+        command.args(&self.args);
+        command
+    }
+
+    fn run_ffmpeg(&self) -> Result<Vec<u8>, FfmpegError> {
+        run_process(self.ffmpeg_command())?;
+        match fs::read(&self.output_path) {
+            Ok(data) if !data.is_empty() => Ok(data),
+            _ => Err(FfmpegError::EmptyOutput),
+        }
+    }
+}
+
+/// Counts a job in [`running_jobs`] until dropped.
+struct JobGuard;
+
+impl JobGuard {
+    fn new() -> Self {
+        RUNNING_JOBS.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        RUNNING_JOBS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Media jobs still running, including their fallbacks and cleanup.
+pub fn running_jobs() -> usize {
+    RUNNING_JOBS.load(Ordering::SeqCst)
+}
+
+/// Kills the processes of running jobs (ffmpeg, mpv and whatever else went
+/// through [`run_killable`]) and fails any job started from now on. The
+/// jobs still remove their temp files.
+pub fn cancel_jobs() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Processes in [`wait_killable`] right now.
+pub fn running_processes() -> usize {
+    RUNNING_PROCESSES.load(Ordering::SeqCst)
+}
+
+/// `command` as a shell would take it.
+fn command_line(command: &Command) -> String {
+    let words: Vec<_> = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|word| word.to_string_lossy())
+        .collect();
+    shlex::try_join(words.iter().map(|word| word.as_ref())).unwrap_or_else(|_| words.join(" "))
+}
+
+/// Runs `command` until it exits or the ffmpeg timeout passes.
+fn run_process(command: Command) -> Result<(), FfmpegError> {
+    let timeout = ffmpeg_timeout();
+    let output = run_killable(command, None, timeout).map_err(|e| match e.kind() {
+        std::io::ErrorKind::TimedOut => FfmpegError::TimedOut(timeout),
+        std::io::ErrorKind::Interrupted => FfmpegError::Cancelled,
+        _ => FfmpegError::Spawn(e),
+    })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let errors: Vec<_> = stderr.lines().rev().take(10).collect();
+        return Err(FfmpegError::Failed {
+            status: output.status.to_string(),
+            stderr: errors.into_iter().rev().collect::<Vec<_>>().join(" | "),
+        });
+    }
+    Ok(())
+}
+
+/// Runs `command` with `input` on its stdin and returns what it printed.
+/// It is killed after `timeout` (a `TimedOut` error) or when the server
+/// shuts down (`Interrupted`, see [`cancel_jobs`]), so a stuck ffmpeg,
+/// whisper or mecab can't hold up a job forever.
+pub fn run_killable(
+    mut command: Command,
+    input: Option<Vec<u8>>,
+    timeout: Duration,
+) -> std::io::Result<Output> {
+    if CANCELLED.load(Ordering::SeqCst) {
+        return Err(cancelled());
+    }
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Fed and drained on the side so neither end can block on a full pipe
+    let stdin = child.stdin.take();
+    let writer = std::thread::spawn(move || {
+        if let (Some(mut stdin), Some(input)) = (stdin, input) {
+            let _ = std::io::Write::write_all(&mut stdin, &input);
+        }
+    });
+    let drain = |pipe: Option<Box<dyn std::io::Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut data = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut data);
+            }
+            data
+        })
+    };
+    let stdout = drain(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = drain(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let status = wait_killable(&mut child, &program, timeout)?;
+    let _ = writer.join();
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Waits for `child` (started from `program`) to exit, killing it after
+/// `timeout` or when the server shuts down, like [`run_killable`], for
+/// processes whose output is handled some other way.
+pub fn wait_killable(
+    child: &mut Child,
+    program: &str,
+    timeout: Duration,
+) -> std::io::Result<ExitStatus> {
+    RUNNING_PROCESSES.fetch_add(1, Ordering::SeqCst);
+    let started = Instant::now();
+    let result = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) if started.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!(
+                        "{} timed out after {}s and was killed",
+                        program,
+                        timeout.as_secs_f64()
+                    ),
+                ));
+            }
+            Ok(None) if CANCELLED.load(Ordering::SeqCst) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break Err(cancelled());
             }
+            Ok(None) => std::thread::sleep(Duration::from_millis(20)),
+            Err(e) => break Err(e),
         }
+    };
+    RUNNING_PROCESSES.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+fn cancelled() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Interrupted,
+        "cancelled, the server is shutting down",
+    )
+}
+
+/// Why an ffmpeg job produced nothing.
+#[derive(Debug)]
+pub enum FfmpegError {
+    /// ffmpeg could not be started
+    Spawn(std::io::Error),
+    /// ffmpeg exited with an error, with the tail of its stderr
+    Failed { status: String, stderr: String },
+    /// ffmpeg succeeded but wrote nothing
+    EmptyOutput,
+    /// ffmpeg ran longer than the configured timeout and was killed
+    TimedOut(Duration),
+    /// The job panicked before ffmpeg finished
+    Panicked,
+    /// The server is shutting down
+    Cancelled,
+    /// Nothing was run, see [`set_dry_run`]; the commands that would have
+    /// been, from [`FfmpegRequest::commands`]
+    DryRun(Vec<String>),
+}
+
+impl FfmpegError {
+    /// The last lines ffmpeg printed, if it ran and failed.
+    pub fn stderr(&self) -> Option<&str> {
+        match self {
+            Self::Failed { stderr, .. } => Some(stderr),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FfmpegError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Spawn(e) => write!(f, "ffmpeg failed to start: {}", e),
+            Self::Failed { status, .. } => write!(f, "ffmpeg failed ({})", status),
+            Self::EmptyOutput => write!(f, "ffmpeg succeeded but output file is empty or missing"),
+            Self::TimedOut(timeout) => {
+                write!(
+                    f,
+                    "ffmpeg timed out after {}s and was killed",
+                    timeout.as_secs_f64()
+                )
+            }
+            Self::Panicked => write!(f, "media job panicked"),
+            Self::Cancelled => write!(f, "media job cancelled, the server is shutting down"),
+            Self::DryRun(_) => write!(f, "dry run, ffmpeg was not started"),
+        }
+    }
+}
+
+impl std::error::Error for FfmpegError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atempo_stays_in_range() {
+        assert_eq!(atempo_chain(1.5), ["atempo=1.5000"]);
+        assert_eq!(atempo_chain(0.25), ["atempo=0.5", "atempo=0.5000"]);
+        assert_eq!(atempo_chain(4.0), ["atempo=2.0", "atempo=2.0000"]);
+        assert_eq!(atempo_chain(3.0), ["atempo=2.0", "atempo=1.5000"]);
+    }
+
+    fn line(path: &Path, start: f64) -> Subtitle {
+        Subtitle {
+            text: "line".into(),
+            media_path: path.display().to_string(),
+            sub_start: start,
+            sub_end: start + 2.0,
+            aid: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn content_key_follows_what_is_cut() {
+        let path = std::env::temp_dir().join(format!("content_key_{}.mkv", uuid::Uuid::new_v4()));
+        std::fs::write(&path, b"first").unwrap();
+        let key = |start| FfmpegRequest::audio(&line(&path, start), None, None, None).content_key();
+
+        // Output paths are random, keys aren't
+        let first = key(10.0);
+        assert_eq!(first, key(10.0));
+        assert_ne!(first, key(12.0));
+        // A new file under the same name
+        std::fs::write(&path, b"replaced").unwrap();
+        assert_ne!(first, key(10.0));
+        std::fs::remove_file(&path).unwrap();
     }
 }
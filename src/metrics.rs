@@ -0,0 +1,186 @@
+//! Counters for the `/metrics` endpoint, in Prometheus' text format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Kinds of media requests clients can make.
+pub const MEDIA_KINDS: [&str; 9] = [
+    "thumbnail",
+    "thumbnail_candidates",
+    "frame_at",
+    "current_frame",
+    "audio",
+    "audio_preview",
+    "audio_range",
+    "merge",
+    "batch_extract",
+];
+
+/// Upper bounds of the ffmpeg duration histogram, in seconds.
+const FFMPEG_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+pub struct Metrics {
+    subtitles_captured: AtomicU64,
+    clients_connected: AtomicI64,
+    media_requests: [AtomicU64; MEDIA_KINDS.len()],
+    media_failures: [AtomicU64; MEDIA_KINDS.len()],
+    ffmpeg_runs: AtomicU64,
+    cache_hits: AtomicU64,
+    ffmpeg_failures: AtomicU64,
+    ffmpeg_micros: AtomicU64,
+    ffmpeg_buckets: [AtomicU64; FFMPEG_BUCKETS.len()],
+}
+
+pub static METRICS: Metrics = Metrics {
+    subtitles_captured: AtomicU64::new(0),
+    clients_connected: AtomicI64::new(0),
+    media_requests: [const { AtomicU64::new(0) }; MEDIA_KINDS.len()],
+    media_failures: [const { AtomicU64::new(0) }; MEDIA_KINDS.len()],
+    ffmpeg_runs: AtomicU64::new(0),
+    cache_hits: AtomicU64::new(0),
+    ffmpeg_failures: AtomicU64::new(0),
+    ffmpeg_micros: AtomicU64::new(0),
+    ffmpeg_buckets: [const { AtomicU64::new(0) }; FFMPEG_BUCKETS.len()],
+};
+
+/// Counts a connected client until dropped, see [`Metrics::client_connected`].
+pub struct ClientGuard(());
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        METRICS.clients_connected.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn client_connected(&self) -> ClientGuard {
+        self.clients_connected.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(())
+    }
+
+    /// Clients connected right now.
+    pub fn connected_clients(&self) -> i64 {
+        self.clients_connected.load(Ordering::Relaxed)
+    }
+
+    pub fn subtitle_captured(&self) {
+        self.subtitles_captured.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a media request of `kind` (one of [`MEDIA_KINDS`]) and whether
+    /// it produced anything.
+    pub fn media_request(&self, kind: &str, ok: bool) {
+        let Some(i) = MEDIA_KINDS.iter().position(|k| *k == kind) else {
+            return;
+        };
+        self.media_requests[i].fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.media_failures[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ffmpeg_run(&self, elapsed: Duration, ok: bool) {
+        self.ffmpeg_runs.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.ffmpeg_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.ffmpeg_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        let secs = elapsed.as_secs_f64();
+        for (bound, count) in FFMPEG_BUCKETS.iter().zip(&self.ffmpeg_buckets) {
+            if secs <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Everything in Prometheus' text exposition format.
+    pub fn render(&self) -> String {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP subtitleminer_subtitles_captured_total Lines received from mpv.\n\
+             # TYPE subtitleminer_subtitles_captured_total counter\n\
+             subtitleminer_subtitles_captured_total {}",
+            get(&self.subtitles_captured)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP subtitleminer_clients_connected WebSocket clients currently connected.\n\
+             # TYPE subtitleminer_clients_connected gauge\n\
+             subtitleminer_clients_connected {}",
+            self.clients_connected.load(Ordering::Relaxed)
+        );
+
+        for (name, help, counts) in [
+            (
+                "media_requests_total",
+                "Media requests by type.",
+                &self.media_requests,
+            ),
+            (
+                "media_failures_total",
+                "Media requests that produced nothing, by type.",
+                &self.media_failures,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP subtitleminer_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE subtitleminer_{} counter", name);
+            for (kind, count) in MEDIA_KINDS.iter().zip(counts) {
+                let _ = writeln!(
+                    out,
+                    "subtitleminer_{}{{type=\"{}\"}} {}",
+                    name,
+                    kind,
+                    get(count)
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP subtitleminer_cache_hits_total Media served from the disk cache without running ffmpeg.\n\
+             # TYPE subtitleminer_cache_hits_total counter\n\
+             subtitleminer_cache_hits_total {}",
+            get(&self.cache_hits)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP subtitleminer_ffmpeg_failures_total ffmpeg runs that failed or produced no output.\n\
+             # TYPE subtitleminer_ffmpeg_failures_total counter\n\
+             subtitleminer_ffmpeg_failures_total {}",
+            get(&self.ffmpeg_failures)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP subtitleminer_ffmpeg_duration_seconds Time spent per ffmpeg run.\n\
+             # TYPE subtitleminer_ffmpeg_duration_seconds histogram"
+        );
+        for (bound, count) in FFMPEG_BUCKETS.iter().zip(&self.ffmpeg_buckets) {
+            let _ = writeln!(
+                out,
+                "subtitleminer_ffmpeg_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                get(count)
+            );
+        }
+        let runs = get(&self.ffmpeg_runs);
+        let _ = writeln!(
+            out,
+            "subtitleminer_ffmpeg_duration_seconds_bucket{{le=\"+Inf\"}} {}\n\
+             subtitleminer_ffmpeg_duration_seconds_sum {}\n\
+             subtitleminer_ffmpeg_duration_seconds_count {}",
+            runs,
+            get(&self.ffmpeg_micros) as f64 / 1e6,
+            runs
+        );
+        out
+    }
+}
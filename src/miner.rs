@@ -0,0 +1,302 @@
+use futures_util::FutureExt;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::asr::AsrOptions;
+use crate::clipboard::ClipboardOptions;
+use crate::event_loop::{
+    ServerEvent, SharedState, Subtitle, get_mpv_pid, handle_mpv, panic_payload_message,
+};
+use crate::filter::{SongLines, Suppression, TextFilter};
+use crate::hooks::HookOptions;
+use crate::media::{AudioConfig, FfmpegRequest, ImageConfig};
+use crate::morph::MorphOptions;
+use crate::mpv_stream::MpvStream;
+use crate::ocr::OcrOptions;
+use crate::offline::AnkiTarget;
+use crate::translate::TranslateOptions;
+
+/// How long either side of `playback-time` a line is assumed to last when mpv
+/// reports no timing for it.
+pub const DEFAULT_TIMING_WINDOW: f64 = 2.0;
+
+/// How old a note `mine_to_last_card` still updates.
+pub const DEFAULT_LAST_CARD_WINDOW: Duration = Duration::from_secs(300);
+
+/// Settings for [`SubtitleMiner::start`].
+#[derive(Debug, Clone)]
+pub struct MinerOptions {
+    /// Fail unless the socket belongs to this mpv process
+    pub expected_mpv_pid: Option<u32>,
+    /// Seconds before and after `playback-time` used for lines without timing
+    pub timing_window: f64,
+    /// How long "Card added" stays on mpv's OSD, 0 to not show it
+    pub mined_osd_ms: u64,
+    /// Where `mine` requests and the mpv hotkey add notes; mining is
+    /// refused when unset
+    pub anki: Option<AnkiTarget>,
+    /// Show each note `mine` and the mpv hotkey add in Anki's browser,
+    /// unless a request says otherwise
+    pub open_mined: bool,
+    /// Where notes wait while Anki is unreachable, to be added once it
+    /// answers again; they fail instead when unset
+    pub anki_queue_dir: Option<PathBuf>,
+    /// Where mining of each file left off and its settings are remembered;
+    /// nothing is when unset
+    pub resume_file: Option<PathBuf>,
+    /// JSON file of settings, see [`crate::config`]
+    pub config: Option<PathBuf>,
+    /// Apply changes made to [`Self::config`] without a restart
+    pub watch_config: bool,
+    /// How recently the note `mine_to_last_card` updates must have been
+    /// added; older ones are refused so unrelated cards aren't overwritten
+    pub last_card_window: Duration,
+    /// What to do with lines in OP/ED chapters and song ranges
+    pub song_lines: SongLines,
+    /// Removed from every line before it is sent, until a client replaces them
+    pub filters: Vec<TextFilter>,
+    /// Lines not sent at all, e.g. too short or only symbols
+    pub suppress: Suppression,
+    /// Count a line whose text was already captured in the session as
+    /// another occurrence of it instead of sending it again
+    pub collapse_duplicates: bool,
+    /// Copy every new line to the system clipboard
+    pub clipboard: Option<ClipboardOptions>,
+    /// Commands run on new lines and added notes
+    pub hooks: HookOptions,
+    /// Vault directory mined lines are logged to as Markdown, one file per
+    /// show
+    pub markdown_dir: Option<PathBuf>,
+    /// Include the tail of ffmpeg's stderr in failed media responses
+    pub ffmpeg_stderr: bool,
+    /// Read image subtitles (PGS, VobSub) with OCR; they are skipped when
+    /// unset
+    pub ocr: Option<OcrOptions>,
+    /// Transcribe audio with whisper.cpp on request; refused when unset
+    pub asr: Option<AsrOptions>,
+    /// Translate every line; lines go out untranslated when unset
+    pub translate: Option<TranslateOptions>,
+    /// Split every line into words with readings and dictionary forms
+    pub morph: Option<MorphOptions>,
+}
+
+impl Default for MinerOptions {
+    fn default() -> Self {
+        Self {
+            expected_mpv_pid: None,
+            timing_window: DEFAULT_TIMING_WINDOW,
+            mined_osd_ms: 2000,
+            anki: None,
+            open_mined: false,
+            anki_queue_dir: None,
+            resume_file: None,
+            config: None,
+            watch_config: false,
+            last_card_window: DEFAULT_LAST_CARD_WINDOW,
+            song_lines: SongLines::default(),
+            filters: Vec::new(),
+            suppress: Suppression::default(),
+            collapse_duplicates: false,
+            clipboard: None,
+            hooks: HookOptions::default(),
+            markdown_dir: None,
+            ffmpeg_stderr: false,
+            ocr: None,
+            asr: None,
+            translate: None,
+            morph: None,
+        }
+    }
+}
+
+/// A connection to mpv that captures subtitles and cuts media on demand,
+/// independent of any network server.
+///
+/// Dropping the miner stops observing mpv. If capturing panics, the miner
+/// reports it to subscribers and reconnects to the socket.
+pub struct SubtitleMiner {
+    pub(crate) state: Arc<SharedState>,
+    mpv_task: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl SubtitleMiner {
+    /// Connects to the mpv IPC socket at `socket_path` and starts capturing.
+    ///
+    /// If `options.expected_mpv_pid` is set, fails unless the socket belongs
+    /// to that mpv process.
+    pub async fn start(socket_path: &str, options: MinerOptions) -> std::io::Result<Self> {
+        let mut mpv = MpvStream::connect(socket_path).await?;
+        if let Some(expected) = options.expected_mpv_pid {
+            let actual = get_mpv_pid(&mut mpv).await?;
+            if actual != expected {
+                return Err(std::io::Error::other(format!(
+                    "MPV_IPC_PID_MISMATCH expected={} actual={} socket={}",
+                    expected, actual, socket_path
+                )));
+            }
+        }
+
+        let clipboard = options.clipboard.clone();
+        let on_subtitle = options.hooks.on_subtitle.clone();
+        let config = options.config.clone().filter(|_| options.watch_config);
+        let (state, mut commands) = SharedState::new(options);
+        if let Some(clipboard) = clipboard {
+            tokio::spawn(crate::clipboard::mirror(
+                state.events.subscribe(),
+                clipboard,
+            ));
+        }
+        if let Some(command) = on_subtitle {
+            tokio::spawn(crate::hooks::on_subtitles(
+                state.events.subscribe(),
+                command,
+            ));
+        }
+        if state.anki_queue.is_some() {
+            // Weak, so the retries end with the miner
+            let queue_state = Arc::downgrade(&state);
+            tokio::spawn(async move {
+                while let Some(state) = queue_state.upgrade() {
+                    state.flush_anki_queue().await;
+                    drop(state);
+                    tokio::time::sleep(crate::anki_queue::RETRY_INTERVAL).await;
+                }
+            });
+        }
+        if let Some(path) = config {
+            match crate::config::Watcher::new(&path) {
+                Ok(mut watcher) => {
+                    let config_state = Arc::downgrade(&state);
+                    tokio::spawn(async move {
+                        loop {
+                            if let Err(e) = watcher.changed().await {
+                                warn!("[config] Stopped watching {}: {}", path.display(), e);
+                                break;
+                            }
+                            let Some(state) = config_state.upgrade() else {
+                                break;
+                            };
+                            state.reload_config().await;
+                        }
+                    });
+                }
+                Err(e) => warn!("[config] Can't watch {} for changes: {}", path.display(), e),
+            }
+        }
+        let task_state = state.clone();
+        let socket_path = socket_path.to_string();
+        let mpv_task = tokio::spawn(async move {
+            let mut mpv = Some(mpv);
+            loop {
+                let conn = match mpv.take() {
+                    Some(conn) => conn,
+                    None => MpvStream::connect(&socket_path).await?,
+                };
+                let run = handle_mpv(conn, task_state.clone(), &mut commands);
+                match AssertUnwindSafe(run).catch_unwind().await {
+                    Ok(result) => return result,
+                    Err(payload) => {
+                        let message =
+                            format!("mpv handler panicked: {}", panic_payload_message(&*payload));
+                        error!("{}", message);
+                        let _ = task_state.events.send(ServerEvent::Diagnostic { message });
+                        info!("Reconnecting to mpv at {}", socket_path);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            state,
+            mpv_task: Some(mpv_task),
+        })
+    }
+
+    /// Stream of captured subtitles and file changes.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.state.events.subscribe()
+    }
+
+    /// Whether the mpv connection is still alive.
+    pub fn is_running(&self) -> bool {
+        self.mpv_task.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    /// Stops observing mpv. Captured subtitles stay available.
+    pub fn stop(&self) {
+        if let Some(task) = &self.mpv_task {
+            task.abort();
+        }
+    }
+
+    /// Waits until the mpv connection ends, returning its error if any.
+    pub async fn closed(&mut self) -> std::io::Result<()> {
+        let Some(task) = self.mpv_task.take() else {
+            return Ok(());
+        };
+        match task.await {
+            Ok(result) => result,
+            Err(e) if e.is_cancelled() => Ok(()),
+            Err(e) => Err(std::io::Error::other(e)),
+        }
+    }
+
+    /// Runs an mpv IPC command and returns mpv's raw response.
+    pub async fn command(&self, args: serde_json::Value) -> Option<serde_json::Value> {
+        self.state.mpv_command(args).await
+    }
+
+    /// Shows `text` on mpv's OSD.
+    pub fn show_text(&self, text: &str, duration_ms: u64) {
+        self.state.show_text(text, duration_ms);
+    }
+
+    pub async fn subtitle(&self, id: u64) -> Option<Subtitle> {
+        self.state.subtitles.read().await.get(&id).cloned()
+    }
+
+    /// All captured subtitles in capture order.
+    pub async fn subtitles(&self) -> Vec<Subtitle> {
+        self.state.sorted_subtitles().await
+    }
+
+    /// Up to `before`/`after` lines around `id` from the same file.
+    pub async fn neighbors(&self, id: u64, before: usize, after: usize) -> Option<Vec<Subtitle>> {
+        self.state.neighbors(id, before, after).await
+    }
+
+    /// Encodes a still (or animated) image for subtitle `id`.
+    pub async fn thumbnail(&self, id: u64, config: Option<ImageConfig>) -> Option<Vec<u8>> {
+        let sub = self.subtitle(id).await?;
+        run(FfmpegRequest::thumbnail(&sub, config)).await
+    }
+
+    /// Cuts the audio for subtitle `id`, padded by the given offsets.
+    pub async fn audio(
+        &self,
+        id: u64,
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+        config: Option<AudioConfig>,
+    ) -> Option<Vec<u8>> {
+        let sub = self.subtitle(id).await?;
+        run(FfmpegRequest::audio(&sub, offset_start, offset_end, config)).await
+    }
+}
+
+impl Drop for SubtitleMiner {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+async fn run(req: FfmpegRequest) -> Option<Vec<u8>> {
+    tokio::task::spawn_blocking(move || req.run().ok())
+        .await
+        .ok()?
+}
@@ -0,0 +1,164 @@
+//! Morphological analysis of Japanese lines: words with their dictionary
+//! forms and readings, from a tokenizer printing MeCab's format (MeCab
+//! itself, or `lindera tokenize`, with an IPADIC-style dictionary).
+//!
+//! The tokenizer runs as a command rather than being linked in as the
+//! lindera or vibrato crate, so any MeCab-compatible one works and the
+//! server doesn't carry a dictionary.
+
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::time::Duration;
+use tracing::debug;
+
+/// Generous even for a tokenizer that loads its dictionary on every run;
+/// a line's words follow it as an update once it is done.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How lines are tokenized.
+#[derive(Debug, Clone)]
+pub struct MorphOptions {
+    /// Tokenizer command line; gets the text on stdin
+    pub command: String,
+}
+
+impl Default for MorphOptions {
+    fn default() -> Self {
+        Self {
+            command: "mecab".into(),
+        }
+    }
+}
+
+/// A word of a line. Line breaks are tokens of their own, with no part of
+/// speech.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    /// The word as written in the line
+    pub surface: String,
+    /// Part of speech, e.g. `名詞` or `動詞`
+    pub pos: String,
+    /// Dictionary form, e.g. `食べる` for `食べ`
+    pub base: Option<String>,
+    /// Reading in hiragana
+    pub reading: Option<String>,
+}
+
+/// Splits `text` into words. Blocks until the tokenizer exits, or is killed
+/// after [`TIMEOUT`].
+pub fn tokenize(text: &str, options: &MorphOptions) -> std::io::Result<Vec<Token>> {
+    let args = shlex::split(&options.command)
+        .filter(|args| !args.is_empty())
+        .ok_or_else(|| std::io::Error::other("invalid --tokenizer-command"))?;
+    debug!("[morph] Running {}", options.command);
+    let mut command = Command::new(&args[0]);
+    command.args(&args[1..]);
+    let input = format!("{}\n", text).into_bytes();
+    let output = crate::media::run_killable(command, Some(input), TIMEOUT)?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "tokenizer failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Reads MeCab's `surface\tpos,..,base,reading,..` lines, one sentence per
+/// `EOS`.
+fn parse(output: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for line in output.lines() {
+        if line == "EOS" {
+            tokens.push(Token {
+                surface: "\n".into(),
+                ..Default::default()
+            });
+            continue;
+        }
+        let Some((surface, features)) = line.split_once('\t') else {
+            continue;
+        };
+        let features: Vec<&str> = features.split(',').collect();
+        let feature = |i: usize| {
+            features
+                .get(i)
+                .filter(|f| !f.is_empty() && **f != "*")
+                .map(|f| f.to_string())
+        };
+        tokens.push(Token {
+            surface: surface.to_string(),
+            pos: feature(0).unwrap_or_default(),
+            base: feature(6),
+            reading: feature(7).map(|r| to_hiragana(&r)),
+        });
+    }
+    // The line break after the last sentence
+    if tokens.last().is_some_and(|t| t.surface == "\n") {
+        tokens.pop();
+    }
+    tokens
+}
+
+/// `text` with katakana turned into hiragana.
+pub fn to_hiragana(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            'ァ'..='ヶ' => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+            _ => c,
+        })
+        .collect()
+}
+
+fn is_kanji(c: char) -> bool {
+    matches!(c, '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' | '々')
+}
+
+/// The line in Anki's furigana syntax, e.g. `お 茶[ちゃ]を 飲[の]む`, with
+/// readings only over the kanji.
+pub fn furigana(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    for token in tokens {
+        let surface: Vec<char> = token.surface.chars().collect();
+        let reading: Vec<char> = match &token.reading {
+            Some(reading) if surface.iter().any(|&c| is_kanji(c)) => reading.chars().collect(),
+            _ => {
+                out.push_str(&token.surface);
+                continue;
+            }
+        };
+        // Kana around the kanji are written as they are
+        let same = |a: &char, b: &char| to_hiragana(&a.to_string()) == b.to_string();
+        let max = surface.len().min(reading.len());
+        let prefix = surface
+            .iter()
+            .zip(&reading)
+            .take_while(|(a, b)| same(a, b))
+            .count()
+            .min(max);
+        let suffix = surface
+            .iter()
+            .rev()
+            .zip(reading.iter().rev())
+            .take(max - prefix)
+            .take_while(|(a, b)| same(a, b))
+            .count();
+        if prefix + suffix >= surface.len() || prefix + suffix >= reading.len() {
+            out.push_str(&token.surface);
+            continue;
+        }
+        let text = |chars: &[char]| chars.iter().collect::<String>();
+        out.push_str(&text(&surface[..prefix]));
+        // Anki puts the reading over everything back to the last space
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push(' ');
+        }
+        out.push_str(&text(&surface[prefix..surface.len() - suffix]));
+        out.push('[');
+        out.push_str(&text(&reading[prefix..reading.len() - suffix]));
+        out.push(']');
+        out.push_str(&text(&surface[surface.len() - suffix..]));
+    }
+    out
+}
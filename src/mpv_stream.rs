@@ -1,5 +1,5 @@
 use std::io::Result;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
 
 #[cfg(unix)]
 type Inner = tokio::net::UnixStream;
@@ -8,7 +8,7 @@ type Inner = tokio::net::UnixStream;
 type Inner = tokio::net::windows::named_pipe::NamedPipeClient;
 
 pub struct MpvStream {
-    reader: BufReader<tokio::io::ReadHalf<Inner>>,
+    reader: Lines<BufReader<tokio::io::ReadHalf<Inner>>>,
     writer: tokio::io::WriteHalf<Inner>,
 }
 
@@ -17,13 +17,14 @@ impl MpvStream {
         let stream = Self::connect_inner(path).await?;
         let (reader, writer) = tokio::io::split(stream);
         Ok(Self {
-            reader: BufReader::new(reader),
+            reader: BufReader::new(reader).lines(),
             writer,
         })
     }
 
-    pub async fn read_line(&mut self, buf: &mut String) -> Result<usize> {
-        self.reader.read_line(buf).await
+    /// Reads the next message line, `None` on EOF. Cancel safe.
+    pub async fn next_line(&mut self) -> Result<Option<String>> {
+        self.reader.next_line().await
     }
 
     pub async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
@@ -0,0 +1,104 @@
+//! Text recognition for image-based subtitles (PGS, VobSub), which leave
+//! mpv's `sub-text` empty.
+
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+use tracing::debug;
+
+/// Longer than any single subtitle image takes; a stuck OCR program is
+/// killed after this.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// mpv's codec names for subtitles that are pictures rather than text.
+pub const IMAGE_CODECS: &[&str] = &["hdmv_pgs_subtitle", "dvd_subtitle", "dvb_subtitle", "xsub"];
+
+/// Languages written without spaces, which Tesseract puts between every
+/// character anyway.
+const UNSPACED_LANGS: &[&str] = &[
+    "jpn",
+    "jpn_vert",
+    "chi_sim",
+    "chi_sim_vert",
+    "chi_tra",
+    "chi_tra_vert",
+    "tha",
+];
+
+/// How image subtitles are read.
+#[derive(Debug, Clone)]
+pub struct OcrOptions {
+    /// Tesseract language, e.g. `jpn` or `jpn+eng`
+    pub lang: String,
+    /// Run this instead of Tesseract, with the image path appended; the
+    /// text is read from its stdout
+    pub command: Option<String>,
+}
+
+impl Default for OcrOptions {
+    fn default() -> Self {
+        Self {
+            lang: "jpn".into(),
+            command: None,
+        }
+    }
+}
+
+/// Whether `codec` is an image-based subtitle format.
+pub fn is_image_codec(codec: &str) -> bool {
+    IMAGE_CODECS.contains(&codec)
+}
+
+/// The text in `png`, one line per subtitle row. Blocks until the OCR
+/// program exits, or is killed after [`TIMEOUT`] or on shutdown.
+pub fn recognize(png: &[u8], options: &OcrOptions) -> std::io::Result<String> {
+    let image = std::env::temp_dir().join(format!("ocr_{}.png", uuid::Uuid::new_v4()));
+    fs::write(&image, png)?;
+    let image_arg = image.display().to_string();
+
+    let command = match &options.command {
+        Some(command_line) => {
+            let args = shlex::split(command_line)
+                .filter(|args| !args.is_empty())
+                .ok_or_else(|| std::io::Error::other("invalid --ocr-command"))?;
+            let mut command = Command::new(&args[0]);
+            command.args(&args[1..]).arg(&image_arg);
+            command
+        }
+        None => {
+            let mut command = Command::new("tesseract");
+            command.args([&image_arg, "stdout", "-l", &options.lang, "--psm", "6"]);
+            command
+        }
+    };
+    debug!("[ocr] Running {:?}", command);
+    let output = crate::media::run_killable(command, None, TIMEOUT);
+    let _ = fs::remove_file(&image);
+    let output = output?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "OCR failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let unspaced = options
+        .lang
+        .split('+')
+        .next()
+        .is_some_and(|lang| UNSPACED_LANGS.contains(&lang));
+    let text = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            if unspaced {
+                line.split_whitespace().collect()
+            } else {
+                line.trim().to_string()
+            }
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+    Ok(lines.join("\n"))
+}
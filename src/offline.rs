@@ -0,0 +1,865 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::anki::{AnkiConnect, AnkiResult, Note, image_tag, sound_tag};
+use crate::anki_file::NoteFile;
+use crate::event_loop::Subtitle;
+use crate::media::{AudioConfig, FfmpegRequest, ImageConfig};
+use crate::morph;
+use crate::subtitle_file::{self, SubtitleFormat};
+
+/// Which media to cut for each line.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    pub audio: Option<AudioConfig>,
+    pub image: Option<ImageConfig>,
+    pub offset_start: Option<f64>,
+    pub offset_end: Option<f64>,
+}
+
+/// Parses `first-last` (1-based, inclusive) or a single line number.
+pub fn parse_line_range(range: &str) -> Option<(u64, u64)> {
+    match range.split_once('-') {
+        Some((a, b)) => Some((a.trim().parse().ok()?, b.trim().parse().ok()?)),
+        None => {
+            let n = range.trim().parse().ok()?;
+            Some((n, n))
+        }
+    }
+}
+
+/// Loads subtitles for `media` from a subtitle file, without mpv.
+pub fn load_subtitles(subs_path: &Path, media: &Path, aid: i64) -> std::io::Result<Vec<Subtitle>> {
+    let content = std::fs::read_to_string(subs_path)?;
+    let subs = subtitle_file::parse(&content, &media.display().to_string(), aid);
+    if subs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("No subtitle cues found in {}", subs_path.display()),
+        ));
+    }
+    Ok(subs)
+}
+
+/// Keeps lines within `range` (see [`parse_line_range`]) whose text matches
+/// `pattern`.
+pub fn select(subs: &mut Vec<Subtitle>, range: Option<(u64, u64)>, pattern: Option<&Regex>) {
+    subs.retain(|s| {
+        range.is_none_or(|(first, last)| (first..=last).contains(&s.id))
+            && pattern.is_none_or(|re| re.is_match(&s.text))
+    });
+}
+
+/// One file cut for a line.
+struct Media {
+    ext: String,
+    data: Vec<u8>,
+    /// See [`FfmpegRequest::content_key`]
+    key: String,
+}
+
+impl Media {
+    fn cut(req: FfmpegRequest) -> Option<Self> {
+        let ext = req.extension().to_string();
+        let key = req.content_key();
+        let data = req.run().ok()?;
+        Some(Self { ext, data, key })
+    }
+}
+
+/// Media cut for one line.
+#[derive(Default)]
+struct Cut {
+    audio: Option<Media>,
+    image: Option<Media>,
+}
+
+impl Cut {
+    /// Whether every requested file was produced.
+    fn is_complete(&self, options: &ExtractOptions) -> bool {
+        options.audio.is_some() == self.audio.is_some()
+            && options.image.is_some() == self.image.is_some()
+    }
+
+    fn write_to(&self, out_dir: &Path, id: u64) -> std::io::Result<()> {
+        for media in [&self.audio, &self.image].into_iter().flatten() {
+            std::fs::write(
+                out_dir.join(format!("{:04}.{}", id, media.ext)),
+                &media.data,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn cut(sub: &Subtitle, options: &ExtractOptions) -> Cut {
+    let mut cut = Cut::default();
+
+    if let Some(config) = &options.audio {
+        cut.audio = Media::cut(FfmpegRequest::audio(
+            sub,
+            options.offset_start,
+            options.offset_end,
+            Some(config.clone()),
+        ));
+    }
+
+    if let Some(config) = &options.image {
+        cut.image = Media::cut(FfmpegRequest::thumbnail(sub, Some(config.clone())));
+    }
+
+    if !cut.is_complete(options) {
+        warn!("[extract] Failed to cut media for line {}", sub.id);
+    }
+    cut
+}
+
+/// Cuts `sub`'s audio and image with the default settings, named as notes
+/// name them. Either is `None` when it couldn't be cut.
+pub fn cut_files(sub: &Subtitle) -> (Option<MediaFile>, Option<MediaFile>) {
+    let options = ExtractOptions {
+        audio: Some(AudioConfig::default()),
+        image: Some(ImageConfig::default()),
+        ..Default::default()
+    };
+    let cut = cut(sub, &options);
+    let stem = Path::new(&sub.media_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("subtitleminer");
+    let named =
+        |media: Option<Media>| media.map(|m| (format!("{}_{}.{}", stem, m.key, m.ext), m.data));
+    (named(cut.audio), named(cut.image))
+}
+
+/// Cuts media for every subtitle into `out_dir` as `NNNN.<ext>` and returns
+/// the number of lines that produced all requested files.
+pub fn extract(
+    subs: &[Subtitle],
+    out_dir: &Path,
+    options: &ExtractOptions,
+) -> std::io::Result<usize> {
+    std::fs::create_dir_all(out_dir)?;
+    let mut ok = 0;
+
+    for sub in subs {
+        info!("[extract] {:04} {}", sub.id, sub.text.replace('\n', " "));
+        let cut = cut(sub, options);
+        cut.write_to(out_dir, sub.id)?;
+        if cut.is_complete(options) {
+            ok += 1;
+        }
+    }
+    Ok(ok)
+}
+
+/// The ffmpeg commands [`extract`] would run for `subs`, see
+/// [`FfmpegRequest::commands`].
+pub fn extract_commands(subs: &[Subtitle], options: &ExtractOptions) -> Vec<String> {
+    let mut commands = Vec::new();
+    for sub in subs {
+        if let Some(config) = &options.audio {
+            let request = FfmpegRequest::audio(
+                sub,
+                options.offset_start,
+                options.offset_end,
+                Some(config.clone()),
+            );
+            commands.extend(request.commands());
+        }
+        if let Some(config) = &options.image {
+            commands.extend(FfmpegRequest::thumbnail(sub, Some(config.clone())).commands());
+        }
+    }
+    commands
+}
+
+/// Like [`extract`], but into an in-memory zip archive. Media files are
+/// already compressed, so entries are stored as is.
+pub fn extract_zip(
+    subs: &[Subtitle],
+    options: &ExtractOptions,
+) -> std::io::Result<(Vec<u8>, usize)> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let file_options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let mut ok = 0;
+
+    for sub in subs {
+        let cut = cut(sub, options);
+        for media in [&cut.audio, &cut.image].into_iter().flatten() {
+            zip.start_file(format!("{:04}.{}", sub.id, media.ext), file_options)
+                .map_err(std::io::Error::other)?;
+            zip.write_all(&media.data)?;
+        }
+        if cut.is_complete(options) {
+            ok += 1;
+        }
+    }
+    let archive = zip.finish().map_err(std::io::Error::other)?;
+    Ok((archive.into_inner(), ok))
+}
+
+/// What field templates can use, each written as `{name}`.
+pub const FIELD_PLACEHOLDERS: &[&str] = &[
+    "sentence",
+    "translation",
+    "reading",
+    "audio",
+    "image",
+    "title",
+    "timestamp",
+    "chapter",
+    "file",
+];
+
+/// A note field filled from a template, parsed from `Field=template` such
+/// as `Notes={title} {timestamp}`. See [`FIELD_PLACEHOLDERS`].
+#[derive(Debug, Clone)]
+pub struct FieldTemplate {
+    pub field: String,
+    pub template: String,
+}
+
+impl FieldTemplate {
+    fn uses(&self, placeholder: &str) -> bool {
+        self.template.contains(&format!("{{{}}}", placeholder))
+    }
+
+    /// The template with each placeholder replaced by its value; missing
+    /// values (no translation yet, no image cut) leave it empty.
+    fn render(&self, values: &HashMap<&str, String>) -> String {
+        placeholder_regex()
+            .replace_all(&self.template, |caps: &regex::Captures| {
+                values.get(&caps[1]).cloned().unwrap_or_default()
+            })
+            .into_owned()
+    }
+}
+
+impl std::str::FromStr for FieldTemplate {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (field, template) = spec
+            .split_once('=')
+            .filter(|(field, _)| !field.trim().is_empty())
+            .ok_or_else(|| format!("expected FIELD=TEMPLATE, got '{}'", spec))?;
+        check_placeholders(template, FIELD_PLACEHOLDERS)?;
+        Ok(Self {
+            field: field.trim().to_string(),
+            template: template.to_string(),
+        })
+    }
+}
+
+/// What tag templates can use, each written as `{name}`. `media_title` is
+/// the same as `title`; `season` and `episode` are read from the file name
+/// or title, e.g. `S01E05` or `Show - 05`.
+pub const TAG_PLACEHOLDERS: &[&str] = &[
+    "title",
+    "media_title",
+    "file",
+    "chapter",
+    "season",
+    "episode",
+];
+
+/// A tag for every note, such as `subtitleminer::{media_title}` or
+/// `{season}-{episode}`. See [`TAG_PLACEHOLDERS`].
+#[derive(Debug, Clone)]
+pub struct TagTemplate(pub String);
+
+impl TagTemplate {
+    /// The tag with its placeholders filled and spaces, which separate
+    /// tags in Anki, made underscores. `None` when a placeholder has no
+    /// value, so `{season}` on a movie adds no tag rather than an empty one.
+    fn render(&self, values: &HashMap<&str, String>) -> Option<String> {
+        let mut missing = false;
+        let tag = placeholder_regex().replace_all(&self.0, |caps: &regex::Captures| {
+            let value = values.get(&caps[1]).filter(|v| !v.trim().is_empty());
+            missing |= value.is_none();
+            value.cloned().unwrap_or_default()
+        });
+        let tag = tag.split_whitespace().collect::<Vec<_>>().join("_");
+        (!missing && !tag.is_empty()).then_some(tag)
+    }
+}
+
+impl std::str::FromStr for TagTemplate {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        check_placeholders(spec, TAG_PLACEHOLDERS)?;
+        Ok(Self(spec.to_string()))
+    }
+}
+
+fn check_placeholders(template: &str, known: &[&str]) -> Result<(), String> {
+    match placeholder_regex()
+        .captures_iter(template)
+        .map(|caps| caps[1].to_string())
+        .find(|name| !known.contains(&name.as_str()))
+    {
+        Some(unknown) => Err(format!(
+            "unknown placeholder {{{}}}, expected one of {}",
+            unknown,
+            known.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+fn placeholder_regex() -> &'static Regex {
+    static PLACEHOLDER: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
+    PLACEHOLDER.get_or_init(|| Regex::new(r"\{([a-z_]+)\}").expect("valid regex"))
+}
+
+/// Where mined notes go in Anki. Unset fields are left empty.
+#[derive(Debug, Clone, Default)]
+pub struct AnkiTarget {
+    pub url: String,
+    pub deck: String,
+    pub model: String,
+    pub sentence_field: Option<String>,
+    pub audio_field: Option<String>,
+    pub image_field: Option<String>,
+    /// Gets the line's machine translation, when it has one
+    pub translation_field: Option<String>,
+    /// Gets the line with furigana, when it was tokenized
+    pub reading_field: Option<String>,
+    /// Fields filled from templates, after and over the ones above
+    pub fields: Vec<FieldTemplate>,
+    /// Fields that get new content appended when an existing note is
+    /// updated, instead of replaced
+    pub append_fields: Vec<String>,
+    /// Skip lines whose sentence is already in the deck, see
+    /// [`find_duplicate`]; without it, the server asks the client whether
+    /// to add them again
+    pub skip_duplicates: bool,
+    pub tags: Vec<TagTemplate>,
+    /// Write notes and media here instead of sending them to AnkiConnect
+    pub file: Option<NoteFile>,
+}
+
+impl AnkiTarget {
+    /// Whether some field gets the line's audio.
+    pub fn wants_audio(&self) -> bool {
+        self.audio_field.is_some() || self.fields.iter().any(|f| f.uses("audio"))
+    }
+
+    /// The field holding the sentence: the sentence field, or else the
+    /// first template that is only `{sentence}`.
+    fn sentence_field(&self) -> Option<&str> {
+        self.sentence_field.as_deref().or_else(|| {
+            self.fields
+                .iter()
+                .find(|f| f.template.trim() == "{sentence}")
+                .map(|f| f.field.as_str())
+        })
+    }
+
+    /// Whether some field gets the line's translation.
+    pub fn wants_translation(&self) -> bool {
+        self.translation_field.is_some() || self.fields.iter().any(|f| f.uses("translation"))
+    }
+
+    /// Whether some field gets the line's image.
+    pub fn wants_image(&self) -> bool {
+        self.image_field.is_some() || self.fields.iter().any(|f| f.uses("image"))
+    }
+
+    /// Whether some field gets the line with furigana.
+    pub fn wants_reading(&self) -> bool {
+        self.reading_field.is_some() || self.fields.iter().any(|f| f.uses("reading"))
+    }
+
+    /// Every field notes get, each once, in the order they are configured.
+    pub fn field_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        let configured = [
+            &self.sentence_field,
+            &self.translation_field,
+            &self.reading_field,
+            &self.audio_field,
+            &self.image_field,
+        ];
+        for name in configured
+            .into_iter()
+            .flatten()
+            .chain(self.fields.iter().map(|f| &f.field))
+        {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+}
+
+/// Cuts media for every subtitle and adds one note per line to Anki,
+/// optionally also writing the files to `out_dir`. Returns the number of
+/// notes added (or lines written when no Anki target is given).
+pub async fn mine(
+    subs: &[Subtitle],
+    out_dir: Option<&Path>,
+    options: &ExtractOptions,
+    anki: Option<&AnkiTarget>,
+) -> std::io::Result<usize> {
+    let client = anki
+        .filter(|target| target.file.is_none())
+        .map(|target| AnkiConnect::new(&target.url));
+    if let Some(client) = &client {
+        let version = client.version().await.map_err(std::io::Error::other)?;
+        info!("[mine] Connected to AnkiConnect v{}", version);
+    }
+    if let Some(dir) = out_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut ok = 0;
+    for sub in subs {
+        info!("[mine] {:04} {}", sub.id, sub.text.replace('\n', " "));
+        if let (Some(client), Some(target)) = (&client, anki.filter(|t| t.skip_duplicates)) {
+            match find_duplicate(client, target, sub).await {
+                Ok(Some(note_id)) => {
+                    info!(
+                        "[mine] Line {} is already note {}, skipped",
+                        sub.id, note_id
+                    );
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => warn!("[mine] Duplicate check for line {} failed: {}", sub.id, e),
+            }
+        }
+        let job_sub = sub.clone();
+        let job_options = options.clone();
+        let cut = tokio::task::spawn_blocking(move || cut(&job_sub, &job_options))
+            .await
+            .map_err(std::io::Error::other)?;
+
+        if let Some(dir) = out_dir {
+            cut.write_to(dir, sub.id)?;
+        }
+
+        match (&client, anki) {
+            (Some(client), Some(target)) => {
+                match add_prepared(client, &prepare(target, sub, None, &cut)).await {
+                    Ok(note_id) => {
+                        info!("[mine] Added note {} for line {}", note_id, sub.id);
+                        ok += 1;
+                    }
+                    Err(e) => warn!("[mine] Failed to add line {}: {}", sub.id, e),
+                }
+            }
+            (
+                None,
+                Some(
+                    target @ AnkiTarget {
+                        file: Some(file), ..
+                    },
+                ),
+            ) => match file.add(&prepare(target, sub, None, &cut), &target.field_names()) {
+                Ok(_) => ok += 1,
+                Err(e) => warn!("[mine] Failed to write line {}: {}", sub.id, e),
+            },
+            _ if cut.is_complete(options) => ok += 1,
+            _ => {}
+        }
+    }
+    Ok(ok)
+}
+
+/// The fields of the note type `--create-model` creates, in order.
+pub const DEFAULT_MODEL_FIELDS: [&str; 5] = ["Sentence", "Audio", "Image", "Translation", "Source"];
+
+/// The name `create_model` requests use when given none and mining isn't
+/// configured.
+pub const DEFAULT_MODEL_NAME: &str = "Subtitle Miner";
+
+const DEFAULT_MODEL_FRONT: &str = "<div class=\"sentence\">{{Sentence}}</div>";
+const DEFAULT_MODEL_BACK: &str = "{{FrontSide}}
+<hr id=answer>
+{{Audio}}
+<div class=\"image\">{{Image}}</div>
+<div class=\"translation\">{{Translation}}</div>
+<div class=\"source\">{{Source}}</div>";
+const DEFAULT_MODEL_CSS: &str =
+    ".card { font-family: sans-serif; font-size: 28px; text-align: center; }
+.image img { max-width: 100%; max-height: 50vh; }
+.translation { font-size: 20px; color: #888; }
+.source { font-size: 14px; color: #888; }";
+
+/// Creates note type `model` with [`DEFAULT_MODEL_FIELDS`] unless Anki
+/// already has it, returning whether it was created. With `create` unset a
+/// missing note type is an error naming the flag that creates it instead.
+pub async fn ensure_model(client: &AnkiConnect, model: &str, create: bool) -> AnkiResult<bool> {
+    if client.model_names().await?.iter().any(|name| name == model) {
+        return Ok(false);
+    }
+    if !create {
+        return Err(format!(
+            "note type \"{}\" does not exist in Anki, pass --create-model to create it with the fields {}",
+            model,
+            DEFAULT_MODEL_FIELDS.join(", ")
+        )
+        .into());
+    }
+    client
+        .create_model(
+            model,
+            &DEFAULT_MODEL_FIELDS,
+            DEFAULT_MODEL_FRONT,
+            DEFAULT_MODEL_BACK,
+            DEFAULT_MODEL_CSS,
+        )
+        .await?;
+    Ok(true)
+}
+
+/// A note in the target deck whose sentence field is exactly `sub`'s text,
+/// so mining it again would add a duplicate. `None` when there is none or
+/// no field holds the sentence alone.
+pub async fn find_duplicate(
+    client: &AnkiConnect,
+    target: &AnkiTarget,
+    sub: &Subtitle,
+) -> AnkiResult<Option<u64>> {
+    let Some(field) = target.sentence_field() else {
+        return Ok(None);
+    };
+    let query = format!(
+        "\"deck:{}\" \"{}:{}\"",
+        search_escape(&target.deck),
+        search_escape(field),
+        search_escape(&html(&sub.text))
+    );
+    Ok(client.find_notes(&query).await?.into_iter().min())
+}
+
+/// `text` as HTML for a field: markup characters escaped, so a line
+/// can't inject tags, and line breaks as `<br>`.
+fn html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("<br>"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes what Anki's search syntax treats specially inside quotes.
+fn search_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '"' | '*' | '_' | ':') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Cuts the media `target` has fields for and adds `sub` as one note, as
+/// the server does for a single line. `title` is mpv's `media-title`, when
+/// known.
+pub async fn mine_line(
+    client: &AnkiConnect,
+    target: &AnkiTarget,
+    sub: &Subtitle,
+    title: Option<&str>,
+) -> AnkiResult<u64> {
+    add_prepared(client, &prepare_line(target, sub, title).await?).await
+}
+
+/// A note ready to add and the media files its fields refer to by name,
+/// so it can be kept until Anki is reachable.
+#[derive(Debug, Clone, Default)]
+pub struct PreparedNote {
+    pub note: Note,
+    pub media: Vec<MediaFile>,
+}
+
+/// A file for a note's media: its name and content.
+pub type MediaFile = (String, Vec<u8>);
+
+/// Cuts the media `target` has fields for and fills the note for `sub`,
+/// without talking to Anki.
+pub async fn prepare_line(
+    target: &AnkiTarget,
+    sub: &Subtitle,
+    title: Option<&str>,
+) -> AnkiResult<PreparedNote> {
+    let options = ExtractOptions {
+        audio: target.wants_audio().then(AudioConfig::default),
+        image: target.wants_image().then(ImageConfig::default),
+        ..Default::default()
+    };
+    let job_sub = sub.clone();
+    let cut = tokio::task::spawn_blocking(move || cut(&job_sub, &options)).await?;
+    Ok(prepare(target, sub, title, &cut))
+}
+
+/// Stores the media of `prepared` and adds its note, returning the note id.
+pub async fn add_prepared(client: &AnkiConnect, prepared: &PreparedNote) -> AnkiResult<u64> {
+    let mut note = prepared.note.clone();
+    store_media(client, &mut note.fields, &prepared.media).await?;
+    client.add_note(&note).await
+}
+
+/// Puts `sub` into the note added last instead of a new one, for cards
+/// made elsewhere (e.g. Yomitan) that lack the sentence and media. Only
+/// fields the note has are written, those in [`AnkiTarget::append_fields`]
+/// after what they hold, and a note added more than `max_age` ago is left
+/// alone rather than clobbered. Returns the note's id.
+pub async fn update_last_note(
+    client: &AnkiConnect,
+    target: &AnkiTarget,
+    sub: &Subtitle,
+    title: Option<&str>,
+    max_age: Duration,
+) -> AnkiResult<u64> {
+    // Note ids are the millisecond they were created at
+    let note_id = client
+        .find_notes("added:1")
+        .await?
+        .into_iter()
+        .max()
+        .ok_or("no note was added today")?;
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let age = Duration::from_millis(now_ms.saturating_sub(note_id));
+    if age > max_age {
+        return Err(format!(
+            "the last note was added {}s ago, more than {}s; not touching it",
+            age.as_secs(),
+            max_age.as_secs()
+        )
+        .into());
+    }
+
+    let existing = client.note_fields(note_id).await?;
+    let options = ExtractOptions {
+        audio: target.wants_audio().then(AudioConfig::default),
+        image: target.wants_image().then(ImageConfig::default),
+        ..Default::default()
+    };
+    let job_sub = sub.clone();
+    let cut = tokio::task::spawn_blocking(move || cut(&job_sub, &options)).await?;
+    let (mut fields, mut media) = note_fields(target, sub, title, &cut);
+    fields.retain(|name, _| existing.iter().any(|(n, _)| n == name));
+    media.retain(|(name, _)| fields.values().any(|value| value.contains(name.as_str())));
+    if fields.is_empty() {
+        let names: Vec<_> = existing.iter().map(|(name, _)| name.as_str()).collect();
+        return Err(format!(
+            "note {} has none of the configured fields (it has {})",
+            note_id,
+            names.join(", ")
+        )
+        .into());
+    }
+    for (name, current) in &existing {
+        if !target.append_fields.contains(name) || current.trim().is_empty() {
+            continue;
+        }
+        if let Some(value) = fields.get_mut(name) {
+            *value = appended(current, value);
+        }
+    }
+    store_media(client, &mut fields, &media).await?;
+    client.update_note_fields(note_id, &fields).await?;
+    Ok(note_id)
+}
+
+/// `current` with `addition` on a new line, keeping its HTML as is.
+/// Mining the same line twice doesn't add it twice.
+fn appended(current: &str, addition: &str) -> String {
+    if addition.is_empty() || current.contains(addition) {
+        current.to_string()
+    } else {
+        format!("{}<br>{}", current, addition)
+    }
+}
+
+fn prepare(target: &AnkiTarget, sub: &Subtitle, title: Option<&str>, cut: &Cut) -> PreparedNote {
+    let (fields, media) = note_fields(target, sub, title, cut);
+    PreparedNote {
+        note: Note {
+            deck: target.deck.clone(),
+            model: target.model.clone(),
+            tags: note_tags(target, sub, title),
+            fields,
+            allow_duplicate: false,
+        },
+        media,
+    }
+}
+
+/// `target`'s tags for `sub`, leaving out those missing a value.
+fn note_tags(target: &AnkiTarget, sub: &Subtitle, title: Option<&str>) -> Vec<String> {
+    if target.tags.is_empty() {
+        return Vec::new();
+    }
+    let stem = Path::new(&sub.media_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    let title = title.unwrap_or(stem);
+    let mut values = HashMap::from([
+        ("title", title.to_string()),
+        ("media_title", title.to_string()),
+        ("file", stem.to_string()),
+    ]);
+    if let Some(chapter) = &sub.chapter {
+        values.insert("chapter", chapter.clone());
+    }
+    let (season, episode) = match season_episode(stem) {
+        (None, None) => season_episode(title),
+        found => found,
+    };
+    values.extend(season.map(|s| ("season", s)));
+    values.extend(episode.map(|e| ("episode", e)));
+    target
+        .tags
+        .iter()
+        .filter_map(|tag| tag.render(&values))
+        .collect()
+}
+
+/// The season and episode numbers in a release name, as written: `S01E05`,
+/// `1x05`, or only the episode in `Show - 05` and `Episode 5`.
+fn season_episode(name: &str) -> (Option<String>, Option<String>) {
+    static PATTERNS: std::sync::OnceLock<[Regex; 3]> = std::sync::OnceLock::new();
+    let [both, cross, episode] = PATTERNS.get_or_init(|| {
+        [
+            Regex::new(r"(?i)\bS(\d{1,2})\s?E(\d{1,4})").expect("valid regex"),
+            Regex::new(r"\b(\d{1,2})x(\d{2,3})\b").expect("valid regex"),
+            Regex::new(r"(?i)(?:\s-\s|\bEp(?:isode)?\.?\s?|\bE)(\d{1,4})\b").expect("valid regex"),
+        ]
+    });
+    if let Some(caps) = both.captures(name).or_else(|| cross.captures(name)) {
+        return (Some(caps[1].to_string()), Some(caps[2].to_string()));
+    }
+    (None, episode.captures(name).map(|caps| caps[1].to_string()))
+}
+
+/// Stores `media` in Anki, renaming it in `fields` if Anki picked another
+/// name.
+async fn store_media(
+    client: &AnkiConnect,
+    fields: &mut HashMap<String, String>,
+    media: &[MediaFile],
+) -> AnkiResult<()> {
+    for (name, data) in media {
+        let stored = client.store_media_file(name, data).await?;
+        if &stored != name {
+            for value in fields.values_mut() {
+                *value = value.replace(name.as_str(), &stored);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The fields `target` fills for `sub` and the media files they refer to.
+fn note_fields(
+    target: &AnkiTarget,
+    sub: &Subtitle,
+    title: Option<&str>,
+    cut: &Cut,
+) -> (HashMap<String, String>, Vec<MediaFile>) {
+    let stem = Path::new(&sub.media_path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("subtitleminer");
+    let reading = (!sub.tokens.is_empty()).then(|| morph::furigana(&sub.tokens));
+
+    // Stored once, whichever fields refer to them, and named after what was
+    // cut, so mining a line again reuses its files
+    let mut files = Vec::new();
+    let mut audio = None;
+    if let Some(media) = cut.audio.as_ref().filter(|_| target.wants_audio()) {
+        let name = format!("{}_{}.{}", stem, media.key, media.ext);
+        audio = Some(sound_tag(&name));
+        files.push((name, media.data.clone()));
+    }
+    let mut image = None;
+    if let Some(media) = cut.image.as_ref().filter(|_| target.wants_image()) {
+        let name = format!("{}_{}.{}", stem, media.key, media.ext);
+        image = Some(image_tag(&name));
+        files.push((name, media.data.clone()));
+    }
+
+    let mut fields = HashMap::new();
+    if let Some(field) = &target.sentence_field {
+        fields.insert(field.clone(), html(&sub.text));
+    }
+    if let (Some(field), Some(translation)) = (&target.translation_field, &sub.translation) {
+        fields.insert(field.clone(), html(translation));
+    }
+    if let (Some(field), Some(reading)) = (&target.reading_field, &reading) {
+        fields.insert(field.clone(), html(reading));
+    }
+    if let (Some(field), Some(audio)) = (&target.audio_field, &audio) {
+        fields.insert(field.clone(), audio.clone());
+    }
+    if let (Some(field), Some(image)) = (&target.image_field, &image) {
+        fields.insert(field.clone(), image.clone());
+    }
+
+    if !target.fields.is_empty() {
+        let mut values = HashMap::from([
+            ("sentence", html(&sub.text)),
+            ("title", html(title.unwrap_or(stem))),
+            (
+                "timestamp",
+                subtitle_file::format_timestamp(sub.sub_start, SubtitleFormat::Vtt),
+            ),
+            ("file", html(stem)),
+        ]);
+        let optional = [
+            ("translation", sub.translation.as_deref().map(html)),
+            ("reading", reading.as_deref().map(html)),
+            ("audio", audio),
+            ("image", image),
+            ("chapter", sub.chapter.as_deref().map(html)),
+        ];
+        values.extend(
+            optional
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, value?))),
+        );
+        for template in &target.fields {
+            fields.insert(template.field.clone(), template.render(&values));
+        }
+    }
+    (fields, files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_escapes_markup() {
+        assert_eq!(
+            html("<b>\"Tom & Jerry\"</b>"),
+            "&lt;b&gt;&quot;Tom &amp; Jerry&quot;&lt;/b&gt;"
+        );
+        assert_eq!(html("一行目\n二行目"), "一行目<br>二行目");
+        assert_eq!(html("it's"), "it's");
+    }
+}
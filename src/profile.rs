@@ -0,0 +1,54 @@
+//! Per-show media settings, picked by a regex on the file's path or title:
+//! e.g. a brighter screenshot and more audio padding for every episode of
+//! one drama. Requests that bring their own settings are left alone.
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::media::{AudioConfig, ImageConfig};
+
+/// Offsets and media settings for requests that leave them out.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MediaSettings {
+    pub offset_start: Option<f64>,
+    pub offset_end: Option<f64>,
+    pub image_config: Option<ImageConfig>,
+    pub audio_config: Option<AudioConfig>,
+}
+
+/// [`MediaSettings`] for the files matching a pattern.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    /// Matched against the media path and `media-title`
+    pub pattern: Regex,
+    pub settings: MediaSettings,
+}
+
+/// A profile as written in the config file:
+/// `{"name": ..., "match": <regex>, "offset_start": ..., ...}`.
+#[derive(Deserialize)]
+pub(crate) struct ProfileEntry {
+    name: Option<String>,
+    #[serde(rename = "match")]
+    pattern: String,
+    #[serde(flatten)]
+    settings: MediaSettings,
+}
+
+impl ProfileEntry {
+    pub(crate) fn compile(self) -> Result<Profile, regex::Error> {
+        Ok(Profile {
+            pattern: Regex::new(&self.pattern)?,
+            name: self.name.unwrap_or(self.pattern),
+            settings: self.settings,
+        })
+    }
+}
+
+/// The first of `profiles` whose pattern matches `path` or `title`.
+pub fn matching<'a>(profiles: &'a [Profile], path: &str, title: &str) -> Option<&'a Profile> {
+    profiles.iter().find(|profile| {
+        profile.pattern.is_match(path) || (!title.is_empty() && profile.pattern.is_match(title))
+    })
+}
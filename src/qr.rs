@@ -0,0 +1,58 @@
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use qrcode::QrCode;
+use qrcode::render::unicode::Dense1x2;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+
+/// Escaped in the token: everything but RFC 3986's unreserved characters.
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Best guess at the address other devices on the LAN can reach us on.
+///
+/// Connecting a UDP socket sends nothing, it only makes the OS pick the
+/// outgoing interface.
+pub fn lan_ip() -> IpAddr {
+    UdpSocket::bind(("0.0.0.0", 0))
+        .and_then(|s| {
+            s.connect(("192.0.2.1", 9))?;
+            s.local_addr()
+        })
+        .map(|a| a.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+pub fn connection_url(port: u16, token: Option<&str>) -> String {
+    match token {
+        Some(token) => format!(
+            "ws://{}:{}/?token={}",
+            lan_ip(),
+            port,
+            utf8_percent_encode(token, QUERY_VALUE)
+        ),
+        None => format!("ws://{}:{}", lan_ip(), port),
+    }
+}
+
+/// Renders `data` as a QR code made of unicode half blocks.
+pub fn render(data: &str) -> Option<String> {
+    let code = QrCode::new(data.as_bytes()).ok()?;
+    Some(
+        code.render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .quiet_zone(true)
+            .build(),
+    )
+}
+
+/// The QR code as an mpv OSD message, forcing a monospace font so the
+/// blocks line up.
+pub fn osd_text(qr: &str) -> String {
+    format!(
+        "${{osd-ass-cc/0}}{{\\fnmonospace\\fs12}}{}",
+        qr.lines().collect::<Vec<_>>().join("\\N")
+    )
+}
@@ -0,0 +1,108 @@
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::{accept_hdr_async, connect_async};
+use tracing::{debug, info, warn};
+
+use crate::event_loop::{OriginPolicy, check_token};
+
+/// Serves local clients by proxying each one to its own connection on an
+/// upstream miner, so events and media responses flow through unchanged.
+pub async fn run_relay(
+    upstream: &str,
+    upstream_token: Option<&str>,
+    port: u16,
+    auth_token: Option<String>,
+    origins: OriginPolicy,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    println!(
+        "Relaying {} on {}",
+        upstream,
+        listener
+            .local_addr()
+            .map_or_else(|_| format!("port {}", port), |a| a.to_string())
+    );
+
+    let mut client_id = 0u64;
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        client_id += 1;
+        let id = client_id;
+
+        let upstream = upstream.to_string();
+        let upstream_token = upstream_token.map(str::to_string);
+        let token = auth_token.clone();
+        let origins = origins.clone();
+
+        tokio::spawn(async move {
+            info!("[relay:{}] Connected from {}", id, addr);
+            match relay_client(stream, &upstream, upstream_token, token, &origins).await {
+                Ok(()) => debug!("[relay:{}] Disconnected", id),
+                Err(e) => warn!("[relay:{}] Disconnected: {}", id, e),
+            }
+        });
+    }
+}
+
+async fn relay_client(
+    stream: TcpStream,
+    upstream: &str,
+    upstream_token: Option<String>,
+    auth_token: Option<String>,
+    origins: &OriginPolicy,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let check = check_token(auth_token);
+    let local = stream.local_addr().ok();
+    #[allow(clippy::result_large_err)] // signature dictated by tungstenite
+    let downstream = accept_hdr_async(stream, |req: &_, resp| {
+        origins.check(req, local)?;
+        check(req, resp)
+    })
+    .await?;
+
+    let mut request = upstream.into_client_request()?;
+    if let Some(token) = upstream_token {
+        request.headers_mut().insert(
+            "Authorization",
+            HeaderValue::from_str(&format!("Bearer {}", token))?,
+        );
+    }
+    let (upstream, _) = connect_async(request).await?;
+
+    let (mut down_tx, mut down_rx) = downstream.split();
+    let (mut up_tx, mut up_rx) = upstream.split();
+
+    loop {
+        tokio::select! {
+            msg = down_rx.next() => match msg {
+                Some(msg) => {
+                    let msg = msg?;
+                    let is_close = msg.is_close();
+                    // Each side answers its own pings
+                    if !msg.is_ping() && !msg.is_pong() {
+                        up_tx.send(msg).await?;
+                    }
+                    if is_close {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            },
+            msg = up_rx.next() => match msg {
+                Some(msg) => {
+                    let msg = msg?;
+                    let is_close = msg.is_close();
+                    if !msg.is_ping() && !msg.is_pong() {
+                        down_tx.send(msg).await?;
+                    }
+                    if is_close {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            },
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Where mining of each media file left off and the settings used for it,
+//! kept across runs so reopening a file picks up from there.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// Files remembered at most; the ones not touched longest are forgotten.
+const MAX_FILES: usize = 1000;
+
+/// What is remembered about one file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileResume {
+    /// Start of the line mined last, in seconds
+    pub position: Option<f64>,
+    /// mpv's subtitle track that line came from
+    pub sid: Option<i64>,
+    /// What clients chose for the file, e.g. audio offsets and image or
+    /// audio settings; opaque to the server
+    #[serde(default)]
+    pub settings: serde_json::Map<String, serde_json::Value>,
+    /// Milliseconds since the epoch
+    #[serde(default)]
+    updated: u64,
+}
+
+/// A JSON file of [`FileResume`]s by media path, rewritten on every change.
+/// Changing it blocks on the disk, so async code does it on a blocking
+/// thread.
+#[derive(Debug)]
+pub struct ResumeStore {
+    path: PathBuf,
+    files: Mutex<HashMap<String, FileResume>>,
+    /// Held while saving, so saves land in the order of the changes
+    saving: Mutex<()>,
+}
+
+impl ResumeStore {
+    /// Reads `path`, starting empty when it doesn't exist yet.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let files = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            files: Mutex::new(files),
+            saving: Mutex::new(()),
+        })
+    }
+
+    /// `mpv-subtitleminer/resume.json` in the per-user data directory.
+    pub fn default_path() -> Option<PathBuf> {
+        crate::instance::data_dir().map(|dir| dir.join("resume.json"))
+    }
+
+    pub fn get(&self, file: &str) -> Option<FileResume> {
+        self.lock().get(file).cloned()
+    }
+
+    /// Remembers that the line starting at `position` of track `sid` was
+    /// mined last.
+    pub fn record_mined(&self, file: &str, position: f64, sid: Option<i64>) {
+        let _ = self.update(file, |resume| {
+            resume.position = Some(position);
+            if sid.is_some() {
+                resume.sid = sid;
+            }
+        });
+    }
+
+    /// Merges `settings` into what is remembered for `file`; a `null`
+    /// value forgets a setting.
+    pub fn set_settings(
+        &self,
+        file: &str,
+        settings: serde_json::Map<String, serde_json::Value>,
+    ) -> io::Result<()> {
+        self.update(file, |resume| {
+            for (key, value) in settings {
+                if value.is_null() {
+                    resume.settings.remove(&key);
+                } else {
+                    resume.settings.insert(key, value);
+                }
+            }
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, FileResume>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Changes the entry of `file` and saves, logging failures as well as
+    /// returning them.
+    fn update(&self, file: &str, change: impl FnOnce(&mut FileResume)) -> io::Result<()> {
+        if file.is_empty() {
+            return Ok(());
+        }
+        let _saving = self.saving.lock().unwrap_or_else(|e| e.into_inner());
+        let mut files = self.lock();
+        let resume = files.entry(file.to_string()).or_default();
+        change(resume);
+        resume.updated = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        if files.len() > MAX_FILES {
+            let mut by_age: Vec<_> = files.iter().map(|(k, v)| (v.updated, k.clone())).collect();
+            by_age.sort();
+            for (_, stale) in by_age.into_iter().take(files.len() - MAX_FILES) {
+                files.remove(&stale);
+            }
+        }
+        // Readers needn't wait for the disk
+        let data = serde_json::to_vec(&*files);
+        drop(files);
+        let result = data
+            .map_err(io::Error::from)
+            .and_then(|data| self.save(&data));
+        match &result {
+            Ok(()) => debug!("[resume] Saved {}", file),
+            Err(e) => warn!("[resume] Cannot save {}: {}", self.path.display(), e),
+        }
+        result
+    }
+
+    /// Writes beside the file and renames over it, so a crash never leaves
+    /// it half written.
+    fn save(&self, data: &[u8]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let part = self.path.with_extension("json.part");
+        fs::write(&part, data)?;
+        fs::rename(part, &self.path)
+    }
+}
@@ -0,0 +1,242 @@
+//! Network sources: what ffmpeg has to open when mpv plays a URL, which is
+//! rarely the URL itself (YouTube pages, signed CDN links, cookie-gated
+//! streams).
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+use tracing::{debug, info};
+
+static YTDL_PATH: OnceLock<String> = OnceLock::new();
+static SOURCES: OnceLock<Mutex<HashMap<String, StreamSource>>> = OnceLock::new();
+static MPV_OPTIONS: OnceLock<Mutex<HashMap<String, Vec<String>>>> = OnceLock::new();
+static MPV_ONLY: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+/// Schemes ffmpeg can't open as they are, or only with mpv's help.
+const NETWORK_SCHEMES: &[&str] = &["http", "https", "ytdl", "rtmp", "rtmps", "rtsp"];
+
+/// Protocols only mpv understands: discs, EDL playlists and files inside
+/// archives. Media from these is always cut by mpv itself.
+const MPV_SCHEMES: &[&str] = &["bd", "br", "bluray", "dvd", "dvdnav", "edl", "archive"];
+
+/// Direct URLs and request headers for a network path.
+#[derive(Debug, Clone, Default)]
+pub struct StreamSource {
+    pub video: String,
+    /// Set when the video and audio come from separate URLs, as with
+    /// YouTube's DASH formats.
+    pub audio: Option<String>,
+    /// `Name: value` pairs, e.g. a referer.
+    pub headers: Vec<String>,
+    pub user_agent: Option<String>,
+    /// Cookies as ffmpeg's `-cookies` takes them, one `Set-Cookie` value
+    /// per line, see [`read_cookies_file`].
+    pub cookies: Option<String>,
+}
+
+/// Sets the yt-dlp binary used when mpv doesn't expose a direct URL.
+pub fn init_ytdl_path(path: &str) {
+    YTDL_PATH.set(path.to_string()).ok();
+}
+
+fn ytdl() -> &'static str {
+    YTDL_PATH.get().map(|s| s.as_str()).unwrap_or("yt-dlp")
+}
+
+fn sources() -> &'static Mutex<HashMap<String, StreamSource>> {
+    SOURCES.get_or_init(Default::default)
+}
+
+/// Whether `path` is a URL that has to be resolved before extracting from it.
+pub fn is_network(path: &str) -> bool {
+    scheme(path).is_some_and(|s| NETWORK_SCHEMES.contains(&s.as_str()))
+}
+
+fn scheme(path: &str) -> Option<String> {
+    path.split_once("://")
+        .map(|(scheme, _)| scheme.to_ascii_lowercase())
+}
+
+/// Whether only mpv can open `path`, so ffmpeg needn't try.
+pub fn is_mpv_only(path: &str) -> bool {
+    scheme(path).is_some_and(|s| MPV_SCHEMES.contains(&s.as_str()))
+        || path.to_ascii_lowercase().ends_with(".edl")
+        || MPV_ONLY
+            .get()
+            .is_some_and(|paths| paths.lock().unwrap().contains(path))
+}
+
+/// Has media from `path` always cut by mpv, e.g. when its timeline pulls in
+/// other files.
+pub fn require_mpv(path: &str) {
+    debug!("[stream] {} can only be cut by mpv", path);
+    MPV_ONLY
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .insert(path.to_string());
+}
+
+/// The mpv option naming the disc `path` is played from, if it is a disc
+/// that can be played without spelling out the device (`bd://1`).
+pub fn device_option(path: &str) -> Option<&'static str> {
+    match scheme(path)?.as_str() {
+        "bd" | "br" | "bluray" => Some("bluray-device"),
+        "dvd" | "dvdnav" => Some("dvd-device"),
+        _ => None,
+    }
+}
+
+/// Options a headless mpv needs to open `path` the way the user's mpv did.
+pub fn register_mpv_options(path: &str, options: Vec<String>) {
+    debug!("[stream] {} needs {}", path, options.join(" "));
+    MPV_OPTIONS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), options);
+}
+
+pub(crate) fn mpv_options(path: &str) -> Vec<String> {
+    MPV_OPTIONS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .get(path)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Makes extraction from `path` use `source` instead.
+pub fn register(path: &str, source: StreamSource) {
+    info!(
+        "[stream] {} -> {}{}",
+        path,
+        source.video,
+        if source.audio.is_some() {
+            " (+ audio)"
+        } else {
+            ""
+        }
+    );
+    sources().lock().unwrap().insert(path.to_string(), source);
+}
+
+/// Drops what was registered for `path` once mpv moved on from it, so
+/// playing many streams doesn't keep every one of their URLs around. Lines
+/// of `path` mined later are cut from `path` itself.
+pub fn forget(path: &str) {
+    let removed = sources().lock().unwrap().remove(path).is_some();
+    if let Some(options) = MPV_OPTIONS.get() {
+        options.lock().unwrap().remove(path);
+    }
+    if let Some(paths) = MPV_ONLY.get() {
+        paths.lock().unwrap().remove(path);
+    }
+    if removed {
+        debug!("[stream] Forgot {}", path);
+    }
+}
+
+/// Whether audio for `path` is a separate input with a single audio stream.
+pub(crate) fn has_separate_audio(path: &str) -> bool {
+    sources()
+        .lock()
+        .unwrap()
+        .get(path)
+        .is_some_and(|s| s.audio.is_some())
+}
+
+/// ffmpeg arguments opening `path`, or its audio when `audio` is set,
+/// ending with `-i`.
+pub(crate) fn input_args(path: &str, audio: bool) -> Vec<String> {
+    let Some(source) = sources().lock().unwrap().get(path).cloned() else {
+        return vec!["-i".into(), path.to_string()];
+    };
+    let url = match (&source.audio, audio) {
+        (Some(url), true) => url.clone(),
+        _ => source.video,
+    };
+
+    let mut args = Vec::new();
+    if let Some(agent) = source.user_agent.filter(|a| !a.is_empty()) {
+        args.extend(["-user_agent".into(), agent]);
+    }
+    if !source.headers.is_empty() {
+        let headers: String = source
+            .headers
+            .iter()
+            .map(|h| format!("{}\r\n", h))
+            .collect();
+        args.extend(["-headers".into(), headers]);
+    }
+    if let Some(cookies) = source.cookies.filter(|c| !c.is_empty()) {
+        args.extend(["-cookies".into(), cookies]);
+    }
+    args.extend(["-i".into(), url]);
+    args
+}
+
+/// Reads a Netscape cookie file, the format mpv's `--cookies-file` and
+/// yt-dlp's `--cookies` use, into the form of [`StreamSource::cookies`].
+pub fn read_cookies_file(path: &Path) -> std::io::Result<String> {
+    let text = std::fs::read_to_string(path)?;
+    let mut cookies = String::new();
+    for line in text.lines() {
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.starts_with('#') {
+            continue;
+        }
+        // domain, subdomains, path, secure, expiry, name, value
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [domain, _, cookie_path, secure, _, name, value] = fields[..] else {
+            continue;
+        };
+        cookies.push_str(&format!(
+            "{}={}; path={}; domain={};{}\n",
+            name,
+            value,
+            cookie_path,
+            domain,
+            if secure.eq_ignore_ascii_case("TRUE") {
+                " secure;"
+            } else {
+                ""
+            }
+        ));
+    }
+    Ok(cookies)
+}
+
+/// Asks yt-dlp for the direct URLs of `path`, sending the cookies in
+/// `cookies_file` if given. Blocks until it exits.
+pub fn resolve_with_ytdl(path: &str, cookies_file: Option<&Path>) -> std::io::Result<StreamSource> {
+    let url = path.strip_prefix("ytdl://").unwrap_or(path);
+    debug!("[stream] Resolving {} with {}", url, ytdl());
+    let mut command = Command::new(ytdl());
+    command.args(["-g", "-f", "bv*+ba/b", "--no-playlist"]);
+    if let Some(file) = cookies_file {
+        command.arg("--cookies").arg(file);
+    }
+    let output = command.args(["--", url]).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(format!(
+            "yt-dlp failed: {}",
+            stderr.lines().last().unwrap_or_default()
+        )));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut urls = stdout.lines().map(str::trim).filter(|l| !l.is_empty());
+    let video = urls
+        .next()
+        .ok_or_else(|| std::io::Error::other("yt-dlp printed no URL"))?;
+    Ok(StreamSource {
+        video: video.to_string(),
+        audio: urls.next().map(str::to_string),
+        cookies: cookies_file.map(read_cookies_file).transpose()?,
+        ..Default::default()
+    })
+}
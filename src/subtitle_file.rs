@@ -0,0 +1,120 @@
+use crate::event_loop::Subtitle;
+
+/// Parses SRT or WebVTT cues into subtitles numbered from 1 in file order.
+///
+/// Styling tags are kept as-is; cue settings after the timestamps are
+/// ignored.
+pub fn parse(content: &str, media_path: &str, aid: i64) -> Vec<Subtitle> {
+    let content = content.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    let mut subs = Vec::new();
+
+    for block in content.split("\n\n") {
+        let mut lines = block.lines().skip_while(|l| !l.contains("-->"));
+        let Some(timing) = lines.next() else {
+            continue;
+        };
+        let Some((start, end)) = parse_timing(timing) else {
+            continue;
+        };
+        let text = lines.collect::<Vec<_>>().join("\n");
+        if text.trim().is_empty() {
+            continue;
+        }
+        subs.push(Subtitle {
+            id: subs.len() as u64 + 1,
+            text,
+            sub_start: start,
+            sub_end: end,
+            media_path: media_path.to_string(),
+            aid,
+            ..Default::default()
+        });
+    }
+    subs
+}
+
+fn parse_timing(line: &str) -> Option<(f64, f64)> {
+    let (start, rest) = line.split_once("-->")?;
+    let end = rest.split_whitespace().next()?;
+    Some((parse_timestamp(start.trim())?, parse_timestamp(end)?))
+}
+
+/// Accepts `HH:MM:SS,mmm`, `HH:MM:SS.mmm` and `MM:SS.mmm`.
+fn parse_timestamp(ts: &str) -> Option<f64> {
+    let ts = ts.replace(',', ".");
+    let parts: Vec<&str> = ts.split(':').collect();
+    let (h, m, s) = match parts.as_slice() {
+        [h, m, s] => (h.parse::<f64>().ok()?, m.parse::<f64>().ok()?, s),
+        [m, s] => (0.0, m.parse::<f64>().ok()?, s),
+        _ => return None,
+    };
+    Some(h * 3600.0 + m * 60.0 + s.parse::<f64>().ok()?)
+}
+
+/// Subtitle file formats we can write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubtitleFormat {
+    Srt,
+    Vtt,
+}
+
+impl std::str::FromStr for SubtitleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "srt" => Ok(Self::Srt),
+            "vtt" | "webvtt" => Ok(Self::Vtt),
+            _ => Err(format!("unknown subtitle format '{}'", s)),
+        }
+    }
+}
+
+/// Writes `subs` as a subtitle file, ordered by start time and renumbered
+/// from 1.
+pub fn write(subs: &[Subtitle], format: SubtitleFormat) -> String {
+    let mut subs: Vec<_> = subs.iter().collect();
+    subs.sort_by(|a, b| a.sub_start.total_cmp(&b.sub_start));
+
+    let mut out = String::new();
+    if format == SubtitleFormat::Vtt {
+        out.push_str("WEBVTT\n\n");
+    }
+    for (i, sub) in subs.iter().enumerate() {
+        // A blank line would end the cue early
+        let text = sub
+            .text
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        if format == SubtitleFormat::Srt {
+            out.push_str(&format!("{}\n", i + 1));
+        }
+        out.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            format_timestamp(sub.sub_start, format),
+            format_timestamp(sub.sub_end, format),
+            text
+        ));
+    }
+    out
+}
+
+/// `HH:MM:SS,mmm` for SRT, `HH:MM:SS.mmm` for WebVTT.
+pub(crate) fn format_timestamp(seconds: f64, format: SubtitleFormat) -> String {
+    let ms = (seconds.max(0.0) * 1000.0).round() as u64;
+    let separator = if format == SubtitleFormat::Srt {
+        ','
+    } else {
+        '.'
+    };
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        separator,
+        ms % 1000
+    )
+}
@@ -0,0 +1,75 @@
+//! Ordered chapters: mpv plays the chapters of such files in the order their
+//! edition lists them, skipping or repeating parts, so times on mpv's
+//! timeline are not positions in the file ffmpeg reads.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use tracing::info;
+
+use crate::event_loop::Subtitle;
+
+/// Chapters that start within this of their place in the file are taken to
+/// be played in place.
+const TOLERANCE: f64 = 0.1;
+
+/// A chapter as mpv plays it.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    /// Start on mpv's timeline
+    start: f64,
+    /// Added to a time on mpv's timeline to get the time in the file
+    offset: f64,
+}
+
+static TIMELINES: OnceLock<Mutex<HashMap<String, Vec<Segment>>>> = OnceLock::new();
+
+fn timelines() -> &'static Mutex<HashMap<String, Vec<Segment>>> {
+    TIMELINES.get_or_init(Default::default)
+}
+
+/// Matches the chapter starts mpv reports for `path` with the chapters'
+/// `(start, end)` in the file. False if they can't be matched up, e.g. when
+/// some chapters come from other files.
+pub fn register(path: &str, timeline: &[f64], file: &[(f64, f64)]) -> bool {
+    if timeline.len() != file.len() {
+        return false;
+    }
+    let segments: Vec<_> = timeline
+        .iter()
+        .zip(file)
+        .map(|(&start, &(file_start, _))| Segment {
+            start,
+            offset: file_start - start,
+        })
+        .collect();
+
+    let mut timelines = timelines().lock().unwrap();
+    if segments.iter().all(|s| s.offset.abs() < TOLERANCE) {
+        timelines.remove(path);
+    } else {
+        info!("[timeline] {} plays its chapters out of order", path);
+        timelines.insert(path.to_string(), segments);
+    }
+    true
+}
+
+/// Where `time` on mpv's timeline for `path` is in the file itself.
+pub fn to_file(path: &str, time: f64) -> f64 {
+    let timelines = timelines().lock().unwrap();
+    let offset = timelines
+        .get(path)
+        .and_then(|segments| segments.iter().rev().find(|s| s.start <= time + TOLERANCE))
+        .map_or(0.0, |s| s.offset);
+    time + offset
+}
+
+/// `sub` with its times moved into the file, see [`to_file`]. Keeps the
+/// line's length even if it runs into the next chapter.
+pub(crate) fn on_file(sub: &Subtitle) -> Subtitle {
+    let start = to_file(&sub.media_path, sub.sub_start);
+    Subtitle {
+        sub_start: start,
+        sub_end: start + (sub.sub_end - sub.sub_start),
+        ..sub.clone()
+    }
+}
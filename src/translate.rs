@@ -0,0 +1,251 @@
+//! Machine translation of lines, shown next to the original and put on
+//! mined notes.
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+
+pub type TranslateResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const DEFAULT_LIBRETRANSLATE_URL: &str = "https://libretranslate.com";
+const DEEPL_URL: &str = "https://api.deepl.com";
+/// DeepL's free plan has its own host; its keys end in `:fx`.
+const DEEPL_FREE_URL: &str = "https://api-free.deepl.com";
+const GOOGLE_URL: &str = "https://translation.googleapis.com";
+
+/// How long a provider gets for one line.
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Translations kept before the cache starts over.
+const MAX_CACHED: usize = 10_000;
+
+/// Where translations come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Deepl,
+    /// Google Cloud Translation (v2, API key)
+    Google,
+    LibreTranslate,
+    /// A local program reading the line on stdin and printing the
+    /// translation
+    Command,
+}
+
+impl FromStr for Provider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "deepl" => Ok(Self::Deepl),
+            "google" => Ok(Self::Google),
+            "libretranslate" => Ok(Self::LibreTranslate),
+            "command" => Ok(Self::Command),
+            _ => Err(format!(
+                "expected deepl, google, libretranslate or command, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+/// How lines are translated.
+#[derive(Debug, Clone)]
+pub struct TranslateOptions {
+    pub provider: Provider,
+    /// Language to translate into, e.g. `en`
+    pub target_lang: String,
+    /// Language of the lines; the provider detects it when unset
+    pub source_lang: Option<String>,
+    /// Needed by DeepL and Google, optional for LibreTranslate
+    pub api_key: Option<String>,
+    /// Server to use instead of the provider's public one
+    pub url: Option<String>,
+    /// Command line for [`Provider::Command`]
+    pub command: Option<String>,
+}
+
+impl TranslateOptions {
+    /// Why these options can't work, if they can't.
+    pub fn check(&self) -> Result<(), String> {
+        match self.provider {
+            Provider::Deepl | Provider::Google if self.api_key.is_none() => {
+                Err("DeepL and Google need an API key".into())
+            }
+            Provider::Command if self.command.is_none() => {
+                Err("the command provider needs a command line".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Translates lines with a provider, remembering what it already asked for.
+pub struct Translator {
+    options: TranslateOptions,
+    client: reqwest::Client,
+    cache: Mutex<HashMap<String, String>>,
+}
+
+impl Translator {
+    pub fn new(options: TranslateOptions) -> Self {
+        Self {
+            options,
+            client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            cache: Mutex::default(),
+        }
+    }
+
+    /// `text` in the target language.
+    pub async fn translate(&self, text: &str) -> TranslateResult<String> {
+        if let Some(cached) = self.cache.lock().unwrap().get(text) {
+            return Ok(cached.clone());
+        }
+        debug!("[translate] {:?}: {}", self.options.provider, text);
+        let translation = match self.options.provider {
+            Provider::Deepl => self.deepl(text).await?,
+            Provider::Google => self.google(text).await?,
+            Provider::LibreTranslate => self.libretranslate(text).await?,
+            Provider::Command => self.command(text).await?,
+        };
+        let translation = translation.trim().to_string();
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.clear();
+        }
+        cache.insert(text.to_string(), translation.clone());
+        Ok(translation)
+    }
+
+    async fn deepl(&self, text: &str) -> TranslateResult<String> {
+        let key = self.options.api_key.as_deref().unwrap_or_default();
+        let base = self
+            .options
+            .url
+            .as_deref()
+            .unwrap_or(if key.ends_with(":fx") {
+                DEEPL_FREE_URL
+            } else {
+                DEEPL_URL
+            });
+        let mut body = serde_json::json!({
+            "text": [text],
+            "target_lang": self.options.target_lang.to_ascii_uppercase(),
+        });
+        if let Some(source) = &self.options.source_lang {
+            body["source_lang"] = source.to_ascii_uppercase().into();
+        }
+        let response: serde_json::Value = self
+            .client
+            .post(format!("{}/v2/translate", base.trim_end_matches('/')))
+            .header("Authorization", format!("DeepL-Auth-Key {}", key))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        response["translations"][0]["text"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| "DeepL returned no translation".into())
+    }
+
+    async fn google(&self, text: &str) -> TranslateResult<String> {
+        let base = self.options.url.as_deref().unwrap_or(GOOGLE_URL);
+        let mut body = serde_json::json!({
+            "q": text,
+            "target": self.options.target_lang,
+            "format": "text",
+        });
+        if let Some(source) = &self.options.source_lang {
+            body["source"] = source.as_str().into();
+        }
+        let response: serde_json::Value = self
+            .client
+            .post(format!(
+                "{}/language/translate/v2",
+                base.trim_end_matches('/')
+            ))
+            .header(
+                "X-Goog-Api-Key",
+                self.options.api_key.as_deref().unwrap_or_default(),
+            )
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        response["data"]["translations"][0]["translatedText"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| "Google returned no translation".into())
+    }
+
+    async fn libretranslate(&self, text: &str) -> TranslateResult<String> {
+        let base = self
+            .options
+            .url
+            .as_deref()
+            .unwrap_or(DEFAULT_LIBRETRANSLATE_URL);
+        let mut body = serde_json::json!({
+            "q": text,
+            "source": self.options.source_lang.as_deref().unwrap_or("auto"),
+            "target": self.options.target_lang,
+            "format": "text",
+        });
+        if let Some(key) = &self.options.api_key {
+            body["api_key"] = key.as_str().into();
+        }
+        let response: serde_json::Value = self
+            .client
+            .post(format!("{}/translate", base.trim_end_matches('/')))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        response["translatedText"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| "LibreTranslate returned no translation".into())
+    }
+
+    async fn command(&self, text: &str) -> TranslateResult<String> {
+        let command_line = self.options.command.as_deref().unwrap_or_default();
+        let args = shlex::split(command_line)
+            .filter(|args| !args.is_empty())
+            .ok_or("invalid --translate-command")?;
+        let mut child = tokio::process::Command::new(&args[0])
+            .args(&args[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes()).await?;
+        }
+        let output = tokio::time::timeout(TIMEOUT, child.wait_with_output())
+            .await
+            .map_err(|_| "translation command timed out")??;
+        if !output.status.success() {
+            return Err(format!(
+                "translation command failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}